@@ -0,0 +1,69 @@
+//! `ShardedHeap` against `LockedHeap`, with each thread sticking to a size
+//! class of its own, which is the case the lock per order is meant for.
+//! Run with `cargo bench --bench sharded`.
+#![feature(test)]
+extern crate test;
+
+use buddyalloc::{Heap, LockedHeap, ShardedHeap};
+use std::alloc::{GlobalAlloc, Layout};
+use std::ptr::NonNull;
+use test::Bencher;
+
+const HEAP_SIZE: usize = 1 << 20;
+const ROUNDS: usize = 100;
+const BURST: usize = 32;
+
+/// Have `threads` threads each allocate and free bursts of blocks of their
+/// own size class: 16, 32, 64 or 128 bytes.
+fn churn(heap: &(impl GlobalAlloc + Sync), threads: usize) {
+    std::thread::scope(|s| {
+        for t in 0..threads {
+            s.spawn(move || unsafe {
+                let layout = Layout::from_size_align(16 << (t % 4), 8).unwrap();
+                let mut live = Vec::with_capacity(BURST);
+                for _ in 0..ROUNDS {
+                    for _ in 0..BURST {
+                        let p = heap.alloc(layout);
+                        assert!(!p.is_null());
+                        live.push(p);
+                    }
+                    for p in live.drain(..) {
+                        heap.dealloc(p, layout);
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Run `churn` under `b` on a fresh heap wrapped by `share`.
+fn bench<H: GlobalAlloc + Sync>(b: &mut Bencher, threads: usize, share: fn(Heap<16>) -> H) {
+    let layout = Layout::from_size_align(HEAP_SIZE, 4096).unwrap();
+    unsafe {
+        let mem = std::alloc::alloc(layout);
+        let heap = share(Heap::new(NonNull::new(mem).unwrap(), HEAP_SIZE).unwrap());
+        b.iter(|| churn(&heap, threads));
+        drop(heap);
+        std::alloc::dealloc(mem, layout);
+    }
+}
+
+#[bench]
+fn locked_1_thread(b: &mut Bencher) {
+    bench(b, 1, LockedHeap::new);
+}
+
+#[bench]
+fn sharded_1_thread(b: &mut Bencher) {
+    bench(b, 1, ShardedHeap::new);
+}
+
+#[bench]
+fn locked_4_threads(b: &mut Bencher) {
+    bench(b, 4, LockedHeap::new);
+}
+
+#[bench]
+fn sharded_4_threads(b: &mut Bencher) {
+    bench(b, 4, ShardedHeap::new);
+}
@@ -0,0 +1,49 @@
+//! Builds the C side of the FreeRTOS smoke test (`tests/freertos.rs`) into
+//! a static library which only that test links.  Nothing else needs a C
+//! compiler, so this does nothing unless the `freertos` feature is on and
+//! the tests could run here.
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    if env::var_os("CARGO_FEATURE_FREERTOS").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=tests/freertos/smoke.c");
+
+    // Not when cross-compiling firmware, or building from a package
+    // without the tests.
+    let source = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap())
+        .join("tests")
+        .join("freertos")
+        .join("smoke.c");
+    if env::var("HOST") != env::var("TARGET") || !source.exists() {
+        return;
+    }
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let object = out_dir.join("smoke.o");
+    let library = out_dir.join("libfreertos_smoke.a");
+    let cc = env::var_os("CC").unwrap_or_else(|| "cc".into());
+    let built = Command::new(cc)
+        .args(["-c", "-Wall", "-Werror", "-o"])
+        .arg(&object)
+        .arg(&source)
+        .status()
+        .is_ok_and(|status| status.success())
+        && Command::new("ar")
+            .arg("crs")
+            .arg(&library)
+            .arg(&object)
+            .status()
+            .is_ok_and(|status| status.success());
+    if !built {
+        println!("cargo:warning=couldn't build the FreeRTOS smoke test, so it won't run");
+        return;
+    }
+
+    println!("cargo:rustc-link-search=native={}", out_dir.display());
+    println!("cargo:rustc-cfg=freertos_smoke");
+}
@@ -0,0 +1,47 @@
+use buddyalloc::Heap;
+use std::{alloc::Layout, ptr::NonNull};
+
+/// Overwrite every byte of the heap's free memory, as a security policy
+/// might require at shutdown.  Volatile writes keep the compiler from
+/// deciding that memory nobody reads again needn't be written.
+fn scrub<const N: usize>(heap: &mut Heap<N>) -> usize {
+    let mut scrubbed = 0;
+    heap.for_each_free_block(|block, size| {
+        for i in 0..size {
+            unsafe { block.add(i).write_volatile(0) };
+        }
+        scrubbed += size;
+    });
+    scrubbed
+}
+
+fn main() {
+    // Allocate the backing memory for our heap. This memory _MUST_
+    // be aligned by at least 4096.
+    let layout = Layout::from_size_align(16384, 4096).unwrap();
+    let mem = unsafe { std::alloc::alloc(layout) };
+    let mut heap: Heap<10> = unsafe { Heap::new(NonNull::new(mem).unwrap(), 16384) }.unwrap();
+
+    // Leave a secret behind in freed memory.
+    let secret = Layout::from_size_align(64, 16).unwrap();
+    let key = heap.allocate(secret).unwrap();
+    let keep = heap.allocate(secret).unwrap();
+    unsafe {
+        key.write_bytes(0x5a, 64);
+        heap.deallocate(key, secret);
+    }
+
+    let scrubbed = scrub(&mut heap);
+    println!("scrubbed {} free bytes", scrubbed);
+    // All of it but the free list pointer at the start of the block.
+    let header = std::mem::size_of::<usize>();
+    assert!((header..64).all(|i| unsafe { *key.add(i) } == 0));
+
+    // The heap carries on as before.
+    unsafe { heap.deallocate(keep, secret) };
+    assert_eq!(0, heap.used_bytes());
+
+    unsafe {
+        std::alloc::dealloc(mem, layout);
+    }
+}
@@ -0,0 +1,134 @@
+"""GDB helper for inspecting buddyalloc heaps on a halted target.
+
+Build with the `debug-info` feature, then in GDB:
+
+    (gdb) source scripts/buddyalloc_gdb.py
+    (gdb) buddyalloc ALLOCATOR
+
+where ALLOCATOR is the symbol (or any expression) naming the static that
+holds the heap.  It can be a bare `Heap` or a wrapper such as `LockedHeap`;
+the heap's debug info is found by searching the object for its magic number.
+
+This reads the layout documented in `src/debug_info.rs`, version 1.
+"""
+
+import struct
+
+import gdb
+
+MAGIC = 0x59445542
+VERSION = 1
+FAILURE_REASONS = {
+    0: "none",
+    1: "heap exhausted",
+    2: "bad alignment",
+    3: "too large",
+}
+
+
+def _target_info():
+    ptr_size = gdb.lookup_type("void").pointer().sizeof
+    endian = "<" if "little" in gdb.execute("show endian", to_string=True) else ">"
+    return ptr_size, endian
+
+
+def _read(addr, size):
+    return bytes(gdb.selected_inferior().read_memory(addr, size))
+
+
+def _word(addr, ptr_size, endian):
+    fmt = endian + ("Q" if ptr_size == 8 else "I")
+    return struct.unpack(fmt, _read(addr, ptr_size))[0]
+
+
+def find_debug_info(addr, size):
+    """Find the address of the debug info within `size` bytes at `addr`."""
+    _, endian = _target_info()
+    data = _read(addr, size)
+    for offset in range(0, size - 7, 4):
+        magic, version = struct.unpack_from(endian + "II", data, offset)
+        if magic == MAGIC:
+            if version != VERSION:
+                raise gdb.GdbError(
+                    "buddyalloc debug info version %d is not supported (expected %d)"
+                    % (version, VERSION)
+                )
+            return addr + offset
+    raise gdb.GdbError(
+        "no buddyalloc debug info found; was it built with the debug-info feature?"
+    )
+
+
+def parse_debug_info(addr):
+    """Parse the debug info at `addr` into a dict."""
+    ptr_size, endian = _target_info()
+
+    def word(i):
+        return _word(addr + 8 + i * ptr_size, ptr_size, endian)
+
+    info = {
+        "heap_base": word(0),
+        "heap_size": word(1),
+        "min_block_size": word(2),
+        "num_orders": word(3),
+        "free_lists": addr + word(4),
+        "failure_size": word(5),
+        "failure_align": word(6),
+        "failure_reason": word(7),
+    }
+    info["free_counts"] = [word(8 + i) for i in range(info["num_orders"])]
+    return info
+
+
+def walk_free_list(info, order, limit=64):
+    """Return the addresses on the free list for `order`, up to `limit`."""
+    ptr_size, endian = _target_info()
+    block = _word(info["free_lists"] + order * ptr_size, ptr_size, endian)
+    blocks = []
+    while block != 0 and len(blocks) < limit:
+        blocks.append(block)
+        # The whole-heap block's `next` pointer is never valid.
+        if order == info["num_orders"] - 1:
+            break
+        block = _word(block, ptr_size, endian)
+    return blocks
+
+
+class BuddyallocCommand(gdb.Command):
+    """Print a summary of a buddyalloc heap: buddyalloc EXPRESSION"""
+
+    def __init__(self):
+        super().__init__("buddyalloc", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        if not arg:
+            raise gdb.GdbError("usage: buddyalloc EXPRESSION")
+
+        value = gdb.parse_and_eval(arg)
+        addr = int(value.address)
+        info = parse_debug_info(find_debug_info(addr, value.type.sizeof))
+
+        base = info["heap_base"]
+        print("heap at %#x, %d bytes, min block %d bytes, %d orders" % (
+            base, info["heap_size"], info["min_block_size"], info["num_orders"]))
+
+        free_bytes = 0
+        for order, count in enumerate(info["free_counts"]):
+            size = info["min_block_size"] << order
+            free_bytes += count * size
+            blocks = walk_free_list(info, order)
+            offsets = ", ".join("+%#x" % (b - base) for b in blocks)
+            if count > len(blocks):
+                offsets += ", ..."
+            print("  order %2d (%8d bytes): %4d free  %s" % (order, size, count, offsets))
+
+        print("free: %d of %d bytes" % (free_bytes, info["heap_size"]))
+
+        reason = info["failure_reason"]
+        if reason != 0:
+            print("last failure: %s (size %d, align %d)" % (
+                FAILURE_REASONS.get(reason, "unknown (%d)" % reason),
+                info["failure_size"], info["failure_align"]))
+
+
+BuddyallocCommand()
@@ -0,0 +1,39 @@
+//! A minimal `thumbv6m-none-eabi` program that exercises the heap with two
+//! different values of `N`, so the measured size includes any code that
+//! gets duplicated per instantiation.
+#![no_std]
+#![no_main]
+
+use buddyalloc::Heap;
+use core::alloc::Layout;
+use core::panic::PanicInfo;
+use core::ptr::{self, NonNull};
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
+
+#[repr(align(4096))]
+struct Arena([u8; 8192]);
+
+static mut ARENA: Arena = Arena([0; 8192]);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let base = unsafe { ptr::addr_of_mut!(ARENA.0) as *mut u8 };
+
+    let mut small: Heap<4> = unsafe { Heap::new(NonNull::new(base).unwrap(), 4096) }.unwrap();
+    let mut large: Heap<8> =
+        unsafe { Heap::new(NonNull::new(base.wrapping_add(4096)).unwrap(), 4096) }.unwrap();
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    loop {
+        if let Ok(p) = small.allocate(layout) {
+            unsafe { small.deallocate(ptr::read_volatile(&p), layout) };
+        }
+        if let Ok(p) = large.allocate(layout) {
+            unsafe { large.deallocate(ptr::read_volatile(&p), layout) };
+        }
+    }
+}
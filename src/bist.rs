@@ -0,0 +1,314 @@
+//! A built-in self-test for the memory under the heap: fill the free
+//! blocks with a known pattern, then check that it reads back intact.
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+use crate::heap::{free_list_for_each, FreeBlock};
+use crate::{Heap, HeapBuilder, HeapError};
+
+/// The patterns [Heap::new_verified] and [crate::HeapBuilder::build_tested]
+/// write, which between them set and
+/// clear every bit.
+pub(crate) const VERIFY_PATTERNS: [usize; 2] = [
+    0x5555_5555_5555_5555_u64 as usize,
+    !(0x5555_5555_5555_5555_u64 as usize),
+];
+
+impl<const N: usize> Heap<N> {
+    /// Create a new heap like [Heap::new], but first check that the memory
+    /// can hold free list pointers: at every place a free block header can
+    /// ever be (the start of each `min_block_size` block), write a couple
+    /// of test patterns and read them back.  This catches stuck bits at
+    /// start-up, rather than when a corrupted free list is followed later.
+    ///
+    /// Returns [HeapError::MemoryVerificationFailed] with the address of
+    /// the first word that didn't read back correctly.
+    ///
+    /// # Safety
+    /// As for [Heap::new].
+    pub unsafe fn new_verified(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+    ) -> Result<Self, HeapError> {
+        Self::new_verified_with(heap_base, heap_size, |word, pattern| {
+            ptr::write_volatile(word, pattern);
+            ptr::read_volatile(word)
+        })
+    }
+
+    /// [Heap::new_verified], with `probe` writing a pattern to a word and
+    /// returning what reads back, so tests can simulate bad memory.
+    unsafe fn new_verified_with(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+        mut probe: impl FnMut(*mut usize, usize) -> usize,
+    ) -> Result<Self, HeapError> {
+        let heap = Self::new(heap_base, heap_size)?;
+
+        let mut offset = 0;
+        while offset < heap_size {
+            let word = heap_base.as_ptr().add(offset) as *mut usize;
+            for pattern in VERIFY_PATTERNS {
+                if probe(word, pattern) != pattern {
+                    return Err(HeapError::MemoryVerificationFailed(word as usize));
+                }
+            }
+            offset += heap.min_block_size;
+        }
+        Ok(heap)
+    }
+
+    /// Create a new heap like [Heap::new], with [HeapBuilder::ecc_test]
+    /// set to `ecc_check`, so that blocks which fail it are left out.
+    ///
+    /// Returns the heap along with the number of bytes left out, which is
+    /// zero if every block passed.
+    ///
+    /// # Safety
+    /// As for [Heap::new].  `ecc_check` gets exclusive use of each block
+    /// while it runs.
+    pub unsafe fn new_with_ecc_test(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+        ecc_check: fn(*mut u8, usize) -> bool,
+    ) -> Result<(Self, usize), HeapError> {
+        let mut unusable = 0;
+        let heap = HeapBuilder::new()
+            .base(heap_base)
+            .size(heap_size)
+            .ecc_test(ecc_check)
+            .build_excluding(&[], &mut unusable)?;
+        Ok((heap, unusable))
+    }
+
+    /// Fill the data region of every free block (everything after its
+    /// free list header) with `pattern`, repeated as many times as needed.
+    /// The pattern restarts at the beginning of each block's data region.
+    ///
+    /// Together with [Heap::verify_known_data] this can be used to test
+    /// RAM without any external tools.  Allocated blocks aren't touched,
+    /// and an empty pattern writes nothing.
+    pub fn populate_with_known_data(&mut self, pattern: &[u8]) {
+        if pattern.is_empty() {
+            return;
+        }
+
+        self.for_each_free_region(|region, len| {
+            for (i, &byte) in pattern.iter().cycle().take(len).enumerate() {
+                // SAFETY: The region is free memory owned by the heap.
+                // Volatile, so the writes really reach memory.
+                unsafe { ptr::write_volatile(region.add(i), byte) };
+            }
+        });
+    }
+
+    /// Read back the pattern written by [Heap::populate_with_known_data]
+    /// and return the number of bytes that don't match.  This must be
+    /// given the same pattern, and nothing may have been allocated or
+    /// freed in between.
+    pub fn verify_known_data(&self, pattern: &[u8]) -> usize {
+        if pattern.is_empty() {
+            return 0;
+        }
+
+        let mut mismatches = 0;
+        self.for_each_free_region(|region, len| {
+            for (i, &byte) in pattern.iter().cycle().take(len).enumerate() {
+                // SAFETY: As above.
+                if unsafe { ptr::read_volatile(region.add(i)) } != byte {
+                    mismatches += 1;
+                }
+            }
+        });
+        mismatches
+    }
+
+    /// Call `f` with the start and length of the data region of every free
+    /// block.
+    fn for_each_free_region(&self, mut f: impl FnMut(*mut u8, usize)) {
+        let header = size_of::<FreeBlock>();
+        for order in 0..N {
+            let len = self.order_size(order) - header;
+            free_list_for_each(&self.free_lists, order, |block| {
+                f(block.wrapping_add(header), len)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+
+    #[test]
+    fn test_known_data() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let pattern = [0xa5, 0x5a, 0x00, 0xff, 0x3c];
+
+            // One big free block.
+            heap.populate_with_known_data(&pattern);
+            assert_eq!(0, heap.verify_known_data(&pattern));
+            assert_eq!(
+                heap_size - size_of::<FreeBlock>(),
+                heap.verify_known_data(&[0x11])
+            );
+
+            // Blocks of many different orders, some of them allocated.
+            let block = Layout::from_size_align(16, 16).unwrap();
+            let a = heap.allocate(block).unwrap();
+            let b = heap
+                .allocate(Layout::from_size_align(100, 4).unwrap())
+                .unwrap();
+            heap.populate_with_known_data(&pattern);
+            assert_eq!(0, heap.verify_known_data(&pattern));
+
+            // Allocated blocks aren't touched.
+            a.write_bytes(0x77, 16);
+            assert_eq!(0, heap.verify_known_data(&pattern));
+
+            // A flipped bit in a free block is caught.
+            let free = heap.free_lists[4] as *mut u8;
+            assert!(!free.is_null());
+            let victim = free.add(size_of::<FreeBlock>() + 7);
+            *victim ^= 0x10;
+            assert_eq!(1, heap.verify_known_data(&pattern));
+
+            // Empty patterns are a no-op.
+            heap.populate_with_known_data(&[]);
+            assert_eq!(0, heap.verify_known_data(&[]));
+
+            heap.deallocate(a, block);
+            heap.deallocate(b, Layout::from_size_align(100, 4).unwrap());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_new_verified() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+
+            let mut heap = Heap::<9>::new_verified(base, heap_size).unwrap();
+            assert_eq!(
+                Ok(mem),
+                heap.allocate(Layout::from_size_align(16, 16).unwrap())
+            );
+            drop(heap);
+
+            // Memory with bit 3 stuck low at offset 0x230.
+            let bad = mem.add(0x230) as *mut usize;
+            let mut probed = 0;
+            let result = Heap::<9>::new_verified_with(base, heap_size, |word, pattern| {
+                probed += 1;
+                word.write(pattern);
+                if word == bad {
+                    pattern & !(1 << 3)
+                } else {
+                    pattern
+                }
+            });
+            assert_eq!(
+                HeapError::MemoryVerificationFailed(bad as usize),
+                result.unwrap_err()
+            );
+            // Everything below the bad word was checked with both patterns.
+            assert_eq!(0x230 / 16 * 2 + 2, probed);
+
+            // Construction errors are still reported.
+            assert_eq!(
+                HeapError::BadSizeAlignment,
+                Heap::<9>::new_verified(base, 4000).unwrap_err()
+            );
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    std::thread_local! {
+        /// The blocks [failing_ecc_check] fails, and how often it's called.
+        static BAD_BLOCKS: core::cell::RefCell<std::vec::Vec<usize>> = Default::default();
+        static ECC_CHECKS: core::cell::Cell<usize> = Default::default();
+    }
+
+    fn failing_ecc_check(block: *mut u8, size: usize) -> bool {
+        assert_eq!(16, size);
+        ECC_CHECKS.with(|checks| checks.set(checks.get() + 1));
+        BAD_BLOCKS.with(|bad| !bad.borrow().contains(&(block as usize)))
+    }
+
+    #[test]
+    fn test_new_with_ecc_test() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+
+            // Every block passes: one big free block, as from `new`.
+            let (heap, unusable) =
+                Heap::<9>::new_with_ecc_test(base, heap_size, failing_ecc_check).unwrap();
+            assert_eq!(0, unusable);
+            assert_eq!(256, ECC_CHECKS.with(|checks| checks.replace(0)));
+            assert!(heap.is_block_free(8, mem));
+            drop(heap);
+
+            // Three blocks fail, two of them buddies.
+            let bad = [mem.add(0x100), mem.add(0x110), mem.add(0xff0)];
+            BAD_BLOCKS.with(|b| b.borrow_mut().extend(bad.iter().map(|&b| b as usize)));
+            let (mut heap, unusable) =
+                Heap::<9>::new_with_ecc_test(base, heap_size, failing_ecc_check).unwrap();
+            assert_eq!(48, unusable);
+            assert_eq!(256, ECC_CHECKS.with(|checks| checks.replace(0)));
+            assert_eq!(48, heap.reserved_bytes());
+            assert_eq!(48, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            // The good blocks around them are merged as far as they can be.
+            assert!(heap.is_block_free(6, mem.add(0x800)));
+            assert!(heap.is_block_free(6, mem.add(0x400)));
+            assert!(heap.is_block_free(4, mem));
+            assert!(heap.is_block_free(1, mem.add(0x120)));
+            assert!(heap.is_block_free(0, mem.add(0xfe0)));
+
+            // None of the bad blocks are ever handed out.
+            let block = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks = std::vec::Vec::new();
+            while let Ok(b) = heap.allocate(block) {
+                assert!(!bad.contains(&b));
+                blocks.push(b);
+            }
+            assert_eq!(256 - 3, blocks.len());
+            for b in blocks {
+                heap.deallocate(b, block);
+            }
+            assert_eq!(48, heap.used_bytes());
+            assert!(heap.accounting_check());
+            drop(heap);
+            BAD_BLOCKS.with(|b| b.borrow_mut().clear());
+
+            // Construction errors come before any checks.
+            assert_eq!(
+                HeapError::BadSizeAlignment,
+                Heap::<9>::new_with_ecc_test(base, 4000, failing_ecc_check).unwrap_err()
+            );
+            assert_eq!(0, ECC_CHECKS.with(|checks| checks.get()));
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
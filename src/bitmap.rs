@@ -0,0 +1,187 @@
+//! A compact bitmap of which parts of a heap are allocated, one bit per
+//! minimum-sized block, for saving a heap's layout or showing it off.
+use core::ptr;
+
+use crate::heap::{free_list_for_each, free_list_insert};
+use crate::Heap;
+
+impl<const N: usize> Heap<N> {
+    /// The number of words in an allocation map: one bit for each of the
+    /// `2^(N - 1)` minimum-sized blocks, rounded up to whole words.
+    pub const ALLOCATION_MAP_WORDS: usize = (1usize << (N - 1)).div_ceil(usize::BITS as usize);
+
+    /// Build a bitmap of the heap with one bit per minimum-sized block,
+    /// set if the block is part of an allocation, and clear if it's free.
+    /// Block `i` is bit `i % usize::BITS` of word `i / usize::BITS`, and
+    /// any bits past the last block are clear.  Blocks held in an
+    /// emergency reserve count as allocated.
+    ///
+    /// `WORDS` must be [Heap::ALLOCATION_MAP_WORDS], which is checked at
+    /// compile time.
+    pub fn generate_allocation_map<const WORDS: usize>(&self) -> [usize; WORDS] {
+        const { assert!(WORDS == Self::ALLOCATION_MAP_WORDS) };
+
+        let mut map = [0; WORDS];
+        set_bits(&mut map, 0, 1 << (N - 1), true);
+        for order in 0..N {
+            free_list_for_each(&self.free_lists, order, |block| {
+                let first = (block as usize - self.heap_base as usize) >> self.min_block_size_log2;
+                set_bits(&mut map, first, 1 << order, false);
+            });
+        }
+        map
+    }
+
+    /// Rebuild the free lists from a bitmap made by
+    /// [Heap::generate_allocation_map], so that exactly the blocks it
+    /// marks free can be allocated.  Free blocks are merged as far as they
+    /// would have been by [Heap::deallocate], and [Heap::used_bytes] is
+    /// recounted from the map.
+    ///
+    /// An emergency reserve, if there is one, is left as it is, so the map
+    /// must mark its block as allocated.  With the `debug-track` feature,
+    /// the recorded orders of live allocations aren't restored.
+    ///
+    /// # Safety
+    /// Every block that's part of a live allocation must be marked as
+    /// allocated in `map`, or it may be handed out a second time.
+    pub unsafe fn load_allocation_map<const WORDS: usize>(&mut self, map: &[usize; WORDS]) {
+        const { assert!(WORDS == Self::ALLOCATION_MAP_WORDS) };
+
+        self.free_lists = [ptr::null_mut(); N];
+        #[cfg(feature = "occupancy-map")]
+        self.mark_occupied(self.heap_base, N - 1, true);
+
+        // Take the largest aligned run of free blocks at each point, which
+        // is how far deallocation would have merged them.
+        let blocks = 1 << (N - 1);
+        let mut used = 0;
+        let mut first = 0;
+        while first < blocks {
+            match (0..N)
+                .rev()
+                .find(|&order| first % (1 << order) == 0 && bits_clear(map, first, 1 << order))
+            {
+                Some(order) => {
+                    let block = self.heap_base.add(first << self.min_block_size_log2);
+                    free_list_insert(&mut self.free_lists, order, block);
+                    #[cfg(feature = "occupancy-map")]
+                    self.mark_occupied(block, order, false);
+                    first += 1 << order;
+                }
+                None => {
+                    used += 1;
+                    first += 1;
+                }
+            }
+        }
+
+        self.used_bytes = used << self.min_block_size_log2;
+        // Memory marked free may have been written since it was last
+        // allocated, so none of it is known to be zero any more.
+        self.zero_from = self.heap_size;
+
+        #[cfg(feature = "debug-info")]
+        self.refresh_free_counts();
+    }
+}
+
+/// Set or clear `count` bits of `map`, starting at bit `first`.
+fn set_bits(map: &mut [usize], first: usize, count: usize, value: bool) {
+    let bits = usize::BITS as usize;
+    for bit in first..first + count {
+        if let Some(word) = map.get_mut(bit / bits) {
+            if value {
+                *word |= 1 << (bit % bits);
+            } else {
+                *word &= !(1 << (bit % bits));
+            }
+        }
+    }
+}
+
+/// Returns true if none of the `count` bits of `map` from bit `first` are
+/// set.
+fn bits_clear(map: &[usize], first: usize, count: usize) -> bool {
+    let bits = usize::BITS as usize;
+    (first..first + count).all(|bit| map[bit / bits] & (1 << (bit % bits)) == 0)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+
+    #[test]
+    fn test_allocation_map() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            const WORDS: usize = Heap::<9>::ALLOCATION_MAP_WORDS;
+            assert_eq!(256 / usize::BITS as usize, WORDS);
+            assert_eq!([0; WORDS], heap.generate_allocation_map::<WORDS>());
+
+            // 16 bytes at 0, 64 at 128, and 256 at 1024: blocks 0, 8 to
+            // 11, and 64 to 79.
+            let at = |offset: usize, size: usize| {
+                let layout = Layout::from_size_align(size, 16).unwrap();
+                (mem.add(offset), layout)
+            };
+            let live = [at(0, 16), at(128, 64), at(1024, 256)];
+            for &(block, layout) in &live {
+                heap.allocate_at(block, layout).unwrap();
+            }
+
+            let map = heap.generate_allocation_map::<WORDS>();
+            let mut expected = [0usize; WORDS];
+            for block in (64..80).chain([0, 8, 9, 10, 11]) {
+                expected[block / usize::BITS as usize] |= 1 << (block % usize::BITS as usize);
+            }
+            assert_eq!(expected, map);
+
+            // Loading it into a fresh heap over the same memory gives the
+            // same free lists, and the same allocations can then be freed
+            // from it.
+            let used = heap.used_bytes();
+            let available = heap.available_orders();
+            let lists: std::vec::Vec<std::vec::Vec<_>> = (0..9)
+                .map(|order| {
+                    let mut blocks = std::vec::Vec::new();
+                    free_list_for_each(&heap.free_lists, order, |block| blocks.push(block));
+                    blocks
+                })
+                .collect();
+            drop(heap);
+
+            let mut copy: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            copy.load_allocation_map(&map);
+            assert_eq!(used, copy.used_bytes());
+            assert!(copy.accounting_check());
+            copy.assert_alignment_invariants();
+            assert_eq!(available, copy.available_orders());
+            for (order, ours) in lists.iter().enumerate() {
+                free_list_for_each(&copy.free_lists, order, |block| {
+                    assert!(ours.contains(&block))
+                });
+                assert_eq!(
+                    ours.len(),
+                    crate::heap::free_list_len(&copy.free_lists, order)
+                );
+            }
+            assert_eq!(map, copy.generate_allocation_map::<WORDS>());
+
+            for (block, layout) in live {
+                copy.deallocate(block, layout);
+            }
+            assert_eq!(0, copy.used_bytes());
+            assert!(copy.is_block_free(8, mem));
+
+            drop(copy);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
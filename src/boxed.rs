@@ -0,0 +1,163 @@
+//! A `Box`-like owning pointer into a [LockedHeap], for programs that can't
+//! use the unstable `Allocator` trait.
+use core::alloc::Layout;
+use core::fmt;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+use crate::{AllocationError, LockedHeap};
+
+/// An owned `T` stored in a [LockedHeap].  Like `Box`, the value is dropped
+/// and its memory freed when the `HeapBox` is dropped.  Create one with
+/// [LockedHeap::alloc_box].
+pub struct HeapBox<'a, T, const N: usize> {
+    ptr: NonNull<T>,
+    heap: &'a LockedHeap<N>,
+}
+
+// SAFETY: A `HeapBox` owns its `T` just like a `Box` does, and the heap it
+// frees it back to is behind a lock.
+unsafe impl<T: Send, const N: usize> Send for HeapBox<'_, T, N> {}
+unsafe impl<T: Sync, const N: usize> Sync for HeapBox<'_, T, N> {}
+
+impl<const N: usize> LockedHeap<N> {
+    /// Move `value` into a new block allocated from this heap.
+    ///
+    /// This is on [LockedHeap] rather than [crate::Heap] because each box
+    /// needs to get back into the heap to free itself, and any number of
+    /// them can be alive at once.
+    pub fn alloc_box<T>(&self, value: T) -> Result<HeapBox<'_, T, N>, AllocationError> {
+        let block = self.lock().allocate(Layout::new::<T>())? as *mut T;
+
+        // SAFETY: The heap never hands out a null block, and it's big
+        // enough and aligned for a `T`.
+        unsafe {
+            block.write(value);
+            Ok(HeapBox {
+                ptr: NonNull::new_unchecked(block),
+                heap: self,
+            })
+        }
+    }
+}
+
+impl<T, const N: usize> HeapBox<'_, T, N> {
+    /// Move the value out of the box, freeing its memory.
+    pub fn into_inner(this: Self) -> T {
+        // SAFETY: We own the value, and forget `this` so it isn't dropped
+        // twice.
+        unsafe {
+            let value = this.ptr.as_ptr().read();
+            this.free();
+            mem::forget(this);
+            value
+        }
+    }
+
+    /// Return the box's memory to the heap.
+    ///
+    /// # Safety
+    /// The value must already have been dropped or moved out.
+    unsafe fn free(&self) {
+        self.heap
+            .lock()
+            .deallocate(self.ptr.as_ptr() as *mut u8, Layout::new::<T>());
+    }
+}
+
+impl<T, const N: usize> Deref for HeapBox<'_, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The box owns a live `T`.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, const N: usize> DerefMut for HeapBox<'_, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: As above, and we have `&mut self`.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, const N: usize> Drop for HeapBox<'_, T, N> {
+    fn drop(&mut self) {
+        // SAFETY: The box owns a live `T`, which isn't used again.
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            self.free();
+        }
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for HeapBox<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::Heap;
+    use core::cell::Cell;
+
+    /// Counts how many times it's been dropped.
+    struct DropCounter<'a>(&'a Cell<usize>, [u8; 40]);
+
+    impl Drop for DropCounter<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_heap_box() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: LockedHeap<5> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            {
+                let drops = Cell::new(0);
+                let mut a = heap.alloc_box(7u64).unwrap();
+                let b = heap.alloc_box(DropCounter(&drops, [1; 40])).unwrap();
+                *a += 1;
+                assert_eq!(8, *a);
+                assert_eq!([1; 40], b.1);
+
+                // `b` rounds up to a 64-byte block.
+                assert_eq!(16 + 64, heap.lock().used_bytes());
+
+                drop(b);
+                assert_eq!(1, drops.get());
+                assert_eq!(16, heap.lock().used_bytes());
+
+                // Moving the value out frees the block without dropping it.
+                let c = heap.alloc_box(DropCounter(&drops, [2; 40])).unwrap();
+                let inner = HeapBox::into_inner(c);
+                assert_eq!(1, drops.get());
+                assert_eq!(16, heap.lock().used_bytes());
+                drop(inner);
+                assert_eq!(2, drops.get());
+
+                // The heap is too small for this.
+                assert_eq!(
+                    Err(AllocationError::HeapExhausted),
+                    heap.alloc_box([0u8; 256]).map(|_| ())
+                );
+            }
+            assert_eq!(0, heap.lock().used_bytes());
+            assert!(heap.lock().accounting_check());
+            heap.lock().assert_alignment_invariants();
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Branded heaps, whose allocations can only be freed back into the heap
+//! they came from, checked at compile time.
+use core::alloc::Layout;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use crate::{AllocationError, Heap};
+
+/// The brand of a [BrandedHeap] and its allocations.  `'id` is invariant,
+/// so the compiler can't shorten or lengthen it to make two brands match.
+type Brand<'id> = PhantomData<fn(&'id ()) -> &'id ()>;
+
+/// A [Heap] whose allocations are [BrandedPtr]s carrying its brand, `'id`.
+/// Each call to [Heap::with_brand] makes up a new `'id` which no other heap
+/// has, so handing one heap's allocation to another heap's
+/// [BrandedHeap::deallocate] doesn't compile:
+///
+/// ```compile_fail,E0521
+/// # use buddyalloc::Heap;
+/// # use core::alloc::Layout;
+/// # use core::ptr::NonNull;
+/// # #[repr(align(4096))]
+/// # struct Memory([u8; 4096]);
+/// # let a = Box::leak(Box::new(Memory([0; 4096])));
+/// # let b = Box::leak(Box::new(Memory([0; 4096])));
+/// # let heap_a: Heap<5> = unsafe { Heap::new(NonNull::from(&mut a.0).cast(), 4096) }.unwrap();
+/// # let heap_b: Heap<5> = unsafe { Heap::new(NonNull::from(&mut b.0).cast(), 4096) }.unwrap();
+/// let layout = Layout::from_size_align(64, 8).unwrap();
+/// heap_a.with_brand(|mut a| {
+///     heap_b.with_brand(|mut b| {
+///         let block = a.allocate(layout).unwrap();
+///         b.deallocate(block); // error[E0521]: borrowed data escapes outside of closure
+///     })
+/// });
+/// ```
+///
+/// A [BrandedPtr] isn't `Copy` or `Clone`, and freeing one consumes it, so
+/// it can't be freed twice either.  It also remembers its layout.  With
+/// all of that checked, [BrandedHeap::deallocate] is safe.
+///
+/// ```
+/// # use buddyalloc::Heap;
+/// # use core::alloc::Layout;
+/// # use core::ptr::NonNull;
+/// # #[repr(align(4096))]
+/// # struct Memory([u8; 4096]);
+/// # let memory = Box::leak(Box::new(Memory([0; 4096])));
+/// let heap: Heap<6> = unsafe { Heap::new(NonNull::from(&mut memory.0).cast(), 4096) }.unwrap();
+/// let used = heap.with_brand(|mut heap| {
+///     let block = heap.allocate(Layout::from_size_align(64, 8).unwrap()).unwrap();
+///     let used = heap.heap().used_bytes();
+///     heap.deallocate(block);
+///     used
+/// });
+/// assert_eq!(128, used);
+/// ```
+pub struct BrandedHeap<'id, const N: usize> {
+    heap: Heap<N>,
+    brand: Brand<'id>,
+}
+
+/// An allocation from a [BrandedHeap] with the brand `'id`, which only
+/// that heap will take back.
+pub struct BrandedPtr<'id> {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    brand: Brand<'id>,
+}
+
+impl<const N: usize> Heap<N> {
+    /// Brand the heap, and pass it to `f`.  The brand is only valid inside
+    /// `f`, so neither the heap nor its allocations can leave it; return
+    /// [BrandedHeap::into_inner] to keep using the heap afterwards.
+    pub fn with_brand<R>(self, f: impl for<'id> FnOnce(BrandedHeap<'id, N>) -> R) -> R {
+        f(BrandedHeap {
+            heap: self,
+            brand: PhantomData,
+        })
+    }
+}
+
+impl<'id, const N: usize> BrandedHeap<'id, N> {
+    /// Allocate a block for `layout`, as [Heap::allocate] does.
+    pub fn allocate(&mut self, layout: Layout) -> Result<BrandedPtr<'id>, AllocationError> {
+        let block = self.heap.allocate(layout)?;
+        Ok(BrandedPtr {
+            // SAFETY: The heap never hands out a null block.
+            ptr: unsafe { NonNull::new_unchecked(block) },
+            layout,
+            brand: PhantomData,
+        })
+    }
+
+    /// Free a block from this heap's [BrandedHeap::allocate].
+    pub fn deallocate(&mut self, ptr: BrandedPtr<'id>) {
+        // SAFETY: The brand says it came from this heap, and it's been
+        // moved in, so it can't be freed again.
+        unsafe { self.heap.deallocate(ptr.ptr.as_ptr(), ptr.layout) }
+    }
+
+    /// The heap, for its statistics and other queries.  It can't be
+    /// borrowed mutably, since its own [Heap::deallocate] would take
+    /// pointers without checking their brand.
+    pub fn heap(&self) -> &Heap<N> {
+        &self.heap
+    }
+
+    /// Remove the brand, returning the heap.  Any [BrandedPtr]s still
+    /// live can no longer be freed, so their blocks stay in use.
+    pub fn into_inner(self) -> Heap<N> {
+        self.heap
+    }
+}
+
+impl<const N: usize> fmt::Debug for BrandedHeap<'_, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BrandedHeap").field(&self.heap).finish()
+    }
+}
+
+impl BrandedPtr<'_> {
+    /// The start of the block.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// The layout the block was allocated with.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+impl fmt::Debug for BrandedPtr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BrandedPtr")
+            .field("ptr", &self.ptr)
+            .field("layout", &self.layout)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_branded_heap() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let small = Layout::from_size_align(16, 4).unwrap();
+            let heap = heap.with_brand(|mut heap| {
+                let blocks: Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+                assert_eq!(
+                    Err(AllocationError::HeapExhausted),
+                    heap.allocate(small).map(|_| ())
+                );
+                assert_eq!(mem, blocks[0].as_ptr());
+                assert_eq!(small, blocks[0].layout());
+                assert_eq!(256, heap.heap().used_bytes());
+
+                for block in blocks {
+                    heap.deallocate(block);
+                }
+                assert_eq!(0, heap.heap().used_bytes());
+
+                // A block still live when the brand is removed stays
+                // allocated.
+                let _leaked = heap.allocate(small).unwrap();
+                heap.into_inner()
+            });
+            assert_eq!(16, heap.used_bytes());
+            assert!(heap.accounting_check());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,897 @@
+//! A builder for heaps, which checks all of its options at once.
+use core::fmt;
+use core::mem::size_of;
+use core::ops::Range;
+use core::ptr::{self, NonNull};
+
+use crate::bist::VERIFY_PATTERNS;
+use crate::heap::{free_list_insert, FreeBlock};
+#[cfg(feature = "occupancy-map")]
+use crate::metadata_bytes;
+#[cfg(feature = "dma")]
+use crate::DmaHooks;
+use crate::{Heap, HeapError, MIN_HEAP_ALIGN};
+
+/// The most ranges a [HeapBuilder] can reserve.
+pub const MAX_RESERVED_RANGES: usize = 4;
+
+/// Options for creating a [Heap], all checked together by
+/// [HeapBuilder::build]:
+///
+/// ```
+/// # use buddyalloc::{Heap, HeapBuilder};
+/// # use core::ptr::NonNull;
+/// #[repr(align(4096))]
+/// struct Memory([u8; 65536]);
+///
+/// let memory = Box::leak(Box::new(Memory([0; 65536])));
+/// let heap: Heap<12> = unsafe {
+///     HeapBuilder::new()
+///         .base(NonNull::from(&mut memory.0).cast())
+///         .size(65536)
+///         .min_block_size(32)
+///         // Keep the first 4 KiB, say for a boot stack.
+///         .reserve(0..4096)
+///         .build()
+/// }
+/// .unwrap();
+/// ```
+///
+/// The setters are `const`, so a builder can be put together in a
+/// constant, but [HeapBuilder::build] has to run at run time unless
+/// nothing is reserved (use [Heap::new_unchecked] for that).
+#[derive(Debug)]
+pub struct HeapBuilder<const N: usize> {
+    base: *mut u8,
+    size: usize,
+    min_block_size: Option<usize>,
+    reserved: [(usize, usize); MAX_RESERVED_RANGES],
+    reserved_len: usize,
+    too_many_reserved: bool,
+    random_start: Option<(usize, usize)>,
+    zeroed: bool,
+    max_allocation_order: usize,
+    ecc_test: Option<fn(*mut u8, usize) -> bool>,
+    #[cfg(feature = "dma")]
+    dma: Option<DmaHooks>,
+    #[cfg(feature = "occupancy-map")]
+    occupancy: Option<&'static mut [u8]>,
+    #[cfg(feature = "debug-track")]
+    metadata: Option<&'static mut [u8]>,
+}
+
+impl<const N: usize> Default for HeapBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> HeapBuilder<N> {
+    /// A builder with no options set.  At least the base and size have to
+    /// be set before building.
+    pub const fn new() -> Self {
+        Self {
+            base: ptr::null_mut(),
+            size: 0,
+            min_block_size: None,
+            reserved: [(0, 0); MAX_RESERVED_RANGES],
+            reserved_len: 0,
+            too_many_reserved: false,
+            random_start: None,
+            zeroed: false,
+            max_allocation_order: usize::MAX,
+            ecc_test: None,
+            #[cfg(feature = "dma")]
+            dma: None,
+            #[cfg(feature = "occupancy-map")]
+            occupancy: None,
+            #[cfg(feature = "debug-track")]
+            metadata: None,
+        }
+    }
+
+    /// The start of the heap's memory.  This must be aligned on a
+    /// `MIN_HEAP_ALIGN` boundary.
+    pub const fn base(mut self, base: NonNull<u8>) -> Self {
+        self.base = base.as_ptr();
+        self
+    }
+
+    /// The size of the heap, which must be a power of two.
+    pub const fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Check that `N` and the heap's size give a minimum block size of
+    /// `min_block_size`, as [Heap::new_with_minimum_block_size] does.
+    pub const fn min_block_size(mut self, min_block_size: usize) -> Self {
+        self.min_block_size = Some(min_block_size);
+        self
+    }
+
+    /// Never hand out the bytes at these offsets from the heap's base, for
+    /// example because they hold something else or aren't backed by RAM.
+    /// Every block which overlaps the range is marked as allocated, so up
+    /// to a minimum block's worth of memory either side is lost with it.
+    ///
+    /// Up to [MAX_RESERVED_RANGES] ranges can be reserved.  Empty ranges
+    /// are ignored.
+    pub const fn reserve(mut self, range: Range<usize>) -> Self {
+        if range.start >= range.end {
+            return self;
+        }
+
+        if self.reserved_len == MAX_RESERVED_RANGES {
+            self.too_many_reserved = true;
+        } else {
+            self.reserved[self.reserved_len] = (range.start, range.end);
+            self.reserved_len += 1;
+        }
+        self
+    }
+
+    /// Reserve a prefix of the heap chosen by `entropy`, so that
+    /// allocations start at an offset an attacker can't predict: a cheap
+    /// stand-in for address space layout randomization on systems without
+    /// virtual memory.  The prefix is `entropy` reduced to a multiple of
+    /// the minimum block size below `max_waste`, and it's reserved just
+    /// like a range passed to [HeapBuilder::reserve] (without counting
+    /// towards [MAX_RESERVED_RANGES]), so it shows up in
+    /// [Heap::reserved_bytes].
+    ///
+    /// `entropy` should come from a hardware random number generator or
+    /// similar; anything predictable defeats the point.  A `max_waste`
+    /// bigger than the heap is a [HeapError::ReservedOutOfRange].
+    pub const fn randomize_start(mut self, entropy: usize, max_waste: usize) -> Self {
+        self.random_start = Some((entropy, max_waste));
+        self
+    }
+
+    /// The heap's memory is all zero to begin with; see
+    /// [Heap::assume_zeroed].  [HeapBuilder::build] relies on this being
+    /// true.
+    pub const fn zeroed(mut self) -> Self {
+        self.zeroed = true;
+        self
+    }
+
+    /// Never hand out blocks bigger than order `order`; see
+    /// [Heap::set_max_allocation_order].
+    pub const fn max_allocation_order(mut self, order: usize) -> Self {
+        self.max_allocation_order = order;
+        self
+    }
+
+    /// Call `check` with the start and size of every minimum-sized block
+    /// outside the reserved ranges while building, for hardware ECC which
+    /// has to be initialized (written once) before anything is read.  A
+    /// block for which it returns `false` is left out of the heap for
+    /// good, just like a reserved range, so it counts towards
+    /// [Heap::reserved_bytes] and is never handed out.  The good blocks
+    /// around it are merged as far as their buddies allow.
+    /// [Heap::new_with_ecc_test] also returns how many bytes were left out.
+    ///
+    /// `check` gets exclusive use of each block while it runs.  If it
+    /// writes anything but zeros, don't set [HeapBuilder::zeroed].
+    pub const fn ecc_test(mut self, check: fn(*mut u8, usize) -> bool) -> Self {
+        self.ecc_test = Some(check);
+        self
+    }
+
+    /// Run `hooks` on the buffers from [Heap::allocate_dma], and align them
+    /// to its cache line size.
+    #[cfg(feature = "dma")]
+    pub const fn dma_hooks(mut self, hooks: DmaHooks) -> Self {
+        self.dma = Some(hooks);
+        self
+    }
+
+    /// Keep a bitmap of the blocks in use in `map`; see
+    /// [Heap::new_with_metadata].
+    #[cfg(feature = "occupancy-map")]
+    pub fn occupancy_map(mut self, map: &'static mut [u8]) -> Self {
+        self.occupancy = Some(map);
+        self
+    }
+
+    /// Register an order table for the heap; see [Heap::set_order_table].
+    #[cfg(feature = "debug-track")]
+    pub fn metadata(mut self, table: &'static mut [u8]) -> Self {
+        self.metadata = Some(table);
+        self
+    }
+
+    /// Check every option, returning all of the problems found.
+    pub fn validate(&self) -> HeapErrors {
+        let mut errors = HeapErrors::NONE;
+
+        // Everything else about the heap's layout is derived from its size,
+        // so a bad size is checked first, and on its own, rather than
+        // showing up as a misleading `MinBlockTooSmall`.
+        if !self.size.is_power_of_two() {
+            errors.insert(HeapError::BadSizeAlignment);
+        }
+        if self.base.is_null() || self.base as usize & (MIN_HEAP_ALIGN - 1) != 0 {
+            errors.insert(HeapError::BadBaseAlignment);
+        }
+
+        if self.size.is_power_of_two() {
+            // On narrow targets `N` can be wider than `usize`, which leaves
+            // no room for blocks at all.
+            let min_block_size = self.size.checked_shr(N as u32 - 1).unwrap_or(0);
+
+            if self.size < min_block_size {
+                errors.insert(HeapError::BadHeapSize);
+            }
+            if min_block_size < core::mem::size_of::<FreeBlock>() {
+                errors.insert(HeapError::MinBlockTooSmall);
+            }
+            if self
+                .min_block_size
+                .is_some_and(|size| size != min_block_size)
+            {
+                errors.insert(HeapError::BadSizeAlignment);
+            }
+
+            #[cfg(feature = "occupancy-map")]
+            if let Some(map) = &self.occupancy {
+                if map.len() < metadata_bytes(self.size, min_block_size) {
+                    errors.insert(HeapError::MetadataTooSmall);
+                }
+            }
+
+            #[cfg(feature = "debug-track")]
+            if let Some(table) = &self.metadata {
+                if min_block_size != 0 && table.len() < self.size / min_block_size {
+                    errors.insert(HeapError::MetadataTooSmall);
+                }
+            }
+        }
+
+        if self.too_many_reserved {
+            errors.insert(HeapError::TooManyReservedRanges);
+        }
+        if self.reserved().iter().any(|&(_, end)| end > self.size)
+            || self
+                .random_start
+                .is_some_and(|(_, max_waste)| max_waste > self.size)
+        {
+            errors.insert(HeapError::ReservedOutOfRange);
+        }
+
+        errors
+    }
+
+    /// Create the heap.  If exactly one option is invalid this returns the
+    /// matching [HeapError], and if several are, a [HeapError::Several]
+    /// listing all of them.
+    ///
+    /// # Safety
+    /// The heap's memory must not be used for anything else for as long
+    /// as the heap is alive, apart from the reserved ranges.  If
+    /// [HeapBuilder::zeroed] was set, all of it must be zero.
+    pub unsafe fn build(self) -> Result<Heap<N>, HeapError> {
+        self.build_excluding(&[], &mut 0)
+    }
+
+    /// Like [HeapBuilder::build], but first test the heap's memory, for
+    /// boards whose RAM can't be trusted.  Each minimum-sized block is
+    /// checked in turn by writing a couple of patterns to every word of it
+    /// and reading them back, and any block that fails is left out of the
+    /// free lists just like a reserved range, so it's never handed out.
+    /// Reserved ranges (including a [HeapBuilder::randomize_start] prefix)
+    /// are in use already, so they aren't touched.  A heap built without
+    /// this isn't tested at all.
+    ///
+    /// The bad ranges are written to `bad` as offsets from the heap's
+    /// base, with neighbouring bad blocks merged, and their number is
+    /// returned along with the heap.  They count towards
+    /// [Heap::reserved_bytes].  If there are more of them than fit, the
+    /// last range is stretched to cover the rest, losing the good memory
+    /// in between, and if `bad` is empty, the first bad word fails the
+    /// build with [HeapError::MemoryVerificationFailed].
+    ///
+    /// The test overwrites the memory, so [HeapBuilder::zeroed] is ignored.
+    ///
+    /// # Safety
+    /// As for [HeapBuilder::build].
+    pub unsafe fn build_tested(
+        self,
+        bad: &mut [Range<usize>],
+    ) -> Result<(Heap<N>, usize), HeapError> {
+        self.build_tested_with(bad, |word, pattern| {
+            ptr::write_volatile(word, pattern);
+            ptr::read_volatile(word)
+        })
+    }
+
+    /// [HeapBuilder::build_tested], with `probe` writing a pattern to a
+    /// word and returning what reads back, so tests can simulate bad
+    /// memory.
+    unsafe fn build_tested_with(
+        mut self,
+        bad: &mut [Range<usize>],
+        mut probe: impl FnMut(*mut usize, usize) -> usize,
+    ) -> Result<(Heap<N>, usize), HeapError> {
+        self.check()?;
+
+        let min_block_size = self.size >> (N - 1);
+        let (reserved, reserved_len) = self.all_reserved(min_block_size);
+        let reserved = &reserved[..reserved_len];
+        let mut found = 0;
+        for offset in (0..self.size).step_by(min_block_size) {
+            let end = offset + min_block_size;
+            if reserved
+                .iter()
+                .any(|&(start, stop)| start < end && offset < stop)
+            {
+                continue;
+            }
+
+            let words = self.base.add(offset) as *mut usize;
+            let failed = (0..min_block_size / size_of::<usize>())
+                .map(|i| words.add(i))
+                .find(|&word| {
+                    VERIFY_PATTERNS
+                        .iter()
+                        .any(|&pattern| probe(word, pattern) != pattern)
+                });
+            let word = match failed {
+                Some(word) => word,
+                None => continue,
+            };
+
+            if found != 0 && bad[found - 1].end == offset {
+                bad[found - 1].end = end;
+            } else if found < bad.len() {
+                bad[found] = offset..end;
+                found += 1;
+            } else if found != 0 {
+                bad[found - 1].end = end;
+            } else {
+                return Err(HeapError::MemoryVerificationFailed(word as usize));
+            }
+        }
+
+        self.zeroed = false;
+        let heap = self.build_excluding(&bad[..found], &mut 0)?;
+        Ok((heap, found))
+    }
+
+    /// [HeapBuilder::build], leaving out the `bad` ranges as well as the
+    /// reserved ones, and the blocks which fail the ECC test.  The number
+    /// of bytes left out for being bad or failing the test is added to
+    /// `unusable`.
+    pub(crate) unsafe fn build_excluding(
+        self,
+        bad: &[Range<usize>],
+        unusable: &mut usize,
+    ) -> Result<Heap<N>, HeapError> {
+        self.check()?;
+
+        let mut heap = Heap::new_unchecked(self.base, self.size);
+        if self.zeroed {
+            heap = heap.assume_zeroed();
+        }
+        heap.set_max_allocation_order(self.max_allocation_order);
+        #[cfg(feature = "dma")]
+        {
+            heap.dma = self.dma;
+        }
+        let (reserved, reserved_len) = self.all_reserved(heap.min_block_size);
+        #[cfg(feature = "occupancy-map")]
+        if let Some(map) = self.occupancy {
+            map.fill(0);
+            heap.occupancy = Some(map);
+        }
+
+        // The order table has to be registered while the heap is unused.
+        #[cfg(feature = "debug-track")]
+        if let Some(table) = self.metadata {
+            heap.set_order_table(table)?;
+        }
+
+        if reserved_len != 0 || !bad.is_empty() || self.ecc_test.is_some() {
+            heap.free_lists[N - 1] = ptr::null_mut();
+            let exclusions = Exclusions {
+                reserved: &reserved[..reserved_len],
+                bad,
+                ecc_test: self.ecc_test,
+            };
+            if heap.place_reserved(&exclusions, 0, N - 1, unusable) {
+                free_list_insert(&mut heap.free_lists, N - 1, heap.heap_base);
+            }
+
+            #[cfg(feature = "debug-info")]
+            heap.refresh_free_counts();
+        }
+
+        Ok(heap)
+    }
+
+    /// [HeapBuilder::validate], as a single error.
+    fn check(&self) -> Result<(), HeapError> {
+        let errors = self.validate();
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.first().unwrap()),
+            _ => Err(HeapError::Several(errors)),
+        }
+    }
+
+    fn reserved(&self) -> &[(usize, usize)] {
+        &self.reserved[..self.reserved_len]
+    }
+
+    /// The reserved ranges, with the random prefix if there is one.
+    fn all_reserved(
+        &self,
+        min_block_size: usize,
+    ) -> ([(usize, usize); MAX_RESERVED_RANGES + 1], usize) {
+        let mut reserved = [(0, 0); MAX_RESERVED_RANGES + 1];
+        reserved[..self.reserved_len].copy_from_slice(&self.reserved[..self.reserved_len]);
+        let mut reserved_len = self.reserved_len;
+        if let Some((entropy, max_waste)) = self.random_start {
+            // One of the multiples of the block size below `max_waste`.
+            let slots = max_waste.div_ceil(min_block_size);
+            let prefix = entropy.checked_rem(slots).unwrap_or(0) * min_block_size;
+            if prefix != 0 {
+                reserved[reserved_len] = (0, prefix);
+                reserved_len += 1;
+            }
+        }
+        (reserved, reserved_len)
+    }
+}
+
+/// What [HeapBuilder::build_excluding] leaves out of a new heap.
+struct Exclusions<'a> {
+    reserved: &'a [(usize, usize)],
+    bad: &'a [Range<usize>],
+    ecc_test: Option<fn(*mut u8, usize) -> bool>,
+}
+
+impl<const N: usize> Heap<N> {
+    /// Place the block of order `order` at `offset`: mark the parts of it
+    /// which overlap a reserved or bad range, or fail the ECC test, as
+    /// used, splitting it so as little as possible is lost, and free the
+    /// rest as the largest blocks which fit.  Returns true, without
+    /// freeing it, if the whole block is usable, so the caller can free it
+    /// merged with its buddy.  The bytes lost to bad ranges and the ECC
+    /// test are added to `unusable`.
+    unsafe fn place_reserved(
+        &mut self,
+        exclusions: &Exclusions,
+        offset: usize,
+        order: usize,
+        unusable: &mut usize,
+    ) -> bool {
+        let size = self.order_size(order);
+        let end = offset + size;
+        let overlaps = |&(start, stop): &(usize, usize)| start < end && offset < stop;
+
+        let reserved = exclusions.reserved.iter().copied().find(overlaps);
+        let bad = exclusions
+            .bad
+            .iter()
+            .map(|range| (range.start, range.end))
+            .find(overlaps);
+        let covered = match reserved.or(bad) {
+            Some((start, stop)) => order == 0 || (start <= offset && end <= stop),
+            // Only minimum-sized blocks are tested.
+            None if order == 0 => exclusions
+                .ecc_test
+                .is_some_and(|check| !check(self.heap_base.add(offset), size)),
+            None if exclusions.ecc_test.is_none() => return true,
+            None => false,
+        };
+        if covered {
+            self.used_bytes += size;
+            self.reserved_bytes += size;
+            if reserved.is_none() {
+                *unusable += size;
+            }
+            #[cfg(feature = "occupancy-map")]
+            self.mark_occupied(self.heap_base.add(offset), order, true);
+            return false;
+        }
+        if order == 0 {
+            return true;
+        }
+
+        let half = size / 2;
+        let lower = self.place_reserved(exclusions, offset, order - 1, unusable);
+        let upper = self.place_reserved(exclusions, offset + half, order - 1, unusable);
+        if lower && upper {
+            return true;
+        }
+        // Free the upper half first, so the lower one ends up at the head
+        // of its free list and is handed out first.
+        if upper {
+            free_list_insert(
+                &mut self.free_lists,
+                order - 1,
+                self.heap_base.add(offset + half),
+            );
+        }
+        if lower {
+            free_list_insert(&mut self.free_lists, order - 1, self.heap_base.add(offset));
+        }
+        false
+    }
+}
+
+/// A set of [HeapError]s, from [HeapBuilder::validate], or in
+/// [HeapError::Several] when more than one option was invalid.  Only the
+/// errors without fields can be in a set.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeapErrors(u16);
+
+impl HeapErrors {
+    /// The errors a set can hold, indexed by their bit, which is the order
+    /// [HeapErrors::iter] lists them in.  This is also the order [Heap::new]
+    /// checks its arguments in.
+    const ERRORS: [HeapError; 8] = [
+        HeapError::BadSizeAlignment,
+        HeapError::BadBaseAlignment,
+        HeapError::BadHeapSize,
+        HeapError::MinBlockTooSmall,
+        HeapError::MetadataTooSmall,
+        HeapError::HeapInUse,
+        HeapError::TooManyReservedRanges,
+        HeapError::ReservedOutOfRange,
+    ];
+
+    /// The empty set.
+    pub const NONE: Self = Self(0);
+
+    fn bit(error: HeapError) -> u16 {
+        let index = match error {
+            HeapError::BadSizeAlignment => 0,
+            HeapError::BadBaseAlignment => 1,
+            HeapError::BadHeapSize => 2,
+            HeapError::MinBlockTooSmall => 3,
+            HeapError::MetadataTooSmall => 4,
+            HeapError::HeapInUse => 5,
+            HeapError::TooManyReservedRanges => 6,
+            HeapError::ReservedOutOfRange => 7,
+            _ => return 0,
+        };
+        1 << index
+    }
+
+    pub(crate) fn insert(&mut self, error: HeapError) {
+        self.0 |= Self::bit(error);
+    }
+
+    /// Returns true if `error` is in the set.
+    pub fn contains(&self, error: HeapError) -> bool {
+        self.0 & Self::bit(error) != 0
+    }
+
+    /// The number of errors in the set.
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Returns true if there are no errors in the set.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The first of the errors, if any.
+    pub fn first(&self) -> Option<HeapError> {
+        Self::ERRORS.get(self.0.trailing_zeros() as usize).copied()
+    }
+
+    /// Every error in the set.
+    pub fn iter(&self) -> impl Iterator<Item = HeapError> + '_ {
+        Self::ERRORS
+            .iter()
+            .copied()
+            .filter(move |&e| self.contains(e))
+    }
+}
+
+impl fmt::Debug for HeapErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+
+    #[test]
+    fn test_composite_errors() {
+        let base = NonNull::new(0x1_0000 as *mut u8).unwrap();
+
+        // A single problem is reported as itself.
+        let builder = HeapBuilder::<5>::new().base(base).size(300);
+        assert_eq!(
+            Err(HeapError::BadSizeAlignment),
+            unsafe { builder.build() }.map(|_| ())
+        );
+
+        // Several come back together.
+        let builder = HeapBuilder::<5>::new()
+            .base(NonNull::new(0x1_0010 as *mut u8).unwrap())
+            .size(256)
+            .min_block_size(32)
+            .reserve(128..512);
+        let errors = builder.validate();
+        assert_eq!(3, errors.len());
+        assert!(errors.contains(HeapError::BadBaseAlignment));
+        assert!(errors.contains(HeapError::BadSizeAlignment));
+        assert!(errors.contains(HeapError::ReservedOutOfRange));
+        assert!(!errors.contains(HeapError::BadHeapSize));
+        assert_eq!(
+            Err(HeapError::Several(errors)),
+            unsafe { builder.build() }.map(|_| ())
+        );
+
+        // No base, and too many reserved ranges.
+        let mut builder = HeapBuilder::<5>::new().size(256);
+        for i in 0..=MAX_RESERVED_RANGES {
+            builder = builder.reserve(i * 16..i * 16 + 1);
+        }
+        assert_eq!(
+            std::vec![
+                HeapError::BadBaseAlignment,
+                HeapError::TooManyReservedRanges
+            ],
+            builder.validate().iter().collect::<std::vec::Vec<_>>()
+        );
+
+        // Nothing set at all.  A size of zero isn't a power of two, and
+        // nothing derived from it is checked.
+        let errors = HeapBuilder::<5>::new().validate();
+        assert_eq!(Some(HeapError::BadSizeAlignment), errors.first());
+        assert!(errors.contains(HeapError::BadBaseAlignment));
+        assert!(!errors.contains(HeapError::MinBlockTooSmall));
+    }
+
+    #[cfg(feature = "debug-track")]
+    #[test]
+    fn test_metadata() {
+        let table = std::boxed::Box::leak(std::vec![0u8; 8].into_boxed_slice());
+        let errors = HeapBuilder::<5>::new()
+            .base(NonNull::new(0x1_0000 as *mut u8).unwrap())
+            .size(256)
+            .reserve(0..512)
+            .metadata(table)
+            .validate();
+        assert_eq!(
+            std::vec![HeapError::MetadataTooSmall, HeapError::ReservedOutOfRange],
+            errors.iter().collect::<std::vec::Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_reserve() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+
+            // 40..70 touches the 32-byte block at 32 and the 16-byte block
+            // at 64; the empty range is ignored.
+            let mut heap: Heap<5> = HeapBuilder::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .reserve(40..70)
+                .reserve(200..200)
+                .build()
+                .unwrap();
+            assert_eq!(48, heap.used_bytes());
+            assert_eq!(48, heap.reserved_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks = std::vec::Vec::new();
+            while let Ok(block) = heap.allocate(small) {
+                let offset = block.offset_from(mem) as usize;
+                assert!(!(32..80).contains(&offset));
+                blocks.push(block);
+            }
+            assert_eq!(13, blocks.len());
+            // The 16-byte buddy of the reserved block at 64 goes first,
+            // since it needs no splitting.
+            assert_eq!(mem.add(80), blocks[0]);
+
+            for block in blocks {
+                heap.deallocate(block, small);
+            }
+            assert_eq!(48, heap.used_bytes());
+            assert_eq!(None, heap.free_list_peek(4));
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_randomize_start() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let build = |entropy: usize, max_waste: usize| -> Heap<5> {
+                HeapBuilder::new()
+                    .base(NonNull::new(mem).unwrap())
+                    .size(heap_size)
+                    .randomize_start(entropy, max_waste)
+                    .build()
+                    .unwrap()
+            };
+
+            // Each entropy value up to the limit gives a different prefix,
+            // and the first block comes straight after it.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut firsts = std::vec::Vec::new();
+            for entropy in 0..8 {
+                let mut heap = build(entropy * 3 + 8, 128);
+                let prefix = (entropy * 3 + 8) % 8 * 16;
+                let first = heap.allocate(small).unwrap();
+                assert_eq!(mem.add(prefix), first);
+                firsts.push(first);
+
+                let stats = heap.stats();
+                assert_eq!(prefix, stats.reserved_bytes);
+                assert_eq!(
+                    heap_size,
+                    stats.free_bytes
+                        + stats.reserved_bytes
+                        + (stats.used_bytes - stats.reserved_bytes)
+                );
+                assert_eq!(16, stats.used_bytes - stats.reserved_bytes);
+                assert!(heap.accounting_check());
+                heap.assert_alignment_invariants();
+
+                // The prefix is never handed out, even once the heap fills.
+                while let Ok(block) = heap.allocate(small) {
+                    assert!(block >= mem.add(prefix));
+                }
+                assert_eq!(0, heap.free_bytes());
+            }
+            firsts.sort();
+            firsts.dedup();
+            assert_eq!(8, firsts.len());
+
+            // A limit that isn't a multiple of the block size still keeps
+            // the prefix below it, and a limit of zero wastes nothing.
+            assert_eq!(32, build(2, 33).reserved_bytes());
+            assert_eq!(0, build(3, 33).reserved_bytes());
+            assert_eq!(0, build(7, 0).reserved_bytes());
+
+            assert_eq!(
+                Err(HeapError::ReservedOutOfRange),
+                HeapBuilder::<5>::new()
+                    .base(NonNull::new(mem).unwrap())
+                    .size(heap_size)
+                    .randomize_start(0, 512)
+                    .build()
+                    .map(|_| ())
+            );
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_build_tested() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let builder = || {
+                HeapBuilder::<5>::new()
+                    .base(NonNull::new(mem).unwrap())
+                    .size(heap_size)
+                    .reserve(0..16)
+            };
+
+            // Good memory passes, every word of it but the reserved block
+            // having been tested.
+            let mut bad = [0..0, 0..0, 0..0];
+            let (heap, found) = builder().build_tested(&mut bad).unwrap();
+            assert_eq!(0, found);
+            assert_eq!(16, heap.reserved_bytes());
+
+            // Memory which lies about the words at 0x48, 0x50 and 0xc8.
+            let liars = [0x48, 0x50, 0xc8].map(|offset| mem.add(offset) as *mut usize);
+            // The reserved block is in use, so it's never probed.
+            let mut lying = |word: *mut usize, pattern: usize| {
+                assert!(word >= mem.add(16) as *mut usize);
+                if liars.contains(&word) {
+                    pattern ^ 1
+                } else {
+                    pattern
+                }
+            };
+
+            // The two neighbouring bad blocks come back as one range, and
+            // nothing bad or reserved is ever handed out.
+            let (mut heap, found) = builder().build_tested_with(&mut bad, &mut lying).unwrap();
+            assert_eq!(2, found);
+            assert_eq!([64..96, 192..208], bad[..2]);
+            assert_eq!(64, heap.reserved_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks = 0;
+            while let Ok(block) = heap.allocate(small) {
+                let offset = block.offset_from(mem) as usize;
+                assert!(!(0..16).contains(&offset));
+                assert!(bad[..2].iter().all(|range| !range.contains(&offset)));
+                blocks += 1;
+            }
+            assert_eq!(12, blocks);
+
+            // With room for only one range, it stretches over both.
+            let (heap, found) = builder()
+                .build_tested_with(&mut bad[..1], &mut lying)
+                .unwrap();
+            assert_eq!(1, found);
+            assert_eq!(64..208, bad[0]);
+            assert_eq!(16 + 144, heap.reserved_bytes());
+
+            // And with no room at all, the first bad word is an error.
+            assert_eq!(
+                Err(HeapError::MemoryVerificationFailed(liars[0] as usize)),
+                builder().build_tested_with(&mut [], &mut lying).map(|_| ())
+            );
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    /// Fails the block at offset 0x40 of whichever heap it's testing, and
+    /// checks it's never given the reserved first block.
+    fn ecc_check_failing_0x40(block: *mut u8, size: usize) -> bool {
+        let offset = block as usize % 4096;
+        assert_eq!(16, size);
+        assert!(offset >= 16);
+        offset != 0x40
+    }
+
+    #[test]
+    fn test_ecc_test() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+
+            // The failed block and the reserved one are both left out, but
+            // only the failed one is unusable.
+            let mut unusable = 0;
+            let mut heap = HeapBuilder::<5>::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .reserve(0..16)
+                .ecc_test(ecc_check_failing_0x40)
+                .build_excluding(&[], &mut unusable)
+                .unwrap();
+            assert_eq!(16, unusable);
+            assert_eq!(32, heap.reserved_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            assert!(heap.is_block_free(3, mem.add(128)));
+            assert!(heap.is_block_free(0, mem.add(0x50)));
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks = 0;
+            while let Ok(block) = heap.allocate(small) {
+                assert!(block != mem && block != mem.add(0x40));
+                blocks += 1;
+            }
+            assert_eq!(14, blocks);
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Per-thread caches of blocks of one order, to take pressure off the lock
+//! of a shared [LockedHeap].
+use core::alloc::Layout;
+use core::ptr;
+
+use crate::{AllocationError, LockedHeap};
+
+/// The most blocks a [ThreadLocalCache] can hold.
+pub const THREAD_LOCAL_CACHE_CAPACITY: usize = 32;
+
+/// A stash of free blocks of a single order, taken from a [LockedHeap] in
+/// batches so that most allocations and deallocations don't need its lock.
+/// Create one per thread (or CPU) with [LockedHeap::thread_local_cache].
+///
+/// Blocks sitting in the cache count as allocated as far as the heap is
+/// concerned.  They're all given back when the cache is dropped.
+///
+/// A cache can be moved to another thread, but not shared between threads.
+pub struct ThreadLocalCache<'a, const N: usize> {
+    heap: &'a LockedHeap<N>,
+    layout: Layout,
+    blocks: [*mut u8; THREAD_LOCAL_CACHE_CAPACITY],
+    len: usize,
+    size: usize,
+}
+
+// SAFETY: The cache owns the blocks it holds, and only touches the heap
+// through its lock.  The raw pointers keep it `!Sync`.
+unsafe impl<const N: usize> Send for ThreadLocalCache<'_, N> {}
+
+impl<const N: usize> LockedHeap<N> {
+    /// Create a cache of up to `cache_size` blocks of order `order` (that
+    /// is, blocks of `min_block_size << order` bytes), clamped to
+    /// [THREAD_LOCAL_CACHE_CAPACITY].  The cache starts out empty.
+    ///
+    /// This is on [LockedHeap] rather than [crate::Heap] because the cache
+    /// has to get back into the heap to refill and drain itself, while
+    /// other threads use it too.
+    pub fn thread_local_cache(&self, order: usize, cache_size: usize) -> ThreadLocalCache<'_, N> {
+        if order >= N {
+            heap_panic!("Cache order out of range");
+        }
+
+        let size = self.lock().order_size(order);
+        ThreadLocalCache {
+            heap: self,
+            // SAFETY: The block size is a power of two no bigger than the
+            // heap, and an alignment of 1 is always valid.
+            layout: unsafe { Layout::from_size_align_unchecked(size, 1) },
+            blocks: [ptr::null_mut(); THREAD_LOCAL_CACHE_CAPACITY],
+            len: 0,
+            size: cache_size.clamp(1, THREAD_LOCAL_CACHE_CAPACITY),
+        }
+    }
+}
+
+impl<const N: usize> ThreadLocalCache<'_, N> {
+    /// The layout of the cache's blocks, for allocating them from the
+    /// heap directly.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// The number of blocks currently in the cache.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the cache holds no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Take a block from the cache.  If it's empty, this first refills
+    /// half of it from the heap in one go, and only fails if the heap
+    /// can't supply even one block.
+    pub fn allocate(&mut self) -> Result<*mut u8, AllocationError> {
+        if self.len == 0 {
+            let mut heap = self.heap.lock();
+            let batch = self.size.div_ceil(2);
+            while self.len < batch {
+                match heap.allocate(self.layout) {
+                    Ok(block) => {
+                        self.blocks[self.len] = block;
+                        self.len += 1;
+                    }
+                    Err(e) if self.len == 0 => return Err(e),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        self.len -= 1;
+        Ok(self.blocks[self.len])
+    }
+
+    /// Put a block back in the cache.  If it's full, half of it is first
+    /// returned to the heap in one go.
+    ///
+    /// # Safety
+    /// `ptr` must be a live block of the cache's order from the same heap,
+    /// for example one returned by [ThreadLocalCache::allocate].
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        if self.len == self.size {
+            self.drain(self.size / 2);
+        }
+
+        self.blocks[self.len] = ptr;
+        self.len += 1;
+    }
+
+    /// Give blocks back to the heap until only `keep` are left.
+    fn drain(&mut self, keep: usize) {
+        let mut heap = self.heap.lock();
+        while self.len > keep {
+            self.len -= 1;
+            // SAFETY: Every block in the cache came from this heap.
+            unsafe { heap.deallocate(self.blocks[self.len], self.layout) };
+        }
+    }
+}
+
+impl<const N: usize> Drop for ThreadLocalCache<'_, N> {
+    fn drop(&mut self) {
+        self.drain(0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::Heap;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_thread_local_cache() {
+        unsafe {
+            let heap_size = 16384;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: LockedHeap<10> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            // Each thread holds on to a few blocks at a time, cycling them
+            // through its cache, and stamps them so overlaps would show.
+            let barrier = std::sync::Barrier::new(4);
+            let held: Vec<Vec<usize>> = std::thread::scope(|s| {
+                let threads: Vec<_> = (0..4u8)
+                    .map(|t| {
+                        let (heap, barrier) = (&heap, &barrier);
+                        s.spawn(move || {
+                            let mut cache = heap.thread_local_cache(2, 8);
+                            let size = cache.layout().size();
+                            let mut live = Vec::new();
+                            for i in 0..1000 {
+                                if live.is_empty() || (live.len() < 8 && i % 3 != 2) {
+                                    let block = cache.allocate().unwrap();
+                                    block.write_bytes(t, size);
+                                    live.push(block);
+                                } else {
+                                    let block: *mut u8 = live.swap_remove(i % live.len());
+                                    let bytes = std::slice::from_raw_parts(block, size);
+                                    assert!(bytes.iter().all(|&b| b == t));
+                                    cache.deallocate(block);
+                                }
+                            }
+
+                            // Wait until every thread is done, so all of
+                            // the blocks still held are live at once.
+                            let still: Vec<usize> = live.iter().map(|&b| b as usize).collect();
+                            barrier.wait();
+                            for block in live {
+                                cache.deallocate(block);
+                            }
+                            assert!(cache.len() <= 8);
+                            still
+                        })
+                    })
+                    .collect();
+                threads.into_iter().map(|t| t.join().unwrap()).collect()
+            });
+
+            // No block was held by two threads at once.
+            let mut all: Vec<usize> = held.into_iter().flatten().collect();
+            let count = all.len();
+            all.sort();
+            all.dedup();
+            assert_eq!(count, all.len());
+
+            // Dropping the caches gave everything back.
+            let heap = heap.into_inner();
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: LockedHeap<5> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            // The first allocation takes half a cache's worth from the heap.
+            let mut cache = heap.thread_local_cache(0, 4);
+            let a = cache.allocate().unwrap();
+            assert_eq!(1, cache.len());
+            assert_eq!(32, heap.lock().used_bytes());
+
+            // Once it's full, the next free sends half of it back.
+            let more: Vec<_> = (0..3).map(|_| cache.allocate().unwrap()).collect();
+            cache.deallocate(a);
+            for &block in &more {
+                cache.deallocate(block);
+            }
+            assert_eq!(4, cache.len());
+            let b = heap.lock().allocate(cache.layout()).unwrap();
+            cache.deallocate(b);
+            assert_eq!(3, cache.len());
+            assert_eq!(48, heap.lock().used_bytes());
+
+            // The whole heap can't be cached in one go, but a partial batch
+            // still succeeds.
+            drop(cache);
+            let mut cache = heap.thread_local_cache(3, 8);
+            let c = cache.allocate().unwrap();
+            assert_eq!(1, cache.len());
+            assert_eq!(256, heap.lock().used_bytes());
+
+            cache.deallocate(c);
+            drop(cache);
+            assert_eq!(0, heap.lock().used_bytes());
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Page coloring, for cores with virtually-indexed, physically-tagged
+//! caches, where a page has to be mapped at a virtual address whose cache
+//! index bits match its physical ones.
+use core::alloc::Layout;
+use core::cmp::{max, min};
+
+use crate::heap::free_list_for_each;
+use crate::paging::PAGE_SIZE;
+use crate::{AllocationError, AllocationSizeError, Heap};
+
+impl<const N: usize> Heap<N> {
+    /// Allocate a block for `layout` whose first 4 KiB page has the color
+    /// `color`: that is, `(offset / 4096) % num_colors == color`, where
+    /// `offset` is the block's offset from the heap's base.  Later pages of
+    /// a bigger block follow on with the colors after it, and smaller
+    /// blocks take the color of the page they're in.
+    ///
+    /// Of the free blocks big enough, this picks the smallest one holding a
+    /// block of the right color, and splits it down to that block, keeping
+    /// whichever halves hold it rather than always the lower one.  With no
+    /// such block it fails with [AllocationError::HeapExhausted], even if
+    /// blocks of other colors are free.  A `color` which isn't less than
+    /// `num_colors` can never be met, and fails with
+    /// [AllocationSizeError::BadAlignment].  As with [Heap::allocate],
+    /// blocks above [Heap::max_allocation_order] fail with
+    /// [AllocationSizeError::TooLarge].
+    ///
+    /// Colors are counted from the heap's base, so for them to match the
+    /// physical addresses' colors the base has to be aligned to
+    /// `num_colors` pages.  Free the block with [Heap::deallocate] as
+    /// usual.
+    pub fn allocate_colored(
+        &mut self,
+        layout: Layout,
+        color: usize,
+        num_colors: usize,
+    ) -> Result<*mut u8, AllocationError> {
+        if color >= num_colors {
+            let error = AllocationError::InvalidSize(AllocationSizeError::BadAlignment);
+            self.note_result(layout, Err(error));
+            return Err(error);
+        }
+        let order = match self.capped_order(layout.size(), layout.align()) {
+            Ok(order) => order,
+            Err(e) => {
+                let error = AllocationError::InvalidSize(e);
+                self.note_result(layout, Err(error));
+                return Err(error);
+            }
+        };
+
+        let size = self.order_size(order);
+        let mut found = None;
+        for free_order in order..N {
+            let free_size = self.order_size(free_order);
+            free_list_for_each(&self.free_lists, free_order, |block| {
+                let offset = block as usize - self.heap_base as usize;
+                let colored = colored_offset(offset, free_size, size, color, num_colors);
+                // Lowest first, so the choice doesn't depend on the order of
+                // the free list.
+                if let Some(colored) = colored {
+                    found = Some(found.map_or(colored, |f| min(f, colored)));
+                }
+            });
+            if found.is_some() {
+                break;
+            }
+        }
+
+        match found {
+            Some(offset) => self.allocate_at_offset(offset, layout),
+            None => {
+                let error = AllocationError::HeapExhausted;
+                self.note_result(layout, Err(error));
+                Err(error)
+            }
+        }
+    }
+}
+
+/// The offset of the first block of `size` bytes with the color `color`
+/// inside the free block of `free_size` bytes at `offset`, if any.
+fn colored_offset(
+    offset: usize,
+    free_size: usize,
+    size: usize,
+    color: usize,
+    num_colors: usize,
+) -> Option<usize> {
+    // Blocks smaller than a page share their page's color, so only the
+    // first block in each page is worth checking.  The colors repeat after
+    // `num_colors` candidates.
+    let step = max(size, PAGE_SIZE);
+    let candidates = max(free_size / step, 1);
+    (0..min(candidates, num_colors))
+        .map(|k| offset + k * step)
+        .find(|&candidate| (candidate / PAGE_SIZE) % num_colors == color)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    /// The color of `block` in a heap at `mem`.
+    fn color_of(mem: *mut u8, block: *mut u8, num_colors: usize) -> usize {
+        ((block as usize - mem as usize) / PAGE_SIZE) % num_colors
+    }
+
+    #[test]
+    fn test_allocate_colored() {
+        unsafe {
+            let heap_size = 64 * PAGE_SIZE;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<13> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+
+            // Ask for every color in turn, freeing every third page so the
+            // heap fragments, until the heap runs out.
+            let num_colors = 4;
+            let mut live = Vec::new();
+            let mut i = 0;
+            let exhausted = loop {
+                let color = i % num_colors;
+                let block = match heap.allocate_colored(page, color, num_colors) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        assert_eq!(AllocationError::HeapExhausted, e);
+                        break color;
+                    }
+                };
+                assert_eq!(color, color_of(mem, block, num_colors));
+                if i % 3 == 2 {
+                    heap.deallocate(block, page);
+                } else {
+                    live.push(block);
+                }
+                i += 1;
+            };
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            // It only failed once every page of that color was in use, with
+            // pages of other colors still free.
+            let count = live
+                .iter()
+                .filter(|&&b| color_of(mem, b, num_colors) == exhausted)
+                .count();
+            assert_eq!(heap_size / PAGE_SIZE / num_colors, count);
+            assert!(heap.free_bytes() > 0);
+            let other = heap
+                .allocate_colored(page, (exhausted + 1) % num_colors, num_colors)
+                .unwrap();
+            heap.deallocate(other, page);
+            for &block in &live {
+                heap.deallocate(block, page);
+            }
+            assert!(heap.is_block_free(12, mem));
+
+            // Once colored pages are scattered around the heap, each color
+            // still comes from the right place.
+            let mut scattered = Vec::new();
+            for &color in &[3, 1, 2, 0, 3, 3, 1, 2] {
+                let block = heap.allocate_colored(page, color, 8).unwrap();
+                assert_eq!(color, color_of(mem, block, 8));
+                scattered.push(block);
+            }
+            for _ in 0..16 {
+                let block = heap.allocate(page).unwrap();
+                scattered.push(block);
+            }
+            for color in 0..8 {
+                let block = heap.allocate_colored(page, color, 8).unwrap();
+                assert_eq!(color, color_of(mem, block, 8));
+                scattered.push(block);
+            }
+
+            // Bigger blocks start on a page of the color, and smaller ones
+            // are in one.
+            let two_pages = Layout::from_size_align(2 * PAGE_SIZE, PAGE_SIZE).unwrap();
+            let big = heap.allocate_colored(two_pages, 2, 4).unwrap();
+            assert_eq!(2, color_of(mem, big, 4));
+            let small = Layout::from_size_align(64, 8).unwrap();
+            let little = heap.allocate_colored(small, 5, 8).unwrap();
+            assert_eq!(5, color_of(mem, little, 8));
+
+            // Blocks of two pages can only start on even colors.
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_colored(two_pages, 1, 4)
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(
+                    AllocationSizeError::BadAlignment
+                )),
+                heap.allocate_colored(page, 4, 4)
+            );
+
+            // The limit on allocation orders applies here too.
+            heap.set_max_allocation_order(6);
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_colored(two_pages, 0, 4)
+            );
+            let capped = heap.allocate_colored(page, 1, 4).unwrap();
+            heap.deallocate(capped, page);
+            heap.set_max_allocation_order(12);
+
+            heap.deallocate(little, small);
+            heap.deallocate(big, two_pages);
+            for block in scattered {
+                heap.deallocate(block, page);
+            }
+            assert!(heap.is_block_free(12, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
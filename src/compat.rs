@@ -0,0 +1,219 @@
+//! The API of `linked_list_allocator`'s `Heap` and `LockedHeap`, so that
+//! code written against it can switch allocators without changes.
+use core::ptr::{self, NonNull};
+
+use crate::heap::MIN_HEAP_ALIGN;
+use crate::{Heap, LockedHeap};
+
+impl<const N: usize> Heap<N> {
+    /// An empty heap, which fails every allocation until it's given memory
+    /// with [Heap::init].
+    pub const fn empty() -> Self {
+        // SAFETY: A heap of size zero never touches its base.
+        unsafe { Self::new_unchecked(ptr::null_mut(), 0) }
+    }
+
+    /// Set the heap up over the `heap_size` bytes at `heap_bottom`,
+    /// replacing any previous state.
+    ///
+    /// Unlike `linked_list_allocator`, a buddy heap has to be a power of two
+    /// in size and aligned to 4096 bytes, so it only uses the largest such
+    /// range inside the memory given, and the rest goes unused.  Panics if
+    /// that's too small to give each of the `N` orders a block.
+    ///
+    /// # Safety
+    /// As for [Heap::new].  Any allocations from the previous memory are
+    /// forgotten.
+    pub unsafe fn init(&mut self, heap_bottom: *mut u8, heap_size: usize) {
+        let start = heap_bottom as usize;
+        let aligned = (start + MIN_HEAP_ALIGN - 1) & !(MIN_HEAP_ALIGN - 1);
+        let usable = start.saturating_add(heap_size).saturating_sub(aligned);
+        if usable == 0 {
+            heap_panic!("Heap memory doesn't contain an aligned block");
+        }
+
+        // The largest power of two that fits.
+        let size = 1 << (usize::BITS - 1 - usable.leading_zeros());
+        match Self::new(NonNull::new_unchecked(aligned as *mut u8), size) {
+            Ok(heap) => *self = heap,
+            Err(_) => heap_panic!("Heap memory is too small for this many orders"),
+        }
+    }
+
+    /// Give the heap `by` more bytes of memory just past its top.
+    ///
+    /// A buddy heap can only grow by doubling, which changes the size of
+    /// every block, so unlike `linked_list_allocator` this only works while
+    /// nothing is allocated, and only once `by` reaches the heap's current
+    /// size.  The heap is then set up again as with [Heap::init], doubled
+    /// as many times as the new memory allows, and the rest goes unused.
+    /// Panics if the heap is in use, or if `by` isn't enough to double it,
+    /// rather than quietly ignoring the memory.
+    ///
+    /// # Safety
+    /// As in `linked_list_allocator`, the memory must be valid and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        if self.used_bytes != 0 {
+            heap_panic!("Can't extend a heap with live allocations");
+        }
+        let end = self.heap_size.saturating_add(by);
+        let mut size = self.heap_size;
+        while size != 0 && size <= end / 2 {
+            size *= 2;
+        }
+        if size == self.heap_size {
+            heap_panic!("Extending a heap needs at least as much memory as it has");
+        }
+
+        // Drop the old heap first, since the new one covers its memory.
+        let base = NonNull::new_unchecked(self.heap_base);
+        *self = Self::empty();
+        match Self::new(base, size) {
+            Ok(heap) => *self = heap,
+            Err(_) => heap_panic!("Heap memory is too small for this many orders"),
+        }
+    }
+
+    /// The start of the heap's memory.
+    pub fn bottom(&self) -> *mut u8 {
+        self.heap_base
+    }
+
+    /// The end of the heap's memory.
+    pub fn top(&self) -> *mut u8 {
+        self.heap_base.wrapping_add(self.heap_size)
+    }
+
+    /// The size of the heap.
+    pub fn size(&self) -> usize {
+        self.heap_size
+    }
+
+    /// The number of bytes allocated.  These are whole blocks, so unlike
+    /// `linked_list_allocator` it includes what was lost to rounding up to
+    /// a power of two.  See [Heap::used_bytes].
+    pub fn used(&self) -> usize {
+        self.used_bytes()
+    }
+
+    /// The number of bytes free.  See [Heap::free_bytes].
+    pub fn free(&self) -> usize {
+        self.free_bytes()
+    }
+}
+
+impl<const N: usize> LockedHeap<N> {
+    /// A locked empty heap, for use as a `#[global_allocator]` that's given
+    /// its memory at runtime:
+    ///
+    /// ```no_run
+    /// use buddyalloc::LockedHeap;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: LockedHeap = LockedHeap::empty();
+    ///
+    /// pub fn init_heap() {
+    ///     let heap_start = 0x4444_4444_0000;
+    ///     let heap_end = 0x4444_4454_0000;
+    ///     let heap_size = heap_end - heap_start;
+    ///     unsafe {
+    ///         ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+    ///     }
+    /// }
+    /// # fn main() {}
+    /// ```
+    pub const fn empty() -> Self {
+        Self::new(Heap::empty())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn test_linked_list_allocator_api() {
+        static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+        unsafe {
+            let layout = Layout::new::<u64>();
+            assert!(ALLOCATOR.alloc(layout).is_null());
+            assert_eq!((0, 0, 0), {
+                let heap = ALLOCATOR.lock();
+                (heap.size(), heap.used(), heap.free())
+            });
+
+            // Unaligned memory that isn't a power of two in size.
+            let mem_layout = std::alloc::Layout::from_size_align(3 << 20, 4096).unwrap();
+            let mem = std::alloc::alloc(mem_layout);
+            ALLOCATOR.lock().init(mem.add(100), (3 << 20) - 100);
+            {
+                let heap = ALLOCATOR.lock();
+                assert_eq!(mem.add(4096), heap.bottom());
+                assert_eq!(2 << 20, heap.size());
+                assert_eq!(heap.bottom().add(2 << 20), heap.top());
+                assert_eq!(2 << 20, heap.free());
+            }
+
+            let p = ALLOCATOR.alloc(layout);
+            assert!(!p.is_null());
+            // The smallest block is 2 MiB >> 15 = 64 bytes.
+            assert_eq!(64, ALLOCATOR.lock().used());
+            assert_eq!((2 << 20) - 64, ALLOCATOR.lock().free());
+
+            ALLOCATOR.dealloc(p, layout);
+            assert_eq!(0, ALLOCATOR.lock().used());
+            *ALLOCATOR.lock() = Heap::empty();
+            std::alloc::dealloc(mem, mem_layout);
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        unsafe {
+            let mem_layout = std::alloc::Layout::from_size_align(16384, 4096).unwrap();
+            let mem = std::alloc::alloc(mem_layout);
+            let mut heap: Heap<9> = Heap::empty();
+            heap.init(mem, 4096);
+
+            // Enough for one doubling and a bit more: the bit is unused.
+            heap.extend(4096 + 2048);
+            assert_eq!((mem, 8192), (heap.bottom(), heap.size()));
+            assert_eq!(8192, heap.free());
+            // And for two at once.
+            heap.extend(8192);
+            assert_eq!(16384, heap.size());
+            let block = Layout::from_size_align(16384, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(block));
+            heap.deallocate(mem, block);
+
+            drop(heap);
+            std::alloc::dealloc(mem, mem_layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "at least as much"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_extend_too_little() {
+        let mut heap: Heap<9> = unsafe { Heap::new_unchecked(0x1_0000 as *mut u8, 4096) };
+        unsafe { heap.extend(4095) };
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "live allocations"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_extend_in_use() {
+        unsafe {
+            let mem_layout = std::alloc::Layout::from_size_align(8192, 4096).unwrap();
+            let mem = std::alloc::alloc(mem_layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), 4096).unwrap();
+            heap.allocate(Layout::from_size_align(16, 1).unwrap())
+                .unwrap();
+            // The memory is leaked, since the panic skips freeing it.
+            heap.extend(4096);
+        }
+    }
+}
@@ -0,0 +1,671 @@
+//! A heap whose free lists are lock-free stacks, so that allocating and
+//! freeing blocks of a size there are free blocks of never takes a lock.
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::cmp::min;
+use core::ptr;
+use core::sync::atomic::Ordering;
+
+#[cfg(all(test, loom))]
+extern crate std;
+#[cfg(not(all(test, loom)))]
+use core::hint::spin_loop;
+#[cfg(not(all(test, loom)))]
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+#[cfg(all(test, loom))]
+use loom::sync::atomic::{AtomicBool, AtomicUsize};
+#[cfg(all(test, loom))]
+use loom::thread::yield_now as spin_loop;
+
+use crate::heap::{buddy, free_list_pop, list_insert, list_remove, MIN_HEAP_ALIGN};
+use crate::math::{allocation_size, log2};
+use crate::{AllocationError, AllocationSizeError, Heap};
+
+/// A [Heap] shared between threads, with each free list a lock-free
+/// (Treiber) stack.  An allocation pops a block off the stack for its
+/// order and a free pushes it back, each with a single compare-and-swap,
+/// so threads working with sizes the heap already has free blocks of never
+/// wait for each other.  This implements [GlobalAlloc].
+///
+/// Only an allocation which finds its stack empty takes the lock.  Under
+/// it, the allocation splits a block popped from a larger stack, pushing
+/// the halves it doesn't need, and if every larger stack is empty too it
+/// pops every free block, merges buddies together, and pushes the results
+/// back before trying again.  Freeing never merges, so until an allocation
+/// needs it, memory freed as small blocks stays small.
+///
+/// As with [crate::ShardedHeap], only the buddy algorithm itself is shared:
+/// the heap's hooks and side tables aren't consulted, so don't set them up
+/// on a heap wrapped in one of these.
+///
+/// # The ABA problem
+///
+/// Popping reads the head block's link to the next one, then swaps the
+/// head for that link.  If in between other threads popped the head block,
+/// changed the stack, and pushed the block back, the swap would succeed
+/// with a stale link.  So rather than a pointer, each head holds the
+/// block's index in the heap in its low bits and a tag in the rest, which
+/// every push and pop increments, and the swap fails if it changed.  The
+/// tag gets whatever bits the index doesn't need; with few left (a 32-bit
+/// target with a big heap of small blocks) it could wrap around while a
+/// thread is stalled between reading and swapping, but it would take that
+/// many operations on the one stack in the meantime.
+///
+/// # The link table
+///
+/// The heap's own free lists link each free block to the next through the
+/// block itself, but the stacks can't: a thread popping a block can read
+/// its link after another thread has popped it, allocated it, and started
+/// writing to it.  The tag makes sure the value read is thrown away, but
+/// the read would still race with the other thread's ordinary writes.  So
+/// the links live in a table of their own, an atomic word for every
+/// minimum-sized block, which nothing but the stacks ever touches.
+#[derive(Debug)]
+pub struct ConcurrentHeap<const N: usize = 16> {
+    /// The heap, whose free lists are only used under the lock, to merge
+    /// blocks.  They're empty the rest of the time.
+    heap: UnsafeCell<Heap<N>>,
+    base: *mut u8,
+    heap_size: usize,
+    min_block_size_log2: u8,
+    /// The number of bits of a head for the index of its block, plus one so
+    /// that zero means the stack is empty.
+    index_bits: u32,
+    heads: [AtomicUsize; N],
+    /// The links of the stacks, one for each minimum-sized block.
+    links: &'static [AtomicUsize],
+    lock: AtomicBool,
+    used_bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+// SAFETY: The heap's free lists are only touched under the lock, and the
+// stacks only through their atomic heads and links.
+unsafe impl<const N: usize> Send for ConcurrentHeap<N> {}
+unsafe impl<const N: usize> Sync for ConcurrentHeap<N> {}
+
+impl<const N: usize> ConcurrentHeap<N> {
+    /// Share `heap` between threads, moving its free blocks onto the
+    /// stacks, with `links` as the table of their links (see above).  It
+    /// needs [ConcurrentHeap::links_needed] entries, or this panics, and
+    /// mustn't be used for anything else while the heap is shared.
+    ///
+    /// ```
+    /// # use buddyalloc::{ConcurrentHeap, Heap};
+    /// # use core::sync::atomic::AtomicUsize;
+    /// #[repr(align(4096))]
+    /// struct Memory([u8; 65536]);
+    ///
+    /// // 64 KiB in blocks of at least 16 bytes has 4096 of them.
+    /// static LINKS: [AtomicUsize; 4096] = [const { AtomicUsize::new(0) }; 4096];
+    ///
+    /// let memory = Box::leak(Box::new(Memory([0; 65536])));
+    /// let heap = Heap::<13>::new_from_bytes(&mut memory.0).unwrap();
+    /// let heap = ConcurrentHeap::new(heap, &LINKS);
+    /// ```
+    pub fn new(heap: Heap<N>, links: &'static [AtomicUsize]) -> Self {
+        let blocks = Self::links_needed(&heap);
+        if links.len() < blocks {
+            heap_panic!("Link table too small for the heap");
+        }
+        let concurrent = Self {
+            base: heap.heap_base,
+            heap_size: heap.heap_size,
+            min_block_size_log2: heap.min_block_size_log2,
+            index_bits: usize::BITS - blocks.leading_zeros(),
+            heads: core::array::from_fn(|_| AtomicUsize::new(0)),
+            links,
+            lock: AtomicBool::new(false),
+            used_bytes: AtomicUsize::new(heap.used_bytes),
+            allocations: AtomicUsize::new(heap.allocations),
+            deallocations: AtomicUsize::new(heap.deallocations),
+            failures: AtomicUsize::new(heap.failures),
+            heap: UnsafeCell::new(heap),
+        };
+        // SAFETY: Nothing else can see the heap yet.
+        unsafe { concurrent.push_free_lists() };
+        concurrent
+    }
+
+    /// The number of entries the link table for `heap` needs: one for each
+    /// minimum-sized block.
+    pub fn links_needed(heap: &Heap<N>) -> usize {
+        heap.heap_size >> heap.min_block_size_log2
+    }
+
+    /// Consume the heap, returning the [Heap] inside it with its free
+    /// blocks merged back together and its counters brought up to date.
+    /// Since blocks were handed out without the heap's own bookkeeping, it
+    /// no longer assumes any of its memory is still zero.
+    pub fn into_inner(self) -> Heap<N> {
+        for order in 0..N {
+            while let Some(block) = self.pop(order) {
+                // SAFETY: We own the heap, and the block is free.
+                unsafe { self.merge(order, block) };
+            }
+        }
+
+        let mut heap = self.heap.into_inner();
+        heap.used_bytes = self.used_bytes.load(Ordering::Relaxed);
+        heap.allocations = self.allocations.load(Ordering::Relaxed);
+        heap.deallocations = self.deallocations.load(Ordering::Relaxed);
+        heap.failures = self.failures.load(Ordering::Relaxed);
+        heap.zero_from = heap.heap_size;
+
+        #[cfg(feature = "debug-info")]
+        heap.refresh_free_counts();
+
+        heap
+    }
+
+    /// The number of bytes in allocated blocks.
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Allocate a block for `layout`, as [Heap::allocate] does.
+    pub fn allocate(&self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        let order = match self.allocation_order(layout) {
+            Ok(order) => order,
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return Err(AllocationError::InvalidSize(e));
+            }
+        };
+
+        match self.pop(order).or_else(|| self.allocate_locked(order)) {
+            Some(block) => {
+                self.used_bytes
+                    .fetch_add(self.order_size(order), Ordering::Relaxed);
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                Ok(block)
+            }
+            None => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                Err(AllocationError::HeapExhausted)
+            }
+        }
+    }
+
+    /// Free a block from [ConcurrentHeap::allocate] by pushing it onto the
+    /// stack for its order, without merging it.
+    ///
+    /// # Safety
+    /// As for [Heap::deallocate].
+    pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let order = match self.allocation_order(layout) {
+            Ok(order) => order,
+            Err(_) => heap_panic!("Tried to dispose of invalid block"),
+        };
+        let offset = (ptr as usize).wrapping_sub(self.base as usize);
+        if offset >= self.heap_size || offset & (self.order_size(order) - 1) != 0 {
+            heap_panic!("Tried to dispose of invalid block");
+        }
+
+        self.used_bytes
+            .fetch_sub(self.order_size(order), Ordering::Relaxed);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.push(order, ptr);
+    }
+
+    /// Find a block of `order` when its stack is empty, by splitting a
+    /// larger one or, failing that, merging every free block first.
+    fn allocate_locked(&self, order: usize) -> Option<*mut u8> {
+        while self
+            .lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.lock.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+
+        // SAFETY: We hold the lock.
+        let block = self.split_from(order).or_else(|| unsafe {
+            self.merge_all();
+            self.split_from(order)
+        });
+
+        self.lock.store(false, Ordering::Release);
+        block
+    }
+
+    /// Pop a block of `order` or larger, pushing the upper halves we don't
+    /// need back onto their stacks.  A thread whose stack was empty can't
+    /// find a block while we're splitting it, but then it waits for the
+    /// lock, and finds one of the halves.
+    fn split_from(&self, order_needed: usize) -> Option<*mut u8> {
+        for order in order_needed..N {
+            if let Some(block) = self.pop(order) {
+                for split in (order_needed..order).rev() {
+                    // SAFETY: The block we popped covers all of them.
+                    unsafe { self.push(split, block.add(self.order_size(split))) };
+                }
+                return Some(block);
+            }
+        }
+        None
+    }
+
+    /// Pop every free block and push them back with buddies merged.
+    ///
+    /// # Safety
+    /// The lock must be held.
+    unsafe fn merge_all(&self) {
+        for order in 0..N {
+            while let Some(block) = self.pop(order) {
+                self.merge(order, block);
+            }
+        }
+        self.push_free_lists();
+    }
+
+    /// Put `block` of `order` on the heap's own free lists, merging it with
+    /// its buddies there.
+    ///
+    /// # Safety
+    /// The lock must be held (or the heap not shared), and the block must
+    /// be free and on no stack.
+    unsafe fn merge(&self, order: usize, block: *mut u8) {
+        let lists = &mut (*self.heap.get()).free_lists;
+        let mut block = block;
+        for (order, head) in lists.iter_mut().enumerate().skip(order) {
+            let buddy = buddy(
+                self.base,
+                self.heap_size,
+                self.min_block_size_log2,
+                order,
+                block,
+            );
+            match buddy {
+                Some(buddy) if list_remove(head, buddy) => block = min(block, buddy),
+                _ => return list_insert(head, block),
+            }
+        }
+    }
+
+    /// Move every block on the heap's own free lists onto the stacks.
+    ///
+    /// # Safety
+    /// As for [ConcurrentHeap::merge].
+    unsafe fn push_free_lists(&self) {
+        let lists = &mut (*self.heap.get()).free_lists;
+        for order in 0..N {
+            while let Some(block) = free_list_pop(lists, order) {
+                self.push(order, block);
+            }
+        }
+    }
+
+    /// Pop a block off the stack for `order`.
+    fn pop(&self, order: usize) -> Option<*mut u8> {
+        let head = &self.heads[order];
+        let mut current = head.load(Ordering::Acquire);
+        loop {
+            let index = current & self.index_mask();
+            if index == 0 {
+                return None;
+            }
+            let block = self.block(index);
+            // Another thread may have popped the block since we read the
+            // head, but then the tag has changed and the swap fails.
+            let next = self.link(block).load(Ordering::Relaxed);
+            match head.compare_exchange(
+                current,
+                self.retag(current, next),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(block),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Push `block` onto the stack for `order`.
+    ///
+    /// # Safety
+    /// The block must be free, of `order`, and on no stack.
+    unsafe fn push(&self, order: usize, block: *mut u8) {
+        let head = &self.heads[order];
+        let index = self.index(block);
+        let mut current = head.load(Ordering::Relaxed);
+        loop {
+            self.link(block)
+                .store(current & self.index_mask(), Ordering::Relaxed);
+            match head.compare_exchange(
+                current,
+                self.retag(current, index),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// The head to replace `current` with to point at `index`: the tag is
+    /// one more than before, wrapping around.
+    fn retag(&self, current: usize, index: usize) -> usize {
+        (current & !self.index_mask()).wrapping_add(self.index_mask() + 1) | index
+    }
+
+    fn index_mask(&self) -> usize {
+        (1 << self.index_bits) - 1
+    }
+
+    /// The index of `block` in a head or link.
+    fn index(&self, block: *mut u8) -> usize {
+        ((block as usize - self.base as usize) >> self.min_block_size_log2) + 1
+    }
+
+    /// The block at `index` in a head or link.
+    fn block(&self, index: usize) -> *mut u8 {
+        self.base
+            .wrapping_add((index - 1) << self.min_block_size_log2)
+    }
+
+    /// The link to the next block on the stack after `block`.
+    fn link(&self, block: *mut u8) -> &AtomicUsize {
+        &self.links[self.index(block) - 1]
+    }
+
+    /// The order of the block [ConcurrentHeap::allocate] needs for
+    /// `layout`.
+    fn allocation_order(&self, layout: Layout) -> Result<usize, AllocationSizeError> {
+        allocation_size(
+            1 << self.min_block_size_log2,
+            self.heap_size,
+            MIN_HEAP_ALIGN,
+            layout.size(),
+            layout.align(),
+        )
+        .map(|s| (log2(s) - self.min_block_size_log2) as usize)
+    }
+
+    /// The size of the blocks of `order`.
+    fn order_size(&self, order: usize) -> usize {
+        1 << (self.min_block_size_log2 as usize + order)
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for ConcurrentHeap<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+
+    /// Share `heap`, with a link table leaked for it.
+    fn share<const N: usize>(heap: Heap<N>) -> ConcurrentHeap<N> {
+        let links = (0..ConcurrentHeap::links_needed(&heap))
+            .map(|_| AtomicUsize::new(0))
+            .collect::<std::vec::Vec<_>>();
+        ConcurrentHeap::new(heap, std::vec::Vec::leak(links))
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    #[cfg_attr(
+        not(feature = "tiny"),
+        should_panic(expected = "Link table too small for the heap")
+    )]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_concurrent_heap_links_too_small() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            static LINKS: [AtomicUsize; 15] = [const { AtomicUsize::new(0) }; 15];
+            let heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            ConcurrentHeap::new(heap, &LINKS);
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_concurrent_heap() {
+        use std::vec::Vec;
+
+        unsafe {
+            let heap_size = 1 << 16;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: ConcurrentHeap<12> =
+                share(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            // As for `ShardedHeap`: mostly one size class per thread, with
+            // big blocks now and then to force splits and merges, and every
+            // block stamped so that handing one out twice would show.
+            std::thread::scope(|s| {
+                for t in 0..8u8 {
+                    let heap = &heap;
+                    s.spawn(move || {
+                        let mut live = Vec::new();
+                        for i in 0..4000usize {
+                            let size = if i % 97 == 0 { 4096 } else { 16 << (t % 4) };
+                            let block = Layout::from_size_align(size, 1).unwrap();
+                            if live.len() < 16 && i % 5 != 4 {
+                                if let Ok(p) = heap.allocate(block) {
+                                    p.write_bytes(t, size);
+                                    live.push((p, block));
+                                }
+                            } else if let Some((p, block)) = live.pop() {
+                                let bytes = std::slice::from_raw_parts(p, block.size());
+                                assert!(bytes.iter().all(|&b| b == t));
+                                heap.deallocate(p, block);
+                            }
+                        }
+                        for (p, block) in live {
+                            heap.deallocate(p, block);
+                        }
+                    });
+                }
+            });
+
+            // Everything merges back together on the way out.
+            assert_eq!(0, heap.used_bytes());
+            let heap = heap.into_inner();
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            assert!(heap.is_block_free(11, mem));
+            assert_eq!(heap.stats().allocations, heap.stats().deallocations);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_concurrent_exhaustion() {
+        use std::vec::Vec;
+
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: ConcurrentHeap<5> =
+                share(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(small));
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate(Layout::from_size_align(512, 1).unwrap())
+            );
+            for &block in &blocks {
+                heap.deallocate(block, small);
+            }
+
+            // The small blocks were left unmerged, until an allocation
+            // needed the whole heap.
+            let whole = Layout::from_size_align(256, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(whole));
+            heap.deallocate(mem, whole);
+
+            let heap = heap.into_inner();
+            let stats = heap.stats();
+            assert_eq!(
+                (17, 17, 2),
+                (stats.allocations, stats.deallocations, stats.failures)
+            );
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    #[cfg_attr(
+        not(feature = "tiny"),
+        should_panic(expected = "Tried to dispose of invalid block")
+    )]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_concurrent_deallocate_misaligned() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: ConcurrentHeap<5> =
+                share(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(small).unwrap();
+            heap.deallocate(block.add(8), small);
+        }
+    }
+
+    /// Run `f` under the model checker on a fresh 64-byte heap of four
+    /// 16-byte blocks, after `setup`.
+    #[cfg(loom)]
+    fn model(setup: fn(&ConcurrentHeap<3>), f: fn(loom::sync::Arc<ConcurrentHeap<3>>, *mut u8)) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(move || unsafe {
+            let heap_size = 64;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap = share(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+            setup(&heap);
+            f(loom::sync::Arc::new(heap), mem);
+            std::alloc::dealloc(mem, layout);
+        });
+    }
+
+    #[cfg(loom)]
+    const SMALL: Layout = unsafe { Layout::from_size_align_unchecked(16, 16) };
+
+    /// Leave all four small blocks on the order 0 stack.
+    #[cfg(loom)]
+    fn split_all(heap: &ConcurrentHeap<3>) {
+        let blocks = [(); 4].map(|_| heap.allocate(SMALL).unwrap());
+        for block in blocks {
+            unsafe { heap.deallocate(block, SMALL) };
+        }
+    }
+
+    /// Take the heap back from the threads and check that every block not
+    /// in `live` is free.
+    #[cfg(loom)]
+    fn check(heap: loom::sync::Arc<ConcurrentHeap<3>>, mem: *mut u8, live: &[*mut u8]) {
+        let heap = loom::sync::Arc::try_unwrap(heap).unwrap().into_inner();
+        assert!(heap.accounting_check());
+        assert_eq!(live.len() * 16, heap.used_bytes());
+        let mut heap = heap;
+        for _ in live.len()..4 {
+            let block = heap.allocate(SMALL).unwrap();
+            assert!(!live.contains(&block));
+            assert!(block >= mem && block < mem.wrapping_add(64));
+        }
+        assert!(heap.allocate(SMALL).is_err());
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn loom_allocate_same_order() {
+        model(split_all, |heap, mem| {
+            let other = heap.clone();
+            let thread = loom::thread::spawn(move || other.allocate(SMALL).unwrap() as usize);
+            let ours = heap.allocate(SMALL).unwrap();
+            let theirs = thread.join().unwrap() as *mut u8;
+            assert_ne!(ours, theirs);
+            check(heap, mem, &[ours, theirs]);
+        });
+    }
+
+    /// The classic ABA interleaving: one thread reads the head and its link,
+    /// then the other pops both blocks and pushes the first back on top.
+    /// Without the tag, the first thread's swap would succeed, and hand out
+    /// the second block twice.
+    #[cfg(loom)]
+    #[test]
+    fn loom_aba() {
+        model(split_all, |heap, mem| {
+            let other = heap.clone();
+            let thread = loom::thread::spawn(move || unsafe {
+                let a = other.allocate(SMALL).unwrap();
+                let b = other.allocate(SMALL).unwrap();
+                other.deallocate(a, SMALL);
+                b as usize
+            });
+            let ours = heap.allocate(SMALL).unwrap();
+            let theirs = thread.join().unwrap() as *mut u8;
+            assert_ne!(ours, theirs);
+            check(heap, mem, &[ours, theirs]);
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn loom_allocate_deallocate() {
+        model(split_all, |heap, mem| {
+            let block = heap.allocate(SMALL).unwrap();
+            let other = heap.clone();
+            let thread = loom::thread::spawn(move || unsafe {
+                other.deallocate(block, SMALL);
+            });
+            let ours = heap.allocate(SMALL).unwrap();
+            thread.join().unwrap();
+            check(heap, mem, &[ours]);
+        });
+    }
+
+    /// Both threads find the order 0 stack empty, and race to split the
+    /// whole heap.
+    #[cfg(loom)]
+    #[test]
+    fn loom_split() {
+        model(
+            |_| {},
+            |heap, mem| {
+                let other = heap.clone();
+                let thread = loom::thread::spawn(move || other.allocate(SMALL).unwrap() as usize);
+                let ours = heap.allocate(SMALL).unwrap();
+                let theirs = thread.join().unwrap() as *mut u8;
+                assert_ne!(ours, theirs);
+                check(heap, mem, &[ours, theirs]);
+            },
+        );
+    }
+}
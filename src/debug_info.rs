@@ -0,0 +1,218 @@
+//! A summary of a heap's state kept in memory in a fixed, versioned layout,
+//! so that a debugger can inspect a halted target's heap without running
+//! any code on it.  `scripts/buddyalloc_gdb.py` knows how to read it.
+use core::alloc::Layout;
+use core::mem::offset_of;
+
+use crate::heap::free_list_len;
+use crate::{AllocationError, Heap};
+
+/// The magic number at the start of [HeapDebugInfo], `"BUDY"` when read as
+/// bytes on a little-endian target.
+pub const DEBUG_INFO_MAGIC: u32 = 0x5944_5542;
+
+/// The version of the [HeapDebugInfo] layout.
+pub const DEBUG_INFO_VERSION: u32 = 1;
+
+/// The last allocation failure a heap saw.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FailureInfo {
+    /// The size of the failed request.
+    pub size: usize,
+    /// The alignment of the failed request.
+    pub align: usize,
+    /// Why it failed: 0 if nothing has failed yet, or the error's code (see
+    /// `impl From<AllocationError> for usize`).
+    pub reason: usize,
+}
+
+impl FailureInfo {
+    /// No failure.
+    pub const NONE: FailureInfo = FailureInfo {
+        size: 0,
+        align: 0,
+        reason: 0,
+    };
+
+    fn new(layout: Layout, error: AllocationError) -> Self {
+        FailureInfo {
+            size: layout.size(),
+            align: layout.align(),
+            reason: error.into(),
+        }
+    }
+}
+
+/// A summary of a heap's state for debuggers.
+///
+/// # Layout
+/// The layout is a semi-stable ABI: any change to it bumps
+/// [DEBUG_INFO_VERSION].  [HeapDebugInfo] is the first field of [Heap], so
+/// it lives at offset 0 of the heap (but note that wrappers such as
+/// `LockedHeap` may put it elsewhere; tools should search for the magic
+/// number).  It starts with two 32-bit words, followed by pointer-sized
+/// words, all in the target's byte order:
+///
+/// | Offset    | Field                                              |
+/// |-----------|----------------------------------------------------|
+/// | 0         | magic, [DEBUG_INFO_MAGIC]                          |
+/// | 4         | version, [DEBUG_INFO_VERSION]                      |
+/// | 8 + 0·W   | heap base address                                  |
+/// | 8 + 1·W   | heap size                                          |
+/// | 8 + 2·W   | minimum block size                                 |
+/// | 8 + 3·W   | number of orders, `N`                              |
+/// | 8 + 4·W   | offset of the heap's free list heads from offset 0 |
+/// | 8 + 5·W   | last failure: requested size                       |
+/// | 8 + 6·W   | last failure: requested alignment                  |
+/// | 8 + 7·W   | last failure: reason (see [FailureInfo])           |
+/// | 8 + 8·W   | `N` words: number of free blocks of each order     |
+///
+/// where `W` is the size of a pointer.  The free list heads are `N` pointers;
+/// each free block starts with a pointer to the next block in its list.
+/// The single block of order `N - 1` never has a valid `next` pointer.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HeapDebugInfo<const N: usize> {
+    pub magic: u32,
+    pub version: u32,
+    pub heap_base: *mut u8,
+    pub heap_size: usize,
+    pub min_block_size: usize,
+    pub num_orders: usize,
+    pub free_lists_offset: usize,
+    pub last_failure: FailureInfo,
+    pub free_counts: [usize; N],
+}
+
+impl<const N: usize> HeapDebugInfo<N> {
+    /// The debug info for a freshly created heap.
+    pub(crate) const fn new(heap_base: *mut u8, heap_size: usize, min_block_size: usize) -> Self {
+        let mut free_counts = [0; N];
+        free_counts[N - 1] = 1;
+
+        Self {
+            magic: DEBUG_INFO_MAGIC,
+            version: DEBUG_INFO_VERSION,
+            heap_base,
+            heap_size,
+            min_block_size,
+            num_orders: N,
+            free_lists_offset: offset_of!(Heap<N>, free_lists),
+            last_failure: FailureInfo::NONE,
+            free_counts,
+        }
+    }
+}
+
+impl<const N: usize> Heap<N> {
+    /// The heap's debug info, as last updated.
+    pub fn debug_info(&self) -> &HeapDebugInfo<N> {
+        &self.debug_info
+    }
+
+    /// Bring the debug info up to date at the end of an operation on
+    /// `layout` which produced `result`.  This walks every free list, so it
+    /// makes each operation O(number of free blocks).
+    pub(crate) fn update_debug_info(
+        &mut self,
+        layout: Layout,
+        result: Result<*mut u8, AllocationError>,
+    ) {
+        if let Err(e) = result {
+            self.debug_info.last_failure = FailureInfo::new(layout, e);
+        }
+
+        self.refresh_free_counts();
+    }
+
+    /// Recount the free blocks of each order in the debug info.
+    pub(crate) fn refresh_free_counts(&mut self) {
+        for order in 0..N {
+            self.debug_info.free_counts[order] = free_list_len(&self.free_lists, order);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::mem::size_of;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    const W: usize = size_of::<usize>();
+
+    /// Read a pointer-sized word out of a memory snapshot.
+    fn word(snapshot: &[u8], offset: usize) -> usize {
+        let mut bytes = [0; W];
+        bytes.copy_from_slice(&snapshot[offset..offset + W]);
+        usize::from_ne_bytes(bytes)
+    }
+
+    /// Read a 32-bit word out of a memory snapshot.
+    fn word32(snapshot: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&snapshot[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    }
+
+    /// Copy the raw bytes of `value`, as a debugger would.
+    fn snapshot<T>(value: &T) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+            .to_vec()
+    }
+
+    #[test]
+    fn test_layout_from_snapshot() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let block_layout = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(block_layout).unwrap();
+            let too_big = Layout::from_size_align(512, 8).unwrap();
+            assert!(heap.allocate(too_big).is_err());
+
+            // Parse the snapshot using nothing but the documented layout.
+            let snap = snapshot(&heap);
+            assert_eq!(DEBUG_INFO_MAGIC, word32(&snap, 0));
+            if cfg!(target_endian = "little") {
+                assert_eq!(&b"BUDY"[..], &snap[..4]);
+            }
+            assert_eq!(DEBUG_INFO_VERSION, word32(&snap, 4));
+            assert_eq!(mem as usize, word(&snap, 8));
+            assert_eq!(heap_size, word(&snap, 8 + W));
+            assert_eq!(16, word(&snap, 8 + 2 * W));
+            assert_eq!(5, word(&snap, 8 + 3 * W));
+            assert_eq!([512, 8, 3], [5, 6, 7].map(|i| word(&snap, 8 + i * W)));
+
+            // After a 16-byte allocation, there's one free block of every
+            // order except the top one.
+            let counts: Vec<_> = (0..5).map(|i| word(&snap, 8 + (8 + i) * W)).collect();
+            assert_eq!(std::vec![1, 1, 1, 1, 0], counts);
+
+            // The free list heads in the snapshot agree with the counts.
+            let heads = word(&snap, 8 + 4 * W);
+            for order in 0..4 {
+                let head = word(&snap, heads + order * W) as *const usize;
+                assert_eq!(mem as usize + (16 << order), head as usize);
+                assert_eq!(0, *head);
+            }
+            assert_eq!(0, word(&snap, heads + 4 * W));
+
+            heap.deallocate(block, block_layout);
+            let snap = snapshot(&heap);
+            let counts: Vec<_> = (0..5).map(|i| word(&snap, 8 + (8 + i) * W)).collect();
+            assert_eq!(std::vec![0, 0, 0, 0, 1], counts);
+            assert_eq!(heap.debug_info().free_counts, [0, 0, 0, 0, 1]);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
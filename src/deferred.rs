@@ -0,0 +1,150 @@
+//! Deferred freeing, which queues freed blocks in a caller-provided list and
+//! gives them back to the heap in batches.
+use core::ptr;
+
+use crate::Heap;
+
+impl<const N: usize> Heap<N> {
+    /// Queue the block at `ptr`, of order `order`, to be freed later,
+    /// instead of freeing it and merging it with its buddies now.  The queue
+    /// is `freed_blocks`, whose unused entries have a null pointer; if it's
+    /// full, everything in it is freed first to make room.  Then, as with
+    /// [Heap::coalesce_with_delay], the whole queue is freed if more than
+    /// `max_pending` blocks are waiting.
+    ///
+    /// This moves the cost of freeing, which can mean a merge at every
+    /// order, out of the caller's hot path and into one batch.  Until then
+    /// the queued blocks still count as used, and can't be allocated.
+    ///
+    /// # Safety
+    /// As for [Heap::deallocate] with [Heap::block_layout]`(order)`.  The
+    /// block mustn't be used again, and `freed_blocks` mustn't be handed to
+    /// another heap while it holds anything.
+    pub unsafe fn deallocate_deferred(
+        &mut self,
+        ptr: *mut u8,
+        order: usize,
+        freed_blocks: &mut [(*mut u8, usize)],
+        max_pending: usize,
+    ) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let slot = match freed_blocks.iter().position(|(p, _)| p.is_null()) {
+            Some(slot) => slot,
+            None => {
+                self.flush_pending_frees(freed_blocks);
+                0
+            }
+        };
+        match freed_blocks.get_mut(slot) {
+            Some(entry) => *entry = (ptr, order),
+            // There's nowhere to queue it at all.
+            None => self.deallocate(ptr, self.block_layout(order)),
+        }
+
+        self.coalesce_with_delay(freed_blocks, max_pending);
+    }
+
+    /// Free every block queued in `freed_blocks` by
+    /// [Heap::deallocate_deferred] if more than `max_pending` are waiting,
+    /// and return how many were freed.
+    ///
+    /// # Safety
+    /// Every entry with a non-null pointer must be a block of this heap,
+    /// queued by [Heap::deallocate_deferred].
+    pub unsafe fn coalesce_with_delay(
+        &mut self,
+        freed_blocks: &mut [(*mut u8, usize)],
+        max_pending: usize,
+    ) -> usize {
+        let pending = freed_blocks.iter().filter(|(p, _)| !p.is_null()).count();
+        if pending > max_pending {
+            self.flush_pending_frees(freed_blocks)
+        } else {
+            0
+        }
+    }
+
+    /// Free every block queued in `freed_blocks` now, leaving it empty, and
+    /// return how many there were.
+    ///
+    /// # Safety
+    /// As for [Heap::coalesce_with_delay].
+    pub unsafe fn flush_pending_frees(&mut self, freed_blocks: &mut [(*mut u8, usize)]) -> usize {
+        let mut freed = 0;
+        for (block, order) in freed_blocks {
+            if !block.is_null() {
+                self.deallocate(*block, self.block_layout(*order));
+                *block = ptr::null_mut();
+                freed += 1;
+            }
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::AllocationError;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+
+    #[test]
+    fn test_deferred_frees() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let block_64 = Layout::from_size_align(64, 64).unwrap();
+            let blocks: std::vec::Vec<_> =
+                (0..4).map(|_| heap.allocate(block_64).unwrap()).collect();
+            let mut pending = [(ptr::null_mut(), 0); 4];
+
+            // Up to two frees wait in the queue, and the memory stays in use.
+            heap.deallocate_deferred(blocks[0], 2, &mut pending, 2);
+            heap.deallocate_deferred(blocks[1], 2, &mut pending, 2);
+            assert_eq!(256, heap.used_bytes());
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(block_64));
+            assert_eq!(0, heap.coalesce_with_delay(&mut pending, 2));
+
+            // Flushing frees them, merged back together.
+            assert_eq!(2, heap.flush_pending_frees(&mut pending));
+            assert!(pending.iter().all(|(p, _)| p.is_null()));
+            assert_eq!(128, heap.used_bytes());
+            assert!(heap.is_block_free(3, mem));
+            let block_128 = Layout::from_size_align(128, 128).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(block_128));
+
+            // Going over the limit frees the whole queue.
+            heap.deallocate_deferred(mem, 3, &mut pending, 1);
+            heap.deallocate_deferred(blocks[2], 2, &mut pending, 1);
+            assert_eq!(64, heap.used_bytes());
+            assert!(pending.iter().all(|(p, _)| p.is_null()));
+
+            // A full queue is flushed to make room.
+            let mut one = [(ptr::null_mut(), 0); 1];
+            let remaining = heap.allocate(block_64).unwrap();
+            heap.deallocate_deferred(remaining, 2, &mut one, 8);
+            assert_eq!(128, heap.used_bytes());
+            heap.deallocate_deferred(blocks[3], 2, &mut one, 8);
+            assert_eq!(64, heap.used_bytes());
+            assert_eq!((blocks[3], 2), one[0]);
+
+            // With no room to queue anything, freeing happens at once.
+            heap.flush_pending_frees(&mut one);
+            let block = heap.allocate(block_64).unwrap();
+            heap.deallocate_deferred(block, 2, &mut [], 8);
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
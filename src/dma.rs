@@ -0,0 +1,191 @@
+//! Allocation of DMA buffers, with cache maintenance hooks for cores whose
+//! DMA isn't cache-coherent.
+use core::alloc::Layout;
+
+use crate::{AllocationError, AllocationSizeError, Heap};
+
+/// Cache maintenance for DMA buffers, registered with
+/// [crate::HeapBuilder::dma_hooks] and run by [Heap::allocate_dma] and
+/// [Heap::deallocate_dma].  Both hooks get the start and length of the
+/// whole block, which is always a whole number of cache lines.
+#[derive(Clone, Copy, Debug)]
+pub struct DmaHooks {
+    /// The size of a cache line, a power of two no bigger than 4096 bytes
+    /// (64 on 16-bit targets).  DMA buffers are aligned to it.
+    pub line_size: usize,
+    /// Called on each new buffer before it's handed out, typically to
+    /// clean (write back) its cache lines so nothing dirty is written over
+    /// what the DMA engine puts there.
+    pub before_dma_alloc: fn(*mut u8, usize),
+    /// Called on each buffer as it's freed, before the heap writes to it,
+    /// typically to invalidate its cache lines.
+    pub after_dma_free: fn(*mut u8, usize),
+}
+
+impl<const N: usize> Heap<N> {
+    /// Allocate a buffer for DMA.  With [DmaHooks] registered, the block is
+    /// aligned to a cache line and a whole number of lines long, so cache
+    /// maintenance on it never touches anything else, and
+    /// [DmaHooks::before_dma_alloc] is called on it.  Without them, this is
+    /// just [Heap::allocate].
+    ///
+    /// A line size that isn't a power of two, or is bigger than the heap
+    /// can align to, fails with [AllocationSizeError::BadAlignment].  Free
+    /// the buffer with [Heap::deallocate_dma] and the same `layout`.
+    pub fn allocate_dma(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        let hooks = match self.dma {
+            Some(hooks) => hooks,
+            None => return self.allocate(layout),
+        };
+        let lines = match layout.align_to(hooks.line_size) {
+            Ok(lines) => lines,
+            Err(_) => {
+                let error = AllocationError::InvalidSize(AllocationSizeError::BadAlignment);
+                self.note_result(layout, Err(error));
+                return Err(error);
+            }
+        };
+
+        // The block is aligned to at least its size, up to the heap's base
+        // alignment, so it's whole lines as long as it's aligned to one.
+        let block = self.allocate(lines)?;
+        let (order, _) = self.order_and_waste(lines)?;
+        (hooks.before_dma_alloc)(block, self.order_size(order));
+        Ok(block)
+    }
+
+    /// Free a buffer from [Heap::allocate_dma], calling
+    /// [DmaHooks::after_dma_free] on it first if the hooks are registered.
+    ///
+    /// # Safety
+    /// `ptr` must have come from [Heap::allocate_dma] on this heap with a
+    /// layout order-equivalent to `layout`, and not have been freed.  The
+    /// DMA engine must be finished with it.
+    pub unsafe fn deallocate_dma(&mut self, ptr: *mut u8, layout: Layout) {
+        let hooks = match self.dma {
+            Some(hooks) if !ptr.is_null() => hooks,
+            _ => return self.deallocate(ptr, layout),
+        };
+        let lines = match layout.align_to(hooks.line_size) {
+            Ok(lines) => lines,
+            Err(_) => heap_panic!("Tried to dispose of invalid block"),
+        };
+
+        // Before the heap writes its free list link into the block, which
+        // invalidating would throw away.
+        if let Ok((order, _)) = self.order_and_waste(lines) {
+            (hooks.after_dma_free)(ptr, self.order_size(order));
+        }
+        self.deallocate(ptr, lines);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::HeapBuilder;
+    use core::ptr::NonNull;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    std::thread_local! {
+        /// Every hook call: whether it was a clean, and the range.
+        static CALLS: RefCell<Vec<(bool, *mut u8, usize)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn clean(ptr: *mut u8, len: usize) {
+        CALLS.with(|calls| calls.borrow_mut().push((true, ptr, len)));
+    }
+
+    fn invalidate(ptr: *mut u8, len: usize) {
+        CALLS.with(|calls| calls.borrow_mut().push((false, ptr, len)));
+    }
+
+    fn take_calls() -> Vec<(bool, *mut u8, usize)> {
+        CALLS.with(|calls| calls.take())
+    }
+
+    #[test]
+    fn test_dma_hooks() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let hooks = DmaHooks {
+                line_size: 64,
+                before_dma_alloc: clean,
+                after_dma_free: invalidate,
+            };
+            let mut heap: Heap<8> = HeapBuilder::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .dma_hooks(hooks)
+                .build()
+                .unwrap();
+
+            // Ordinary allocations don't get cache maintenance, and can be
+            // smaller than a line.
+            let small = Layout::from_size_align(16, 4).unwrap();
+            let plain = heap.allocate(small).unwrap();
+            assert_eq!(mem, plain);
+            heap.deallocate(plain, small);
+            assert!(take_calls().is_empty());
+
+            // DMA buffers are cache-line aligned and whole lines long, and
+            // get exactly one clean each.
+            let mut buffers = Vec::new();
+            for size in [16, 64, 100, 200, 1] {
+                let buffer = Layout::from_size_align(size, 4).unwrap();
+                let block = heap.allocate_dma(buffer).unwrap();
+                assert_eq!(0, block as usize % 64);
+                buffers.push((block, buffer));
+            }
+            let calls = take_calls();
+            assert_eq!(5, calls.len());
+            for ((clean, ptr, len), (block, buffer)) in calls.iter().zip(&buffers) {
+                assert!(clean);
+                assert_eq!(block, ptr);
+                assert_eq!(0, len % 64);
+                assert!(*len >= buffer.size());
+            }
+            let lens: Vec<_> = calls.iter().map(|&(_, _, len)| len).collect();
+            assert_eq!([64, 64, 128, 256, 64], lens[..]);
+
+            // And exactly one invalidate when freed, over the same range.
+            for &(block, buffer) in &buffers {
+                heap.deallocate_dma(block, buffer);
+            }
+            let freed = take_calls();
+            assert_eq!(5, freed.len());
+            for (freed, cleaned) in freed.iter().zip(&calls) {
+                assert_eq!((false, cleaned.1, cleaned.2), *freed);
+            }
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.accounting_check());
+
+            drop(heap);
+
+            // A line size the heap can't align to is refused.
+            let mut heap: Heap<8> = HeapBuilder::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .dma_hooks(DmaHooks {
+                    line_size: 48,
+                    ..hooks
+                })
+                .build()
+                .unwrap();
+            assert_eq!(
+                Err(AllocationError::InvalidSize(
+                    AllocationSizeError::BadAlignment
+                )),
+                heap.allocate_dma(small)
+            );
+            assert!(take_calls().is_empty());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! A compact binary dump of a heap's free lists, which a crash handler can
+//! capture into a fixed-size buffer without allocating, and which can be
+//! loaded back later to pick the heap up where it left off.
+use core::convert::TryFrom;
+use core::ptr::{self, NonNull};
+
+use crate::heap::FreeBlock;
+use crate::{Heap, HeapBuilder, HeapError};
+
+/// The magic number at the start of a dump, `"BDMP"` as bytes.
+pub const DUMP_MAGIC: u32 = 0x504D_4442;
+
+/// The version of the dump format.
+pub const DUMP_VERSION: u32 = 1;
+
+/// The size of a dump's header.
+const HEADER_SIZE: usize = 32;
+
+/// The size of each free block's record.
+const RECORD_SIZE: usize = 9;
+
+impl<const N: usize> Heap<N> {
+    /// Serialize the heap's free lists into a `[u8; M]`, returning it and
+    /// the number of bytes of it used.  Nothing is allocated, so this is
+    /// safe to call from a crash handler.  The dump is little-endian, and
+    /// gives blocks as offsets from the base of the heap:
+    ///
+    /// | Offset     | Size | Field                                 |
+    /// |------------|------|---------------------------------------|
+    /// | 0          | 4    | magic, [DUMP_MAGIC]                   |
+    /// | 4          | 4    | version, [DUMP_VERSION]               |
+    /// | 8          | 8    | heap size                             |
+    /// | 16         | 8    | minimum block size                    |
+    /// | 24         | 4    | number of orders, `N`                 |
+    /// | 28         | 4    | number of free blocks, `B`            |
+    /// | 32 + 9·i   | 1    | block `i`: its order                  |
+    /// | 33 + 9·i   | 8    | block `i`: its offset                 |
+    ///
+    /// The blocks are grouped by order, smallest first, each in the order
+    /// it appears on its free list.
+    ///
+    /// If the dump doesn't fit, the length returned is the size it would
+    /// have needed, which is more than `M`, and the array holds only what
+    /// fit.  Each list is walked no further than the number of blocks of
+    /// its order the heap could hold, so a corrupt list with a cycle in it
+    /// doesn't hang the crash handler.
+    pub fn dump_to_array<const M: usize>(&self) -> ([u8; M], usize) {
+        let mut buf = [0u8; M];
+        let mut len = HEADER_SIZE;
+        let mut count = 0u32;
+        for order in 0..N {
+            let size = self.order_size(order);
+            let mut block = self.free_lists[order];
+            for _ in 0..self.heap_size / size {
+                if block.is_null() {
+                    break;
+                }
+                let offset = (block as usize).wrapping_sub(self.heap_base as usize);
+                put(&mut buf, len, &[order as u8]);
+                put(&mut buf, len + 1, &(offset as u64).to_le_bytes());
+                len += RECORD_SIZE;
+                count += 1;
+
+                // As in `free_list_pop`, the whole-heap block's `next`
+                // pointer may never have been written.
+                if order == N - 1 {
+                    break;
+                }
+                // SAFETY: Blocks on the free lists are ours to read.
+                block = unsafe { (*block).next };
+            }
+        }
+
+        put(&mut buf, 0, &DUMP_MAGIC.to_le_bytes());
+        put(&mut buf, 4, &DUMP_VERSION.to_le_bytes());
+        put(&mut buf, 8, &(self.heap_size as u64).to_le_bytes());
+        put(&mut buf, 16, &(self.min_block_size as u64).to_le_bytes());
+        put(&mut buf, 24, &(N as u32).to_le_bytes());
+        put(&mut buf, 28, &count.to_le_bytes());
+        (buf, len)
+    }
+
+    /// Rebuild a heap at `heap_base` from a dump made by
+    /// [Heap::dump_to_array], relinking its free lists in the order they
+    /// were dumped.  As with [Heap::restore], no allocations need to be
+    /// replayed, and the used byte count is worked out from the lists.
+    ///
+    /// Returns [HeapError::BadDump] if `buf` isn't a whole dump of a heap
+    /// with `N` orders, or if it doesn't match the heap [Heap::new] would
+    /// create with its size.  Blocks outside the heap or misaligned for
+    /// their order are reported as [HeapError::CorruptFreeList], before
+    /// anything is written to them.
+    ///
+    /// # Safety
+    /// As for [Heap::restore].  The free blocks' headers are rewritten, but
+    /// the memory has to hold every allocation the dumped heap had live.
+    pub unsafe fn restore_from_dump(heap_base: NonNull<u8>, buf: &[u8]) -> Result<Self, HeapError> {
+        if buf.len() < HEADER_SIZE
+            || get::<4>(buf, 0) != DUMP_MAGIC.to_le_bytes()
+            || get::<4>(buf, 4) != DUMP_VERSION.to_le_bytes()
+            || u32::from_le_bytes(get(buf, 24)) as usize != N
+        {
+            return Err(HeapError::BadDump);
+        }
+        let heap_size =
+            usize::try_from(u64::from_le_bytes(get(buf, 8))).map_err(|_| HeapError::BadDump)?;
+        let min_block_size = u64::from_le_bytes(get(buf, 16));
+        let count = u32::from_le_bytes(get(buf, 28)) as usize;
+        let records = match count.checked_mul(RECORD_SIZE) {
+            Some(bytes) if bytes <= buf.len() - HEADER_SIZE => {
+                &buf[HEADER_SIZE..HEADER_SIZE + bytes]
+            }
+            _ => return Err(HeapError::BadDump),
+        };
+
+        // This is only for the geometry, since `restore` makes the heap
+        // itself, so it's built without registering it as `new` would.
+        let heap: Self = HeapBuilder::new()
+            .base(heap_base)
+            .size(heap_size)
+            .build()
+            .map_err(HeapError::first)?;
+        if heap.min_block_size as u64 != min_block_size {
+            return Err(HeapError::BadDump);
+        }
+
+        // Check every block before linking any of them.
+        for record in records.chunks_exact(RECORD_SIZE) {
+            let order = record[0] as usize;
+            if order >= N {
+                return Err(HeapError::BadDump);
+            }
+            let offset = u64::from_le_bytes(get(record, 1));
+            let block = heap.heap_base.wrapping_add(offset as usize);
+            let size = heap.order_size(order);
+            if offset >= heap_size as u64 || offset as usize & (size - 1) != 0 {
+                return Err(HeapError::CorruptFreeList {
+                    order,
+                    block: block as usize,
+                });
+            }
+        }
+
+        // Link each list in dump order, which `restore` then checks for
+        // the rest.
+        let mut free_lists = [ptr::null_mut(); N];
+        let mut tails: [*mut FreeBlock; N] = [ptr::null_mut(); N];
+        for record in records.chunks_exact(RECORD_SIZE) {
+            let order = record[0] as usize;
+            let offset = u64::from_le_bytes(get(record, 1)) as usize;
+            let block = heap.heap_base.add(offset) as *mut FreeBlock;
+            *block = FreeBlock::new(ptr::null_mut());
+            match tails[order].as_mut() {
+                Some(tail) => tail.next = block,
+                None => free_lists[order] = block,
+            }
+            tails[order] = block;
+        }
+
+        Self::restore(heap_base, heap_size, free_lists)
+    }
+}
+
+/// Copy as much of `bytes` into `buf` at `at` as fits.
+fn put(buf: &mut [u8], at: usize, bytes: &[u8]) {
+    for (i, &byte) in bytes.iter().enumerate() {
+        if let Some(slot) = buf.get_mut(at + i) {
+            *slot = byte;
+        }
+    }
+}
+
+/// Read `L` bytes from `buf` at `at`, which the caller has checked are
+/// there.
+fn get<const L: usize>(buf: &[u8], at: usize) -> [u8; L] {
+    let mut bytes = [0; L];
+    bytes.copy_from_slice(&buf[at..at + L]);
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::heap::free_list_len;
+    use core::alloc::Layout;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_dump_round_trip() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+            let mut heap: Heap<9> = Heap::new(base, heap_size).unwrap();
+
+            // A fresh heap is a single block at offset 0.
+            let (buf, len) = heap.dump_to_array::<64>();
+            assert_eq!(HEADER_SIZE + RECORD_SIZE, len);
+            assert_eq!(*b"BDMP", buf[0..4]);
+            assert_eq!([8, 0, 0, 0, 0, 0, 0, 0, 0], buf[32..41]);
+
+            // Scatter some allocations of different sizes through the heap.
+            let mut live = Vec::new();
+            for i in 0..40 {
+                let layout = Layout::from_size_align(16 << (i % 4), 16).unwrap();
+                live.push((heap.allocate(layout).unwrap(), layout));
+            }
+            let mut kept = Vec::new();
+            for (i, (block, layout)) in live.into_iter().enumerate() {
+                if i % 3 == 0 {
+                    heap.deallocate(block, layout);
+                } else {
+                    kept.push((block, layout));
+                }
+            }
+
+            // The dump lists every free block, and restoring it gives the
+            // same free lists, in the same order, over the same memory.
+            let (buf, len) = heap.dump_to_array::<1024>();
+            let blocks: usize = (0..9)
+                .map(|order| free_list_len(&heap.free_lists, order))
+                .sum();
+            assert_eq!(HEADER_SIZE + blocks * RECORD_SIZE, len);
+            let (heads, used) = (heap.free_list_heads(), heap.used_bytes());
+            drop(heap);
+            let mut restored: Heap<9> = Heap::restore_from_dump(base, &buf[..len]).unwrap();
+            assert_eq!(heads, restored.free_list_heads());
+            assert_eq!(used, restored.used_bytes());
+            let (again, again_len) = restored.dump_to_array::<1024>();
+            assert_eq!(&buf[..len], &again[..again_len]);
+
+            // And the restored heap carries on where the old one left off.
+            for (block, layout) in kept {
+                restored.deallocate(block, layout);
+            }
+            assert_eq!(0, restored.used_bytes());
+            assert!(restored.is_block_free(8, mem));
+
+            drop(restored);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_bad_dumps() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+            let mut heap: Heap<5> = Heap::new(base, heap_size).unwrap();
+            let small = Layout::from_size_align(16, 16).unwrap();
+            heap.allocate(small).unwrap();
+
+            // A buffer too small for the whole dump says how big it needs
+            // to be, and what it did capture won't restore.
+            let (buf, len) = heap.dump_to_array::<40>();
+            assert_eq!(HEADER_SIZE + 4 * RECORD_SIZE, len);
+            assert_eq!(
+                Err(HeapError::BadDump),
+                Heap::<5>::restore_from_dump(base, &buf).map(|_| ())
+            );
+
+            let (buf, len) = heap.dump_to_array::<128>();
+            drop(heap);
+            let restore = |buf: &[u8]| Heap::<5>::restore_from_dump(base, buf).map(|_| ());
+            assert_eq!(Ok(()), restore(&buf[..len]));
+            assert_eq!(Err(HeapError::BadDump), restore(&buf[..len - 1]));
+            assert_eq!(
+                Err(HeapError::BadDump),
+                Heap::<6>::restore_from_dump(base, &buf[..len]).map(|_| ())
+            );
+
+            let mut bad = buf;
+            bad[0] ^= 1;
+            assert_eq!(Err(HeapError::BadDump), restore(&bad[..len]));
+
+            // A misaligned block is caught before anything is written.
+            let mut bad = buf;
+            bad[33] = 8;
+            assert_eq!(
+                Err(HeapError::CorruptFreeList {
+                    order: 0,
+                    block: mem as usize + 8
+                }),
+                restore(&bad[..len])
+            );
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! A reserve of memory held aside for allocations which mustn't fail, such
+//! as the ones made while handling running out of memory.
+use core::alloc::Layout;
+use core::ptr;
+
+use crate::heap::{allocate_order, free_block, free_list_insert, rebase, FreeBlock};
+use crate::{AllocationError, AllocationSizeError, Heap};
+
+/// A block held aside by [Heap::reserve_emergency].  It counts as
+/// allocated in the heap, and has its own free lists for the blocks inside
+/// it, which only go up to its own order.
+#[derive(Debug)]
+pub(crate) struct EmergencyReserve<const N: usize> {
+    block: *mut u8,
+    order: usize,
+    free_lists: [*mut FreeBlock; N],
+}
+
+impl<const N: usize> EmergencyReserve<N> {
+    fn free_lists(&mut self) -> &mut [*mut FreeBlock] {
+        &mut self.free_lists[..=self.order]
+    }
+
+    /// Follow the reserve's block from a heap at `old_base` to one at
+    /// `new_base`, where it's already been copied, fixing up the free list
+    /// pointers inside it.
+    ///
+    /// # Safety
+    /// The reserve's block must have been copied to `new_base`.
+    pub(crate) unsafe fn relocate(&mut self, old_base: *mut u8, new_base: *mut u8) {
+        self.block = rebase(self.block, old_base, new_base);
+        for head in self.free_lists() {
+            *head = rebase(*head, old_base, new_base);
+            let mut block = *head;
+            while !block.is_null() {
+                (*block).next = rebase((*block).next, old_base, new_base);
+                block = (*block).next;
+            }
+        }
+    }
+}
+
+impl<const N: usize> Heap<N> {
+    /// Set aside a block of at least `bytes` bytes which only
+    /// [Heap::allocate_emergency] can allocate from, so that code handling
+    /// an out of memory error can still allocate a little.  [Heap::allocate]
+    /// never touches it.  The block counts as allocated for as long as it's
+    /// held.
+    ///
+    /// Any reserve already held is given back first, and passing 0 just
+    /// gives it back.  That panics if anything is still allocated from it.
+    pub fn reserve_emergency(&mut self, bytes: usize) -> Result<(), AllocationError> {
+        if let Some(reserve) = self.emergency.take() {
+            if reserve.free_lists[reserve.order] != reserve.block as *mut FreeBlock {
+                heap_panic!("Emergency reserve is still in use");
+            }
+
+            let size = self.order_size(reserve.order);
+            // SAFETY: The block was allocated below, with a layout of the
+            // same order.
+            unsafe { self.deallocate(reserve.block, Layout::from_size_align_unchecked(size, 1)) };
+        }
+
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        let layout = Layout::from_size_align(bytes, 1)
+            .map_err(|_| AllocationError::InvalidSize(AllocationSizeError::TooLarge))?;
+        let order = self
+            .order_and_waste(layout)
+            .map_err(AllocationError::InvalidSize)?
+            .0;
+        let block = self.allocate(layout)?;
+
+        let mut reserve = EmergencyReserve {
+            block,
+            order,
+            free_lists: [ptr::null_mut(); N],
+        };
+        // SAFETY: We just allocated the block.
+        unsafe { free_list_insert(reserve.free_lists(), order, block) };
+        self.emergency = Some(reserve);
+        Ok(())
+    }
+
+    /// Allocate from the reserve set aside by [Heap::reserve_emergency],
+    /// exactly as [Heap::allocate] does from the rest of the heap.  Fails
+    /// with [AllocationError::HeapExhausted] if there's no reserve, or no
+    /// room left in it.
+    ///
+    /// The block must be freed with [Heap::deallocate_emergency].  It's
+    /// already counted in [Heap::used_bytes] as part of the reserve.
+    pub fn allocate_emergency(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        let result = match (self.order_and_waste(layout), self.emergency.as_mut()) {
+            (Err(e), _) => Err(AllocationError::InvalidSize(e)),
+            (Ok((order, _)), Some(reserve)) => allocate_order(
+                reserve.free_lists(),
+                self.heap_base,
+                self.heap_size,
+                self.min_block_size_log2,
+                order,
+            )
+            .ok_or(AllocationError::HeapExhausted),
+            (Ok(_), None) => Err(AllocationError::HeapExhausted),
+        };
+
+        self.note_result(layout, result);
+        result
+    }
+
+    /// Free a block allocated with [Heap::allocate_emergency].
+    ///
+    /// # Safety
+    /// As for [Heap::deallocate]: `ptr` must have come from
+    /// `allocate_emergency` with an order-equivalent `layout`, and the
+    /// reserve must not have been given back since.
+    pub unsafe fn deallocate_emergency(&mut self, ptr: *mut u8, layout: Layout) {
+        let (order, reserve) = match (self.order_and_waste(layout), self.emergency.as_mut()) {
+            (Ok((order, _)), Some(reserve)) => (order, reserve),
+            _ => heap_panic!("Tried to dispose of invalid block"),
+        };
+
+        // Merging stops at the reserve's own order, since its free lists
+        // only go that far.
+        free_block(
+            reserve.free_lists(),
+            self.heap_base,
+            self.heap_size,
+            self.min_block_size_log2,
+            ptr,
+            order,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_emergency_reserve() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let small = Layout::from_size_align(16, 1).unwrap();
+
+            // No reserve, no emergency allocations.
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_emergency(small)
+            );
+
+            // 40 bytes rounds up to a 64-byte block.
+            heap.reserve_emergency(40).unwrap();
+            assert_eq!(64, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            // Use up the rest of the heap; the reserve isn't touched.
+            let mut blocks = Vec::new();
+            while let Ok(block) = heap.allocate(small) {
+                blocks.push(block);
+            }
+            assert_eq!(12, blocks.len());
+            assert_eq!(heap_size, heap.used_bytes());
+
+            // But the reserve still has room, for four small blocks or one
+            // that fills it, and no more.
+            let a = heap.allocate_emergency(small).unwrap();
+            let b = heap
+                .allocate_emergency(Layout::from_size_align(32, 1).unwrap())
+                .unwrap();
+            assert!(!blocks.contains(&a) && !blocks.contains(&b));
+            assert_eq!(0, (b as usize - a as usize) % 32);
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_emergency(Layout::from_size_align(32, 1).unwrap())
+            );
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_emergency(Layout::from_size_align(128, 1).unwrap())
+            );
+            assert_eq!(heap_size, heap.used_bytes());
+
+            // Freed emergency blocks merge back into the whole reserve.
+            heap.deallocate_emergency(a, small);
+            heap.deallocate_emergency(b, Layout::from_size_align(32, 1).unwrap());
+            let whole = Layout::from_size_align(64, 1).unwrap();
+            let c = heap.allocate_emergency(whole).unwrap();
+            heap.deallocate_emergency(c, whole);
+
+            // Giving the reserve back makes it available to everyone.
+            heap.reserve_emergency(0).unwrap();
+            for block in blocks {
+                heap.deallocate(block, small);
+            }
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(4, mem));
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_emergency(small)
+            );
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "still in use"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_emergency_reserve_in_use() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            heap.reserve_emergency(64).unwrap();
+            heap.allocate_emergency(Layout::from_size_align(16, 1).unwrap())
+                .unwrap();
+            let _ = heap.reserve_emergency(0);
+        }
+    }
+}
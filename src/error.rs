@@ -0,0 +1,539 @@
+//! The errors the heap reports, and [Error], which any of them converts
+//! into for code that doesn't care which part of the heap failed.
+//!
+//! Every error type here is `#[non_exhaustive]`, so matches on them need a
+//! wildcard arm, and new errors or fields can be added without breaking
+//! anybody.
+use core::convert::TryFrom;
+use core::fmt;
+
+use crate::HeapErrors;
+
+/// What kind of error something is, without any of its details.  Every
+/// error in the crate has one, from its `kind` method, which is handy for
+/// logging and for handling errors the same way wherever they came from.
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorKind {
+    /// [AllocationError::HeapExhausted].
+    HeapExhausted,
+    /// [AllocationSizeError::BadAlignment].
+    BadAlignment,
+    /// [AllocationSizeError::TooLarge].
+    TooLarge,
+    /// [HeapError::BadBaseAlignment].
+    BadBaseAlignment,
+    /// [HeapError::BadSizeAlignment].
+    BadSizeAlignment,
+    /// [HeapError::BadHeapSize].
+    BadHeapSize,
+    /// [HeapError::MinBlockTooSmall].
+    MinBlockTooSmall,
+    /// [HeapError::MetadataTooSmall].
+    MetadataTooSmall,
+    /// [HeapError::HeapInUse].
+    HeapInUse,
+    /// [HeapError::RegionOverlap].
+    RegionOverlap,
+    /// [HeapError::RegionTooSmall].
+    RegionTooSmall,
+    /// [HeapError::MemoryVerificationFailed].
+    MemoryVerificationFailed,
+    /// [HeapError::TooManyReservedRanges].
+    TooManyReservedRanges,
+    /// [HeapError::ReservedOutOfRange].
+    ReservedOutOfRange,
+    /// [HeapError::Several].
+    Several,
+    /// [HeapError::CorruptFreeList].
+    CorruptFreeList,
+    /// [HeapError::BadDump].
+    BadDump,
+}
+
+impl ErrorKind {
+    /// A short description of the error, which is also how it's displayed.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::HeapExhausted => "heap exhausted",
+            ErrorKind::BadAlignment => "unsupported alignment",
+            ErrorKind::TooLarge => "allocation larger than the heap",
+            ErrorKind::BadBaseAlignment => "misaligned heap base",
+            ErrorKind::BadSizeAlignment => "heap size not a power of two",
+            ErrorKind::BadHeapSize => "heap too small",
+            ErrorKind::MinBlockTooSmall => "minimum block too small",
+            ErrorKind::MetadataTooSmall => "metadata buffer too small",
+            ErrorKind::HeapInUse => "heap in use",
+            ErrorKind::RegionOverlap => "region overlaps the heap",
+            ErrorKind::RegionTooSmall => "region too small",
+            ErrorKind::MemoryVerificationFailed => "memory verification failed",
+            ErrorKind::TooManyReservedRanges => "too many reserved ranges",
+            ErrorKind::ReservedOutOfRange => "reserved range outside the heap",
+            ErrorKind::Several => "several errors",
+            ErrorKind::CorruptFreeList => "corrupt free list",
+            ErrorKind::BadDump => "bad heap dump",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Represents an error for an allocation's size.
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllocationSizeError {
+    /// The alignment isn't a power of two, or is more than the heap's base
+    /// alignment (4096 bytes, or 64 on 16-bit targets) guarantees.
+    BadAlignment,
+    /// The allocation is bigger than the whole heap.  An allocation of
+    /// exactly `heap_size` bytes is fine, and takes the whole heap.
+    TooLarge,
+}
+
+impl AllocationSizeError {
+    /// What kind of error this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AllocationSizeError::BadAlignment => ErrorKind::BadAlignment,
+            AllocationSizeError::TooLarge => ErrorKind::TooLarge,
+        }
+    }
+}
+
+impl fmt::Display for AllocationSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.kind(), f)
+    }
+}
+
+/// Represents the reason for an allocation error.
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllocationError {
+    /// There's no free block big enough.
+    HeapExhausted,
+    /// No heap could satisfy the layout, however empty.
+    InvalidSize(AllocationSizeError),
+}
+
+impl AllocationError {
+    /// What kind of error this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AllocationError::HeapExhausted => ErrorKind::HeapExhausted,
+            AllocationError::InvalidSize(e) => e.kind(),
+        }
+    }
+}
+
+impl From<AllocationSizeError> for AllocationError {
+    fn from(error: AllocationSizeError) -> AllocationError {
+        AllocationError::InvalidSize(error)
+    }
+}
+
+impl fmt::Display for AllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.kind(), f)
+    }
+}
+
+/// A distinct nonzero code for each error, for reporting it to a fault
+/// handler or over a debug link: 1 for [AllocationError::HeapExhausted],
+/// 2 for a bad alignment, and 3 for an allocation that's too large.
+impl From<AllocationError> for usize {
+    fn from(error: AllocationError) -> usize {
+        match error {
+            AllocationError::HeapExhausted => 1,
+            AllocationError::InvalidSize(AllocationSizeError::BadAlignment) => 2,
+            AllocationError::InvalidSize(AllocationSizeError::TooLarge) => 3,
+        }
+    }
+}
+
+/// Decode an error code produced by `usize::from`.  Codes that don't
+/// correspond to an error are handed back.
+impl TryFrom<usize> for AllocationError {
+    type Error = usize;
+
+    fn try_from(code: usize) -> Result<Self, usize> {
+        match code {
+            1 => Ok(AllocationError::HeapExhausted),
+            2 => Ok(AllocationError::InvalidSize(
+                AllocationSizeError::BadAlignment,
+            )),
+            3 => Ok(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+            _ => Err(code),
+        }
+    }
+}
+
+/// An error in the creation of the heap.
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeapError {
+    /// The heap's base is null or isn't aligned to 4096 bytes (64 on
+    /// 16-bit targets).
+    BadBaseAlignment,
+    /// The heap's size isn't a power of two, or doesn't agree with the
+    /// minimum block size asked for.
+    BadSizeAlignment,
+    /// The heap is too small for its minimum block, or too big to address.
+    BadHeapSize,
+    /// The minimum block can't hold a free block header.
+    MinBlockTooSmall,
+    /// A metadata buffer is too small for this heap.
+    MetadataTooSmall,
+    /// The operation requires the heap to have no live allocations.
+    HeapInUse,
+    /// A region added to the heap overlaps memory it already manages.
+    /// Reserved for `add_region`, which doesn't exist yet.
+    RegionOverlap { base: usize, size: usize },
+    /// A region added to the heap can't hold even one block of
+    /// `min_block_size` bytes.  Reserved for `add_region`, which doesn't
+    /// exist yet.
+    RegionTooSmall { size: usize, min_block_size: usize },
+    /// [crate::Heap::new_verified] wrote to this address and read back
+    /// something else.
+    MemoryVerificationFailed(usize),
+    /// More ranges were reserved than a [crate::HeapBuilder] can hold; see
+    /// [crate::MAX_RESERVED_RANGES].
+    TooManyReservedRanges,
+    /// A range reserved with [crate::HeapBuilder::reserve] extends past the
+    /// end of the heap.
+    ReservedOutOfRange,
+    /// [crate::HeapBuilder::build] found more than one problem.
+    Several(HeapErrors),
+    /// A free list passed to [crate::Heap::restore] holds a block at this
+    /// address which is outside the heap or misaligned for its order, or
+    /// the list holds more blocks than could fit.
+    CorruptFreeList { order: usize, block: usize },
+    /// The buffer passed to [crate::Heap::restore_from_dump] isn't a whole
+    /// dump of a heap like this one.
+    BadDump,
+}
+
+impl HeapError {
+    /// What kind of error this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            HeapError::BadBaseAlignment => ErrorKind::BadBaseAlignment,
+            HeapError::BadSizeAlignment => ErrorKind::BadSizeAlignment,
+            HeapError::BadHeapSize => ErrorKind::BadHeapSize,
+            HeapError::MinBlockTooSmall => ErrorKind::MinBlockTooSmall,
+            HeapError::MetadataTooSmall => ErrorKind::MetadataTooSmall,
+            HeapError::HeapInUse => ErrorKind::HeapInUse,
+            HeapError::RegionOverlap { .. } => ErrorKind::RegionOverlap,
+            HeapError::RegionTooSmall { .. } => ErrorKind::RegionTooSmall,
+            HeapError::MemoryVerificationFailed(_) => ErrorKind::MemoryVerificationFailed,
+            HeapError::TooManyReservedRanges => ErrorKind::TooManyReservedRanges,
+            HeapError::ReservedOutOfRange => ErrorKind::ReservedOutOfRange,
+            HeapError::Several(_) => ErrorKind::Several,
+            HeapError::CorruptFreeList { .. } => ErrorKind::CorruptFreeList,
+            HeapError::BadDump => ErrorKind::BadDump,
+        }
+    }
+
+    /// The first error of a [HeapError::Several], for constructors which
+    /// only ever report one.
+    pub(crate) fn first(self) -> HeapError {
+        match self {
+            HeapError::Several(errors) => errors.first().unwrap_or(self),
+            _ => self,
+        }
+    }
+}
+
+/// The kind of error, followed by its details, if it has any.
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.kind(), f)?;
+        match *self {
+            HeapError::RegionOverlap { base, size } => {
+                write!(f, ": {:#x} bytes at {:#x}", size, base)
+            }
+            HeapError::RegionTooSmall {
+                size,
+                min_block_size,
+            } => write!(
+                f,
+                ": {} bytes, minimum block {} bytes",
+                size, min_block_size
+            ),
+            HeapError::MemoryVerificationFailed(address) => write!(f, " at {:#x}", address),
+            HeapError::Several(errors) => {
+                f.write_str(":")?;
+                for (i, error) in errors.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { " " } else { ", " }, error)?;
+                }
+                Ok(())
+            }
+            HeapError::CorruptFreeList { order, block } => {
+                write!(f, ": order {} block at {:#x}", order, block)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Any error from the heap.  The errors from each part of the heap convert
+/// into this with `?`, and back out with `TryFrom`.
+#[non_exhaustive]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// An allocation failed.
+    Allocation(AllocationError),
+    /// A heap couldn't be created or reconfigured.
+    Heap(HeapError),
+}
+
+impl Error {
+    /// What kind of error this is.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Allocation(e) => e.kind(),
+            Error::Heap(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Allocation(e) => fmt::Display::fmt(e, f),
+            Error::Heap(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl From<AllocationError> for Error {
+    fn from(error: AllocationError) -> Error {
+        Error::Allocation(error)
+    }
+}
+
+impl From<AllocationSizeError> for Error {
+    fn from(error: AllocationSizeError) -> Error {
+        Error::Allocation(error.into())
+    }
+}
+
+impl From<HeapError> for Error {
+    fn from(error: HeapError) -> Error {
+        Error::Heap(error)
+    }
+}
+
+/// Get the [AllocationError] back out, or the [Error] if it's another kind.
+impl TryFrom<Error> for AllocationError {
+    type Error = Error;
+
+    fn try_from(error: Error) -> Result<Self, Error> {
+        match error {
+            Error::Allocation(e) => Ok(e),
+            _ => Err(error),
+        }
+    }
+}
+
+/// Get the [HeapError] back out, or the [Error] if it's another kind.
+impl TryFrom<Error> for HeapError {
+    type Error = Error;
+
+    fn try_from(error: Error) -> Result<Self, Error> {
+        match error {
+            Error::Heap(e) => Ok(e),
+            _ => Err(error),
+        }
+    }
+}
+
+impl core::error::Error for ErrorKind {}
+impl core::error::Error for AllocationSizeError {}
+impl core::error::Error for AllocationError {}
+impl core::error::Error for HeapError {}
+impl core::error::Error for Error {}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use std::string::ToString;
+
+    /// One error of every kind.
+    fn every_kind() -> [(Error, ErrorKind, &'static str); 17] {
+        let several = {
+            let mut errors = HeapErrors::NONE;
+            errors.insert(HeapError::BadSizeAlignment);
+            errors.insert(HeapError::BadBaseAlignment);
+            errors
+        };
+        [
+            (
+                AllocationError::HeapExhausted.into(),
+                ErrorKind::HeapExhausted,
+                "heap exhausted",
+            ),
+            (
+                AllocationSizeError::BadAlignment.into(),
+                ErrorKind::BadAlignment,
+                "unsupported alignment",
+            ),
+            (
+                AllocationSizeError::TooLarge.into(),
+                ErrorKind::TooLarge,
+                "allocation larger than the heap",
+            ),
+            (
+                HeapError::BadBaseAlignment.into(),
+                ErrorKind::BadBaseAlignment,
+                "misaligned heap base",
+            ),
+            (
+                HeapError::BadSizeAlignment.into(),
+                ErrorKind::BadSizeAlignment,
+                "heap size not a power of two",
+            ),
+            (
+                HeapError::BadHeapSize.into(),
+                ErrorKind::BadHeapSize,
+                "heap too small",
+            ),
+            (
+                HeapError::MinBlockTooSmall.into(),
+                ErrorKind::MinBlockTooSmall,
+                "minimum block too small",
+            ),
+            (
+                HeapError::MetadataTooSmall.into(),
+                ErrorKind::MetadataTooSmall,
+                "metadata buffer too small",
+            ),
+            (
+                HeapError::HeapInUse.into(),
+                ErrorKind::HeapInUse,
+                "heap in use",
+            ),
+            (
+                HeapError::RegionOverlap {
+                    base: 0x1000,
+                    size: 0x200,
+                }
+                .into(),
+                ErrorKind::RegionOverlap,
+                "region overlaps the heap: 0x200 bytes at 0x1000",
+            ),
+            (
+                HeapError::RegionTooSmall {
+                    size: 8,
+                    min_block_size: 16,
+                }
+                .into(),
+                ErrorKind::RegionTooSmall,
+                "region too small: 8 bytes, minimum block 16 bytes",
+            ),
+            (
+                HeapError::MemoryVerificationFailed(0x1234).into(),
+                ErrorKind::MemoryVerificationFailed,
+                "memory verification failed at 0x1234",
+            ),
+            (
+                HeapError::TooManyReservedRanges.into(),
+                ErrorKind::TooManyReservedRanges,
+                "too many reserved ranges",
+            ),
+            (
+                HeapError::ReservedOutOfRange.into(),
+                ErrorKind::ReservedOutOfRange,
+                "reserved range outside the heap",
+            ),
+            (
+                HeapError::Several(several).into(),
+                ErrorKind::Several,
+                "several errors: heap size not a power of two, misaligned heap base",
+            ),
+            (
+                HeapError::CorruptFreeList {
+                    order: 3,
+                    block: 0x80,
+                }
+                .into(),
+                ErrorKind::CorruptFreeList,
+                "corrupt free list: order 3 block at 0x80",
+            ),
+            (
+                HeapError::BadDump.into(),
+                ErrorKind::BadDump,
+                "bad heap dump",
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let errors = every_kind();
+        for (i, (error, kind, message)) in errors.iter().enumerate() {
+            assert_eq!(*kind, error.kind());
+            assert_eq!(*message, error.to_string());
+            assert!(message.starts_with(kind.as_str()));
+            assert_eq!(kind.as_str(), kind.to_string());
+
+            // The kinds are all different.
+            for (other, other_kind, _) in &errors[..i] {
+                assert_ne!(other_kind, kind, "{:?} and {:?}", other, error);
+            }
+
+            // The inner error converts back out, and displays the same.
+            match *error {
+                Error::Allocation(e) => {
+                    assert_eq!(Ok(e), AllocationError::try_from(*error));
+                    assert_eq!(Err(*error), HeapError::try_from(*error));
+                    assert_eq!(*kind, e.kind());
+                    assert_eq!(*message, e.to_string());
+                }
+                Error::Heap(e) => {
+                    assert_eq!(Ok(e), HeapError::try_from(*error));
+                    assert_eq!(Err(*error), AllocationError::try_from(*error));
+                    assert_eq!(*kind, e.kind());
+                    assert_eq!(*message, e.to_string());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_conversions() {
+        let too_large = AllocationSizeError::TooLarge;
+        assert_eq!(
+            AllocationError::InvalidSize(too_large),
+            AllocationError::from(too_large)
+        );
+        assert_eq!(ErrorKind::TooLarge, too_large.kind());
+        assert_eq!("allocation larger than the heap", too_large.to_string());
+        assert_eq!(
+            Error::Allocation(AllocationError::InvalidSize(too_large)),
+            Error::from(too_large)
+        );
+
+        // `?` converts any of them.
+        fn build() -> Result<(), Error> {
+            Err(HeapError::HeapInUse)?
+        }
+        assert_eq!(Err(Error::Heap(HeapError::HeapInUse)), build());
+
+        // The numeric codes round-trip.
+        for error in [
+            AllocationError::HeapExhausted,
+            AllocationError::InvalidSize(AllocationSizeError::BadAlignment),
+            AllocationError::InvalidSize(AllocationSizeError::TooLarge),
+        ] {
+            assert_eq!(Ok(error), AllocationError::try_from(usize::from(error)));
+        }
+        assert_eq!(Err(0), AllocationError::try_from(0));
+        assert_eq!(Err(4), AllocationError::try_from(4));
+    }
+}
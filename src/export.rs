@@ -0,0 +1,185 @@
+//! Machine-readable snapshots of a heap's state, for attaching to bug
+//! reports, diffing between runs, and feeding visualization tools.
+use std::io::{self, Write};
+use std::string::ToString;
+use std::vec::Vec;
+
+use crate::heap::free_list_for_each;
+use crate::Heap;
+
+/// The version of the [Heap::export_json] schema.  This is bumped whenever
+/// a field is renamed, removed, or changes meaning; new fields may be added
+/// without bumping it.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// The state of a node of the buddy tree in [Heap::export_dot].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum NodeState {
+    Free,
+    Split,
+    Allocated,
+}
+
+impl<const N: usize> Heap<N> {
+    /// Write a JSON snapshot of the heap to `w`.  All addresses except
+    /// `heap_base` are given as byte offsets from the start of the heap, so
+    /// snapshots of the same sequence of operations are identical from run
+    /// to run.  The schema (version [EXPORT_VERSION]) is:
+    ///
+    /// | Field            | Contents                                          |
+    /// |------------------|---------------------------------------------------|
+    /// | `version`        | [EXPORT_VERSION]                                  |
+    /// | `heap_base`      | The heap's address, as a hex string               |
+    /// | `heap_size`      | The heap's size in bytes                          |
+    /// | `min_block_size` | The size of an order 0 block                      |
+    /// | `orders`         | The number of orders, `N`                         |
+    /// | `used_bytes`     | [Heap::used_bytes]                                |
+    /// | `free_bytes`     | [Heap::free_bytes]                                |
+    /// | `free_lists`     | One `{"order", "block_size", "blocks"}` object per order, with the sorted offsets of its free blocks |
+    /// | `allocations`    | With the `debug-track` feature and an order table registered, the sorted `{"offset", "order"}` of every live allocation; otherwise `null` |
+    pub fn export_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "\"version\": {},", EXPORT_VERSION)?;
+        writeln!(w, "\"heap_base\": \"{:#x}\",", self.heap_base as usize)?;
+        writeln!(w, "\"heap_size\": {},", self.heap_size)?;
+        writeln!(w, "\"min_block_size\": {},", self.min_block_size)?;
+        writeln!(w, "\"orders\": {},", N)?;
+        writeln!(w, "\"used_bytes\": {},", self.used_bytes())?;
+        writeln!(w, "\"free_bytes\": {},", self.free_bytes())?;
+
+        writeln!(w, "\"free_lists\": [")?;
+        for order in 0..N {
+            let blocks: Vec<_> = self
+                .free_offsets(order)
+                .iter()
+                .map(|offset| offset.to_string())
+                .collect();
+            writeln!(
+                w,
+                "{{\"order\":{},\"block_size\":{},\"blocks\":[{}]}}{}",
+                order,
+                self.order_size(order),
+                blocks.join(","),
+                if order + 1 < N { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "],")?;
+
+        match self.allocations() {
+            Some(allocations) => {
+                writeln!(w, "\"allocations\": [")?;
+                for (i, (offset, order)) in allocations.iter().enumerate() {
+                    writeln!(
+                        w,
+                        "{{\"offset\":{},\"order\":{}}}{}",
+                        offset,
+                        order,
+                        if i + 1 < allocations.len() { "," } else { "" }
+                    )?;
+                }
+                writeln!(w, "]")?;
+            }
+            None => writeln!(w, "\"allocations\": null")?,
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Write the buddy tree to `w` as a Graphviz `digraph`.  Each node is a
+    /// block, labelled with its offset and size, and colored by its state:
+    /// free (green), split into two smaller blocks (white), or allocated
+    /// (red).
+    ///
+    /// Without an order table (see the `debug-track` feature) the heap
+    /// can't tell one allocation from several smaller ones filling the same
+    /// block, so completely allocated subtrees are drawn as a single node.
+    pub fn export_dot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let free: Vec<_> = (0..N)
+            .flat_map(|order| {
+                self.free_offsets(order)
+                    .into_iter()
+                    .map(move |offset| (offset, order))
+            })
+            .collect();
+        let allocations = self.allocations();
+
+        writeln!(w, "digraph buddyalloc {{")?;
+        writeln!(w, "  node [shape=box, style=filled];")?;
+        self.dot_node(&mut w, &free, allocations.as_deref(), 0, N - 1)?;
+        writeln!(w, "}}")
+    }
+
+    /// Write the node for the block at `offset` of order `order`, and its
+    /// children.
+    fn dot_node<W: Write>(
+        &self,
+        w: &mut W,
+        free: &[(usize, usize)],
+        allocations: Option<&[(usize, usize)]>,
+        offset: usize,
+        order: usize,
+    ) -> io::Result<()> {
+        let size = self.order_size(order);
+        let state = if free.contains(&(offset, order)) {
+            NodeState::Free
+        } else if order == 0 || allocations.is_some_and(|a| a.contains(&(offset, order))) {
+            NodeState::Allocated
+        } else if free.iter().any(|&(o, _)| o >= offset && o < offset + size)
+            || allocations.is_some()
+        {
+            NodeState::Split
+        } else {
+            NodeState::Allocated
+        };
+
+        let (name, color) = match state {
+            NodeState::Free => ("free", "palegreen"),
+            NodeState::Split => ("split", "white"),
+            NodeState::Allocated => ("allocated", "salmon"),
+        };
+        writeln!(
+            w,
+            "  b{}_{} [label=\"{:#x}\\n{} bytes\\n{}\", fillcolor={}];",
+            order, offset, offset, size, name, color
+        )?;
+
+        if state == NodeState::Split {
+            let half = size / 2;
+            for child in [offset, offset + half] {
+                writeln!(w, "  b{}_{} -> b{}_{};", order, offset, order - 1, child)?;
+                self.dot_node(w, free, allocations, child, order - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The sorted offsets of the free blocks of order `order`.
+    fn free_offsets(&self, order: usize) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        free_list_for_each(&self.free_lists, order, |block| {
+            offsets.push(block as usize - self.heap_base as usize)
+        });
+        offsets.sort_unstable();
+        offsets
+    }
+
+    /// The sorted `(offset, order)` of every live allocation, if they're
+    /// being tracked.
+    #[cfg(feature = "debug-track")]
+    fn allocations(&self) -> Option<Vec<(usize, usize)>> {
+        self.order_table.as_ref()?;
+        Some(
+            (0..self.heap_size)
+                .step_by(self.min_block_size)
+                .filter_map(|offset| {
+                    let order = self.tracked_order(self.heap_base.wrapping_add(offset))?;
+                    Some((offset, order))
+                })
+                .collect(),
+        )
+    }
+
+    #[cfg(not(feature = "debug-track"))]
+    fn allocations(&self) -> Option<Vec<(usize, usize)>> {
+        None
+    }
+}
@@ -0,0 +1,188 @@
+//! A debugging allocator which puts each allocation at the very end of its
+//! block, behind a canary, so that overruns can't hide in the slack.
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::{Heap, LockedHeap};
+
+/// The byte [FencePost] fills the front of each block with.
+pub const FENCE_CANARY: u8 = 0xfd;
+
+/// The least number of canary bytes in front of each allocation.
+const CANARY_LEN: usize = 8;
+
+/// Wraps a [LockedHeap] so that each allocation ends exactly at the end of
+/// its block (as far as its alignment allows), the way page heap debuggers
+/// place allocations against a guard page.  Writing even one byte past the
+/// end of an allocation then lands in the next block rather than in unused
+/// slack.
+///
+/// The bytes in front of each allocation, at least 8 of them, are filled
+/// with [FENCE_CANARY], and checked when it's freed.  So an underrun is
+/// caught when the block itself is freed, and an overrun when the next
+/// block is, if it's also allocated through the wrapper.  Either panics.
+///
+/// Since every allocation carries its canary, and is then rounded up to a
+/// block, this uses noticeably more memory than the bare heap.
+///
+/// ```
+/// # use buddyalloc::{FencePost, Heap, LockedHeap};
+/// # use core::ptr::NonNull;
+/// # #[repr(align(4096))]
+/// # struct Memory([u8; 65536]);
+/// # let memory = Box::leak(Box::new(Memory([0; 65536])));
+/// # let heap = Heap::<12>::new_from_bytes(&mut memory.0).unwrap();
+/// let fenced = FencePost::new(LockedHeap::new(heap));
+/// // Use `fenced` as the `#[global_allocator]`.
+/// ```
+#[derive(Debug)]
+pub struct FencePost<const N: usize> {
+    heap: LockedHeap<N>,
+}
+
+impl<const N: usize> FencePost<N> {
+    /// Wrap `heap`.
+    pub const fn new(heap: LockedHeap<N>) -> Self {
+        Self { heap }
+    }
+
+    /// The underlying heap.  Blocks allocated through the wrapper must be
+    /// freed through it too.
+    pub fn heap(&self) -> &LockedHeap<N> {
+        &self.heap
+    }
+
+    /// The layout to ask the heap for, with room for the canary.
+    fn padded(layout: Layout) -> Option<Layout> {
+        let padding = CANARY_LEN.max(layout.align());
+        Layout::from_size_align(layout.size().checked_add(padding)?, layout.align()).ok()
+    }
+
+    /// How far into its block an allocation of `layout` starts, given
+    /// the heap's layout for it.
+    fn offset(heap: &Heap<N>, layout: Layout, padded: Layout) -> usize {
+        let order = match heap.order_and_waste(padded) {
+            Ok((order, _)) => order,
+            Err(_) => heap_panic!("Tried to dispose of invalid block"),
+        };
+
+        // The block is aligned to its size, and so to `layout.align()`,
+        // and the padding guarantees at least `CANARY_LEN` bytes are left
+        // in front after rounding down.
+        (heap.order_size(order) - layout.size()) & !(layout.align() - 1)
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for FencePost<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let padded = match Self::padded(layout) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let mut heap = self.heap.lock();
+        let block = match heap.allocate(padded) {
+            Ok(block) => block,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        let offset = Self::offset(&heap, layout, padded);
+        block.write_bytes(FENCE_CANARY, offset);
+        block.add(offset)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let padded = match Self::padded(layout) {
+            Some(padded) => padded,
+            None => heap_panic!("Tried to dispose of invalid block"),
+        };
+
+        // `GlobalAlloc` promises the same layout as the allocation, so the
+        // offset comes out the same.
+        let mut heap = self.heap.lock();
+        let offset = Self::offset(&heap, layout, padded);
+        let block = ptr.sub(offset);
+        let canary = core::slice::from_raw_parts(block, offset);
+        if canary.iter().any(|&b| b != FENCE_CANARY) {
+            // The panic may well allocate, so don't hold the lock.
+            drop(heap);
+            heap_panic!("Fence-post canary overwritten");
+        }
+
+        heap.deallocate(block, padded);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+
+    fn with_fenced(f: impl FnOnce(&FencePost<5>, *mut u8)) {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let fenced = FencePost::new(LockedHeap::new(heap));
+            f(&fenced, mem);
+            assert_eq!(0, fenced.heap().lock().used_bytes());
+            drop(fenced);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_fence_post() {
+        with_fenced(|fenced, mem| unsafe {
+            // 10 bytes and a canary take a 32-byte block, and end at its end.
+            let small = Layout::from_size_align(10, 1).unwrap();
+            let a = fenced.alloc(small);
+            assert_eq!(mem.add(22), a);
+            assert!((0..22).all(|i| *mem.add(i) == FENCE_CANARY));
+
+            // Alignment can leave a gap at the end, but never eats into
+            // the canary.
+            let aligned = Layout::from_size_align(20, 16).unwrap();
+            let b = fenced.alloc(aligned);
+            assert_eq!(mem.add(64 + 32), b);
+            assert_eq!(0, b as usize % 16);
+
+            // Writing every byte of the allocations is fine.
+            a.write_bytes(0, 10);
+            b.write_bytes(0, 20);
+            fenced.dealloc(a, small);
+            fenced.dealloc(b, aligned);
+        });
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "canary overwritten"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_fence_post_overrun() {
+        with_fenced(|fenced, _| unsafe {
+            let small = Layout::from_size_align(10, 1).unwrap();
+            let a = fenced.alloc(small);
+            let b = fenced.alloc(small);
+            assert_eq!(a.add(32), b);
+
+            // One byte too far lands on the next block's canary, which is
+            // caught when that block is freed.
+            *a.add(10) = 0;
+            fenced.dealloc(a, small);
+            fenced.dealloc(b, small);
+        });
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "canary overwritten"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_fence_post_underrun() {
+        with_fenced(|fenced, _| unsafe {
+            let small = Layout::from_size_align(10, 1).unwrap();
+            let a = fenced.alloc(small);
+            *a.sub(1) = 0;
+            fenced.dealloc(a, small);
+        });
+    }
+}
@@ -0,0 +1,201 @@
+//! A physical frame allocator for kernels using the `x86_64` crate.
+use core::alloc::Layout;
+use core::ops::Range;
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{Heap, HeapError};
+
+/// The size of the smallest frames, and so of the heap's smallest blocks.
+const FRAME_SIZE: usize = 4096;
+
+/// Hands out physical frames from a buddy heap, implementing the `x86_64`
+/// crate's [FrameAllocator] and [FrameDeallocator] for every page size.
+/// Frames of 2 MiB or 1 GiB are single blocks of the right order, so they
+/// come out naturally aligned.
+///
+/// The heap covers physical addresses `0..4096 << (N - 1)` with one order
+/// per frame size doubling, so `N = 21` manages the first 4 GiB.  It keeps
+/// its free lists inside the free frames themselves, so all physical memory
+/// must be mapped at some offset in the virtual address space (as with the
+/// `bootloader` crate's `map_physical_memory`).
+#[derive(Debug)]
+pub struct FrameHeap<const N: usize> {
+    heap: Heap<N>,
+}
+
+impl<const N: usize> FrameHeap<N> {
+    /// Create a frame allocator for the `usable` ranges of physical memory,
+    /// usually taken from the bootloader's memory map, which is all mapped
+    /// starting at `physical_memory_offset`.  Anything outside the usable
+    /// ranges is never handed out; in particular frame 0 isn't, so that no
+    /// frame can have a null address.  Ranges are shrunk to whole frames,
+    /// and ignored past the end of the heap.
+    ///
+    /// # Safety
+    /// All physical memory in `usable` must be mapped at
+    /// `physical_memory_offset`, unused, and stay that way for as long as
+    /// the allocator is.  The ranges must not overlap.
+    pub unsafe fn new(
+        physical_memory_offset: VirtAddr,
+        usable: impl IntoIterator<Item = Range<u64>>,
+    ) -> Result<Self, HeapError> {
+        let base = physical_memory_offset.as_u64() as usize;
+        if base & (FRAME_SIZE - 1) != 0 {
+            return Err(HeapError::BadBaseAlignment);
+        }
+        if N - 1 > usize::BITS as usize - 13 {
+            return Err(HeapError::BadHeapSize);
+        }
+        let span = FRAME_SIZE << (N - 1);
+
+        // Start with the whole heap allocated, then free the usable parts.
+        let mut heap = Heap::new_unchecked(base as *mut u8, span);
+        heap.allocate(frames(span))
+            .map_err(|_| HeapError::BadHeapSize)?;
+
+        for range in usable {
+            let mut start = align_up(range.start.max(FRAME_SIZE as u64));
+            let end = range.end.min(span as u64) & !(FRAME_SIZE as u64 - 1);
+
+            // Free the largest naturally aligned blocks that fit.
+            while start < end {
+                let size = (1 << start.trailing_zeros()).min(prev_power_of_two(end - start));
+                heap.deallocate((base + start as usize) as *mut u8, frames(size as usize));
+                start += size;
+            }
+        }
+
+        Ok(Self { heap })
+    }
+
+    /// The heap the frames come from, for statistics.
+    pub fn heap(&self) -> &Heap<N> {
+        &self.heap
+    }
+
+    /// The virtual address `frame` is mapped at.
+    fn virt<S: PageSize>(&self, frame: PhysFrame<S>) -> *mut u8 {
+        self.heap
+            .heap_base
+            .wrapping_add(frame.start_address().as_u64() as usize)
+    }
+}
+
+unsafe impl<S: PageSize, const N: usize> FrameAllocator<S> for FrameHeap<N> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let block = self.heap.allocate(frames(S::SIZE as usize)).ok()?;
+        let phys = block as u64 - self.heap.heap_base as u64;
+        PhysFrame::from_start_address(PhysAddr::new(phys)).ok()
+    }
+}
+
+impl<S: PageSize, const N: usize> FrameDeallocator<S> for FrameHeap<N> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let block = self.virt(frame);
+        self.heap.deallocate(block, frames(S::SIZE as usize));
+    }
+}
+
+/// The layout of a run of `size` bytes of frames.  Blocks are always
+/// aligned to their size, so we only need to ask for the frame alignment.
+fn frames(size: usize) -> Layout {
+    Layout::from_size_align(size, FRAME_SIZE).unwrap()
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE as u64 - 1) & !(FRAME_SIZE as u64 - 1)
+}
+
+fn prev_power_of_two(n: u64) -> u64 {
+    1 << (63 - n.leading_zeros())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::vec::Vec;
+    use x86_64::structures::paging::{Size2MiB, Size4KiB};
+
+    /// Run `f` with `size` bytes of fake physical memory, mapped at the
+    /// returned offset.
+    fn with_physical_memory(size: usize, f: impl FnOnce(VirtAddr)) {
+        let layout = std::alloc::Layout::from_size_align(size, 4096).unwrap();
+        unsafe {
+            let mem = std::alloc::alloc(layout);
+            f(VirtAddr::new(mem as u64));
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        with_physical_memory(0x10000, |offset| unsafe {
+            let usable = [0x0..0x5800, 0x8000..0x10000, 0x20000..0x30000];
+            let mut frames: FrameHeap<5> = FrameHeap::new(offset, usable.iter().cloned()).unwrap();
+            assert_eq!(12 * 4096, frames.heap().free_bytes());
+
+            let mut seen = BTreeSet::new();
+            while let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(&mut frames) {
+                let addr = frame.start_address().as_u64();
+                assert_eq!(0, addr % 4096);
+                assert!((0x1000..0x5000).contains(&addr) || (0x8000..0x10000).contains(&addr));
+                assert!(seen.insert(addr), "{:#x} handed out twice", addr);
+
+                // The frame really is mapped where we said.
+                frames.virt(frame).write_bytes(0xcc, 4096);
+            }
+            assert_eq!(12, seen.len());
+
+            // Frames can be recycled.
+            for &addr in &seen {
+                frames.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
+                    addr,
+                )));
+            }
+            assert!(frames.heap().accounting_check());
+            frames.heap().assert_alignment_invariants();
+            let again: Vec<_> = (0..12)
+                .map(|_| FrameAllocator::<Size4KiB>::allocate_frame(&mut frames).unwrap())
+                .collect();
+            assert_eq!(0, frames.heap().free_bytes());
+            for frame in again {
+                frames.deallocate_frame(frame);
+            }
+        });
+    }
+
+    #[test]
+    fn test_huge_frames() {
+        with_physical_memory(0x40_0000, |offset| unsafe {
+            let mut frames: FrameHeap<11> = FrameHeap::new(offset, Some(0..0x40_0000)).unwrap();
+
+            // Frame 0 is never handed out, so only the second 2 MiB is whole.
+            let huge: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+            assert_eq!(0x20_0000, huge.start_address().as_u64());
+            assert_eq!(
+                None,
+                FrameAllocator::<Size2MiB>::allocate_frame(&mut frames)
+            );
+
+            // The rest of the first 2 MiB is still there as small frames.
+            let small: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+            assert!(small.start_address().as_u64() < 0x20_0000);
+            frames.deallocate_frame(small);
+
+            frames.deallocate_frame(huge);
+            assert_eq!(0x40_0000 - 0x1000, frames.heap().free_bytes());
+        });
+    }
+
+    #[test]
+    fn test_bad_offset() {
+        assert_eq!(
+            HeapError::BadBaseAlignment,
+            unsafe { FrameHeap::<5>::new(VirtAddr::new(0x1234), None) }.unwrap_err()
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! A FreeRTOS heap, in place of `heap_1.c` to `heap_5.c`: this exports
+//! `pvPortMalloc`, `vPortFree`, `xPortGetFreeHeapSize` and
+//! `xPortGetMinimumEverFreeHeapSize`, backed by a static buddy heap.
+//! Leave the FreeRTOS heap source out of the build, and link this crate in
+//! its place.
+//!
+//! The heap's memory is a static array of [HEAP_SIZE] bytes, which plays
+//! the part of `configTOTAL_HEAP_SIZE`: set the
+//! `BUDDYALLOC_FREERTOS_HEAP_SIZE` environment variable when building to
+//! change it.  It's set up on the first call to `pvPortMalloc`, as FreeRTOS
+//! does.
+//!
+//! Calls are serialized with a `critical-section` critical section, rather
+//! than by suspending the scheduler as FreeRTOS's own heaps do, so that
+//! they're safe from interrupts too.  The firmware has to provide a
+//! `critical-section` implementation, which on FreeRTOS can simply call
+//! `taskENTER_CRITICAL` and `taskEXIT_CRITICAL`.
+use core::alloc::Layout;
+use core::cell::{RefCell, UnsafeCell};
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+use critical_section::Mutex;
+
+use crate::Heap;
+
+/// The size of the heap in bytes, from `BUDDYALLOC_FREERTOS_HEAP_SIZE` at
+/// build time, or 64 KiB by default.  It has to be a power of two, and at
+/// least [MIN_BLOCK_SIZE] bytes.
+pub const HEAP_SIZE: usize = match option_env!("BUDDYALLOC_FREERTOS_HEAP_SIZE") {
+    Some(size) => parse_size(size),
+    None => 64 * 1024,
+};
+
+/// The smallest block the heap hands out.  Every allocation carries a
+/// 8 byte header, so this leaves room for 24 bytes of data.
+pub const MIN_BLOCK_SIZE: usize = 32;
+
+/// The size of the header in front of each allocation, which records its
+/// size for `vPortFree`.  It keeps the data aligned to 8 bytes, which is
+/// `portBYTE_ALIGNMENT` on most ports.
+const HEADER_SIZE: usize = 8;
+
+/// The number of orders which gives the heap [MIN_BLOCK_SIZE] blocks.
+const ORDERS: usize = (HEAP_SIZE / MIN_BLOCK_SIZE).trailing_zeros() as usize + 1;
+
+const _: () = assert!(
+    HEAP_SIZE.is_power_of_two() && HEAP_SIZE >= MIN_BLOCK_SIZE,
+    "BUDDYALLOC_FREERTOS_HEAP_SIZE must be a power of two of at least 32"
+);
+const _: () = assert!(size_of::<usize>() <= HEADER_SIZE);
+
+/// Parse a decimal size at compile time.
+const fn parse_size(size: &str) -> usize {
+    let digits = size.as_bytes();
+    assert!(!digits.is_empty(), "BUDDYALLOC_FREERTOS_HEAP_SIZE is empty");
+    let mut value: usize = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        let digit = digits[i];
+        assert!(
+            digit.is_ascii_digit(),
+            "BUDDYALLOC_FREERTOS_HEAP_SIZE isn't a decimal number"
+        );
+        value = value * 10 + (digit - b'0') as usize;
+        i += 1;
+    }
+    value
+}
+
+/// The heap's memory, aligned as [Heap::new] needs it.
+#[repr(C, align(4096))]
+struct Memory(UnsafeCell<[u8; HEAP_SIZE]>);
+
+// SAFETY: Only the heap touches it, under the lock.
+unsafe impl Sync for Memory {}
+
+static MEMORY: Memory = Memory(UnsafeCell::new([0; HEAP_SIZE]));
+
+/// The heap, once it's been set up, and the least free memory it's ever
+/// had.
+struct State {
+    heap: Heap<ORDERS>,
+    min_ever_free: usize,
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+/// Run `f` on the heap, setting it up first if this is the first call.
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let state = state.get_or_insert_with(|| {
+            // SAFETY: The memory is aligned, a power of two in size, and
+            // only ever used by this heap, which is only created once.
+            let base = unsafe { NonNull::new_unchecked(MEMORY.0.get() as *mut u8) };
+            let heap = match unsafe { Heap::new(base, HEAP_SIZE) } {
+                Ok(heap) => heap,
+                Err(_) => heap_panic!("Couldn't set up the FreeRTOS heap"),
+            };
+            State {
+                min_ever_free: heap.free_bytes(),
+                heap,
+            }
+        });
+        f(state)
+    })
+}
+
+/// FreeRTOS's `pvPortMalloc`: allocate `size` bytes, aligned to 8, or
+/// return null if there's no room or `size` is zero.
+///
+/// # Safety
+/// None, but it's called from C.
+#[no_mangle]
+pub unsafe extern "C" fn pvPortMalloc(size: usize) -> *mut u8 {
+    let layout = match size.checked_add(HEADER_SIZE) {
+        Some(total) if size > 0 => match Layout::from_size_align(total, HEADER_SIZE) {
+            Ok(layout) => layout,
+            Err(_) => return ptr::null_mut(),
+        },
+        _ => return ptr::null_mut(),
+    };
+
+    with_state(|state| {
+        let block = match state.heap.allocate(layout) {
+            Ok(block) => block,
+            Err(_) => return ptr::null_mut(),
+        };
+        state.min_ever_free = state.min_ever_free.min(state.heap.free_bytes());
+
+        (block as *mut usize).write(size);
+        block.add(HEADER_SIZE)
+    })
+}
+
+/// FreeRTOS's `vPortFree`: free memory from [pvPortMalloc].  Null is
+/// ignored.
+///
+/// # Safety
+/// `ptr` must be null, or a live allocation from [pvPortMalloc].
+#[no_mangle]
+pub unsafe extern "C" fn vPortFree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let block = ptr.sub(HEADER_SIZE);
+    let size = (block as *const usize).read();
+    // SAFETY: `pvPortMalloc` made this layout from the same size.
+    let layout = Layout::from_size_align_unchecked(size + HEADER_SIZE, HEADER_SIZE);
+    with_state(|state| state.heap.deallocate(block, layout));
+}
+
+/// FreeRTOS's `xPortGetFreeHeapSize`: the number of bytes free.  Blocks
+/// are rounded up to powers of two, so this drops by more than the size
+/// asked for.
+#[no_mangle]
+pub extern "C" fn xPortGetFreeHeapSize() -> usize {
+    with_state(|state| state.heap.free_bytes())
+}
+
+/// FreeRTOS's `xPortGetMinimumEverFreeHeapSize`: the fewest bytes that have
+/// been free since the heap was set up.
+#[no_mangle]
+pub extern "C" fn xPortGetMinimumEverFreeHeapSize() -> usize {
+    with_state(|state| state.min_ever_free)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The heap is shared by the whole process, so this is the only test
+    // that uses it.
+    #[test]
+    fn test_freertos_heap() {
+        unsafe {
+            assert_eq!(HEAP_SIZE, xPortGetFreeHeapSize());
+            assert_eq!(HEAP_SIZE, xPortGetMinimumEverFreeHeapSize());
+            assert!(pvPortMalloc(0).is_null());
+            assert!(pvPortMalloc(usize::MAX).is_null());
+            assert!(pvPortMalloc(HEAP_SIZE).is_null());
+
+            // 24 bytes and the header fit in a minimum-sized block; 25
+            // don't.
+            let a = pvPortMalloc(24);
+            assert_eq!(0, a as usize % 8);
+            assert_eq!(HEAP_SIZE - 32, xPortGetFreeHeapSize());
+            let b = pvPortMalloc(25);
+            assert_eq!(HEAP_SIZE - 96, xPortGetFreeHeapSize());
+            a.write_bytes(0xaa, 24);
+            b.write_bytes(0xbb, 25);
+
+            vPortFree(a);
+            vPortFree(ptr::null_mut());
+            assert_eq!(HEAP_SIZE - 64, xPortGetFreeHeapSize());
+            vPortFree(b);
+            assert_eq!(HEAP_SIZE, xPortGetFreeHeapSize());
+            assert_eq!(HEAP_SIZE - 96, xPortGetMinimumEverFreeHeapSize());
+
+            // The whole heap, less the header.
+            let all = pvPortMalloc(HEAP_SIZE - HEADER_SIZE);
+            assert!(!all.is_null());
+            assert_eq!(0, xPortGetMinimumEverFreeHeapSize());
+            vPortFree(all);
+        }
+    }
+}
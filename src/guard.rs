@@ -0,0 +1,120 @@
+//! Blocks with a guard region, for stacks whose overflow the caller wants
+//! to catch with the MMU or MPU.
+use core::alloc::Layout;
+
+use crate::{AllocationError, AllocationSizeError, Heap};
+
+impl<const N: usize> Heap<N> {
+    /// Allocate a block holding `usable` with a guard region of at least
+    /// `guard_bytes` below it, and return the start of the usable region
+    /// and the start of the guard.  The heap can't change page
+    /// protections, so making the guard inaccessible (with `mprotect`, or
+    /// an MPU region) is up to the caller.
+    ///
+    /// The guard comes first because stacks grow down, so an overflow runs
+    /// into it.  It starts at the start of the block, which is aligned to
+    /// the block's size, up to 4096 bytes (64 on 16-bit targets): a block
+    /// of a page or more starts on a page boundary.  The guard is rounded
+    /// up to `usable.align()`, and the usable region follows it directly, so
+    /// it's aligned to that too; any slack from rounding up to a whole
+    /// block is left above it.  Pass a page-sized `guard_bytes` and a
+    /// page-aligned `usable` to keep both regions whole pages.
+    ///
+    /// Free the block with [Heap::deallocate_with_guard].
+    pub fn allocate_with_guard(
+        &mut self,
+        usable: Layout,
+        guard_bytes: usize,
+    ) -> Result<(*mut u8, *mut u8), AllocationError> {
+        let layout = match guarded_layout(usable, guard_bytes) {
+            Some(layout) => layout,
+            None => {
+                let error = AllocationError::InvalidSize(AllocationSizeError::TooLarge);
+                self.note_result(usable, Err(error));
+                return Err(error);
+            }
+        };
+
+        let guard = self.allocate(layout)?;
+        let usable_start = guard.wrapping_add(layout.size() - usable.size());
+        Ok((usable_start, guard))
+    }
+
+    /// Free a block from [Heap::allocate_with_guard], given the start of
+    /// its guard region.
+    ///
+    /// # Safety
+    /// `guard` must be the guard region's start returned by
+    /// [Heap::allocate_with_guard] with the same `usable` and
+    /// `guard_bytes`, and not have been freed.  The guard must be
+    /// accessible again, since the heap writes to the start of free blocks.
+    pub unsafe fn deallocate_with_guard(
+        &mut self,
+        guard: *mut u8,
+        usable: Layout,
+        guard_bytes: usize,
+    ) {
+        match guarded_layout(usable, guard_bytes) {
+            Some(layout) => self.deallocate(guard, layout),
+            None => heap_panic!("Tried to dispose of invalid block"),
+        }
+    }
+}
+
+/// The layout of a guard region of `guard_bytes`, rounded up to
+/// `usable.align()`, followed by `usable`.
+fn guarded_layout(usable: Layout, guard_bytes: usize) -> Option<Layout> {
+    let guard = guard_bytes.checked_add(usable.align() - 1)? & !(usable.align() - 1);
+    let size = guard.checked_add(usable.size())?;
+    Layout::from_size_align(size, usable.align()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+
+    #[test]
+    fn test_allocate_with_guard() {
+        unsafe {
+            let heap_size = 64 * 1024;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<13> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // A page of guard under a page-aligned stack of three pages
+            // takes a 16 KiB block, with the stack at its end.
+            let stack = Layout::from_size_align(3 * 4096, 4096).unwrap();
+            let (usable, guard) = heap.allocate_with_guard(stack, 4096).unwrap();
+            assert_eq!(0, guard as usize % 4096);
+            assert_eq!(guard.add(4096), usable);
+            assert_eq!(16 * 1024, heap.used_bytes());
+            usable.write_bytes(0xa5, stack.size());
+
+            // The guard is rounded up to the stack's alignment.
+            let small = Layout::from_size_align(192, 64).unwrap();
+            let (usable_small, guard_small) = heap.allocate_with_guard(small, 10).unwrap();
+            assert_eq!(guard_small.add(64), usable_small);
+            assert_eq!(0, usable_small as usize % 64);
+            assert_eq!(16 * 1024 + 256, heap.used_bytes());
+
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_with_guard(stack, usize::MAX - 100)
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_with_guard(stack, heap_size)
+            );
+
+            heap.deallocate_with_guard(guard_small, small, 10);
+            heap.deallocate_with_guard(guard, stack, 4096);
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(12, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
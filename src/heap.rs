@@ -12,50 +12,59 @@
 //! block size.
 use core::alloc::Layout;
 use core::cmp::{max, min};
+use core::convert::TryFrom;
 use core::mem::size_of;
 use core::ptr::{self, NonNull};
 use core::result::Result;
 
-use crate::math::log2;
+#[cfg(feature = "debug-info")]
+use crate::debug_info::HeapDebugInfo;
+use crate::math::{allocation_size, log2};
+use crate::{AllocationError, AllocationSizeError, HeapBuilder, HeapError};
 
-const MIN_HEAP_ALIGN: usize = 4096;
+/// The alignment the heap's base needs, which is also the largest alignment
+/// we can allocate with.  On 16-bit targets a 4 KiB floor would be a large
+/// part of RAM, so it's much smaller there.
+#[cfg(not(target_pointer_width = "16"))]
+pub(crate) const MIN_HEAP_ALIGN: usize = 4096;
+#[cfg(target_pointer_width = "16")]
+pub(crate) const MIN_HEAP_ALIGN: usize = 64;
 
-/// Represents an error for an allocation's size.
+/// An entry in a heap's remap table, recording that the block which used
+/// to live at `old` was moved to `new` by [Heap::migrate_block].  Unused
+/// entries have a null `old` pointer.
+#[cfg(feature = "remap-table")]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub enum AllocationSizeError {
-    BadAlignment,
-    TooLarge,
+pub struct RemapEntry {
+    pub old: *mut u8,
+    pub new: *mut u8,
 }
 
-/// Represents the reason for an allocation error.
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub enum AllocationError {
-    HeapExhausted,
-    InvalidSize(AllocationSizeError),
-}
-
-/// An error in the creation of the heap.
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub enum HeapError {
-    BadBaseAlignment,
-    BadSizeAlignment,
-    BadHeapSize,
-    MinBlockTooSmall,
+#[cfg(feature = "remap-table")]
+impl RemapEntry {
+    /// An unused entry.
+    pub const EMPTY: RemapEntry = RemapEntry {
+        old: ptr::null_mut(),
+        new: ptr::null_mut(),
+    };
 }
 
 /// A free block in our heap.  This is actually a header that we store at
 /// the start of the block.  We don't store any size information in the
 /// header, because we allocate a separate free block list for each block
 /// size.
-struct FreeBlock {
+///
+/// Outside this crate it's opaque; pointers to it only carry free lists
+/// from [Heap::free_list_heads] to [Heap::restore].
+pub struct FreeBlock {
     /// The next block in the free list, or NULL if this is the final
     /// block.
-    next: *mut FreeBlock,
+    pub(crate) next: *mut FreeBlock,
 }
 
 impl FreeBlock {
     /// Construct a `FreeBlock` header pointing at `next`.
-    const fn new(next: *mut FreeBlock) -> FreeBlock {
+    pub(crate) const fn new(next: *mut FreeBlock) -> FreeBlock {
         FreeBlock { next }
     }
 }
@@ -66,6 +75,17 @@ impl FreeBlock {
 ///
 /// The generic parameter N specifies the number of steps to divide the
 /// available heap size by two. This will be the minimum allocable block size.
+/// In other words, `N` is the number of block sizes (orders) the heap has,
+/// and `min_block_size == heap_size >> (N - 1)`.  Some typical
+/// configurations:
+///
+/// | `heap_size` | `N` | `min_block_size` |
+/// |-------------|-----|------------------|
+/// | 4 KiB       | 9   | 16 bytes         |
+/// | 64 KiB      | 13  | 16 bytes         |
+/// | 512 KiB     | 16  | 16 bytes         |
+/// | 1 MiB       | 17  | 16 bytes         |
+/// | 16 MiB      | 13  | 4 KiB            |
 ///
 /// # Usage
 /// ```no_run
@@ -104,71 +124,736 @@ impl FreeBlock {
 /// }
 /// ```
 #[derive(Debug)]
+#[cfg_attr(feature = "debug-info", repr(C))]
 pub struct Heap<const N: usize> {
+    /// A summary of the heap's state for debuggers.  This must stay the
+    /// first field, so that it lives at offset 0.
+    #[cfg(feature = "debug-info")]
+    pub(crate) debug_info: HeapDebugInfo<N>,
+
     /// The base address of our heap.  This must be aligned on a
     /// `MIN_HEAP_ALIGN` boundary.
-    heap_base: *mut u8,
+    pub(crate) heap_base: *mut u8,
 
     /// The space available in our heap.  This must be a power of 2.
-    heap_size: usize,
+    pub(crate) heap_size: usize,
 
     /// The free lists for our heap.  The list at `free_lists[0]` contains
     /// the smallest block size we can allocate, and the list at the end
     /// can only contain a single free block the size of our entire heap,
     /// and only when no memory is allocated.
-    free_lists: [*mut FreeBlock; N],
+    pub(crate) free_lists: [*mut FreeBlock; N],
 
     /// Our minimum block size.  This is calculated based on `heap_size`
     /// and the generic parameter N, and it must be
     /// big enough to contain a `FreeBlock` header object.
-    min_block_size: usize,
+    pub(crate) min_block_size: usize,
 
     /// The log base 2 of our block size.  Cached here so we don't have to
     /// recompute it on every allocation (but we haven't benchmarked the
     /// performance gain).
-    min_block_size_log2: u8,
+    pub(crate) min_block_size_log2: u8,
+
+    /// The largest order an ordinary allocation may take.  See
+    /// [Heap::set_max_allocation_order].
+    max_order: u8,
+
+    /// An optional table recording where migrated blocks went.  See
+    /// [Heap::set_remap_table].
+    #[cfg(feature = "remap-table")]
+    remap_table: Option<&'static mut [RemapEntry]>,
+
+    /// The next entry of `remap_table` to overwrite once it's full.
+    #[cfg(feature = "remap-table")]
+    remap_next: usize,
+
+    /// The number of bytes in allocated blocks.
+    pub(crate) used_bytes: usize,
+
+    /// The number of those bytes set aside by a [HeapBuilder] and never
+    /// allocatable.  See [Heap::reserved_bytes].
+    pub(crate) reserved_bytes: usize,
+
+    /// The offset from `heap_base` above which no block has ever been
+    /// allocated.  If the heap's memory started out zeroed, everything up
+    /// there is still zero apart from free block headers; otherwise this
+    /// is `heap_size`, and nothing is known to be zero.  It never moves
+    /// down.  See [Heap::allocate_zeroed].
+    pub(crate) zero_from: usize,
+
+    /// Running totals of successful allocations, deallocations, and
+    /// failed allocations.  These wrap around; see [HeapStats].  They're
+    /// only counted with the `stats` feature, and stay at zero otherwise.
+    pub(crate) allocations: usize,
+    pub(crate) deallocations: usize,
+    pub(crate) failures: usize,
+
+    /// Called when the heap is exhausted, to free some memory.  See
+    /// [Heap::set_reclaim].
+    #[cfg(feature = "reclaim")]
+    reclaim: Option<fn(&mut Heap<N>, Layout) -> bool>,
+
+    /// Cache maintenance for DMA buffers.  See [crate::HeapBuilder::dma_hooks].
+    #[cfg(feature = "dma")]
+    pub(crate) dma: Option<crate::DmaHooks>,
+
+    /// The offset of the break moved by [Heap::sbrk_compatibility_layer].
+    #[cfg(feature = "sbrk")]
+    pub(crate) brk: usize,
+
+    /// The low watermark and what to do about crossing it, if set.  See
+    /// [Heap::set_low_watermark].
+    #[cfg(feature = "low-watermark")]
+    pub(crate) watermark: Option<crate::watermark::Watermark>,
+
+    /// Allocation rotation and per-block allocation counts.  See
+    /// [Heap::set_wear_leveling].
+    #[cfg(feature = "wear-leveling")]
+    pub(crate) wear: crate::wear::WearLeveling<N>,
+
+    /// The order of the blocks the order 0 free list is refilled with.
+    /// See [Heap::set_small_batch_order].
+    #[cfg(feature = "small-batch")]
+    pub(crate) batch_order: u8,
+
+    /// The block held aside by [Heap::reserve_emergency], if any.
+    #[cfg(feature = "emergency")]
+    pub(crate) emergency: Option<crate::emergency::EmergencyReserve<N>>,
+
+    /// The order of every live allocation, if registered.  See
+    /// [Heap::set_order_table].
+    #[cfg(feature = "debug-track")]
+    pub(crate) order_table: Option<&'static mut [u8]>,
+
+    /// When each live allocation was made, and the clock used to tell.  See
+    /// [Heap::set_age_table].
+    #[cfg(feature = "debug-track")]
+    pub(crate) age_table: Option<crate::track::AgeTable>,
+
+    /// The ID of each live allocation, and the next ID to hand out.  See
+    /// [Heap::set_id_table].
+    #[cfg(feature = "debug-track")]
+    pub(crate) id_table: Option<crate::track::IdTable>,
+
+    /// A bitmap with one bit per minimum-sized block, set while it's part
+    /// of a live allocation, if one was supplied.  See
+    /// [Heap::new_with_metadata].
+    #[cfg(feature = "occupancy-map")]
+    pub(crate) occupancy: Option<&'static mut [u8]>,
+
+    /// The range this heap was registered over by [Heap::new], so it can
+    /// be unregistered when it's dropped.
+    #[cfg(all(feature = "heap-registry", debug_assertions))]
+    registered: Option<(usize, usize)>,
 }
 
 // This structure can safely be sent between threads.
 unsafe impl<const N: usize> Send for Heap<N> {}
 
+#[cfg(all(feature = "heap-registry", debug_assertions))]
+impl<const N: usize> Drop for Heap<N> {
+    fn drop(&mut self) {
+        if let Some((start, end)) = self.registered {
+            crate::overlap::unregister(start, end);
+        }
+    }
+}
+
+/// The number of bytes of metadata `Heap::new_with_metadata` (with the
+/// `occupancy-map` feature) needs for a heap of `heap_size` bytes with
+/// blocks of at least `min_block_size` bytes: one bit per minimum-sized
+/// block, rounded up to whole bytes.
+///
+/// This is a `const fn`, so the buffer can be sized at compile time:
+///
+/// ```
+/// # use buddyalloc::metadata_bytes;
+/// static mut METADATA: [u8; metadata_bytes(1 << 20, 64)] = [0; metadata_bytes(1 << 20, 64)];
+/// assert_eq!(2048, metadata_bytes(1 << 20, 64));
+/// ```
+pub const fn metadata_bytes(heap_size: usize, min_block_size: usize) -> usize {
+    match heap_size.checked_div(min_block_size) {
+        Some(slots) => slots.div_ceil(8),
+        None => 0,
+    }
+}
+
 impl<const N: usize> Heap<N> {
     /// Create a new heap. If any parameter is invalid, this will return a [HeapError].
+    /// The size is checked first, since the other checks depend on it.
+    ///
+    /// With the `heap-registry` feature, debug builds keep a list, shared by
+    /// every thread, of the memory under every live heap made by this (or
+    /// the constructors built on it), and panic if a new one overlaps any of
+    /// them.  So drop a heap before freeing its memory.
+    ///
+    /// # Safety
+    /// `heap_base` must point to `heap_size` bytes of memory that are not
+    /// used for anything else for as long as this heap is alive.
     pub unsafe fn new(heap_base: NonNull<u8>, heap_size: usize) -> Result<Self, HeapError> {
-        // Calculate our minimum block size based on the number of free
-        // lists we have available.
-        let min_block_size = heap_size >> (N - 1);
+        let heap = HeapBuilder::new()
+            .base(heap_base)
+            .size(heap_size)
+            .build()
+            .map_err(HeapError::first)?;
 
-        // The heap must be aligned on a 4K bounday.
-        if heap_base.as_ptr() as usize & (MIN_HEAP_ALIGN - 1) != 0 {
-            return Err(HeapError::BadBaseAlignment);
+        #[cfg(all(feature = "heap-registry", debug_assertions))]
+        let heap = heap.register();
+        Ok(heap)
+    }
+
+    /// Add the heap to the registry of live heaps; see [Heap::new].
+    #[cfg(all(feature = "heap-registry", debug_assertions))]
+    fn register(mut self) -> Self {
+        let start = self.heap_base as usize;
+        crate::overlap::register(start, start + self.heap_size);
+        self.registered = Some((start, start + self.heap_size));
+        self
+    }
+
+    /// Move the heap's entry in the registry of live heaps to `new_base`,
+    /// if it has one, before its memory moves there, and update it to the
+    /// heap's current size.  The old range goes first, since the new one may
+    /// overlap it.
+    #[cfg(all(feature = "heap-registry", debug_assertions))]
+    fn reregister(&mut self, new_base: *mut u8) {
+        if let Some((start, end)) = self.registered.take() {
+            crate::overlap::unregister(start, end);
+            let start = new_base as usize;
+            crate::overlap::register(start, start + self.heap_size);
+            self.registered = Some((start, start + self.heap_size));
         }
+    }
+
+    /// Create a new heap, checking that `N` and `heap_size` give the
+    /// expected `min_block_size` (that is, `heap_size >> (N - 1)`).  If they
+    /// don't, this returns [HeapError::BadSizeAlignment]; otherwise it
+    /// behaves exactly like [Heap::new].
+    ///
+    /// # Safety
+    /// See [Heap::new].
+    pub unsafe fn new_with_minimum_block_size(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+        min_block_size: usize,
+    ) -> Result<Self, HeapError> {
+        HeapBuilder::new()
+            .base(heap_base)
+            .size(heap_size)
+            .min_block_size(min_block_size)
+            .build()
+            .map_err(HeapError::first)
+    }
+
+    /// Create a new heap which keeps a bitmap of which minimum-sized blocks
+    /// are in use in `metadata`, outside of the memory it manages.  The
+    /// buffer needs [metadata_bytes] bytes, or this returns
+    /// [HeapError::MetadataTooSmall]; it's cleared first, and can be read
+    /// back with [Heap::occupancy_map].
+    ///
+    /// # Safety
+    /// See [Heap::new].
+    #[cfg(feature = "occupancy-map")]
+    pub unsafe fn new_with_metadata(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+        metadata: &'static mut [u8],
+    ) -> Result<Self, HeapError> {
+        HeapBuilder::new()
+            .base(heap_base)
+            .size(heap_size)
+            .occupancy_map(metadata)
+            .build()
+            .map_err(HeapError::first)
+    }
+
+    /// Create a new heap over a byte array, with no raw pointers involved:
+    ///
+    /// ```
+    /// # use buddyalloc::Heap;
+    /// #[repr(align(4096))]
+    /// struct Memory([u8; 65536]);
+    ///
+    /// let memory = Box::leak(Box::new(Memory([0; 65536])));
+    /// let heap = Heap::<8>::new_from_bytes(&mut memory.0).unwrap();
+    /// ```
+    ///
+    /// The array must be aligned to 4096 bytes (64 on 16-bit targets), and
+    /// its size is checked the same way as [Heap::new] does.  A `HEAP_SIZE`
+    /// too small to give every one of the `N` orders a block is rejected at
+    /// compile time.
+    pub fn new_from_bytes<const HEAP_SIZE: usize>(
+        bytes: &'static mut [u8; HEAP_SIZE],
+    ) -> Result<Self, HeapError> {
+        const { assert!(HEAP_SIZE >= 1 << (N - 1)) };
 
-        // The heap must be big enough to contain at least one block.
-        if heap_size < min_block_size {
+        // SAFETY: We have the memory to ourselves, forever.
+        unsafe { Self::new(NonNull::from(bytes).cast(), HEAP_SIZE) }
+    }
+
+    /// Create a new heap from the base address and size of a RAM region as
+    /// read from a microcontroller's configuration registers (for example
+    /// `STM32_SRAM_BASE` and `STM32_SRAM_SIZE`).  The values are checked as
+    /// [Heap::new] checks its arguments; a null base is a
+    /// [HeapError::BadBaseAlignment], and a region that runs off the end of
+    /// the address space is a [HeapError::BadHeapSize].
+    ///
+    /// On 32-bit targets the registers are exactly `usize`, and on 64-bit
+    /// ones they're zero-extended.  Targets with a narrower `usize` return
+    /// the same errors for values that don't fit.
+    ///
+    /// # Safety
+    /// See [Heap::new].
+    pub unsafe fn new_from_device_registers(
+        base_reg: u32,
+        size_reg: u32,
+    ) -> Result<Self, HeapError> {
+        let base = usize::try_from(base_reg).map_err(|_| HeapError::BadBaseAlignment)?;
+        let size = usize::try_from(size_reg).map_err(|_| HeapError::BadHeapSize)?;
+        if size != 0 && base.checked_add(size - 1).is_none() {
             return Err(HeapError::BadHeapSize);
         }
 
-        // The smallest possible heap block must be big enough to contain
-        // the block header.
-        if min_block_size < size_of::<FreeBlock>() {
-            return Err(HeapError::MinBlockTooSmall);
+        let base = NonNull::new(base as *mut u8).ok_or(HeapError::BadBaseAlignment)?;
+        Self::new(base, size)
+    }
+
+    /// Rebuild a heap from the free list heads saved by
+    /// [Heap::free_list_heads], over memory that still holds everything
+    /// the saved heap left there, for example after a warm reboot.  No
+    /// allocations need to be replayed.
+    ///
+    /// Every block on the lists is checked to be inside the heap and
+    /// aligned to its order, and no list may hold more blocks than the heap
+    /// could, so a corrupt list (or one with a cycle) is reported as a
+    /// [HeapError::CorruptFreeList] rather than trusted.  The used byte
+    /// count is worked out from the lists; the statistics start from zero.
+    ///
+    /// # Safety
+    /// As for [Heap::new], and the memory must be exactly as the saved
+    /// heap left it, with every allocation it had still live.  The
+    /// checks here can't tell a stale list from a current one.
+    pub unsafe fn restore(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+        free_lists: [*mut FreeBlock; N],
+    ) -> Result<Self, HeapError> {
+        let mut heap = Self::new(heap_base, heap_size)?;
+
+        let mut free_bytes = 0usize;
+        for (order, &head) in free_lists.iter().enumerate() {
+            let size = heap.order_size(order);
+            let mut room = heap_size / size;
+            let mut block = head;
+            while !block.is_null() {
+                let offset = (block as usize).wrapping_sub(heap.heap_base as usize);
+                free_bytes += size;
+                if room == 0 || offset >= heap_size || offset & (size - 1) != 0 {
+                    return Err(HeapError::CorruptFreeList {
+                        order,
+                        block: block as usize,
+                    });
+                }
+                room -= 1;
+
+                // As in `free_list_pop`, the whole-heap block's `next`
+                // pointer may never have been written.
+                if order == N - 1 {
+                    break;
+                }
+                block = (*block).next;
+            }
+            if free_bytes > heap_size {
+                return Err(HeapError::CorruptFreeList {
+                    order,
+                    block: head as usize,
+                });
+            }
+        }
+
+        heap.free_lists = free_lists;
+        heap.used_bytes = heap_size - free_bytes;
+
+        #[cfg(feature = "debug-info")]
+        heap.refresh_free_counts();
+
+        Ok(heap)
+    }
+
+    /// Create a new heap whose own metadata (this struct, including the
+    /// free lists) is stored inside the memory it manages, for systems with
+    /// nowhere else to put it.  The heap is set up over the whole region
+    /// as with [Heap::new], and then the block holding the struct is
+    /// allocated from it, so it's never handed out again.
+    ///
+    /// That block (`size_of::<Heap<N>>()` rounded up to a block size) stays
+    /// allocated for good, and counts towards [Heap::used_bytes].  Returns
+    /// [HeapError::BadHeapSize] if the heap is too small to hold it.
+    ///
+    /// # Safety
+    /// As for [Heap::new], and additionally the memory must stay valid and
+    /// unused by anything else for the rest of the program.
+    pub unsafe fn new_self_hosted(
+        heap_base: NonNull<u8>,
+        heap_size: usize,
+    ) -> Result<&'static mut Self, HeapError> {
+        let mut heap = Self::new(heap_base, heap_size)?;
+        let home = heap
+            .allocate(Layout::new::<Self>())
+            .map_err(|_| HeapError::BadHeapSize)? as *mut Self;
+
+        home.write(heap);
+        Ok(&mut *home)
+    }
+
+    /// Merge two completely free heaps over adjacent regions of the same
+    /// size, in either order, into one heap twice as big.  On failure (the
+    /// regions aren't adjacent or the same size, either heap has live
+    /// allocations, or the combined heap isn't valid for `M` orders) both
+    /// heaps are handed back unchanged.
+    ///
+    /// Stable Rust can't write the result type as `Heap<{ N + 1 }>`, so the
+    /// number of orders of the merged heap is up to the caller: `N + 1`
+    /// keeps the same minimum block size, and `N` doubles it.  Since
+    /// neither heap has anything allocated, there's no state to carry over
+    /// (side tables such as the remap table are dropped), so a heap without
+    /// a const generic `N` isn't needed for this.
+    // The heaps are handed back by value, since there's nowhere to box them.
+    #[allow(clippy::result_large_err)]
+    pub fn try_merge_adjacent<const M: usize>(self, other: Self) -> Result<Heap<M>, (Self, Self)> {
+        let (low, high) = if self.heap_base < other.heap_base {
+            (&self, &other)
+        } else {
+            (&other, &self)
+        };
+
+        let adjacent = low.heap_base.wrapping_add(low.heap_size) == high.heap_base;
+        let free = self.used_bytes == 0 && other.used_bytes == 0;
+        if !adjacent || self.heap_size != other.heap_size || !free {
+            return Err((self, other));
+        }
+
+        // SAFETY: Between them, the two heaps own the whole region.
+        let merged = unsafe {
+            HeapBuilder::new()
+                .base(NonNull::new_unchecked(low.heap_base))
+                .size(2 * low.heap_size)
+                .build()
+        };
+        match merged {
+            Ok(merged) => {
+                // The halves have to go before the whole can be registered
+                // in their place.
+                #[cfg(all(feature = "heap-registry", debug_assertions))]
+                let merged = {
+                    drop((self, other));
+                    merged.register()
+                };
+                Ok(merged)
+            }
+            Err(_) => Err((self, other)),
+        }
+    }
+
+    /// Allocate a block for `layout` and create a child heap with `M`
+    /// orders over the whole of it, typically with a much smaller minimum
+    /// block size than this heap's, for lots of small objects.  Give the
+    /// block back with [Heap::release_into] once the child is empty.
+    ///
+    /// Blocks are aligned to their size, so the child's base is too, which
+    /// is all it needs even when that's less than `MIN_HEAP_ALIGN`: the
+    /// child never hands out anything more aligned than its own size.  If
+    /// the block is too small to give each of the child's `M` orders a
+    /// block big enough for a free block header, this fails with
+    /// [AllocationSizeError::TooLarge] without allocating anything.
+    pub fn suballocate_heap<const M: usize>(
+        &mut self,
+        layout: Layout,
+    ) -> Result<Heap<M>, AllocationError> {
+        let order = self
+            .capped_order(layout.size(), layout.align())
+            .map_err(AllocationError::InvalidSize)?;
+        let size = self.order_size(order);
+        if M == 0 || size.checked_shr(M as u32 - 1).unwrap_or(0) < size_of::<FreeBlock>() {
+            return Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge));
+        }
+
+        // Allocate the whole block, so the child gets all of it even if
+        // `layout` is smaller.
+        let block = self.allocate(Layout::from_size_align(size, 1).unwrap())?;
+
+        // SAFETY: The block is ours until it's released, aligned to its
+        // size, and big enough for `M` orders.
+        Ok(unsafe { Heap::new_unchecked(block, size) })
+    }
+
+    /// Give the memory of a child heap made by [Heap::suballocate_heap]
+    /// back to its parent.  If anything is still allocated from the child,
+    /// it's handed back unchanged.
+    ///
+    /// # Safety
+    /// This heap must have been created by calling `suballocate_heap` on
+    /// `parent`.
+    // The heap is handed back by value, since there's nowhere to box it.
+    #[allow(clippy::result_large_err)]
+    pub unsafe fn release_into<const P: usize>(self, parent: &mut Heap<P>) -> Result<(), Self> {
+        if self.used_bytes != 0 {
+            return Err(self);
+        }
+
+        parent.deallocate(
+            self.heap_base,
+            Layout::from_size_align_unchecked(self.heap_size, 1),
+        );
+        Ok(())
+    }
+
+    /// Shrink the heap to `target_size` bytes, rounded up to a power of two
+    /// no smaller than the minimum block size, by taking the free blocks
+    /// which make up the rest of it off the free lists.  Afterwards nothing
+    /// will be allocated above `heap_base + target_size`, so the caller can
+    /// unmap those pages or hand them back to firmware.  The number of
+    /// orders and the minimum block size don't change; the largest possible
+    /// allocation just gets smaller.
+    ///
+    /// With the `heap-registry` feature, the heap's entry in the registry of
+    /// live heaps (see [Heap::new]) shrinks with it, so the trimmed range
+    /// can be given to a new heap.
+    ///
+    /// Fails with [HeapError::BadHeapSize] if `target_size` is bigger than
+    /// the heap, or with [HeapError::BadSizeAlignment] if anything in the
+    /// trimmed range is allocated, in which case the heap isn't changed.
+    pub fn shrink_heap_to_fit(&mut self, target_size: usize) -> Result<(), HeapError> {
+        let new_size = match target_size
+            .max(self.min_block_size)
+            .checked_next_power_of_two()
+        {
+            Some(size) if size <= self.heap_size => size,
+            _ => return Err(HeapError::BadHeapSize),
+        };
+
+        let new_top = (log2(new_size) - self.min_block_size_log2) as usize;
+        let old_top = (log2(self.heap_size) - self.min_block_size_log2) as usize;
+
+        // The checks below need every free block merged, which a small
+        // batch's blocks may not be.
+        #[cfg(feature = "small-batch")]
+        self.flush_small_batch();
+
+        // If the whole heap is free it's a single block, so split it into
+        // the pieces we're about to trim and the part we keep.
+        if let Some(block) = free_list_pop(&mut self.free_lists, old_top) {
+            // SAFETY: The block is the whole heap, which we own.
+            unsafe {
+                split_free_block(
+                    &mut self.free_lists,
+                    self.heap_base,
+                    self.heap_size,
+                    self.min_block_size_log2,
+                    block,
+                    old_top,
+                    new_top,
+                );
+                free_list_insert(&mut self.free_lists, new_top, block);
+            }
         }
 
-        // The heap size must be a power of 2.
-        if !heap_size.is_power_of_two() {
+        // The trimmed range is made of one block of each order from
+        // `new_top` up, each starting where the one before it ends.  They
+        // all have to be free.
+        let (base, min_block_size_log2) = (self.heap_base, self.min_block_size_log2);
+        let tail_block =
+            |order: usize| base.wrapping_add(1 << (min_block_size_log2 as usize + order));
+        if !(new_top..old_top)
+            .all(|order| free_list_contains(&self.free_lists, order, tail_block(order)))
+        {
             return Err(HeapError::BadSizeAlignment);
         }
 
-        // We must have one free list per possible heap block size.
-        // FIXME: Can this assertion even be hit?
-        // assert_eq!(
-        //     min_block_size * (2u32.pow(N as u32 - 1)) as usize,
-        //     heap_size
-        // );
+        for order in new_top..old_top {
+            let block = tail_block(order);
+            free_list_remove(&mut self.free_lists, order, block);
+        }
+        self.heap_size = new_size;
+
+        // The trimmed range is no longer this heap's, so another can be
+        // made over it.
+        #[cfg(all(feature = "heap-registry", debug_assertions))]
+        self.reregister(self.heap_base);
+
+        #[cfg(feature = "debug-info")]
+        {
+            self.debug_info.heap_size = new_size;
+            self.refresh_free_counts();
+        }
+
+        Ok(())
+    }
+
+    /// Move an empty heap to `new_base`, for example after its memory has
+    /// been remapped to a different address.  The heap's bytes are copied
+    /// across and every free list pointer is relocated by the distance it
+    /// moved, so a heap which was shrunk or split up keeps its shape.  With
+    /// the `heap-registry` feature, its entry in the registry of live heaps
+    /// (see [Heap::new]) moves with it.
+    ///
+    /// Fails with [HeapError::HeapInUse] if anything is allocated, since
+    /// those pointers would be left dangling, with
+    /// [HeapError::BadBaseAlignment] if `new_base` isn't aligned on a
+    /// `MIN_HEAP_ALIGN` boundary, or with [HeapError::BadHeapSize] if
+    /// `new_size` isn't the heap's size.  The heap isn't changed on failure.
+    ///
+    /// # Safety
+    /// `new_base` must point to `new_size` bytes of memory that are not
+    /// used for anything else.  They may overlap the heap's current memory.
+    pub unsafe fn move_heap(
+        &mut self,
+        new_base: NonNull<u8>,
+        new_size: usize,
+    ) -> Result<(), HeapError> {
+        if self.used_bytes != 0 {
+            return Err(HeapError::HeapInUse);
+        }
+        if new_base.as_ptr() as usize & (MIN_HEAP_ALIGN - 1) != 0 {
+            return Err(HeapError::BadBaseAlignment);
+        }
+        if new_size != self.heap_size {
+            return Err(HeapError::BadHeapSize);
+        }
+
+        let old_base = self.heap_base;
+        let new_base = new_base.as_ptr();
+        #[cfg(all(feature = "heap-registry", debug_assertions))]
+        self.reregister(new_base);
+        ptr::copy(old_base, new_base, self.heap_size);
+
+        let relocate = |block| rebase(block, old_base, new_base);
+        for order in 0..N {
+            self.free_lists[order] = relocate(self.free_lists[order]);
+
+            // The whole-heap block's `next` pointer may never have been
+            // written (see `free_list_pop`), so leave it alone.
+            if order == N - 1 {
+                continue;
+            }
+
+            let mut block = self.free_lists[order];
+            while !block.is_null() {
+                (*block).next = relocate((*block).next);
+                block = (*block).next;
+            }
+        }
+        self.heap_base = new_base;
+
+        #[cfg(feature = "debug-info")]
+        {
+            self.debug_info.heap_base = new_base;
+        }
+
+        Ok(())
+    }
+
+    /// Move the heap, live allocations and all, to `new_base`, for example
+    /// to hot-replace the memory behind it.  Each run of allocated memory
+    /// is handed to `copy(src, dst, len)`, which may be a DMA engine, but
+    /// must have finished by the time it returns.  The free lists are then
+    /// rebuilt in the new memory, relocated to the new base, by writing
+    /// the free blocks' headers directly.  The old memory is only read.
+    ///
+    /// The heap keeps its size, so a larger new region is fine, but
+    /// nothing past `heap_size` bytes of it is used.  Entries in the remap
+    /// table, if there is one, aren't changed.  With the `heap-registry`
+    /// feature, the heap's entry in the registry of live heaps (see
+    /// [Heap::new]) moves with it.
+    ///
+    /// Fails with [HeapError::BadBaseAlignment] if `new_base` isn't
+    /// aligned on a `MIN_HEAP_ALIGN` boundary, leaving the heap unchanged.
+    ///
+    /// # Safety
+    /// `new_base` must point to at least `heap_size` bytes of memory that
+    /// are not used for anything else, and don't overlap the heap's current
+    /// memory.
+    ///
+    /// Every live allocation moves with the heap, so pointers to them are
+    /// left dangling: this is only sound if nothing holds on to raw
+    /// pointers into the heap across the call.  Clients must refer to their
+    /// allocations by offsets from the heap's base, or through handles
+    /// that can be updated, and must not use them while the heap is moving.
+    pub unsafe fn migrate(
+        &mut self,
+        new_base: NonNull<u8>,
+        mut copy: impl FnMut(*const u8, *mut u8, usize),
+    ) -> Result<(), HeapError> {
+        if new_base.as_ptr() as usize & (MIN_HEAP_ALIGN - 1) != 0 {
+            return Err(HeapError::BadBaseAlignment);
+        }
+
+        // Copy everything between the free blocks, in address order.
+        let old_base = self.heap_base;
+        let new_base = new_base.as_ptr();
+        #[cfg(all(feature = "heap-registry", debug_assertions))]
+        self.reregister(new_base);
+        let mut start = 0;
+        loop {
+            let next = self.next_free_block(start);
+            let end = next.map_or(self.heap_size, |(offset, _)| offset);
+            if end > start {
+                copy(old_base.add(start), new_base.add(start), end - start);
+            }
+            match next {
+                Some((offset, size)) => start = offset + size,
+                None => break,
+            }
+        }
+
+        // Write the free blocks' headers into the new memory.
+        for order in 0..N {
+            let mut block = self.free_lists[order];
+            while !block.is_null() {
+                // The whole-heap block's `next` pointer may never have
+                // been written (see `free_list_pop`).
+                let next = if order == N - 1 {
+                    ptr::null_mut()
+                } else {
+                    (*block).next
+                };
+                *rebase(block, old_base, new_base) =
+                    FreeBlock::new(rebase(next, old_base, new_base));
+                block = next;
+            }
+            self.free_lists[order] = rebase(self.free_lists[order], old_base, new_base);
+        }
+        #[cfg(feature = "emergency")]
+        if let Some(reserve) = &mut self.emergency {
+            reserve.relocate(old_base, new_base);
+        }
+        self.heap_base = new_base;
+
+        // Only the live blocks were copied, so nothing is known to be zero
+        // in the new memory.
+        self.zero_from = self.heap_size;
+
+        #[cfg(feature = "debug-info")]
+        {
+            self.debug_info.heap_base = new_base;
+        }
+
+        Ok(())
+    }
 
-        // assert!(N > 0);
-        Ok(Self::new_unchecked(heap_base.as_ptr(), heap_size))
+    /// The offset and size of the free block with the lowest address at or
+    /// above `offset`, if there is one.
+    fn next_free_block(&self, offset: usize) -> Option<(usize, usize)> {
+        let mut next: Option<(usize, usize)> = None;
+        for order in 0..N {
+            free_list_for_each(&self.free_lists, order, |block| {
+                let at = block as usize - self.heap_base as usize;
+                if at >= offset && next.is_none_or(|(best, _)| at < best) {
+                    next = Some((at, self.order_size(order)));
+                }
+            });
+        }
+        next
     }
 
     /// Create a new heap without checking for parameter validity.
@@ -193,50 +878,189 @@ impl<const N: usize> Heap<N> {
 
         // Store all the info about our heap in our struct.
         Self {
-            heap_base: heap_base,
+            #[cfg(feature = "debug-info")]
+            debug_info: HeapDebugInfo::new(heap_base, heap_size, min_block_size),
+            heap_base,
             heap_size,
             free_lists,
             min_block_size,
             min_block_size_log2: log2(min_block_size),
+            max_order: (N - 1) as u8,
+            #[cfg(feature = "remap-table")]
+            remap_table: None,
+            #[cfg(feature = "remap-table")]
+            remap_next: 0,
+            used_bytes: 0,
+            reserved_bytes: 0,
+            zero_from: heap_size,
+            allocations: 0,
+            deallocations: 0,
+            failures: 0,
+            #[cfg(feature = "reclaim")]
+            reclaim: None,
+            #[cfg(feature = "dma")]
+            dma: None,
+            #[cfg(feature = "sbrk")]
+            brk: 0,
+            #[cfg(feature = "low-watermark")]
+            watermark: None,
+            #[cfg(feature = "wear-leveling")]
+            wear: crate::wear::WearLeveling::new(),
+            #[cfg(feature = "small-batch")]
+            batch_order: 0,
+            #[cfg(feature = "emergency")]
+            emergency: None,
+            #[cfg(feature = "debug-track")]
+            order_table: None,
+            #[cfg(feature = "debug-track")]
+            age_table: None,
+            #[cfg(feature = "debug-track")]
+            id_table: None,
+            #[cfg(feature = "occupancy-map")]
+            occupancy: None,
+            #[cfg(all(feature = "heap-registry", debug_assertions))]
+            registered: None,
         }
     }
 
-    /// Figure out what size block we'll need to fulfill an allocation
-    /// request.  This is deterministic, and it does not depend on what
-    /// we've already allocated.  In particular, it's important to be able
-    /// to calculate the same `allocation_size` when freeing memory as we
-    /// did when allocating it, or everything will break horribly.
-    fn allocation_size(&self, mut size: usize, align: usize) -> Result<usize, AllocationSizeError> {
-        // Sorry, we don't support weird alignments.
-        if !align.is_power_of_two() {
-            return Err(AllocationSizeError::BadAlignment);
-        }
+    /// The bitmap of minimum-sized blocks in use, if the heap was given
+    /// one (see [Heap::new_with_metadata]).  Bit `i % 8` of byte `i / 8`
+    /// is set while the `i`th block from `heap_base` is allocated or
+    /// reserved.
+    #[cfg(feature = "occupancy-map")]
+    pub fn occupancy_map(&self) -> Option<&[u8]> {
+        self.occupancy.as_deref()
+    }
 
-        // We can't align any more precisely than our heap base alignment
-        // without getting much too clever, so don't bother.
-        if align > MIN_HEAP_ALIGN {
-            return Err(AllocationSizeError::BadAlignment);
+    /// Mark the minimum-sized blocks making up the block of order `order`
+    /// at `block` as used or not in the occupancy map, if there is one.
+    #[cfg(feature = "occupancy-map")]
+    pub(crate) fn mark_occupied(&mut self, block: *mut u8, order: usize, used: bool) {
+        if let Some(map) = self.occupancy.as_deref_mut() {
+            let first = (block as usize - self.heap_base as usize) >> self.min_block_size_log2;
+            mark_slots(map, first, 1 << order, used);
         }
+    }
 
-        // We're automatically aligned to `size` because of how our heap is
-        // sub-divided, but if we need a larger alignment, we can only do
-        // it be allocating more memory.
-        if align > size {
-            size = align;
-        }
+    /// Promise that the heap's memory is all zero, as it is in `.bss` or
+    /// fresh pages from the OS, so that [Heap::allocate_zeroed] can skip
+    /// clearing blocks which have never been handed out.
+    ///
+    /// # Safety
+    /// Every byte of the heap must be zero, and nothing may have been
+    /// allocated from it yet.
+    pub const unsafe fn assume_zeroed(mut self) -> Self {
+        self.zero_from = 0;
+        self
+    }
 
-        // We can't allocate blocks smaller than `min_block_size`.
-        size = max(size, self.min_block_size);
+    /// Register a table that [Heap::migrate_block] will use to record
+    /// where blocks were moved to, so that clients still holding an old
+    /// pointer can look up the new one with [Heap::remapped].  The table
+    /// is cleared, and once it's full, the oldest entries are overwritten.
+    ///
+    /// Returns the previously registered table, if any.
+    #[cfg(feature = "remap-table")]
+    pub fn set_remap_table(
+        &mut self,
+        table: &'static mut [RemapEntry],
+    ) -> Option<&'static mut [RemapEntry]> {
+        table.fill(RemapEntry::EMPTY);
+        self.remap_next = 0;
+        self.remap_table.replace(table)
+    }
 
-        // Round up to the next power of two.
-        size = size.next_power_of_two();
+    /// Register a function to call when [Heap::allocate] is about to fail
+    /// with [AllocationError::HeapExhausted], so that memory held in caches
+    /// can be given back under pressure.  It's passed the heap and the
+    /// layout which couldn't be allocated, and should deallocate whatever it
+    /// can spare, returning true if it freed anything.  If it did, the
+    /// allocation is retried once.
+    ///
+    /// The function isn't called again for allocations it makes itself.
+    #[cfg(feature = "reclaim")]
+    pub fn set_reclaim(&mut self, reclaim: fn(&mut Heap<N>, Layout) -> bool) {
+        self.reclaim = Some(reclaim);
+    }
 
-        // We can't allocate a block bigger than our heap.
-        if size > self.heap_size {
-            return Err(AllocationSizeError::TooLarge);
+    /// Look up where the block that used to live at `old` was migrated to,
+    /// if it's still recorded in the remap table.
+    #[cfg(feature = "remap-table")]
+    pub fn remapped(&self, old: *mut u8) -> Option<*mut u8> {
+        if old.is_null() {
+            return None;
         }
 
-        Ok(size)
+        self.remap_table
+            .as_deref()?
+            .iter()
+            .find(|e| e.old == old)
+            .map(|e| e.new)
+    }
+
+    /// Record that the block at `old` now lives at `new`.
+    #[cfg(feature = "remap-table")]
+    fn record_remap(&mut self, old: *mut u8, new: *mut u8) {
+        let table = match self.remap_table.as_deref_mut() {
+            Some(t) if !t.is_empty() => t,
+            _ => return,
+        };
+
+        // `old` may have been freed, reallocated and migrated again, in which
+        // case we update its existing entry rather than adding a stale twin.
+        let entry = match table.iter().position(|e| e.old == old) {
+            Some(i) => i,
+            None => {
+                let i = self.remap_next;
+                self.remap_next = (i + 1) % table.len();
+                i
+            }
+        };
+
+        table[entry] = RemapEntry { old, new };
+    }
+
+    /// Move the block at `ptr` from this heap to `new_heap`: allocate space
+    /// for `old_layout` in `new_heap`, copy `old_layout.size()` bytes over,
+    /// and free the original block.  With the `remap-table` feature, if a
+    /// remap table is registered (see `Heap::set_remap_table`), the move is
+    /// recorded in it.
+    ///
+    /// If `new_heap` can't satisfy the allocation, the error is returned and
+    /// neither heap is modified.
+    ///
+    /// # Safety
+    /// `ptr` and `old_layout` must match what was passed to / returned from
+    /// `allocate` on this heap.
+    pub unsafe fn migrate_block(
+        &mut self,
+        ptr: *mut u8,
+        old_layout: Layout,
+        new_heap: &mut Heap<N>,
+    ) -> Result<*mut u8, AllocationError> {
+        let new = new_heap.allocate(old_layout)?;
+
+        ptr::copy_nonoverlapping(ptr, new, old_layout.size());
+        self.deallocate(ptr, old_layout);
+        #[cfg(feature = "remap-table")]
+        self.record_remap(ptr, new);
+
+        Ok(new)
+    }
+
+    /// Figure out what size block we'll need to fulfill an allocation
+    /// request.  This is deterministic, and it does not depend on what
+    /// we've already allocated.  In particular, it's important to be able
+    /// to calculate the same `allocation_size` when freeing memory as we
+    /// did when allocating it, or everything will break horribly.
+    fn allocation_size(&self, size: usize, align: usize) -> Result<usize, AllocationSizeError> {
+        allocation_size(
+            self.min_block_size,
+            self.heap_size,
+            MIN_HEAP_ALIGN,
+            size,
+            align,
+        )
     }
 
     /// The "order" of an allocation is how many times we need to double
@@ -247,114 +1071,405 @@ impl<const N: usize> Heap<N> {
             .map(|s| (log2(s) - self.min_block_size_log2) as usize)
     }
 
+    /// Like `allocation_order`, but refusing anything above
+    /// [Heap::max_allocation_order].  Allocating uses this, and freeing
+    /// doesn't, so blocks handed out before the limit was lowered can still
+    /// be freed.
+    pub(crate) fn capped_order(
+        &self,
+        size: usize,
+        align: usize,
+    ) -> Result<usize, AllocationSizeError> {
+        self.order_up_to(size, align, self.max_order as usize)
+    }
+
+    /// Like `allocation_order`, but refusing anything above `max_order`.
+    fn order_up_to(
+        &self,
+        size: usize,
+        align: usize,
+        max_order: usize,
+    ) -> Result<usize, AllocationSizeError> {
+        match self.allocation_order(size, align) {
+            Ok(order) if order > max_order => Err(AllocationSizeError::TooLarge),
+            result => result,
+        }
+    }
+
     /// The size of the blocks we allocate for a given order.
-    const fn order_size(&self, order: usize) -> usize {
+    //
+    // A `[usize; N]` table of these, filled in by `new`, was tried and
+    // measured: allocating a minimum-size block from an empty 1 MiB
+    // `Heap<16>` and freeing it again (a 15-level split and merge) got at
+    // most 3% faster on x86_64, within the noise, while the `size-check`
+    // program grew by about 200 bytes of flash and every heap by `N` words.
+    // On Cortex-M0 a load also costs more than a shift, so we shift.
+    pub(crate) const fn order_size(&self, order: usize) -> usize {
         1 << (self.min_block_size_log2 as usize + order)
     }
 
-    /// Pop a block off the appropriate free list.
-    fn free_list_pop(&mut self, order: usize) -> Option<*mut u8> {
-        let candidate = self.free_lists[order];
-        if !candidate.is_null() {
-            // N.B: If this is the entry corresponding to the entire heap,
-            // the next entry is always going to be NULL. Special-case it here
-            // to allow for uninitialized initial data.
-            if order != self.free_lists.len() - 1 {
-                self.free_lists[order] = unsafe { (*candidate).next };
-            } else {
-                self.free_lists[order] = ptr::null_mut();
-            }
+    /// The order of the block `layout` would be allocated from, and the
+    /// number of bytes of it that would go unused.  A waste of zero means
+    /// `layout` fits its block exactly.
+    ///
+    /// This is a pure computation, useful for finding the allocation sites
+    /// that lose the most space to rounding.
+    pub fn order_and_waste(&self, layout: Layout) -> Result<(usize, usize), AllocationSizeError> {
+        let order = self.allocation_order(layout.size(), layout.align())?;
+        Ok((order, self.order_size(order) - layout.size()))
+    }
 
-            Some(candidate as *mut u8)
-        } else {
-            None
+    /// The layout of a whole block of order `order`, for freeing a block
+    /// whose order is known but whose original layout isn't.  It covers
+    /// the entire block, and has the same order as any layout the block
+    /// could have been allocated for.
+    ///
+    /// Blocks are aligned to their size relative to the heap's base, but
+    /// the base itself is only aligned to 4096 bytes (64 on 16-bit
+    /// targets), so that's as far as the layout's alignment goes.  Panics
+    /// if `order` is past the top order.
+    pub fn block_layout(&self, order: usize) -> Layout {
+        if order >= N {
+            heap_panic!("Block order is past the top order");
+        }
+        let size = self.order_size(order);
+        match Layout::from_size_align(size, min(size, MIN_HEAP_ALIGN)) {
+            Ok(layout) => layout,
+            Err(_) => heap_panic!("Block is too large for a layout"),
         }
     }
 
-    /// Insert `block` of order `order` onto the appropriate free list.
-    unsafe fn free_list_insert(&mut self, order: usize, block: *mut u8) {
-        let free_block_ptr = block as *mut FreeBlock;
-        *free_block_ptr = FreeBlock::new(self.free_lists[order]);
-        self.free_lists[order] = free_block_ptr;
+    /// Returns true if the block of order `order` at `block` is currently
+    /// on the free list.  Unlike `free_list_remove`, this leaves the free
+    /// list untouched, so it's safe to use in assertions and validators.
+    pub fn is_block_free(&self, order: usize, block: *mut u8) -> bool {
+        free_list_contains(&self.free_lists, order, block)
     }
 
-    /// Attempt to remove a block from our free list, returning true
-    /// success, and false if the block wasn't on our free list.  This is
-    /// the slowest part of a primitive buddy allocator, because it runs in
-    /// O(log N) time where N is the number of blocks of a given size.
-    ///
-    /// We could perhaps improve this by keeping our free lists sorted,
-    /// because then "nursery generation" allocations would probably tend
-    /// to occur at lower addresses and then be faster to find / rule out
-    /// finding.
-    fn free_list_remove(&mut self, order: usize, block: *mut u8) -> bool {
-        let block_ptr = block as *mut FreeBlock;
+    /// Check the free lists against a model of what they should hold, for
+    /// model-based tests.  `reference[slot][order]` says whether there
+    /// should be a free block of order `order` starting at minimum-block
+    /// slot `slot`, and there has to be one entry per slot in the heap.
+    /// Returns true if the free lists hold exactly those blocks.
+    pub fn compare_with_reference_state(&self, reference: &[[bool; N]]) -> bool {
+        if reference.len() != self.heap_size >> self.min_block_size_log2 {
+            return false;
+        }
 
-        // Yuck, list traversals are gross without recursion.  Here,
-        // `*checking` is the pointer we want to check, and `checking` is
-        // the memory location we found it at, which we'll need if we want
-        // to replace the value `*checking` with a new value.
-        let mut checking: &mut *mut FreeBlock = &mut self.free_lists[order];
+        // Every free block has to be expected, and there have to be as many
+        // as expected.
+        let mut matches = true;
+        let mut free = 0;
+        for order in 0..N {
+            free_list_for_each(&self.free_lists, order, |block| {
+                let slot = (block as usize - self.heap_base as usize) >> self.min_block_size_log2;
+                matches &= reference.get(slot).is_some_and(|orders| orders[order]);
+                free += 1;
+            });
+        }
+        matches
+            && free
+                == reference
+                    .iter()
+                    .flatten()
+                    .filter(|&&expected| expected)
+                    .count()
+    }
 
-        // Loop until we run out of free blocks.
-        while !(*checking).is_null() {
-            // Is this the pointer we want to remove from the free list?
-            if *checking == block_ptr {
-                // Yup, this is the one, so overwrite the value we used to
-                // get here with the next one in the sequence.
-                *checking = unsafe { (*(*checking)).next };
-                return true;
-            }
+    /// Returns true if every one of the heap's blocks of order `order` is
+    /// free, that is, nothing has been allocated (or reserved) out of any
+    /// of them.  A free block of a larger order counts as all of the
+    /// blocks of order `order` inside it, so on a fresh heap this is true
+    /// for every order, and after any allocation it's false for every
+    /// order.  An order past the top order is never free.
+    pub fn is_order_fully_free(&self, order: usize) -> bool {
+        if order >= N {
+            return false;
+        }
+        let free: usize = (order..N)
+            .map(|o| free_list_len(&self.free_lists, o) << (o - order))
+            .sum();
+        free == self.heap_size / self.order_size(order)
+    }
 
-            // Haven't found it yet, so point `checking` at the address
-            // containing our `next` field.  (Once again, this is so we'll
-            // be able to reach back and overwrite it later if necessary.)
-            checking = unsafe { &mut ((*(*checking)).next) };
+    /// Which orders an allocation could be made at right now: entry `k` is
+    /// true if there's a free block of order `k` or larger to take (and, if
+    /// it's larger, split).
+    pub fn available_orders(&self) -> [bool; N] {
+        let mut available = [false; N];
+        let mut any = false;
+        for order in (0..N).rev() {
+            any |= !self.free_lists[order].is_null();
+            available[order] = any;
         }
-        false
+        available
+    }
+
+    /// The heads of all of the free lists, to be saved along with the
+    /// heap's memory and passed to [Heap::restore] later.
+    pub fn free_list_heads(&self) -> [*mut FreeBlock; N] {
+        self.free_lists
+    }
+
+    /// The block at the head of the free list for `order`, which is the
+    /// next one a request of that order would be given, if there is one.
+    /// Unlike popping it, this leaves the free list untouched.
+    pub fn free_list_peek(&self, order: usize) -> Option<*mut u8> {
+        match self.free_lists.get(order) {
+            Some(&head) if !head.is_null() => Some(head as *mut u8),
+            _ => None,
+        }
+    }
+
+    /// Sort the free list for `order` into ascending address order, so that
+    /// the next allocations of that order come from the lowest free blocks
+    /// and walk memory sequentially.  Unless the `sorted-free-lists`
+    /// feature is on, blocks freed later still go on the front of the list,
+    /// so the order only lasts until then.
+    ///
+    /// This is an insertion sort, so it needs no memory but takes
+    /// O(n²) time in the length of the list.
+    pub fn free_list_sort(&mut self, order: usize) {
+        free_list_sort(&mut self.free_lists, order);
     }
 
-    /// Split a `block` of order `order` down into a block of order
-    /// `order_needed`, placing any unused chunks on the free list.
+    /// Take every block off the free list for `order`, passing each to
+    /// `visitor`, and return how many there were.  The list is left empty,
+    /// and each block counts as an allocation of that order, so it can be
+    /// given back with [Heap::deallocate] and a layout of the block's size.
+    /// Orders past the top have no blocks.
     ///
     /// # Safety
-    /// The block must be owned by this heap, otherwise bad things
-    /// will happen.
-    unsafe fn split_free_block(&mut self, block: *mut u8, mut order: usize, order_needed: usize) {
-        // Get the size of our starting block.
-        let mut size_to_split = self.order_size(order);
+    /// The caller takes ownership of the blocks, for example to migrate
+    /// them to another heap, and must not give any of them back here
+    /// unless it's done with it.
+    pub unsafe fn free_list_remove_all(
+        &mut self,
+        order: usize,
+        mut visitor: impl FnMut(*mut u8),
+    ) -> usize {
+        let mut count = 0;
+        while let Some(block) = free_list_pop(&mut self.free_lists, order) {
+            self.note_allocated(block, order);
+            visitor(block);
+            count += 1;
+        }
 
-        // Progressively cut our block down to size.
-        while order > order_needed {
-            // Update our loop counters to describe a block half the size.
-            size_to_split >>= 1;
-            order -= 1;
+        #[cfg(feature = "low-watermark")]
+        self.check_watermark();
+        #[cfg(feature = "debug-info")]
+        self.refresh_free_counts();
+        count
+    }
+
+    /// Call `f` with the address and size of every free block, for
+    /// overwriting free memory at shutdown or scrubbing it now and then.
+    /// `f` gets the whole block, header and all: the header is read before
+    /// `f` is called and written back afterwards, so nothing `f` writes
+    /// inside the block can corrupt the free lists, which are left in the
+    /// same order.  `f` mustn't keep the pointers.
+    ///
+    /// Free memory may not be zero afterwards, so [Heap::allocate_zeroed]
+    /// stops assuming any of it is.
+    pub fn for_each_free_block(&mut self, mut f: impl FnMut(*mut u8, usize)) {
+        for order in 0..N {
+            let size = self.order_size(order);
+            let mut block = self.free_lists[order];
+            while !block.is_null() {
+                // As in `free_list_pop`, the whole-heap block's `next`
+                // pointer may never have been written.
+                let next = if order == N - 1 {
+                    ptr::null_mut()
+                } else {
+                    // SAFETY: Blocks on the free lists are ours to read.
+                    unsafe { (*block).next }
+                };
 
-            // Insert the "upper half" of the block into the free list.
-            let split = block.add(size_to_split);
-            self.free_list_insert(order, split);
+                f(block as *mut u8, size);
+                // SAFETY: And to write.
+                unsafe { block.write(FreeBlock::new(next)) };
+                block = next;
+            }
+        }
+        self.zero_from = self.heap_size;
+    }
+
+    /// The length of each order's free list, which is how many blocks
+    /// freeing a block of that order may have to look through to find its
+    /// buddy.  The largest entry is the worst case for [Heap::deallocate],
+    /// so a caller with a latency budget can check it now and then and
+    /// call [Heap::free_list_sort] (or simply allocate differently) when
+    /// a list gets too long.
+    ///
+    /// This walks every free list.
+    pub fn measure_free_list_depth(&self) -> [usize; N] {
+        let mut depths = [0; N];
+        for (order, depth) in depths.iter_mut().enumerate() {
+            *depth = free_list_len(&self.free_lists, order);
         }
+        depths
     }
 
     /// Given a `block` with the specified `order`, find the "buddy" block,
     /// that is, the other half of the block we originally split it from,
     /// and also the block we could potentially merge it with.
+    #[cfg(test)]
     fn buddy(&self, order: usize, block: *mut u8) -> Option<*mut u8> {
-        assert!(block >= self.heap_base);
+        buddy(
+            self.heap_base,
+            self.heap_size,
+            self.min_block_size_log2,
+            order,
+            block,
+        )
+    }
+
+    /// Find the order of the block `allocate` would take (and, if it's too
+    /// big, split) to fulfill a request of `size` bytes aligned to `align`,
+    /// without actually allocating anything.  Returns `None` if the request
+    /// is invalid or the heap has no block big enough.
+    ///
+    /// When choosing between several heaps, the one returning the lowest
+    /// order has to split the least to fulfill the request.
+    pub fn first_fit_order(&self, size: usize, align: usize) -> Option<usize> {
+        let order_needed = self.capped_order(size, align).ok()?;
+        (order_needed..N).find(|&order| !self.free_lists[order].is_null())
+    }
 
-        let relative = unsafe { block.offset_from(self.heap_base) } as usize;
+    /// Bookkeeping for a block of order `order` we just handed out.
+    fn note_allocated(&mut self, block: *mut u8, order: usize) {
         let size = self.order_size(order);
-        if size >= self.heap_size {
-            // The main heap itself does not have a budy.
-            None
-        } else {
-            // Fun: We can find our buddy by xoring the right bit in our
-            // offset from the base of the heap.
-            Some(unsafe { self.heap_base.add(relative ^ size) })
+        self.used_bytes += size;
+        #[cfg(feature = "stats")]
+        {
+            self.allocations = self.allocations.wrapping_add(1);
         }
+
+        let end = block as usize - self.heap_base as usize + size;
+        self.zero_from = self.zero_from.max(end);
+
+        #[cfg(feature = "occupancy-map")]
+        self.mark_occupied(block, order, true);
+
+        #[cfg(feature = "debug-track")]
+        self.track_allocate(block, order);
+
+        #[cfg(feature = "wear-leveling")]
+        self.count_wear(block, order);
     }
 
-    /// Allocate a block of memory large enough to contain `layout`,
+    /// Bookkeeping for a block of order `order` which is about to be freed.
+    #[cfg_attr(
+        not(any(feature = "debug-track", feature = "occupancy-map")),
+        allow(unused_variables)
+    )]
+    fn note_freed(&mut self, block: *mut u8, order: usize) {
+        #[cfg(feature = "debug-track")]
+        self.track_deallocate(block, order);
+
+        #[cfg(feature = "occupancy-map")]
+        self.mark_occupied(block, order, false);
+
+        self.used_bytes -= self.order_size(order);
+        #[cfg(feature = "stats")]
+        {
+            self.deallocations = self.deallocations.wrapping_add(1);
+        }
+    }
+
+    /// Bookkeeping for the outcome of a request for `layout`.
+    #[cfg_attr(
+        not(any(feature = "debug-info", feature = "stats")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn note_result(&mut self, layout: Layout, result: Result<*mut u8, AllocationError>) {
+        #[cfg(feature = "stats")]
+        if result.is_err() {
+            self.failures = self.failures.wrapping_add(1);
+        }
+        #[cfg(feature = "low-watermark")]
+        self.check_watermark();
+
+        #[cfg(feature = "debug-info")]
+        self.update_debug_info(layout, result);
+    }
+
+    /// The number of bytes currently allocated.  This counts whole blocks,
+    /// so it includes the space wasted by rounding requests up to a power
+    /// of two.
+    pub const fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    /// The number of bytes the [HeapBuilder] set aside which can never be
+    /// allocated, either with [HeapBuilder::reserve] or
+    /// [HeapBuilder::randomize_start].  They're counted in
+    /// [Heap::used_bytes] too, so the bytes actually allocated are the
+    /// difference between the two.
+    pub const fn reserved_bytes(&self) -> usize {
+        self.reserved_bytes
+    }
+
+    /// The number of bytes currently free.  Since free memory may be split
+    /// into many blocks, this doesn't mean an allocation of this size will
+    /// succeed.
+    pub const fn free_bytes(&self) -> usize {
+        self.heap_size - self.used_bytes
+    }
+
+    /// The index of the minimum-sized block containing `ptr`, counting
+    /// from 0 at the heap's base, or `None` if `ptr` isn't inside the
+    /// heap.  This is the key for metadata kept outside the heap with one
+    /// entry per minimum-sized block, such as a bitmap of blocks in use;
+    /// there are `heap_size / min_block_size` of them.
+    pub fn block_index(&self, ptr: *const u8) -> Option<usize> {
+        let offset = (ptr as usize).checked_sub(self.heap_base as usize)?;
+        if offset < self.heap_size {
+            Some(offset >> self.min_block_size_log2)
+        } else {
+            None
+        }
+    }
+
+    /// Check that the heap's accounting is consistent: the blocks on the
+    /// free lists must add up to exactly [Heap::free_bytes].  A mismatch
+    /// means there's a bug in the allocator (or the heap was corrupted).
+    ///
+    /// This walks every free list, so it's O(N + number of free blocks),
+    /// and is meant for tests and periodic health checks.
+    pub fn accounting_check(&self) -> bool {
+        let listed: usize = (0..N)
+            .map(|order| free_list_len(&self.free_lists, order) * self.order_size(order))
+            .sum();
+        listed == self.free_bytes()
+    }
+
+    /// Check that every block on the free lists is aligned to the size of
+    /// its order (and so to the minimum block size), relative to the base
+    /// of the heap, and panic with the first block and order that isn't.
+    /// A misaligned free block means the splitting or merging logic has
+    /// gone wrong.
+    ///
+    /// Like [Heap::accounting_check], this walks every free list, and is
+    /// meant for tests and debug builds.
+    pub fn assert_alignment_invariants(&self) {
+        for order in 0..N {
+            free_list_for_each(&self.free_lists, order, |block| {
+                if !self.block_is_aligned_to_order(block, order) {
+                    heap_panic!("Free block {:p} isn't aligned for order {}", block, order);
+                }
+            });
+        }
+    }
+
+    /// Returns true if `block` is at a multiple of the size of order
+    /// `order` from the base of the heap.
+    fn block_is_aligned_to_order(&self, block: *mut u8, order: usize) -> bool {
+        let offset = (block as usize).wrapping_sub(self.heap_base as usize);
+        offset & (self.order_size(order) - 1) == 0
+    }
+
+    /// Allocate a block of memory large enough to contain `layout`,
     /// and aligned to `layout`.  This will return an [`AllocationError`]
     /// if the alignment is greater than `MIN_HEAP_ALIGN`, or if
     /// we can't find enough memory.
@@ -362,230 +1477,3430 @@ impl<const N: usize> Heap<N> {
     /// All allocated memory must be passed to `deallocate` with the same
     /// `layout` parameter, or else horrible things will happen.
     pub fn allocate(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        self.allocate_up_to(layout, self.max_order as usize)
+    }
+
+    /// [Heap::allocate], refusing blocks above order `max_order`.
+    fn allocate_up_to(
+        &mut self,
+        layout: Layout,
+        max_order: usize,
+    ) -> Result<*mut u8, AllocationError> {
+        let result = self.allocate_block(layout, max_order);
+
+        // Give the reclaim function one chance to free something.  It's
+        // taken out while it runs so that its own allocations can't recurse
+        // into it.
+        #[cfg(feature = "reclaim")]
+        let result = match self.reclaim {
+            Some(reclaim) if result == Err(AllocationError::HeapExhausted) => {
+                self.reclaim = None;
+                let freed = reclaim(self, layout);
+                self.reclaim.get_or_insert(reclaim);
+                if freed {
+                    self.allocate_block(layout, max_order)
+                } else {
+                    result
+                }
+            }
+            _ => result,
+        };
+
+        self.note_result(layout, result);
+        result
+    }
+
+    /// Allocate uninitialized space for one `T`, with `Layout::new::<T>()`.
+    /// Free it with [Heap::deallocate_for_value].
+    pub fn allocate_for_value<T>(&mut self) -> Result<NonNull<T>, AllocationError> {
+        let block = self.allocate(Layout::new::<T>())?;
+        // SAFETY: The heap never hands out a null block.
+        Ok(unsafe { NonNull::new_unchecked(block as *mut T) })
+    }
+
+    /// Free space from [Heap::allocate_for_value].  Whatever `T` is left
+    /// in it isn't dropped.
+    ///
+    /// # Safety
+    /// `ptr` must have come from [Heap::allocate_for_value] for the same
+    /// `T` (or a type with the same layout), and not have been freed.
+    pub unsafe fn deallocate_for_value<T>(&mut self, ptr: NonNull<T>) {
+        self.deallocate(ptr.as_ptr() as *mut u8, Layout::new::<T>())
+    }
+
+    /// Like [Heap::allocate], but never handing out a block smaller than
+    /// order `min_order`, however small `layout` is.  A subsystem which
+    /// only ever uses this keeps all of its blocks in a few uniform size
+    /// classes, which coalesce more readily than a mix of tiny ones.
+    ///
+    /// Fails with [AllocationSizeError::TooLarge] if `min_order` is past
+    /// the top order, or above [Heap::max_allocation_order].  Free the
+    /// block with [Heap::deallocate_min_order] and the same `min_order`.
+    pub fn allocate_min_order(
+        &mut self,
+        layout: Layout,
+        min_order: usize,
+    ) -> Result<*mut u8, AllocationError> {
+        match self.widen_to_order(layout, min_order) {
+            Some(layout) => self.allocate(layout),
+            None => {
+                let result = Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge));
+                self.note_result(layout, result);
+                result
+            }
+        }
+    }
+
+    /// Free a block from [Heap::allocate_min_order].
+    ///
+    /// # Safety
+    /// `ptr` must have come from [Heap::allocate_min_order] with a
+    /// `layout` order-equivalent to this one and the same `min_order`, and
+    /// not have been freed.
+    pub unsafe fn deallocate_min_order(&mut self, ptr: *mut u8, layout: Layout, min_order: usize) {
+        match self.widen_to_order(layout, min_order) {
+            Some(layout) => self.deallocate(ptr, layout),
+            None => heap_panic!("Tried to dispose of invalid block"),
+        }
+    }
+
+    /// `layout`, grown if need be to fill a block of order `order`, or
+    /// `None` if there's no such order.  Growing the size only ever raises
+    /// the order, and so the alignment, so the block still suits `layout`.
+    fn widen_to_order(&self, layout: Layout, order: usize) -> Option<Layout> {
+        if order >= N {
+            return None;
+        }
+        let size = max(layout.size(), self.order_size(order));
+        Layout::from_size_align(size, layout.align()).ok()
+    }
+
+    /// Like [Heap::allocate], but ignoring [Heap::max_allocation_order], so
+    /// that the orders above it are kept for the callers trusted to use
+    /// this.  Allocations made by a reclaim function it calls are still
+    /// capped as usual.
+    pub fn allocate_privileged(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        self.allocate_up_to(layout, N - 1)
+    }
+
+    /// Like [Heap::allocate], but waiting for memory: whenever the heap is
+    /// exhausted, `retry_fn` is called with the heap, and if it returns
+    /// true the allocation is tried again.  It might free memory itself,
+    /// or collect blocks which finished DMA transfers have given back, and
+    /// it decides how long to keep trying, for example until a deadline.
+    /// Invalid layouts fail straight away.
+    ///
+    /// Each failed attempt counts towards the heap's failure statistics.
+    pub fn allocate_with_timeout(
+        &mut self,
+        layout: Layout,
+        retry_fn: &mut impl FnMut(&mut Self) -> bool,
+    ) -> Result<*mut u8, AllocationError> {
+        loop {
+            match self.allocate(layout) {
+                Err(AllocationError::HeapExhausted) if retry_fn(self) => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// The largest order of block an allocation may take, other than
+    /// through [Heap::allocate_privileged].  This is the top order, the
+    /// whole heap, unless it's been lowered.
+    pub fn max_allocation_order(&self) -> usize {
+        self.max_order as usize
+    }
+
+    /// Refuse to allocate blocks bigger than order `order`, so that no one
+    /// allocation can take more than `min_block_size << order` bytes and
+    /// starve everybody else.  Larger requests fail with
+    /// [AllocationSizeError::TooLarge] even if the heap has room for them.
+    /// Orders past the top are clamped to it, which lifts the limit.
+    ///
+    /// This can be changed at any time.  Blocks allocated before it was
+    /// lowered can still be freed.
+    pub fn set_max_allocation_order(&mut self, order: usize) {
+        self.max_order = min(order, N - 1) as u8;
+    }
+
+    /// Like [Heap::allocate], but the first `layout.size()` bytes of the
+    /// block are zeroed.
+    ///
+    /// If the heap was created over zeroed memory (see
+    /// [Heap::assume_zeroed]), a block which has never been handed out
+    /// before only needs its free block header cleared, which saves
+    /// clearing large buffers the first time round.  Freed blocks are
+    /// never assumed to be zero again.
+    pub fn allocate_zeroed(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        let zero_from = self.zero_from;
+        let (block, fresh) = match self.allocate_block(layout, self.max_order as usize) {
+            // A block is fresh if it's above where the frontier was.
+            Ok(block) => {
+                self.note_result(layout, Ok(block));
+                (block, block as usize - self.heap_base as usize >= zero_from)
+            }
+
+            // Otherwise leave it to `allocate`, which may call the reclaim
+            // function.  That might allocate above the frontier and dirty
+            // the memory itself, so the block isn't taken to be fresh.
+            Err(_) => (self.allocate(layout)?, false),
+        };
+        let dirty = if fresh {
+            min(layout.size(), size_of::<FreeBlock>())
+        } else {
+            layout.size()
+        };
+
+        // SAFETY: The block is at least `layout.size()` bytes long.
+        unsafe { block.write_bytes(0, dirty) };
+        Ok(block)
+    }
+
+    /// The body of [Heap::allocate], without the reclaim retry, refusing
+    /// blocks above order `max_order`.
+    fn allocate_block(
+        &mut self,
+        layout: Layout,
+        max_order: usize,
+    ) -> Result<*mut u8, AllocationError> {
         // Figure out which order block we need.
-        match self.allocation_order(layout.size(), layout.align()) {
-            Ok(order_needed) => {
-                // Start with the smallest acceptable block size, and search
-                // upwards until we reach blocks the size of the entire heap.
-                for order in order_needed..self.free_lists.len() {
-                    // Do we have a block of this size?
-                    if let Some(block) = self.free_list_pop(order) {
-                        // If the block is too big, break it up.  This leaves
-                        // the address unchanged, because we always allocate at
-                        // the head of a block.
-                        if order > order_needed {
-                            // SAFETY: The block came from the heap.
-                            unsafe { self.split_free_block(block, order, order_needed) };
-                        }
+        match self.order_up_to(layout.size(), layout.align(), max_order) {
+            Ok(order_needed) => match self.take_block(order_needed) {
+                Some(block) => {
+                    self.note_allocated(block, order_needed);
+                    Ok(block)
+                }
+                None => Err(AllocationError::HeapExhausted),
+            },
+
+            // We can't allocate a block with the specified size and
+            // alignment.
+            Err(e) => Err(AllocationError::InvalidSize(e)),
+        }
+    }
+
+    /// Take a free block of order `order_needed` for [Heap::allocate],
+    /// splitting a larger one if we have to.
+    fn take_block(&mut self, order_needed: usize) -> Option<*mut u8> {
+        #[cfg(feature = "wear-leveling")]
+        if self.wear_leveling() {
+            return self.allocate_rotating(order_needed);
+        }
+
+        #[cfg(feature = "small-batch")]
+        if order_needed == 0 && self.batch_order > 0 && self.free_lists[0].is_null() {
+            self.reserve_order(self.batch_order as usize);
+        }
+
+        allocate_order(
+            &mut self.free_lists,
+            self.heap_base,
+            self.heap_size,
+            self.min_block_size_log2,
+            order_needed,
+        )
+    }
+
+    /// Like [Heap::allocate], but only ever hands out a block which is
+    /// already free at exactly the order `layout` needs.  Larger blocks are
+    /// never split to satisfy the request; if there's no block of the
+    /// right size this returns [AllocationError::HeapExhausted] instead.
+    ///
+    /// This is useful for fixed-size pools which shouldn't fragment the
+    /// large blocks shared with everybody else.
+    pub fn allocate_exact(&mut self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        let result = match self.capped_order(layout.size(), layout.align()) {
+            Ok(order) => match free_list_pop(&mut self.free_lists, order) {
+                Some(block) => {
+                    self.note_allocated(block, order);
+                    Ok(block)
+                }
+                None => Err(AllocationError::HeapExhausted),
+            },
+            Err(e) => Err(AllocationError::InvalidSize(e)),
+        };
+
+        self.note_result(layout, result);
+        result
+    }
+
+    /// Make sure there's a free block of exactly order `order`, splitting
+    /// the smallest larger free block down to it if there isn't one
+    /// already.  Nothing is allocated: both halves of the last split are
+    /// left on the free list for `order`, so a following
+    /// [Heap::allocate_exact] at that order will succeed.
+    ///
+    /// Returns [AllocationError::HeapExhausted] if there's no free block
+    /// at `order` or above, and [AllocationSizeError::TooLarge] if `order`
+    /// is past the top order.
+    pub fn ensure_min_free_order(&mut self, order: usize) -> Result<(), AllocationError> {
+        if order >= N {
+            return Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge));
+        }
+        if !self.free_lists[order].is_null() {
+            return Ok(());
+        }
+
+        let block = allocate_order(
+            &mut self.free_lists,
+            self.heap_base,
+            self.heap_size,
+            self.min_block_size_log2,
+            order,
+        )
+        .ok_or(AllocationError::HeapExhausted)?;
+        // SAFETY: The block just came off our own free lists.
+        unsafe { free_list_insert(&mut self.free_lists, order, block) };
+
+        #[cfg(feature = "debug-info")]
+        self.refresh_free_counts();
+        Ok(())
+    }
+
+    /// Allocate the block for `layout` which starts exactly at `ptr`,
+    /// splitting whichever free block contains it.  This is for putting
+    /// something at a known address, or replaying a recorded pattern of
+    /// allocations.
+    ///
+    /// `ptr` has to be aligned to the size of the block `layout` needs,
+    /// relative to the heap's base, or this fails with
+    /// [AllocationSizeError::BadAlignment]; a block which doesn't fit in
+    /// the heap fails with [AllocationSizeError::TooLarge].  If any part of
+    /// the block is in use, this returns [AllocationError::HeapExhausted].
+    pub fn allocate_at(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<*mut u8, AllocationError> {
+        let result = self.allocate_at_block(ptr, layout);
+        self.note_result(layout, result);
+        result
+    }
+
+    /// Like [Heap::allocate_at], but with the block's address given as an
+    /// offset from the heap's base.
+    pub fn allocate_at_offset(
+        &mut self,
+        offset: usize,
+        layout: Layout,
+    ) -> Result<*mut u8, AllocationError> {
+        self.allocate_at(self.heap_base.wrapping_add(offset), layout)
+    }
+
+    /// The body of [Heap::allocate_at].
+    fn allocate_at_block(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<*mut u8, AllocationError> {
+        let order = self
+            .capped_order(layout.size(), layout.align())
+            .map_err(AllocationError::InvalidSize)?;
+        let size = self.order_size(order);
+
+        let offset = (ptr as usize).wrapping_sub(self.heap_base as usize);
+        if offset > self.heap_size - size {
+            return Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge));
+        }
+        if offset & (size - 1) != 0 {
+            return Err(AllocationError::InvalidSize(
+                AllocationSizeError::BadAlignment,
+            ));
+        }
+
+        if !allocate_order_at(
+            &mut self.free_lists,
+            self.heap_base,
+            self.min_block_size_log2,
+            ptr,
+            order,
+        ) {
+            return Err(AllocationError::HeapExhausted);
+        }
+
+        self.note_allocated(ptr, order);
+        Ok(ptr)
+    }
+
+    /// Allocate a stripe of `num_blocks` contiguous blocks of order
+    /// `order`, returned as one slice of `num_blocks * order_size(order)`
+    /// bytes.  Unlike a single block, `num_blocks` needn't be a power of
+    /// two: the stripe is cut from a block of the next power of two up, and
+    /// the blocks past its end are freed at once.
+    ///
+    /// Each block of the stripe counts as an allocation of its own, so
+    /// they can be freed one at a time with [Heap::deallocate] and
+    /// [Heap::block_layout]`(order)`, or all together with
+    /// [Heap::deallocate_stripe].  A stripe of no blocks is an empty slice
+    /// at a dangling pointer, and takes nothing from the heap.
+    ///
+    /// Fails with [AllocationSizeError::TooLarge] if the stripe would need
+    /// a block past the top order, or above [Heap::max_allocation_order].
+    pub fn allocate_stripe(
+        &mut self,
+        num_blocks: usize,
+        order: usize,
+    ) -> Result<*mut [u8], AllocationError> {
+        if num_blocks == 0 {
+            return Ok(ptr::slice_from_raw_parts_mut(
+                NonNull::dangling().as_ptr(),
+                0,
+            ));
+        }
+        let whole_order = num_blocks
+            .checked_next_power_of_two()
+            .map(|blocks| order + blocks.trailing_zeros() as usize)
+            .filter(|&whole_order| whole_order <= self.max_allocation_order());
+        let whole_order = match whole_order {
+            Some(whole_order) => whole_order,
+            None => {
+                // The closest a layout can come to describing it.
+                let error = AllocationError::InvalidSize(AllocationSizeError::TooLarge);
+                self.note_result(self.block_layout(N - 1), Err(error));
+                return Err(error);
+            }
+        };
 
-                        // We have an allocation, so quit now.
-                        return Ok(block);
+        let size = self.order_size(order);
+        let layout = Layout::from_size_align(num_blocks * size, self.block_layout(order).align())
+            .map_err(|_| AllocationError::InvalidSize(AllocationSizeError::TooLarge))?;
+        let result = match self.take_block(whole_order) {
+            Some(block) => {
+                // Free everything past the stripe as the largest blocks
+                // that fit.  Each one's buddy holds part of the stripe, so
+                // there's nothing to merge them with.
+                let mut end =
+                    block as usize + (1 << (self.min_block_size_log2 as usize + whole_order));
+                let stripe_end = block as usize + layout.size();
+                for free_order in (order..whole_order).rev() {
+                    let free_size = self.order_size(free_order);
+                    if end - free_size >= stripe_end {
+                        end -= free_size;
+                        // SAFETY: It's part of the block we just took.
+                        unsafe {
+                            free_list_insert(&mut self.free_lists, free_order, end as *mut u8)
+                        };
                     }
                 }
 
-                // We couldn't find a large enough block for this allocation.
-                Err(AllocationError::HeapExhausted)
+                for i in 0..num_blocks {
+                    self.note_allocated(block.wrapping_add(i * size), order);
+                }
+                Ok(block)
+            }
+            None => Err(AllocationError::HeapExhausted),
+        };
+
+        self.note_result(layout, result);
+        result.map(|block| ptr::slice_from_raw_parts_mut(block, layout.size()))
+    }
+
+    /// Free every block of a stripe from [Heap::allocate_stripe], merging
+    /// them back together.
+    ///
+    /// # Safety
+    /// `stripe` must have come from [Heap::allocate_stripe] with the same
+    /// `order`, and none of its blocks may have been freed.
+    pub unsafe fn deallocate_stripe(&mut self, stripe: *mut [u8], order: usize) {
+        let len = stripe.len();
+        if len == 0 {
+            return;
+        }
+        let block = self.block_layout(order);
+        if len & (block.size() - 1) != 0 {
+            heap_panic!("Tried to dispose of invalid block");
+        }
+
+        let start = stripe as *mut u8;
+        for i in 0..len / block.size() {
+            self.deallocate(start.add(i * block.size()), block);
+        }
+    }
+
+    /// Deallocate a block allocated using `allocate`.
+    ///
+    /// `layout` doesn't have to be identical to the one the block was
+    /// allocated with, only *order-equivalent*: it must round up to the same
+    /// block size.  For example, a block allocated with a size of 24 and an
+    /// alignment of 8 can be freed with a size of 17 and an alignment of 1,
+    /// since both need a 32-byte block.
+    ///
+    /// The freed block is merged with its buddy, and the result with its
+    /// buddy, for as long as they're free, so the heap never holds two free
+    /// buddies.  There's no separate coalescing pass to run, and nothing a
+    /// more aggressive strategy could merge.  The one exception is the
+    /// blocks of a small batch (see `Heap::set_small_batch_order`), which
+    /// stay split until they're handed out or flushed.
+    ///
+    /// With the `debug-track` feature and an order table registered (see
+    /// `Heap::set_order_table`), a layout of a different order, or a pointer
+    /// which isn't a live allocation, causes a panic instead of corrupting
+    /// the heap.
+    ///
+    /// Like `free(NULL)`, deallocating a null `ptr` does nothing, whatever
+    /// `layout` is.
+    ///
+    /// # Safety
+    /// `ptr` must be null or have been returned from `allocate`, and
+    /// `layout` must be order-equivalent to the layout passed to it, or our
+    /// heap will be corrupted.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let initial_order = match self.allocation_order(layout.size(), layout.align()) {
+            Ok(order) => order,
+            Err(_) => heap_panic!("Tried to dispose of invalid block"),
+        };
+
+        self.note_freed(ptr, initial_order);
+
+        free_block(
+            &mut self.free_lists,
+            self.heap_base,
+            self.heap_size,
+            self.min_block_size_log2,
+            ptr,
+            initial_order,
+        );
+        #[cfg(feature = "low-watermark")]
+        self.check_watermark();
+
+        #[cfg(feature = "debug-info")]
+        self.update_debug_info(layout, Ok(ptr));
+    }
+
+    /// Resize the allocation at `ptr` from `old_layout` to `new_layout`,
+    /// returning its new address.  The contents are kept, up to the smaller
+    /// of the two sizes.
+    ///
+    /// The new block's order is worked out from the whole of `new_layout`,
+    /// exactly as [Heap::allocate] does, so the result is always aligned to
+    /// `new_layout.align()`.  If that's the order the block already has,
+    /// it's returned as is; if it's smaller, the block is split in place
+    /// and its upper part freed.  If it's bigger and the block is the lower
+    /// half of a run of free buddies big enough, it's grown in place by
+    /// taking them.  Otherwise a new block is allocated, the contents copied
+    /// across, and the old block freed.  On failure the old block is left
+    /// alone.
+    ///
+    /// A null `ptr` is simply allocated.  Each resize counts as one
+    /// allocation and one deallocation in the heap's statistics.
+    ///
+    /// # Safety
+    /// `ptr` must be null or have been returned from `allocate`, and
+    /// `old_layout` must be order-equivalent to the layout passed to it.
+    pub unsafe fn reallocate(
+        &mut self,
+        ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<*mut u8, AllocationError> {
+        if ptr.is_null() {
+            return self.allocate(new_layout);
+        }
+
+        let old_order = match self.allocation_order(old_layout.size(), old_layout.align()) {
+            Ok(order) => order,
+            Err(_) => heap_panic!("Tried to resize an invalid block"),
+        };
+        let new_order = match self.capped_order(new_layout.size(), new_layout.align()) {
+            Ok(order) => order,
+            Err(e) => {
+                let result = Err(AllocationError::InvalidSize(e));
+                self.note_result(new_layout, result);
+                return result;
+            }
+        };
+
+        if new_order > old_order
+            && !grow_in_place(
+                &mut self.free_lists,
+                self.heap_base,
+                self.min_block_size_log2,
+                ptr,
+                old_order,
+                new_order,
+            )
+        {
+            let new = self.allocate(new_layout)?;
+            ptr::copy_nonoverlapping(ptr, new, min(old_layout.size(), new_layout.size()));
+            self.deallocate(ptr, old_layout);
+            return Ok(new);
+        }
+
+        // Blocks are aligned to their size, so the block we already have
+        // is aligned well enough for any order up to its own.  Its upper
+        // parts are the buddies of the part we keep, so they can go
+        // straight onto the free lists without merging.  (If it just grew,
+        // there's nothing to split.)
+        self.note_freed(ptr, old_order);
+        self.note_allocated(ptr, new_order);
+        split_free_block(
+            &mut self.free_lists,
+            self.heap_base,
+            self.heap_size,
+            self.min_block_size_log2,
+            ptr,
+            old_order,
+            new_order,
+        );
+
+        self.note_result(new_layout, Ok(ptr));
+        Ok(ptr)
+    }
+}
+
+// The core algorithm lives in the free functions below rather than in
+// `impl Heap<N>`.  They only ever see the free lists as a slice, so they're
+// compiled once no matter how many different values of `N` a program uses,
+// and the methods above shrink to thin wrappers.
+
+/// Pop a block off the appropriate free list.
+pub(crate) fn free_list_pop(free_lists: &mut [*mut FreeBlock], order: usize) -> Option<*mut u8> {
+    let top = order == free_lists.len() - 1;
+    list_pop(free_lists.get_mut(order)?, top)
+}
+
+/// Pop a block off the free list starting at `head`.  `top` says whether
+/// it's the list for the whole heap.
+pub(crate) fn list_pop(head: &mut *mut FreeBlock, top: bool) -> Option<*mut u8> {
+    let candidate = *head;
+    if !candidate.is_null() {
+        // N.B: If this is the entry corresponding to the entire heap,
+        // the next entry is always going to be NULL. Special-case it here
+        // to allow for uninitialized initial data.
+        if !top {
+            *head = unsafe { (*candidate).next };
+        } else {
+            *head = ptr::null_mut();
+        }
+
+        Some(candidate as *mut u8)
+    } else {
+        None
+    }
+}
+
+/// Insert `block` of order `order` onto the appropriate free list.
+pub(crate) unsafe fn free_list_insert(
+    free_lists: &mut [*mut FreeBlock],
+    order: usize,
+    block: *mut u8,
+) {
+    if let Some(head) = free_lists.get_mut(order) {
+        list_insert(head, block);
+    }
+}
+
+/// Insert `block` onto the free list starting at `head`.  With the
+/// `sorted-free-lists` feature, it goes in address order, which takes time
+/// linear in the length of the list; otherwise it goes on the front.
+pub(crate) unsafe fn list_insert(head: &mut *mut FreeBlock, block: *mut u8) {
+    let free_block_ptr = block as *mut FreeBlock;
+
+    #[cfg(feature = "sorted-free-lists")]
+    let head = {
+        let mut link = head;
+        while !(*link).is_null() && *link < free_block_ptr {
+            link = &mut (**link).next;
+        }
+        link
+    };
+
+    *free_block_ptr = FreeBlock::new(*head);
+    *head = free_block_ptr;
+}
+
+/// Attempt to remove a block from our free list, returning true
+/// success, and false if the block wasn't on our free list.  This is
+/// the slowest part of a primitive buddy allocator, because it runs in
+/// O(log N) time where N is the number of blocks of a given size.
+///
+/// With the `sorted-free-lists` feature the lists are kept in address
+/// order, so the search can stop as soon as it passes `block`.  That pays
+/// off when freeing into a badly fragmented heap, but every insertion has
+/// to search the list too.
+pub(crate) fn free_list_remove(
+    free_lists: &mut [*mut FreeBlock],
+    order: usize,
+    block: *mut u8,
+) -> bool {
+    match free_lists.get_mut(order) {
+        Some(head) => list_remove(head, block),
+        None => false,
+    }
+}
+
+/// Remove `block` from the free list starting at `head`, as
+/// `free_list_remove` does.
+pub(crate) fn list_remove(head: &mut *mut FreeBlock, block: *mut u8) -> bool {
+    let block_ptr = block as *mut FreeBlock;
+
+    // Yuck, list traversals are gross without recursion.  Here,
+    // `*checking` is the pointer we want to check, and `checking` is
+    // the memory location we found it at, which we'll need if we want
+    // to replace the value `*checking` with a new value.
+    let mut checking: &mut *mut FreeBlock = head;
+
+    // Loop until we run out of free blocks.
+    while !(*checking).is_null() {
+        // Is this the pointer we want to remove from the free list?
+        if *checking == block_ptr {
+            // Yup, this is the one, so overwrite the value we used to
+            // get here with the next one in the sequence.
+            *checking = unsafe { (*(*checking)).next };
+            return true;
+        }
+
+        // Sorted lists have nothing further along that could match.
+        #[cfg(feature = "sorted-free-lists")]
+        if *checking > block_ptr {
+            return false;
+        }
+
+        // Haven't found it yet, so point `checking` at the address
+        // containing our `next` field.  (Once again, this is so we'll
+        // be able to reach back and overwrite it later if necessary.)
+        checking = unsafe { &mut ((*(*checking)).next) };
+    }
+    false
+}
+
+/// Returns true if `block` is on the free list for `order`.  This is the
+/// read-only sibling of `free_list_remove`.
+pub(crate) fn free_list_contains(
+    free_lists: &[*mut FreeBlock],
+    order: usize,
+    block: *mut u8,
+) -> bool {
+    let block_ptr = block as *mut FreeBlock;
+    let mut checking = match free_lists.get(order) {
+        Some(&head) => head,
+        None => return false,
+    };
+
+    // The block covering the entire heap may never have had its header
+    // written (see `free_list_pop`), so don't follow its `next` pointer.
+    if order == free_lists.len() - 1 {
+        return !checking.is_null() && checking == block_ptr;
+    }
+
+    while !checking.is_null() {
+        if checking == block_ptr {
+            return true;
+        }
+        checking = unsafe { (*checking).next };
+    }
+    false
+}
+
+/// Call `f` with every block on the free list for `order`.
+pub(crate) fn free_list_for_each(
+    free_lists: &[*mut FreeBlock],
+    order: usize,
+    mut f: impl FnMut(*mut u8),
+) {
+    let mut checking = match free_lists.get(order) {
+        Some(&head) => head,
+        None => return,
+    };
+
+    // As in `free_list_contains`, the whole-heap block's `next` pointer
+    // may never have been written.
+    if order == free_lists.len() - 1 {
+        if !checking.is_null() {
+            f(checking as *mut u8);
+        }
+        return;
+    }
+
+    while !checking.is_null() {
+        f(checking as *mut u8);
+        checking = unsafe { (*checking).next };
+    }
+}
+
+/// Sort the free list for `order` by address, lowest first.
+fn free_list_sort(free_lists: &mut [*mut FreeBlock], order: usize) {
+    // The whole-heap list never has more than one block, and its `next`
+    // pointer may never have been written.
+    if order >= free_lists.len() - 1 {
+        return;
+    }
+
+    // Move the blocks one at a time onto `sorted`, each into its place.
+    let mut sorted: *mut FreeBlock = ptr::null_mut();
+    let mut unsorted = free_lists[order];
+    while !unsorted.is_null() {
+        let block = unsorted;
+        // SAFETY: Every block on a free list starts with a valid header.
+        unsafe {
+            unsorted = (*block).next;
+
+            let mut link = &mut sorted;
+            while !(*link).is_null() && *link < block {
+                link = &mut (**link).next;
+            }
+            (*block).next = *link;
+            *link = block;
+        }
+    }
+    free_lists[order] = sorted;
+}
+
+/// Move `block`, a pointer into a heap at `old_base`, to the same offset in
+/// a heap at `new_base`.  Null stays null.
+pub(crate) fn rebase<T>(block: *mut T, old_base: *mut u8, new_base: *mut u8) -> *mut T {
+    if block.is_null() {
+        block
+    } else {
+        new_base.wrapping_add(block as usize - old_base as usize) as *mut T
+    }
+}
+
+/// Count the blocks on the free list for `order`.
+pub(crate) fn free_list_len(free_lists: &[*mut FreeBlock], order: usize) -> usize {
+    let mut len = 0;
+    free_list_for_each(free_lists, order, |_| len += 1);
+    len
+}
+
+/// Split a `block` of order `order` down into a block of order
+/// `order_needed`, placing any unused chunks on the free list.
+///
+/// In debug builds, this panics rather than put a chunk outside the heap
+/// on a free list, which would otherwise only show up as corruption much
+/// later.  That can only happen if `block` and `order` don't agree.
+///
+/// # Safety
+/// The block must be owned by this heap, otherwise bad things
+/// will happen.
+unsafe fn split_free_block(
+    free_lists: &mut [*mut FreeBlock],
+    heap_base: *mut u8,
+    heap_size: usize,
+    min_block_size_log2: u8,
+    block: *mut u8,
+    mut order: usize,
+    order_needed: usize,
+) {
+    // Get the size of our starting block.
+    let mut size_to_split = 1 << (min_block_size_log2 as usize + order);
+
+    // Progressively cut our block down to size.
+    while order > order_needed {
+        // Update our loop counters to describe a block half the size.
+        size_to_split >>= 1;
+        order -= 1;
+
+        // Insert the "upper half" of the block into the free list.
+        let split = block.wrapping_add(size_to_split);
+        if cfg!(debug_assertions)
+            && (split < heap_base || split >= heap_base.wrapping_add(heap_size))
+        {
+            heap_panic!("Split block is outside the heap");
+        }
+        free_list_insert(free_lists, order, split);
+    }
+}
+
+/// Grow the allocated `block` of order `order` to `order_needed` without
+/// moving it, by taking its buddy at each order in between off the free
+/// lists.  That only works if `block` is the lower half all the way up and
+/// every one of those buddies is free; otherwise nothing is changed and
+/// this returns false.
+unsafe fn grow_in_place(
+    free_lists: &mut [*mut FreeBlock],
+    heap_base: *mut u8,
+    min_block_size_log2: u8,
+    block: *mut u8,
+    order: usize,
+    order_needed: usize,
+) -> bool {
+    let relative = block.offset_from(heap_base) as usize;
+    if relative & ((1 << (min_block_size_log2 as usize + order_needed)) - 1) != 0 {
+        return false;
+    }
+
+    // Check every buddy before taking any, so a failure leaves the free
+    // lists alone.
+    let upper = |o: usize| block.add(1 << (min_block_size_log2 as usize + o));
+    if !(order..order_needed).all(|o| free_list_contains(free_lists, o, upper(o))) {
+        return false;
+    }
+    for o in order..order_needed {
+        free_list_remove(free_lists, o, upper(o));
+    }
+    true
+}
+
+/// Set or clear `count` bits of `map`, starting at bit `first`.
+#[cfg(feature = "occupancy-map")]
+fn mark_slots(map: &mut [u8], first: usize, count: usize, used: bool) {
+    for slot in first..first + count {
+        if let Some(byte) = map.get_mut(slot / 8) {
+            let bit = 1 << (slot % 8);
+            if used {
+                *byte |= bit;
+            } else {
+                *byte &= !bit;
+            }
+        }
+    }
+}
+
+/// Given a `block` with the specified `order`, find the "buddy" block,
+/// that is, the other half of the block we originally split it from,
+/// and also the block we could potentially merge it with.
+pub(crate) fn buddy(
+    heap_base: *mut u8,
+    heap_size: usize,
+    min_block_size_log2: u8,
+    order: usize,
+    block: *mut u8,
+) -> Option<*mut u8> {
+    if block < heap_base {
+        heap_panic!("Block is below the heap base");
+    }
+
+    let relative = unsafe { block.offset_from(heap_base) } as usize;
+    let size = 1 << (min_block_size_log2 as usize + order);
+    if size >= heap_size {
+        // The main heap itself does not have a budy.
+        None
+    } else {
+        // Fun: We can find our buddy by xoring the right bit in our
+        // offset from the base of the heap.
+        Some(unsafe { heap_base.add(relative ^ size) })
+    }
+}
+
+/// Find a block of order `order_needed`, splitting a larger one if we
+/// have to.  Returns `None` if the heap is exhausted.
+pub(crate) fn allocate_order(
+    free_lists: &mut [*mut FreeBlock],
+    heap_base: *mut u8,
+    heap_size: usize,
+    min_block_size_log2: u8,
+    order_needed: usize,
+) -> Option<*mut u8> {
+    // Start with the smallest acceptable block size, and search
+    // upwards until we reach blocks the size of the entire heap.
+    for order in order_needed..free_lists.len() {
+        // Do we have a block of this size?
+        if let Some(block) = free_list_pop(free_lists, order) {
+            // If the block is too big, break it up.  This leaves
+            // the address unchanged, because we always allocate at
+            // the head of a block.
+            if order > order_needed {
+                // SAFETY: The block came from the heap.
+                unsafe {
+                    split_free_block(
+                        free_lists,
+                        heap_base,
+                        heap_size,
+                        min_block_size_log2,
+                        block,
+                        order,
+                        order_needed,
+                    )
+                };
+            }
+
+            // We have an allocation, so quit now.
+            return Some(block);
+        }
+    }
+
+    // We couldn't find a large enough block for this allocation.
+    None
+}
+
+/// Take the block of order `order_needed` at `block` off the free lists,
+/// splitting the free block which contains it and freeing the rest.
+/// Returns false if no free block contains it.  `block` must lie inside
+/// the heap, aligned to its size.
+fn allocate_order_at(
+    free_lists: &mut [*mut FreeBlock],
+    heap_base: *mut u8,
+    min_block_size_log2: u8,
+    block: *mut u8,
+    order_needed: usize,
+) -> bool {
+    let top_order = free_lists.len() - 1;
+    let offset = block as usize - heap_base as usize;
+
+    // Blocks are aligned to their size relative to the heap's base, so
+    // rounding the offset down gives the block of each order which
+    // contains `block`.
+    for order in order_needed..free_lists.len() {
+        let size = 1usize << (min_block_size_log2 as usize + order);
+        let mut candidate = heap_base.wrapping_add(offset & !(size - 1));
+
+        let found = if order == top_order {
+            // As in `free_list_pop`, the whole-heap block's `next` pointer
+            // may never have been written.
+            let found = free_lists[order] == candidate as *mut FreeBlock;
+            if found {
+                free_lists[order] = ptr::null_mut();
+            }
+            found
+        } else {
+            free_list_remove(free_lists, order, candidate)
+        };
+        if !found {
+            continue;
+        }
+
+        // Cut the block down to size, keeping whichever half holds
+        // `block` and freeing the other.
+        let mut order = order;
+        while order > order_needed {
+            order -= 1;
+            let upper = candidate.wrapping_add(1 << (min_block_size_log2 as usize + order));
+            // SAFETY: Both halves came from the heap.
+            unsafe {
+                if block >= upper {
+                    free_list_insert(free_lists, order, candidate);
+                    candidate = upper;
+                } else {
+                    free_list_insert(free_lists, order, upper);
+                }
+            }
+        }
+        return true;
+    }
+
+    false
+}
+
+/// Return `ptr`, a block of order `initial_order`, to the free lists,
+/// merging it with its buddies as far up as we can.
+///
+/// # Safety
+/// `ptr` must be a block of order `initial_order` allocated from this heap.
+pub(crate) unsafe fn free_block(
+    free_lists: &mut [*mut FreeBlock],
+    heap_base: *mut u8,
+    heap_size: usize,
+    min_block_size_log2: u8,
+    ptr: *mut u8,
+    initial_order: usize,
+) {
+    // The fun part: When deallocating a block, we also want to check
+    // to see if its "buddy" is on the free list.  If the buddy block
+    // is also free, we merge them and continue walking up.
+    //
+    // `block` is the biggest merged block we have so far.
+    let mut block = ptr;
+    for order in initial_order..free_lists.len() {
+        // Would this block have a buddy?
+        if let Some(buddy) = buddy(heap_base, heap_size, min_block_size_log2, order, block) {
+            // Is this block's buddy free?
+            if free_list_remove(free_lists, order, buddy) {
+                // Merge them!  The lower address of the two is the
+                // newly-merged block.  Then we want to try again.
+                block = min(block, buddy);
+                continue;
+            }
+        }
+
+        // If we reach here, we didn't find a buddy block of this size,
+        // so take what we've got and mark it as free.
+        free_list_insert(free_lists, order, block);
+        return;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // Use std in tests.
+    extern crate std;
+    use super::*;
+    #[cfg(feature = "reclaim")]
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_allocation_size_and_order() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Can't align beyond MIN_HEAP_ALIGN.
+            assert_eq!(
+                Err(AllocationSizeError::BadAlignment),
+                heap.allocation_size(256, 8192)
+            );
+
+            // Can't align beyond heap_size.
+            assert_eq!(
+                Err(AllocationSizeError::TooLarge),
+                heap.allocation_size(256, 256 * 2)
+            );
+
+            // Simple allocations just round up to next block size.
+            assert_eq!(Ok(16), heap.allocation_size(0, 1));
+            assert_eq!(Ok(16), heap.allocation_size(1, 1));
+            assert_eq!(Ok(16), heap.allocation_size(16, 1));
+            assert_eq!(Ok(32), heap.allocation_size(17, 1));
+            assert_eq!(Ok(32), heap.allocation_size(32, 32));
+            assert_eq!(Ok(256), heap.allocation_size(256, 256));
+
+            // Aligned allocations use alignment as block size.
+            assert_eq!(Ok(64), heap.allocation_size(16, 64));
+
+            // Block orders.
+            assert_eq!(Ok(0), heap.allocation_order(0, 1));
+            assert_eq!(Ok(0), heap.allocation_order(1, 1));
+            assert_eq!(Ok(0), heap.allocation_order(16, 16));
+            assert_eq!(Ok(1), heap.allocation_order(32, 32));
+            assert_eq!(Ok(2), heap.allocation_order(64, 64));
+            assert_eq!(Ok(3), heap.allocation_order(128, 128));
+            assert_eq!(Ok(4), heap.allocation_order(256, 256));
+            assert_eq!(
+                Err(AllocationSizeError::TooLarge),
+                heap.allocation_order(512, 512)
+            );
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_new_with_minimum_block_size() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+
+            let heap = Heap::<5>::new_with_minimum_block_size(base, heap_size, 16).unwrap();
+            assert_eq!(16, heap.min_block_size);
+
+            assert_eq!(
+                Err(HeapError::BadSizeAlignment),
+                Heap::<5>::new_with_minimum_block_size(base, heap_size, 32).map(|_| ())
+            );
+            assert_eq!(
+                Err(HeapError::BadSizeAlignment),
+                Heap::<4>::new_with_minimum_block_size(base, heap_size, 16).map(|_| ())
+            );
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_metadata_bytes() {
+        assert_eq!(2, metadata_bytes(256, 16));
+        assert_eq!(2048, metadata_bytes(1 << 20, 64));
+        // A partial byte still needs a whole one.
+        assert_eq!(1, metadata_bytes(64, 16));
+        assert_eq!(0, metadata_bytes(256, 0));
+    }
+
+    #[cfg(feature = "occupancy-map")]
+    #[test]
+    fn test_new_with_metadata() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+            let leak = |len| std::boxed::Box::leak(std::vec![0xffu8; len].into_boxed_slice());
+
+            assert_eq!(
+                Err(HeapError::MetadataTooSmall),
+                Heap::<5>::new_with_metadata(base, heap_size, leak(1)).map(|_| ())
+            );
+
+            // The buffer is cleared, and then follows the live blocks.
+            let mut heap: Heap<5> = Heap::new_with_metadata(base, heap_size, leak(2)).unwrap();
+            assert_eq!(Some(&[0, 0][..]), heap.occupancy_map());
+            let small = Layout::from_size_align(16, 1).unwrap();
+            let big = Layout::from_size_align(64, 1).unwrap();
+            let a = heap.allocate(small).unwrap();
+            let b = heap.allocate(big).unwrap();
+            let c = heap
+                .allocate(Layout::from_size_align(128, 1).unwrap())
+                .unwrap();
+            assert_eq!((mem, mem.add(64), mem.add(128)), (a, b, c));
+            assert_eq!(Some(&[0b1111_0001, 0xff][..]), heap.occupancy_map());
+
+            heap.deallocate(b, big);
+            heap.deallocate(c, Layout::from_size_align(128, 1).unwrap());
+            assert_eq!(Some(&[0b0000_0001, 0][..]), heap.occupancy_map());
+            heap.deallocate(a, small);
+            assert_eq!(Some(&[0, 0][..]), heap.occupancy_map());
+
+            // Reserved blocks are marked too; 200..210 straddles two.
+            let heap: Heap<5> = HeapBuilder::new()
+                .base(base)
+                .size(heap_size)
+                .occupancy_map(leak(2))
+                .reserve(200..210)
+                .build()
+                .unwrap();
+            assert_eq!(Some(&[0, 0b0011_0000][..]), heap.occupancy_map());
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_new_error_order() {
+        let base = NonNull::new(0x1_0000 as *mut u8).unwrap();
+        let misaligned = NonNull::new(0x1_0010 as *mut u8).unwrap();
+
+        // 300 >> 15 is zero, which would look like `MinBlockTooSmall`, but
+        // the real problem is the size.
+        unsafe {
+            assert_eq!(
+                Err(HeapError::BadSizeAlignment),
+                Heap::<16>::new(base, 300).map(|_| ())
+            );
+            assert_eq!(
+                Err(HeapError::BadSizeAlignment),
+                Heap::<16>::new(misaligned, 300).map(|_| ())
+            );
+            assert_eq!(
+                Err(HeapError::BadSizeAlignment),
+                Heap::<16>::new(base, 0).map(|_| ())
+            );
+
+            // With a good size, the derived checks still apply.
+            assert_eq!(
+                Err(HeapError::MinBlockTooSmall),
+                Heap::<16>::new(base, 256).map(|_| ())
+            );
+            assert_eq!(
+                Err(HeapError::BadBaseAlignment),
+                Heap::<16>::new(misaligned, 256).map(|_| ())
+            );
+        }
+    }
+
+    #[test]
+    fn test_buddy() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let block_16_0 = mem;
+            let block_16_1 = mem.offset(16);
+            assert_eq!(Some(block_16_1), heap.buddy(0, block_16_0));
+            assert_eq!(Some(block_16_0), heap.buddy(0, block_16_1));
+
+            let block_32_0 = mem;
+            let block_32_1 = mem.offset(32);
+            assert_eq!(Some(block_32_1), heap.buddy(1, block_32_0));
+            assert_eq!(Some(block_32_0), heap.buddy(1, block_32_1));
+
+            let block_32_2 = mem.offset(64);
+            let block_32_3 = mem.offset(96);
+            assert_eq!(Some(block_32_3), heap.buddy(1, block_32_2));
+            assert_eq!(Some(block_32_2), heap.buddy(1, block_32_3));
+
+            let block_256_0 = mem;
+            assert_eq!(None, heap.buddy(4, block_256_0));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_alloc_and_dealloc() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            alloc_and_dealloc(&mut heap, mem);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_for_value() {
+        #[derive(Debug, PartialEq)]
+        struct Packet {
+            id: u32,
+            payload: [u8; 40],
+        }
+
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let packet = heap.allocate_for_value::<Packet>().unwrap();
+            assert_eq!(
+                0,
+                packet.as_ptr() as usize % core::mem::align_of::<Packet>()
+            );
+            assert_eq!(64, heap.used_bytes());
+            packet.as_ptr().write(Packet {
+                id: 7,
+                payload: [0xa5; 40],
+            });
+            assert_eq!(
+                Packet {
+                    id: 7,
+                    payload: [0xa5; 40]
+                },
+                packet.as_ptr().read()
+            );
+
+            heap.deallocate_for_value(packet);
+            assert_eq!(0, heap.used_bytes());
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_with_timeout() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // The heap is full until the retry function frees the block it
+            // was holding on to, which it only does the first time.
+            let half = Layout::from_size_align(128, 1).unwrap();
+            let a = heap.allocate(half).unwrap();
+            let mut held = Some(heap.allocate(half).unwrap());
+            let mut calls = 0;
+            let mut retry = |heap: &mut Heap<5>| {
+                calls += 1;
+                match held.take() {
+                    Some(block) => {
+                        heap.deallocate(block, half);
+                        true
+                    }
+                    None => false,
+                }
+            };
+            let b = heap.allocate_with_timeout(half, &mut retry).unwrap();
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_with_timeout(half, &mut retry)
+            );
+            assert_eq!(2, calls);
+            #[cfg(feature = "stats")]
+            assert_eq!(2, heap.stats().failures);
+
+            // An invalid layout never waits.
+            let mut never = |_: &mut Heap<5>| -> bool { unreachable!() };
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_with_timeout(Layout::from_size_align(512, 1).unwrap(), &mut never)
+            );
+
+            heap.deallocate(a, half);
+            heap.deallocate(b, half);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_lower_address() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // At each order, free the upper buddy of the top half of the
+            // heap first, then the lower one, and check the merged block
+            // starts at the lower address.  (Raw pointers compare as
+            // unsigned addresses, so `min` is right wherever the heap is.)
+            for order in 0..3 {
+                let size = 16 << order;
+                let block = Layout::from_size_align(size, 1).unwrap();
+                let lower = heap.allocate_at(mem.add(128), block).unwrap();
+                let upper = heap.allocate_at(mem.add(128 + size), block).unwrap();
+
+                // Hold on to the merged block's own buddy, so the merge
+                // stops there.
+                let parent = Layout::from_size_align(2 * size, 1).unwrap();
+                let stopper = heap.allocate_at(mem.add(128 ^ (2 * size)), parent).unwrap();
+
+                heap.deallocate(upper, block);
+                assert!(heap.is_block_free(order, upper));
+                heap.deallocate(lower, block);
+                assert!(!heap.is_block_free(order, upper));
+                assert_eq!(Some(lower), heap.free_list_peek(order + 1));
+                assert!(heap.is_block_free(order + 1, lower));
+
+                heap.deallocate(stopper, parent);
+                assert!(heap.is_block_free(4, mem));
+            }
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_deallocate_coalesces_fully() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Fill the heap with small blocks and free them in an order
+            // that never frees two buddies back to back.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: std::vec::Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+            for &i in &[0, 2, 4, 6, 8, 10, 12, 14, 1, 5, 9, 13, 3, 11, 7, 15] {
+                heap.deallocate(blocks[i], small);
+            }
+
+            // Every merge happened as it became possible, so the whole heap
+            // is one block again.
+            assert_eq!([0, 0, 0, 0, 1], heap.measure_free_list_depth());
+            let whole = Layout::from_size_align(heap_size, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(whole));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    /// Put a fresh 256-byte heap at `mem` through its paces, leaving one
+    /// allocation of the whole heap behind.
+    unsafe fn alloc_and_dealloc(heap: &mut Heap<5>, mem: *mut u8) {
+        let heap_size = 256;
+        let block_16_0 = heap
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert_eq!(mem, block_16_0);
+
+        let bigger_than_heap = heap.allocate(Layout::from_size_align(heap_size, 4096).unwrap());
+        assert_eq!(
+            Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+            bigger_than_heap
+        );
+
+        let bigger_than_free =
+            heap.allocate(Layout::from_size_align(heap_size, heap_size).unwrap());
+        assert_eq!(Err(AllocationError::HeapExhausted), bigger_than_free);
+
+        let block_16_1 = heap
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(16), block_16_1);
+
+        let block_16_2 = heap
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(32), block_16_2);
+
+        let block_32_2 = heap
+            .allocate(Layout::from_size_align(32, 32).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(64), block_32_2);
+
+        let block_16_3 = heap
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(48), block_16_3);
+
+        let block_128_1 = heap
+            .allocate(Layout::from_size_align(128, 128).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(128), block_128_1);
+
+        let too_fragmented = heap.allocate(Layout::from_size_align(64, 64).unwrap());
+        assert_eq!(Err(AllocationError::HeapExhausted), too_fragmented);
+
+        heap.deallocate(block_32_2, Layout::from_size_align(32, 32).unwrap());
+        heap.deallocate(block_16_0, Layout::from_size_align(8, 8).unwrap());
+        heap.deallocate(block_16_3, Layout::from_size_align(8, 8).unwrap());
+        heap.deallocate(block_16_1, Layout::from_size_align(8, 8).unwrap());
+        heap.deallocate(block_16_2, Layout::from_size_align(8, 8).unwrap());
+
+        let block_128_0 = heap
+            .allocate(Layout::from_size_align(128, 128).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(0), block_128_0);
+
+        heap.deallocate(block_128_1, Layout::from_size_align(128, 128).unwrap());
+        heap.deallocate(block_128_0, Layout::from_size_align(128, 128).unwrap());
+
+        // And allocate the whole heap, just to make sure everything
+        // got cleaned up correctly.
+        let block_256_0 = heap
+            .allocate(Layout::from_size_align(256, 256).unwrap())
+            .unwrap();
+        assert_eq!(mem.offset(0), block_256_0);
+    }
+
+    #[test]
+    fn test_suballocate_heap() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut parent: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let page = parent
+                .allocate(Layout::from_size_align(256, 1).unwrap())
+                .unwrap();
+
+            // The child takes a whole 256-byte block of the parent, and
+            // splits it into blocks as small as 16 bytes.
+            let mut child: Heap<5> = parent
+                .suballocate_heap(Layout::from_size_align(200, 8).unwrap())
+                .unwrap();
+            let base = mem.add(256);
+            assert_eq!(512, parent.used_bytes());
+            assert!(parent.accounting_check());
+            parent.assert_alignment_invariants();
+            alloc_and_dealloc(&mut child, base);
+            assert_eq!(256, child.used_bytes());
+
+            // It can't go back while it's in use.
+            let mut child = child.release_into(&mut parent).unwrap_err();
+            child.deallocate(base, Layout::from_size_align(256, 256).unwrap());
+            child.release_into(&mut parent).unwrap();
+            assert_eq!(256, parent.used_bytes());
+            assert!(parent.accounting_check());
+            parent.assert_alignment_invariants();
+
+            // Too many orders for the block leaves the parent alone.
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                parent
+                    .suballocate_heap::<7>(Layout::from_size_align(256, 1).unwrap())
+                    .map(|_| ())
+            );
+            assert_eq!(256, parent.used_bytes());
+
+            parent.deallocate(page, Layout::from_size_align(256, 1).unwrap());
+            assert!(parent.is_block_free(4, mem));
+            drop(parent);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_deallocate_order_equivalent_layout() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Both layouts need a 32-byte block, so either frees it.
+            let block = heap
+                .allocate(Layout::from_size_align(24, 8).unwrap())
+                .unwrap();
+            heap.deallocate(block, Layout::from_size_align(17, 1).unwrap());
+
+            let whole = Layout::from_size_align(heap_size, heap_size).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(whole));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_order_and_waste() {
+        let heap: Heap<5> = unsafe { Heap::new_unchecked(0x1000 as *mut u8, 256) };
+        let waste =
+            |size, align| heap.order_and_waste(Layout::from_size_align(size, align).unwrap());
+
+        assert_eq!(Ok((0, 0)), waste(16, 1));
+        assert_eq!(Ok((0, 15)), waste(1, 1));
+        assert_eq!(Ok((2, 0)), waste(64, 64));
+        assert_eq!(Ok((3, 28)), waste(100, 4));
+        assert_eq!(Ok((4, 0)), waste(256, 256));
+
+        // Over-alignment forces a bigger block, which is all waste.
+        assert_eq!(Ok((2, 60)), waste(4, 64));
+
+        assert_eq!(Err(AllocationSizeError::TooLarge), waste(512, 1));
+        assert_eq!(Err(AllocationSizeError::BadAlignment), waste(16, 8192));
+    }
+
+    #[test]
+    fn test_block_layout() {
+        let heap: Heap<5> = unsafe { Heap::new_unchecked(0x1000 as *mut u8, 256) };
+        assert_eq!(
+            Layout::from_size_align(16, 16).unwrap(),
+            heap.block_layout(0)
+        );
+        assert_eq!(
+            Layout::from_size_align(256, 256).unwrap(),
+            heap.block_layout(4)
+        );
+        for order in 0..5 {
+            assert_eq!(
+                Ok((order, 0)),
+                heap.order_and_waste(heap.block_layout(order))
+            );
+        }
+
+        // Past the heap's base alignment, the alignment stops growing.
+        let big: Heap<16> = unsafe { Heap::new_unchecked(0x10_0000 as *mut u8, 1 << 20) };
+        assert_eq!(
+            Layout::from_size_align(1 << 20, 4096).unwrap(),
+            big.block_layout(15)
+        );
+        assert_eq!(Ok((15, 0)), big.order_and_waste(big.block_layout(15)));
+
+        // A block can be freed with its block layout.
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let block = heap
+                .allocate(Layout::from_size_align(40, 4).unwrap())
+                .unwrap();
+            heap.deallocate(block, heap.block_layout(2));
+            assert!(heap.is_block_free(4, mem));
+            assert!(heap.accounting_check());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "past the top order"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_block_layout_past_top_order() {
+        let heap: Heap<5> = unsafe { Heap::new_unchecked(0x1000 as *mut u8, 256) };
+        heap.block_layout(5);
+    }
+
+    #[test]
+    fn test_free_list_peek() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            assert_eq!(Some(mem), heap.free_list_peek(4));
+            assert_eq!(None, heap.free_list_peek(0));
+            assert_eq!(None, heap.free_list_peek(5));
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(small).unwrap();
+            for order in 0..4 {
+                // Peeking doesn't change anything, so it can be repeated, and
+                // the pop that follows gets the same block.
+                let peeked = heap.free_list_peek(order);
+                assert_eq!(Some(mem.add(16 << order)), peeked);
+                assert_eq!(peeked, heap.free_list_peek(order));
+                assert_eq!(peeked, free_list_pop(&mut heap.free_lists, order));
+                assert_eq!(None, heap.free_list_peek(order));
+                free_list_insert(&mut heap.free_lists, order, peeked.unwrap());
+            }
+            assert_eq!(None, heap.free_list_peek(4));
+
+            heap.deallocate(block, small);
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_available_orders() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            assert_eq!([true; 5], heap.available_orders());
+
+            let whole = Layout::from_size_align(256, 256).unwrap();
+            let block = heap.allocate(whole).unwrap();
+            assert_eq!([false; 5], heap.available_orders());
+            heap.deallocate(block, whole);
+
+            // Taking half the heap leaves everything up to 128 bytes.
+            let half = Layout::from_size_align(128, 128).unwrap();
+            let a = heap.allocate(half).unwrap();
+            assert_eq!([true, true, true, true, false], heap.available_orders());
+
+            // Taking a 64-byte block from the other half leaves a 64-byte one.
+            let quarter = Layout::from_size_align(64, 64).unwrap();
+            let b = heap.allocate(quarter).unwrap();
+            assert_eq!([true, true, true, false, false], heap.available_orders());
+
+            // Each answer matches what allocation actually does.
+            for (order, &available) in heap.available_orders().iter().enumerate() {
+                let layout = Layout::from_size_align(16 << order, 16).unwrap();
+                assert_eq!(available, heap.first_fit_order(layout.size(), 16).is_some());
+            }
+
+            heap.deallocate(b, quarter);
+            heap.deallocate(a, half);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    /// Check the whole-heap boundary for a heap of `heap_size` bytes.
+    fn check_whole_heap<const N: usize>(heap_size: usize) {
+        unsafe {
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<N> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // The base is only guaranteed to be aligned to `MIN_HEAP_ALIGN`,
+            // so that's as far as the alignment can go.
+            let align = min(heap_size, MIN_HEAP_ALIGN);
+            let whole = Layout::from_size_align(heap_size, align).unwrap();
+            assert_eq!(Ok((N - 1, 0)), heap.order_and_waste(whole));
+
+            let too_big = Layout::from_size_align(heap_size + 1, align).unwrap();
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate(too_big)
+            );
+
+            assert_eq!(Ok(mem), heap.allocate(whole));
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(whole));
+            heap.deallocate(mem, whole);
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_whole_heap_allocation() {
+        check_whole_heap::<1>(16);
+        check_whole_heap::<5>(256);
+        check_whole_heap::<9>(4096);
+        check_whole_heap::<13>(65536);
+        check_whole_heap::<17>(1 << 20);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            AllocationError::HeapExhausted,
+            AllocationError::InvalidSize(AllocationSizeError::BadAlignment),
+            AllocationError::InvalidSize(AllocationSizeError::TooLarge),
+        ];
+        for (error, expected) in errors.iter().zip(1..) {
+            let code: usize = (*error).into();
+            assert_eq!(expected, code);
+            assert_eq!(Ok(*error), AllocationError::try_from(code));
+        }
+
+        assert_eq!(Err(0), AllocationError::try_from(0));
+        assert_eq!(Err(4), AllocationError::try_from(4));
+    }
+
+    #[test]
+    fn test_first_fit_order() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Everything has to come out of the whole heap at first.
+            assert_eq!(Some(4), heap.first_fit_order(16, 16));
+            assert_eq!(Some(4), heap.first_fit_order(256, 256));
+            assert_eq!(None, heap.first_fit_order(512, 512));
+            assert_eq!(None, heap.first_fit_order(16, 3));
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block_16_0 = heap.allocate(small).unwrap();
+
+            // Now there's a free block of every order below the top.
+            assert_eq!(Some(0), heap.first_fit_order(16, 16));
+            assert_eq!(Some(2), heap.first_fit_order(64, 64));
+            assert_eq!(Some(3), heap.first_fit_order(100, 8));
+            assert_eq!(None, heap.first_fit_order(256, 256));
+
+            // Probing doesn't change anything, and the real allocation takes
+            // the block it predicted.
+            assert_eq!(Some(0), heap.first_fit_order(8, 8));
+            assert!(heap.is_block_free(0, mem.offset(16)));
+            let block_16_1 = heap.allocate(small).unwrap();
+            assert_eq!(mem.offset(16), block_16_1);
+
+            // With order 0 empty, the next small request has to split the
+            // order 1 block.
+            assert_eq!(Some(1), heap.first_fit_order(16, 16));
+            let block_16_2 = heap.allocate(small).unwrap();
+            assert_eq!(mem.offset(32), block_16_2);
+            assert!(heap.is_block_free(0, mem.offset(48)));
+
+            heap.deallocate(block_16_0, small);
+            heap.deallocate(block_16_1, small);
+            heap.deallocate(block_16_2, small);
+            assert_eq!(Some(4), heap.first_fit_order(16, 16));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    /// A block our test reclaim function can free, or 0.
+    #[cfg(feature = "reclaim")]
+    static CACHED: AtomicUsize = AtomicUsize::new(0);
+    #[cfg(feature = "reclaim")]
+    static RECLAIM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[cfg(feature = "reclaim")]
+    fn reclaim_cached(heap: &mut Heap<5>, _layout: Layout) -> bool {
+        RECLAIM_CALLS.fetch_add(1, Ordering::Relaxed);
+        match CACHED.swap(0, Ordering::Relaxed) {
+            0 => false,
+            block => {
+                unsafe {
+                    heap.deallocate(block as *mut u8, Layout::from_size_align(128, 1).unwrap())
+                };
+                true
+            }
+        }
+    }
+
+    #[cfg(feature = "reclaim")]
+    #[test]
+    fn test_reclaim() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            heap.set_reclaim(reclaim_cached);
+
+            // Fill the heap, with one half of it held in the cache.
+            let half = Layout::from_size_align(128, 1).unwrap();
+            let block = heap.allocate(half).unwrap();
+            CACHED.store(heap.allocate(half).unwrap() as usize, Ordering::Relaxed);
+
+            // The retry succeeds once the cache gives its block back.
+            assert_eq!(mem.offset(128), heap.allocate(half).unwrap());
+            assert_eq!(1, RECLAIM_CALLS.load(Ordering::Relaxed));
+            assert_eq!(0, heap.failures);
+
+            // With nothing left to reclaim, the allocation fails without a
+            // retry.
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(half));
+            assert_eq!(2, RECLAIM_CALLS.load(Ordering::Relaxed));
+            assert_eq!(1, heap.failures);
+
+            // Invalid sizes aren't the heap's fault, so don't reclaim.
+            assert!(heap
+                .allocate(Layout::from_size_align(512, 1).unwrap())
+                .is_err());
+            assert_eq!(2, RECLAIM_CALLS.load(Ordering::Relaxed));
+
+            heap.deallocate(block, half);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[cfg(feature = "reclaim")]
+    fn reclaim_by_capping(heap: &mut Heap<5>, _layout: Layout) -> bool {
+        heap.set_max_allocation_order(1);
+        false
+    }
+
+    #[cfg(feature = "reclaim")]
+    #[test]
+    fn test_reclaim_during_privileged_allocation() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = HeapBuilder::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .max_allocation_order(2)
+                .build()
+                .unwrap();
+            heap.set_reclaim(reclaim_by_capping);
+
+            // A limit set by the reclaim function while a privileged
+            // allocation is under way outlives it.
+            let half = Layout::from_size_align(128, 1).unwrap();
+            let block = heap.allocate_privileged(half).unwrap();
+            let other = heap.allocate_privileged(half).unwrap();
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_privileged(half)
+            );
+            assert_eq!(1, heap.max_allocation_order());
+
+            heap.deallocate(other, half);
+            heap.deallocate(block, half);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_at_offset() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let small = Layout::from_size_align(16, 1).unwrap();
+
+            // The first, second and last minimum-sized blocks.
+            for offset in [0, 16, heap_size - 16] {
+                assert_eq!(Ok(mem.add(offset)), heap.allocate_at_offset(offset, small));
+                assert!(heap.accounting_check());
+                heap.assert_alignment_invariants();
+            }
+            assert_eq!(48, heap.used_bytes());
+
+            // Everything around them was freed when the heap was split.
+            assert!(heap.is_block_free(1, mem.add(32)));
+            assert!(heap.is_block_free(2, mem.add(64)));
+            assert!(heap.is_block_free(2, mem.add(128)));
+            assert!(heap.is_block_free(1, mem.add(192)));
+            assert!(heap.is_block_free(0, mem.add(224)));
+
+            // Ordinary allocations go around them.
+            let other = heap.allocate(small).unwrap();
+            assert!(![0, 16, heap_size - 16].contains(&(other as usize - mem as usize)));
+            heap.deallocate(other, small);
+
+            // Taken, misaligned, and out of range.
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_at_offset(16, small)
+            );
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_at_offset(0, Layout::from_size_align(64, 1).unwrap())
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(
+                    AllocationSizeError::BadAlignment
+                )),
+                heap.allocate_at_offset(32, Layout::from_size_align(64, 1).unwrap())
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_at_offset(heap_size, small)
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_at(mem.wrapping_sub(16), small)
+            );
+
+            // Freeing them merges everything back together.
+            for offset in [0, 16, heap_size - 16] {
+                heap.deallocate(mem.add(offset), small);
+            }
+            assert!(heap.is_block_free(4, mem));
+
+            // The whole heap can be taken at offset 0.
+            let all = Layout::from_size_align(heap_size, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate_at_offset(0, all));
+            heap.deallocate(mem, all);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_stripe() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Three blocks come from a block of four, whose last block is
+            // freed straight away.
+            let stripe = heap.allocate_stripe(3, 0).unwrap();
+            assert_eq!(mem, stripe as *mut u8);
+            assert_eq!(48, stripe.len());
+            assert_eq!(48, heap.used_bytes());
+            assert!(heap.is_block_free(0, mem.add(48)));
+            assert!(heap.is_block_free(2, mem.add(64)));
+            assert!(heap.is_block_free(3, mem.add(128)));
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            (stripe as *mut u8).write_bytes(0xa5, stripe.len());
+
+            // Three blocks of 32 bytes come from the other half of the
+            // heap, leaving 32 bytes past them.
+            let wide = heap.allocate_stripe(3, 1).unwrap();
+            assert_eq!(mem.add(128), wide as *mut u8);
+            assert_eq!(96, wide.len());
+            assert!(heap.is_block_free(1, mem.add(224)));
+            assert!(heap.accounting_check());
+
+            // There's no room for another 64 contiguous bytes.
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_stripe(3, 1)
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_stripe(17, 0)
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_stripe(usize::MAX, 0)
+            );
+            assert_eq!(0, heap.allocate_stripe(0, 3).unwrap().len());
+
+            // The block a stripe is cut from is capped like any other.
+            heap.set_max_allocation_order(1);
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_stripe(3, 0)
+            );
+            let pair = heap.allocate_stripe(2, 0).unwrap();
+            assert_eq!(32, pair.len());
+            heap.deallocate_stripe(pair, 0);
+            heap.set_max_allocation_order(4);
+
+            // The blocks of a stripe can be freed singly too.
+            heap.deallocate(mem.add(16), heap.block_layout(0));
+            assert_eq!(144 - 16, heap.used_bytes());
+            heap.deallocate_stripe(ptr::slice_from_raw_parts_mut(mem, 16), 0);
+            heap.deallocate(mem.add(32), heap.block_layout(0));
+            heap.deallocate_stripe(wide, 1);
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(4, mem));
+            assert!(heap.accounting_check());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_exact() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Only the whole heap is free, so a small exact allocation fails
+            // rather than splitting it.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_exact(small)
+            );
+            assert!(heap.is_block_free(4, mem));
+
+            // Splitting it with a normal allocation leaves a buddy of every
+            // order, which `allocate_exact` can then use.
+            let block_16_0 = heap.allocate(small).unwrap();
+            assert_eq!(Ok(mem.offset(16)), heap.allocate_exact(small));
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.allocate_exact(small)
+            );
+
+            let block_64 = Layout::from_size_align(64, 64).unwrap();
+            assert_eq!(Ok(mem.offset(64)), heap.allocate_exact(block_64));
+
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_exact(Layout::from_size_align(512, 512).unwrap())
+            );
+
+            heap.deallocate(mem.offset(64), block_64);
+            heap.deallocate(mem.offset(16), small);
+            heap.deallocate(block_16_0, small);
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_is_order_fully_free() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            assert!((0..5).all(|order| heap.is_order_fully_free(order)));
+            assert!(!heap.is_order_fully_free(5));
+
+            // Any allocation, however small, uses part of a block of every
+            // order.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(small).unwrap();
+            assert!((0..5).all(|order| !heap.is_order_fully_free(order)));
+
+            // Freeing it merges everything back.
+            heap.deallocate(block, small);
+            assert!(heap.is_order_fully_free(0));
+            assert!(heap.is_order_fully_free(4));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_compare_with_reference_state() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // A fresh heap is one free block of the top order at slot 0.
+            let mut reference = [[false; 5]; 16];
+            reference[0][4] = true;
+            assert!(heap.compare_with_reference_state(&reference));
+            assert!(!heap.compare_with_reference_state(&reference[..8]));
+
+            // Taking the first slot leaves the upper half free at each order.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(small).unwrap();
+            assert!(!heap.compare_with_reference_state(&reference));
+            reference[0][4] = false;
+            for order in 0..4 {
+                reference[1 << order][order] = true;
+            }
+            assert!(heap.compare_with_reference_state(&reference));
+
+            // A block missing from the model, or one the model expects but
+            // which isn't there, is a mismatch.
+            reference[8][3] = false;
+            assert!(!heap.compare_with_reference_state(&reference));
+            reference[8][3] = true;
+            reference[12][2] = true;
+            assert!(!heap.compare_with_reference_state(&reference));
+
+            heap.deallocate(block, small);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_ensure_min_free_order() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Only the whole heap is free, so there's nothing at order 2
+            // until it's split down.
+            let block_64 = Layout::from_size_align(64, 64).unwrap();
+            assert!(heap.free_lists[2].is_null());
+            assert_eq!(Ok(()), heap.ensure_min_free_order(2));
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.accounting_check());
+            assert_eq!(Ok(mem), heap.allocate_exact(block_64));
+            assert_eq!(Ok(mem.offset(64)), heap.allocate_exact(block_64));
+
+            // The next call splits the upper half, and calling it again with
+            // blocks already there changes nothing.
+            assert!(heap.is_block_free(3, mem.offset(128)));
+            assert_eq!(Ok(()), heap.ensure_min_free_order(2));
+            assert_eq!(Ok(()), heap.ensure_min_free_order(2));
+            assert!(heap.is_block_free(2, mem.offset(192)));
+            let block_128 = Layout::from_size_align(128, 128).unwrap();
+            assert_eq!(Ok(mem.offset(128)), heap.allocate_exact(block_64));
+            assert_eq!(Ok(mem.offset(192)), heap.allocate_exact(block_64));
+
+            // Now there's nothing left to split.
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.ensure_min_free_order(0)
+            );
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.ensure_min_free_order(5)
+            );
+
+            for offset in [0, 64, 128, 192] {
+                heap.deallocate(mem.offset(offset), block_64);
+            }
+            assert!(heap.is_block_free(4, mem));
+            assert_eq!(Ok(mem), heap.allocate(block_128));
+            heap.deallocate(mem, block_128);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_min_order() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // An 8-byte request takes a whole order 2 block.
+            let tiny = Layout::from_size_align(8, 8).unwrap();
+            let first = heap.allocate_min_order(tiny, 2).unwrap();
+            assert_eq!(heap.order_size(2), heap.used_bytes());
+            let second = heap.allocate_min_order(tiny, 2).unwrap();
+            assert_eq!(first.add(heap.order_size(2)), second);
+
+            // Bigger requests aren't made any smaller.
+            let big = Layout::from_size_align(128, 16).unwrap();
+            let third = heap.allocate_min_order(big, 2).unwrap();
+            assert_eq!(256, heap.used_bytes());
+
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_min_order(tiny, 5)
+            );
+            heap.set_max_allocation_order(1);
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate_min_order(tiny, 2)
+            );
+            heap.set_max_allocation_order(4);
+
+            heap.deallocate_min_order(third, big, 2);
+            heap.deallocate_min_order(second, tiny, 2);
+            heap.deallocate_min_order(first, tiny, 2);
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_is_block_free() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // A fresh heap is a single free block of the top order.
+            assert!(heap.is_block_free(4, mem));
+            assert!(!heap.is_block_free(0, mem));
+            assert!(!heap.is_block_free(5, mem));
+
+            let block_layout = Layout::from_size_align(16, 16).unwrap();
+            let block_16_0 = heap.allocate(block_layout).unwrap();
+            assert!(!heap.is_block_free(4, mem));
+            assert!(!heap.is_block_free(0, block_16_0));
+            assert!(heap.is_block_free(0, mem.offset(16)));
+            assert!(heap.is_block_free(1, mem.offset(32)));
+            assert!(heap.is_block_free(3, mem.offset(128)));
+
+            // Looking doesn't take the block off the free list.
+            let block_16_1 = heap.allocate(block_layout).unwrap();
+            assert_eq!(mem.offset(16), block_16_1);
+            assert!(!heap.is_block_free(0, block_16_1));
+
+            heap.deallocate(block_16_1, block_layout);
+            assert!(heap.is_block_free(0, block_16_1));
+
+            let block_16_1 = heap.allocate(block_layout).unwrap();
+            assert!(!heap.is_block_free(0, block_16_1));
+
+            heap.deallocate(block_16_0, block_layout);
+            heap.deallocate(block_16_1, block_layout);
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_block_index() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            assert_eq!(Some(0), heap.block_index(mem));
+            assert_eq!(Some(0), heap.block_index(mem.add(15)));
+            assert_eq!(Some(1), heap.block_index(mem.add(16)));
+            assert_eq!(Some(255), heap.block_index(mem.add(heap_size - 16)));
+            assert_eq!(Some(255), heap.block_index(mem.add(heap_size - 1)));
+
+            assert_eq!(None, heap.block_index(mem.add(heap_size)));
+            assert_eq!(None, heap.block_index(mem.wrapping_sub(1)));
+            assert_eq!(None, heap.block_index(ptr::null()));
+            assert_eq!(None, heap.block_index(usize::MAX as *const u8));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_accounting_check() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            assert_eq!(heap_size, heap.free_bytes());
+
+            // A simple xorshift generator, so the test is reproducible.
+            let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+            let mut random = move || {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed
+            };
+
+            let mut live = std::vec::Vec::new();
+            for _ in 0..2000 {
+                if live.is_empty() || random() % 3 != 0 {
+                    let size = 1 + (random() % 300) as usize;
+                    let align = 1 << (random() % 6);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    if let Ok(p) = heap.allocate(layout) {
+                        live.push((p, layout));
+                    }
+                } else {
+                    let (p, layout) = live.swap_remove((random() as usize) % live.len());
+                    heap.deallocate(p, layout);
+                }
+                assert!(heap.accounting_check());
+                heap.assert_alignment_invariants();
+            }
+
+            for (p, layout) in live {
+                heap.deallocate(p, layout);
+                assert!(heap.accounting_check());
+                heap.assert_alignment_invariants();
+            }
+            assert_eq!(0, heap.used_bytes());
+
+            // Corrupting the free lists behind the heap's back is caught.
+            heap.free_lists[0] = mem as *mut FreeBlock;
+            (*heap.free_lists[0]).next = ptr::null_mut();
+            assert!(!heap.accounting_check());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "aligned for order 1"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_misaligned_free_block_panics() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            heap.assert_alignment_invariants();
+
+            // A 32-byte block can't start 16 bytes in.
+            let block = heap
+                .allocate(Layout::from_size_align(64, 64).unwrap())
+                .unwrap();
+            free_list_insert(&mut heap.free_lists, 1, block.add(16));
+            heap.assert_alignment_invariants();
+        }
+    }
+
+    #[test]
+    fn test_deallocate_null() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(small).unwrap();
+            let before = heap.stats();
+
+            heap.deallocate(ptr::null_mut(), small);
+            heap.deallocate(ptr::null_mut(), Layout::from_size_align(0, 1).unwrap());
+            heap.deallocate(
+                ptr::null_mut(),
+                Layout::from_size_align(1 << 20, 1).unwrap(),
+            );
+            assert_eq!(before, heap.stats());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            heap.deallocate(block, small);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_new_self_hosted() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+
+            // The metadata doesn't fit in a tiny heap.
+            assert_eq!(
+                Err(HeapError::BadHeapSize),
+                Heap::<2>::new_self_hosted(NonNull::new(mem).unwrap(), 64).map(|_| ())
+            );
+
+            let heap: &mut Heap<9> =
+                Heap::new_self_hosted(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // The struct lives inside the heap, and takes up a whole block.
+            let meta_start = heap as *mut Heap<9> as usize;
+            let meta_end = meta_start + size_of::<Heap<9>>();
+            assert!(meta_start >= mem as usize && meta_end <= mem as usize + heap_size);
+            assert_eq!(size_of::<Heap<9>>().next_power_of_two(), heap.used_bytes());
+
+            // Nothing we allocate overlaps it.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks = std::vec::Vec::new();
+            while let Ok(block) = heap.allocate(small) {
+                let block = block as usize;
+                assert!(block + 16 <= meta_start || block >= meta_end);
+                blocks.push(block);
+            }
+            assert_eq!(heap_size, heap.used_bytes());
+            for block in blocks {
+                heap.deallocate(block as *mut u8, small);
+            }
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            // The heap is never dropped on its own, since it owns itself.
+            ptr::drop_in_place(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_new_from_bytes() {
+        #[repr(align(4096))]
+        struct Memory([u8; 8192]);
+
+        let memory = std::boxed::Box::leak(std::boxed::Box::new(Memory([0; 8192])));
+        let (aligned, rest) = memory.0.split_at_mut(4096);
+        let aligned = aligned.first_chunk_mut::<4096>().unwrap();
+        let base = aligned.as_mut_ptr();
+
+        let mut heap = Heap::<9>::new_from_bytes(aligned).unwrap();
+        let layout = Layout::from_size_align(16, 16).unwrap();
+        assert_eq!(Ok(base), heap.allocate(layout));
+
+        // Misaligned memory is still caught at runtime.
+        let misaligned = rest[16..].first_chunk_mut::<2048>().unwrap();
+        assert_eq!(
+            Err(HeapError::BadBaseAlignment),
+            Heap::<8>::new_from_bytes(misaligned).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_new_from_device_registers() {
+        // These only describe the heaps, and never touch the memory, so
+        // made-up addresses are fine.
+        unsafe {
+            // 128 KiB of STM32 SRAM, and the 512 KiB at the top of a
+            // 32-bit address space.
+            let heap = Heap::<12>::new_from_device_registers(0x2000_0000, 0x2_0000).unwrap();
+            assert_eq!(0x2000_0000, heap.heap_base as usize);
+            assert_eq!(0x2_0000, heap.heap_size);
+            let heap = Heap::<12>::new_from_device_registers(0xfff8_0000, 0x8_0000).unwrap();
+            assert_eq!(0xfff8_0000, heap.heap_base as usize);
+            assert_eq!(0x8_0000, heap.heap_size);
+
+            let new = |base, size| Heap::<12>::new_from_device_registers(base, size).map(|_| ());
+            assert_eq!(Err(HeapError::BadBaseAlignment), new(0, 0x2_0000));
+            assert_eq!(Err(HeapError::BadBaseAlignment), new(0x2000_0010, 0x2_0000));
+            assert_eq!(Err(HeapError::BadSizeAlignment), new(0x2000_0000, 0x1_8000));
+            assert_eq!(Err(HeapError::MinBlockTooSmall), new(0x2000_0000, 0x1000));
+        }
+
+        // A region running past the top of the address space only exists
+        // where `usize` is as narrow as the registers.
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(
+            Err(HeapError::BadHeapSize),
+            unsafe { Heap::<12>::new_from_device_registers(0xfff8_0000, 0x10_0000) }.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let base = NonNull::new(mem).unwrap();
+            let mut heap: Heap<5> = Heap::new(base, heap_size).unwrap();
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let medium = Layout::from_size_align(64, 16).unwrap();
+            let a = heap.allocate(small).unwrap();
+            let b = heap.allocate(medium).unwrap();
+            let c = heap.allocate(small).unwrap();
+            heap.deallocate(a, small);
+
+            // Save the lists, and bring the heap back from them.
+            let saved = heap.free_list_heads();
+            let used = heap.used_bytes();
+            drop(heap);
+            let mut heap = Heap::<5>::restore(base, heap_size, saved).unwrap();
+            assert_eq!(used, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            heap.deallocate(b, medium);
+            heap.deallocate(c, small);
+            assert!(heap.is_block_free(4, mem));
+
+            // A fresh heap's lists can be restored too.
+            let saved = heap.free_list_heads();
+            drop(heap);
+            assert_eq!(
+                0,
+                Heap::<5>::restore(base, heap_size, saved)
+                    .unwrap()
+                    .used_bytes()
+            );
+
+            // Heads outside the heap or misaligned for their order are
+            // caught.
+            let corrupt = |order: usize, block: *mut u8| {
+                let mut lists = [ptr::null_mut(); 5];
+                lists[order] = block as *mut FreeBlock;
+                Heap::<5>::restore(base, heap_size, lists).map(|_| ())
+            };
+            let error = |order, block: *mut u8| {
+                Err(HeapError::CorruptFreeList {
+                    order,
+                    block: block as usize,
+                })
+            };
+            assert_eq!(error(0, mem.add(256)), corrupt(0, mem.add(256)));
+            assert_eq!(
+                error(0, mem.wrapping_sub(16)),
+                corrupt(0, mem.wrapping_sub(16))
+            );
+            assert_eq!(error(2, mem.add(32)), corrupt(2, mem.add(32)));
+            assert_eq!(error(0, mem.add(8)), corrupt(0, mem.add(8)));
+
+            // So is a cycle.
+            let block = mem.add(16) as *mut FreeBlock;
+            *block = FreeBlock::new(block);
+            assert_eq!(error(0, block as *mut u8), corrupt(0, block as *mut u8));
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_max_allocation_order() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = HeapBuilder::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .max_allocation_order(2)
+                .build()
+                .unwrap();
+            assert_eq!(2, heap.max_allocation_order());
+
+            // Even with the whole heap free, nothing over a quarter of it
+            // can be had, except through the privileged entry point.
+            let quarter = Layout::from_size_align(64, 1).unwrap();
+            let half = Layout::from_size_align(128, 1).unwrap();
+            let too_large = Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge));
+            assert_eq!(too_large, heap.allocate(half));
+            assert_eq!(too_large, heap.allocate_exact(half));
+            assert_eq!(None, heap.first_fit_order(128, 1));
+            let a = heap.allocate(quarter).unwrap();
+            assert_eq!(too_large, heap.reallocate(a, quarter, half));
+            let b = heap.allocate_privileged(half).unwrap();
+            assert_eq!(2, heap.max_allocation_order());
+
+            // Lowering the limit further doesn't stop big blocks being
+            // freed, and raising it past the top lifts it.
+            heap.set_max_allocation_order(0);
+            assert_eq!(too_large, heap.allocate(quarter));
+            heap.deallocate(b, half);
+            heap.deallocate(a, quarter);
+            heap.set_max_allocation_order(100);
+            assert_eq!(4, heap.max_allocation_order());
+            let whole = Layout::from_size_align(heap_size, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(whole));
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_shrink_heap_to_fit() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Too big.
+            assert_eq!(Err(HeapError::BadHeapSize), heap.shrink_heap_to_fit(512));
+
+            // Something allocated in the upper half.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let low = heap.allocate(small).unwrap();
+            let high = heap
+                .allocate(Layout::from_size_align(128, 1).unwrap())
+                .unwrap();
+            assert_eq!(mem.add(128), high);
+            assert_eq!(
+                Err(HeapError::BadSizeAlignment),
+                heap.shrink_heap_to_fit(100)
+            );
+            assert_eq!(256, heap.heap_size);
+            heap.deallocate(high, Layout::from_size_align(128, 1).unwrap());
+
+            // 100 rounds up to 128, leaving only the first half in use.
+            assert_eq!(Ok(()), heap.shrink_heap_to_fit(100));
+            assert_eq!(128, heap.heap_size);
+            assert_eq!(128 - 16, heap.free_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate(Layout::from_size_align(256, 1).unwrap())
+            );
+
+            // Every allocation stays below the new end.
+            let mut blocks = std::vec::Vec::new();
+            while let Ok(block) = heap.allocate(small) {
+                assert!(block < mem.add(128));
+                blocks.push(block);
+            }
+            assert_eq!(7, blocks.len());
+            for block in blocks {
+                heap.deallocate(block, small);
             }
+            heap.deallocate(low, small);
 
-            // We can't allocate a block with the specified size and
-            // alignment.
-            Err(e) => Err(AllocationError::InvalidSize(e)),
+            // Once everything is free again, the heap is a single block.
+            let half = Layout::from_size_align(128, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(half));
+            heap.deallocate(mem, half);
+
+            // A completely free heap can be shrunk, down to one minimum
+            // block.
+            assert_eq!(Ok(()), heap.shrink_heap_to_fit(0));
+            assert_eq!(16, heap.heap_size);
+            assert_eq!(Ok(mem), heap.allocate(small));
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(small));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
         }
     }
 
-    /// Deallocate a block allocated using `allocate`.
-    ///
-    /// # Safety
-    /// `ptr` and `layout` must match what was passed to / returned from `allocate`,
-    /// or our heap will be corrupted.
-    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
-        let initial_order = self
-            .allocation_order(layout.size(), layout.align())
-            .expect("Tried to dispose of invalid block");
-
-        // The fun part: When deallocating a block, we also want to check
-        // to see if its "buddy" is on the free list.  If the buddy block
-        // is also free, we merge them and continue walking up.
-        //
-        // `block` is the biggest merged block we have so far.
-        let mut block = ptr;
-        for order in initial_order..self.free_lists.len() {
-            // Would this block have a buddy?
-            if let Some(buddy) = self.buddy(order, block) {
-                // Is this block's buddy free?
-                if self.free_list_remove(order, buddy) {
-                    // Merge them!  The lower address of the two is the
-                    // newly-merged block.  Then we want to try again.
-                    block = min(block, buddy);
-                    continue;
+    #[test]
+    fn test_align_beyond_small_heap() {
+        unsafe {
+            // Only three orders, so the top block is 4 minimum-sized blocks.
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<3> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            assert_eq!(64, heap.min_block_size);
+
+            // An alignment up to the heap size takes a block that big, even
+            // for a tiny request.
+            assert_eq!(Ok(128), heap.allocation_size(1, 128));
+            assert_eq!(Ok(256), heap.allocation_size(1, 256));
+            assert_eq!(Ok(2), heap.allocation_order(0, 256));
+
+            // Past it, the alignment alone makes the request too large,
+            // however small it is, and even though the heap's base could
+            // satisfy the alignment.
+            for align in [512, 1024, MIN_HEAP_ALIGN] {
+                for size in [0, 1, 64, 256] {
+                    assert_eq!(
+                        Err(AllocationSizeError::TooLarge),
+                        heap.allocation_size(size, align)
+                    );
+                    let request = Layout::from_size_align(size, align).unwrap();
+                    assert_eq!(
+                        Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                        heap.allocate(request)
+                    );
                 }
             }
+            assert_eq!(heap_size, heap.free_bytes());
 
-            // If we reach here, we didn't find a buddy block of this size,
-            // so take what we've got and mark it as free.
-            self.free_list_insert(order, block);
-            return;
+            // The largest alignment still works, and gets the whole heap.
+            let whole = Layout::from_size_align(1, 256).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(whole));
+            assert_eq!(0, heap.free_bytes());
+            heap.deallocate(mem, whole);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
         }
     }
-}
-
-#[cfg(test)]
-mod test {
-    // Use std in tests.
-    extern crate std;
-    use super::*;
 
     #[test]
-    fn test_allocation_size_and_order() {
+    fn test_move_heap() {
         unsafe {
             let heap_size = 256;
-            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let layout = std::alloc::Layout::from_size_align(8192, 4096).unwrap();
             let mem = std::alloc::alloc(layout);
-            let heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let new_mem = mem.add(4096);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let small = Layout::from_size_align(16, 16).unwrap();
 
-            // Can't align beyond MIN_HEAP_ALIGN.
+            let block = heap.allocate(small).unwrap();
             assert_eq!(
-                Err(AllocationSizeError::BadAlignment),
-                heap.allocation_size(256, 8192)
+                Err(HeapError::HeapInUse),
+                heap.move_heap(NonNull::new(new_mem).unwrap(), heap_size)
+            );
+            heap.deallocate(block, small);
+            assert_eq!(
+                Err(HeapError::BadBaseAlignment),
+                heap.move_heap(NonNull::new(mem.add(256)).unwrap(), heap_size)
             );
+            assert_eq!(
+                Err(HeapError::BadHeapSize),
+                heap.move_heap(NonNull::new(new_mem).unwrap(), 512)
+            );
+            assert_eq!(mem, heap.heap_base);
 
-            // Can't align beyond heap_size.
+            // Allocations now come from the new range.
             assert_eq!(
-                Err(AllocationSizeError::TooLarge),
-                heap.allocation_size(256, 256 * 2)
+                Ok(()),
+                heap.move_heap(NonNull::new(new_mem).unwrap(), heap_size)
             );
+            let mut blocks = std::vec::Vec::new();
+            while let Ok(block) = heap.allocate(small) {
+                assert!(new_mem <= block && block < new_mem.add(heap_size));
+                blocks.push(block);
+            }
+            assert_eq!(16, blocks.len());
+            for block in blocks {
+                heap.deallocate(block, small);
+            }
 
-            // Simple allocations just round up to next block size.
-            assert_eq!(Ok(16), heap.allocation_size(0, 1));
-            assert_eq!(Ok(16), heap.allocation_size(1, 1));
-            assert_eq!(Ok(16), heap.allocation_size(16, 1));
-            assert_eq!(Ok(32), heap.allocation_size(17, 1));
-            assert_eq!(Ok(32), heap.allocation_size(32, 32));
-            assert_eq!(Ok(256), heap.allocation_size(256, 256));
+            // A shrunk heap's free block isn't at the top order, so it has
+            // a real `next` pointer which moves with it.
+            heap.shrink_heap_to_fit(128).unwrap();
+            assert_eq!(Ok(()), heap.move_heap(NonNull::new(mem).unwrap(), 128));
+            assert_eq!(Some(mem), heap.free_list_peek(3));
+            let half = Layout::from_size_align(128, 1).unwrap();
+            assert_eq!(Ok(mem), heap.allocate(half));
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(small));
 
-            // Aligned allocations use alignment as block size.
-            assert_eq!(Ok(64), heap.allocation_size(16, 64));
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let old_mem = std::alloc::alloc(layout);
+            let new_mem = std::alloc::alloc(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(old_mem).unwrap(), heap_size).unwrap();
+
+            // Fragment the heap, stamping each block with its own byte, and
+            // leave something allocated from an emergency reserve too.
+            let mut live = std::vec::Vec::new();
+            for i in 0..40u8 {
+                let layout = Layout::from_size_align(16 << (i % 4), 16).unwrap();
+                let block = heap.allocate(layout).unwrap();
+                block.write_bytes(i, layout.size());
+                live.push((block, layout, i));
+            }
+            for (block, layout, _) in live.iter().filter(|(_, _, i)| i % 3 == 0) {
+                heap.deallocate(*block, *layout);
+            }
+            live.retain(|(_, _, i)| i % 3 != 0);
+            #[cfg(feature = "emergency")]
+            let small = Layout::from_size_align(16, 16).unwrap();
+            #[cfg(feature = "emergency")]
+            let urgent = {
+                heap.reserve_emergency(64).unwrap();
+                let urgent = heap.allocate_emergency(small).unwrap();
+                urgent.write_bytes(0xAA, 16);
+                urgent
+            };
+            let used = heap.used_bytes();
 
-            // Block orders.
-            assert_eq!(Ok(0), heap.allocation_order(0, 1));
-            assert_eq!(Ok(0), heap.allocation_order(1, 1));
-            assert_eq!(Ok(0), heap.allocation_order(16, 16));
-            assert_eq!(Ok(1), heap.allocation_order(32, 32));
-            assert_eq!(Ok(2), heap.allocation_order(64, 64));
-            assert_eq!(Ok(3), heap.allocation_order(128, 128));
-            assert_eq!(Ok(4), heap.allocation_order(256, 256));
             assert_eq!(
-                Err(AllocationSizeError::TooLarge),
-                heap.allocation_order(512, 512)
+                Err(HeapError::BadBaseAlignment),
+                heap.migrate(NonNull::new(new_mem.add(16)).unwrap(), |_, _, _| {
+                    unreachable!()
+                })
+            );
+
+            // Only the live memory is copied, and the old memory isn't
+            // needed afterwards.
+            let mut copied = 0;
+            heap.migrate(NonNull::new(new_mem).unwrap(), |src, dst, len| {
+                ptr::copy_nonoverlapping(src, dst, len);
+                copied += len;
+            })
+            .unwrap();
+            assert_eq!(used, copied);
+            old_mem.write_bytes(0xEE, heap_size);
+
+            // Every allocation kept its contents at its new address.
+            let moved = |block: *mut u8| new_mem.add(block as usize - old_mem as usize);
+            for (block, layout, i) in &live {
+                let bytes = std::slice::from_raw_parts(moved(*block), layout.size());
+                assert!(bytes.iter().all(|b| b == i));
+            }
+            #[cfg(feature = "emergency")]
+            let urgent = moved(urgent);
+            #[cfg(feature = "emergency")]
+            assert!(std::slice::from_raw_parts(urgent, 16)
+                .iter()
+                .all(|&b| b == 0xAA));
+            assert_eq!(used, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            // The heap and its reserve carry on working in the new memory,
+            // and freeing everything leaves it whole again.
+            #[cfg(feature = "emergency")]
+            {
+                let extra = heap.allocate_emergency(small).unwrap();
+                assert!(new_mem <= extra && extra < new_mem.add(heap_size));
+                heap.deallocate_emergency(extra, small);
+                heap.deallocate_emergency(urgent, small);
+                heap.reserve_emergency(0).unwrap();
+            }
+            for (block, layout, _) in live {
+                heap.deallocate(moved(block), layout);
+            }
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(8, new_mem));
+
+            drop(heap);
+            std::alloc::dealloc(old_mem, layout);
+            std::alloc::dealloc(new_mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_reallocate() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Take the 16 bytes at the start, so a 64-aligned block can't
+            // come from the low end of the heap.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let first = heap.allocate(small).unwrap();
+
+            // Growing 16 to 24 bytes doesn't change the size's order, but
+            // the alignment still needs a 64-byte block.
+            let aligned_16 = Layout::from_size_align(16, 64).unwrap();
+            let aligned_24 = Layout::from_size_align(24, 64).unwrap();
+            let block = heap.allocate(aligned_16).unwrap();
+            *block = 0xa5;
+            let grown = heap.reallocate(block, aligned_16, aligned_24).unwrap();
+            assert_eq!(0, grown as usize % 64);
+            assert_eq!(block, grown);
+            assert_eq!(0xa5, *grown);
+
+            // Growing past the block moves it, keeping the contents.
+            let aligned_100 = Layout::from_size_align(100, 64).unwrap();
+            let moved = heap.reallocate(grown, aligned_24, aligned_100).unwrap();
+            assert_eq!(0, moved as usize % 64);
+            assert_eq!(mem.add(128), moved);
+            assert_eq!(0xa5, *moved);
+            assert!(heap.is_block_free(2, mem.add(64)));
+
+            // Shrinking splits in place, and frees the upper part.
+            let shrunk = heap.reallocate(moved, aligned_100, small).unwrap();
+            assert_eq!(moved, shrunk);
+            assert_eq!(0xa5, *shrunk);
+            assert_eq!(32, heap.used_bytes());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+
+            // A failed resize leaves the block alone.
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap.reallocate(shrunk, small, Layout::from_size_align(256, 1).unwrap())
             );
+            assert_eq!(32, heap.used_bytes());
+
+            // With its buddies above it free, the block grows in place, but
+            // one in use in the way forces a move.
+            let aligned_64 = Layout::from_size_align(64, 64).unwrap();
+            assert_eq!(Ok(shrunk), heap.reallocate(shrunk, small, aligned_64));
+            assert_eq!(80, heap.used_bytes());
+            let shrunk = heap.reallocate(shrunk, aligned_64, small).unwrap();
+            let blocker = heap.allocate_at(mem.add(144), small).unwrap();
+            let moved = heap.reallocate(shrunk, small, aligned_64).unwrap();
+            assert_ne!(shrunk, moved);
+            assert_eq!(0xa5, *moved);
+            let shrunk = heap.reallocate(moved, aligned_64, small).unwrap();
+            heap.deallocate(blocker, small);
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
 
+            heap.deallocate(shrunk, small);
+            heap.deallocate(first, small);
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
             std::alloc::dealloc(mem, layout);
         }
     }
 
     #[test]
-    fn test_buddy() {
+    fn test_allocate_zeroed() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc_zeroed(layout);
+            let mut heap: Heap<5> = HeapBuilder::new()
+                .base(NonNull::new(mem).unwrap())
+                .size(heap_size)
+                .zeroed()
+                .build()
+                .unwrap();
+            let header = size_of::<FreeBlock>();
+            let is_zero = |block: *mut u8, len: usize| {
+                std::slice::from_raw_parts(block, len)
+                    .iter()
+                    .all(|&b| b == 0)
+            };
+
+            // The first allocation splits the heap all the way down, and
+            // only the block it takes is no longer known to be zero.
+            let small = Layout::from_size_align(16, 1).unwrap();
+            let a = heap.allocate_zeroed(small).unwrap();
+            assert_eq!(mem, a);
+            assert_eq!(16, heap.zero_from);
+
+            // Its buddy has never been handed out, so only its header is
+            // cleared.  (The byte planted after it shows the rest was left
+            // alone; the header itself is a live free list link.)
+            *mem.add(16 + header) = 0xff;
+            let b = heap.allocate_zeroed(small).unwrap();
+            assert_eq!(mem.add(16), b);
+            assert!(is_zero(b, header));
+            assert_eq!(0xff, *b.add(header));
+            *b.add(header) = 0;
+            assert_eq!(32, heap.zero_from);
+
+            // A bigger block further up moves the frontier past the
+            // never-used block below it, which then gets cleared in full.
+            let c = heap
+                .allocate_zeroed(Layout::from_size_align(64, 1).unwrap())
+                .unwrap();
+            assert_eq!(mem.add(64), c);
+            assert_eq!(128, heap.zero_from);
+            let medium = Layout::from_size_align(32, 1).unwrap();
+            mem.add(32 + header).write_bytes(0xff, 32 - header);
+            let d = heap.allocate_zeroed(medium).unwrap();
+            assert_eq!(mem.add(32), d);
+            assert!(is_zero(d, 32));
+
+            // A freed block is dirty, and is cleared when it's reused.
+            a.write_bytes(0xaa, 16);
+            heap.deallocate(a, small);
+            let a = heap.allocate_zeroed(small).unwrap();
+            assert_eq!(mem, a);
+            assert!(is_zero(a, 16));
+            assert_eq!(128, heap.zero_from);
+
+            heap.deallocate(a, small);
+            heap.deallocate(b, small);
+            heap.deallocate(c, Layout::from_size_align(64, 1).unwrap());
+            heap.deallocate(d, medium);
+            assert_eq!(0, heap.used_bytes());
+
+            // Without the promise, nothing is assumed to be zero.
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            mem.add(header).write_bytes(0xff, 8);
+            let a = heap.allocate_zeroed(small).unwrap();
+            assert!(is_zero(a, 16));
+            heap.deallocate(a, small);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_measure_free_list_depth() {
         unsafe {
             let heap_size = 256;
             let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
             let mem = std::alloc::alloc(layout);
-            let heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            assert_eq!([0, 0, 0, 0, 1], heap.measure_free_list_depth());
 
-            let block_16_0 = mem;
-            let block_16_1 = mem.offset(16);
-            assert_eq!(Some(block_16_1), heap.buddy(0, block_16_0));
-            assert_eq!(Some(block_16_0), heap.buddy(0, block_16_1));
+            // Taking every other small block leaves each one's buddy on
+            // the order 0 list, one more each time.
+            let small = Layout::from_size_align(16, 1).unwrap();
+            let mut last = 0;
+            for offset in (0..heap_size).step_by(32) {
+                heap.allocate_at_offset(offset, small).unwrap();
+                let depth = heap.measure_free_list_depth()[0];
+                assert_eq!(last + 1, depth);
+                last = depth;
+            }
+            assert_eq!([8, 0, 0, 0, 0], heap.measure_free_list_depth());
 
-            let block_32_0 = mem;
-            let block_32_1 = mem.offset(32);
-            assert_eq!(Some(block_32_1), heap.buddy(1, block_32_0));
-            assert_eq!(Some(block_32_0), heap.buddy(1, block_32_1));
+            for offset in (0..heap_size).step_by(32) {
+                heap.deallocate(mem.add(offset), small);
+            }
+            assert_eq!([0, 0, 0, 0, 1], heap.measure_free_list_depth());
 
-            let block_32_2 = mem.offset(64);
-            let block_32_3 = mem.offset(96);
-            assert_eq!(Some(block_32_3), heap.buddy(1, block_32_2));
-            assert_eq!(Some(block_32_2), heap.buddy(1, block_32_3));
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
 
-            let block_256_0 = mem;
-            assert_eq!(None, heap.buddy(4, block_256_0));
+    #[test]
+    fn test_free_list_sort() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Allocate every 16-byte block, then free every other one in a
+            // scrambled order, so none of them can merge.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: std::vec::Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+            for &i in &[6, 0, 14, 4, 10, 2, 12, 8] {
+                heap.deallocate(blocks[i], small);
+            }
+            let mut listed = std::vec::Vec::new();
+            free_list_for_each(&heap.free_lists, 0, |block| listed.push(block));
+            #[cfg(not(feature = "sorted-free-lists"))]
+            assert!(!listed.windows(2).all(|w| w[0] < w[1]));
+
+            heap.free_list_sort(0);
+            let mut listed = std::vec::Vec::new();
+            free_list_for_each(&heap.free_lists, 0, |block| listed.push(block));
+            let expected: std::vec::Vec<_> = (0..16).step_by(2).map(|i| blocks[i]).collect();
+            assert_eq!(expected, listed);
+
+            // Sequential allocations now walk up through memory.
+            for &block in &expected {
+                assert_eq!(Ok(block), heap.allocate(small));
+            }
 
+            // Sorting an empty list, or the whole-heap list, is harmless.
+            heap.free_list_sort(0);
+            heap.free_list_sort(4);
+            heap.free_list_sort(5);
+            assert_eq!(256, heap.used_bytes());
+
+            drop(heap);
             std::alloc::dealloc(mem, layout);
         }
     }
 
     #[test]
-    fn test_alloc_and_dealloc() {
+    fn test_free_list_remove_all() {
         unsafe {
             let heap_size = 256;
             let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
             let mem = std::alloc::alloc(layout);
             let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
 
-            let block_16_0 = heap
-                .allocate(Layout::from_size_align(8, 8).unwrap())
-                .unwrap();
-            assert_eq!(mem, block_16_0);
+            // Leave six 16-byte blocks free, none of which can merge.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: std::vec::Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+            for i in (0..12).step_by(2) {
+                heap.deallocate(blocks[i], small);
+            }
+            let free = heap.free_bytes();
 
-            let bigger_than_heap = heap.allocate(Layout::from_size_align(heap_size, 4096).unwrap());
+            let before = heap.measure_free_list_depth()[0];
+            let mut drained = std::vec::Vec::new();
             assert_eq!(
-                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
-                bigger_than_heap
+                before,
+                heap.free_list_remove_all(0, |block| drained.push(block))
             );
+            assert_eq!(6, drained.len());
+            assert!(heap.free_lists[0].is_null());
+            assert_eq!(free - 6 * 16, heap.free_bytes());
+            assert!(heap.accounting_check());
 
-            let bigger_than_free =
-                heap.allocate(Layout::from_size_align(heap_size, heap_size).unwrap());
-            assert_eq!(Err(AllocationError::HeapExhausted), bigger_than_free);
+            // Empty lists, the whole-heap list and orders past the top are
+            // all fine.
+            assert_eq!(0, heap.free_list_remove_all(0, |_| panic!()));
+            assert_eq!(0, heap.free_list_remove_all(5, |_| panic!()));
 
-            let block_16_1 = heap
-                .allocate(Layout::from_size_align(8, 8).unwrap())
-                .unwrap();
-            assert_eq!(mem.offset(16), block_16_1);
+            // The drained blocks can be given back like any others.
+            for block in drained {
+                heap.deallocate(block, small);
+            }
+            for (i, &block) in blocks.iter().enumerate() {
+                if i % 2 == 1 || i >= 12 {
+                    heap.deallocate(block, small);
+                }
+            }
+            assert_eq!(0, heap.used_bytes());
+            assert_eq!(
+                1,
+                heap.free_list_remove_all(4, |block| assert_eq!(mem, block))
+            );
+            assert!(heap.free_lists[4].is_null());
+            assert_eq!(256, heap.used_bytes());
 
-            let block_16_2 = heap
-                .allocate(Layout::from_size_align(8, 8).unwrap())
-                .unwrap();
-            assert_eq!(mem.offset(32), block_16_2);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
 
-            let block_32_2 = heap
-                .allocate(Layout::from_size_align(32, 32).unwrap())
-                .unwrap();
-            assert_eq!(mem.offset(64), block_32_2);
+    #[test]
+    fn test_for_each_free_block() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc_zeroed(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size)
+                .unwrap()
+                .assume_zeroed();
 
-            let block_16_3 = heap
-                .allocate(Layout::from_size_align(8, 8).unwrap())
-                .unwrap();
-            assert_eq!(mem.offset(48), block_16_3);
+            // Free blocks of several orders, with a few live ones between.
+            let mut live = std::vec::Vec::new();
+            for i in 0..12 {
+                let layout = Layout::from_size_align(16 << (i % 3), 16).unwrap();
+                live.push((heap.allocate(layout).unwrap(), layout));
+            }
+            let freed: std::vec::Vec<_> = live.iter().copied().step_by(2).collect();
+            live.retain(|block| !freed.contains(block));
+            for (block, layout) in freed {
+                heap.deallocate(block, layout);
+            }
+            for (block, layout) in &live {
+                block.write_bytes(0x11, layout.size());
+            }
+            let heads = heap.free_list_heads();
+            let depths = heap.measure_free_list_depth();
 
-            let block_128_1 = heap
-                .allocate(Layout::from_size_align(128, 128).unwrap())
-                .unwrap();
-            assert_eq!(mem.offset(128), block_128_1);
+            // Fill all of every free block, headers included.
+            let mut scrubbed = 0;
+            heap.for_each_free_block(|block, size| {
+                block.write_bytes(0xa5, size);
+                scrubbed += size;
+            });
+            assert_eq!(heap.free_bytes(), scrubbed);
+
+            // The lists are as they were, and everything but the headers
+            // reads back as the pattern.
+            assert_eq!(heads, heap.free_list_heads());
+            assert_eq!(depths, heap.measure_free_list_depth());
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            let header = size_of::<FreeBlock>();
+            heap.for_each_free_block(|block, size| {
+                for i in header..size {
+                    assert_eq!(0xa5, *block.add(i));
+                }
+            });
 
-            let too_fragmented = heap.allocate(Layout::from_size_align(64, 64).unwrap());
-            assert_eq!(Err(AllocationError::HeapExhausted), too_fragmented);
+            // The live blocks weren't touched, and new allocations still
+            // work, zeroed ones included, now that free memory isn't zero.
+            for (block, layout) in &live {
+                assert!((0..layout.size()).all(|i| *block.add(i) == 0x11));
+            }
+            let big = Layout::from_size_align(1024, 16).unwrap();
+            let zeroed = heap.allocate_zeroed(big).unwrap();
+            assert!((0..1024).all(|i| *zeroed.add(i) == 0));
+            heap.deallocate(zeroed, big);
+            for (block, layout) in live {
+                heap.deallocate(block, layout);
+            }
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(8, mem));
 
-            heap.deallocate(block_32_2, Layout::from_size_align(32, 32).unwrap());
-            heap.deallocate(block_16_0, Layout::from_size_align(8, 8).unwrap());
-            heap.deallocate(block_16_3, Layout::from_size_align(8, 8).unwrap());
-            heap.deallocate(block_16_1, Layout::from_size_align(8, 8).unwrap());
-            heap.deallocate(block_16_2, Layout::from_size_align(8, 8).unwrap());
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
 
-            let block_128_0 = heap
-                .allocate(Layout::from_size_align(128, 128).unwrap())
-                .unwrap();
-            assert_eq!(mem.offset(0), block_128_0);
+    #[cfg(feature = "sorted-free-lists")]
+    #[test]
+    fn test_sorted_free_lists() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Freeing unmergeable blocks in a scrambled order still leaves
+            // the list in address order.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: std::vec::Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+            for &i in &[6, 0, 14, 4, 10, 2, 12, 8] {
+                heap.deallocate(blocks[i], small);
+            }
+            let mut listed = std::vec::Vec::new();
+            free_list_for_each(&heap.free_lists, 0, |block| listed.push(block));
+            let mut expected: std::vec::Vec<_> = (0..16).step_by(2).map(|i| blocks[i]).collect();
+            assert_eq!(expected, listed);
+
+            // Removal finds blocks at the front, middle and back, and stops
+            // early without finding ones that are in use.
+            for &i in &[1, 7, 15] {
+                assert!(!free_list_remove(&mut heap.free_lists, 0, blocks[i]));
+            }
+            for &i in &[8, 0, 14] {
+                assert!(free_list_remove(&mut heap.free_lists, 0, blocks[i]));
+                expected.retain(|&block| block != blocks[i]);
+                let mut listed = std::vec::Vec::new();
+                free_list_for_each(&heap.free_lists, 0, |block| listed.push(block));
+                assert_eq!(expected, listed);
+            }
+            for &i in &[8, 0, 14] {
+                free_list_insert(&mut heap.free_lists, 0, blocks[i]);
+            }
+
+            // Freeing the rest merges everything back together.
+            for i in (1..16).step_by(2) {
+                heap.deallocate(blocks[i], small);
+            }
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_try_merge_adjacent() {
+        unsafe {
+            let layout = std::alloc::Layout::from_size_align(8192, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let new = |offset, size| -> Heap<9> {
+                Heap::new(NonNull::new(mem.add(offset)).unwrap(), size).unwrap()
+            };
+
+            // Same size and adjacent, in either order.
+            let merged: Heap<10> = new(0, 4096).try_merge_adjacent(new(4096, 4096)).unwrap();
+            assert_eq!(
+                (mem, 8192, 16),
+                (merged.heap_base, merged.heap_size, merged.min_block_size)
+            );
+            drop(merged);
+            let mut merged: Heap<9> = new(4096, 4096).try_merge_adjacent(new(0, 4096)).unwrap();
+            assert_eq!(
+                (mem, 8192, 32),
+                (merged.heap_base, merged.heap_size, merged.min_block_size)
+            );
+            let whole = Layout::from_size_align(8192, 4096).unwrap();
+            assert_eq!(Ok(mem), merged.allocate(whole));
+            drop(merged);
+
+            // Not adjacent, or different sizes.
+            let (a, b) = new(0, 2048)
+                .try_merge_adjacent::<10>(new(4096, 2048))
+                .unwrap_err();
+            assert_eq!((mem, mem.add(4096)), (a.heap_base, b.heap_base));
+            drop((a, b));
+            assert!(new(0, 4096)
+                .try_merge_adjacent::<10>(new(4096, 2048))
+                .is_err());
+
+            // In use.
+            let mut a = new(0, 4096);
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let block = a.allocate(small).unwrap();
+            let (mut a, _) = a.try_merge_adjacent::<10>(new(4096, 4096)).unwrap_err();
+            a.deallocate(block, small);
+            drop(a);
+
+            // A minimum block size too small for the merged heap.
+            assert!(new(0, 4096)
+                .try_merge_adjacent::<12>(new(4096, 4096))
+                .is_err());
+
+            std::alloc::dealloc(mem, layout);
+        }
+    }
 
-            heap.deallocate(block_128_1, Layout::from_size_align(128, 128).unwrap());
-            heap.deallocate(block_128_0, Layout::from_size_align(128, 128).unwrap());
+    #[test]
+    fn test_migrate_block() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem_a = std::alloc::alloc(layout);
+            let mem_b = std::alloc::alloc(layout);
+            let mut heap_a: Heap<5> = Heap::new(NonNull::new(mem_a).unwrap(), heap_size).unwrap();
+            let mut heap_b: Heap<5> = Heap::new(NonNull::new(mem_b).unwrap(), heap_size).unwrap();
+
+            #[cfg(feature = "remap-table")]
+            {
+                let table =
+                    std::boxed::Box::leak(std::vec![RemapEntry::EMPTY; 2].into_boxed_slice());
+                assert!(heap_a.set_remap_table(table).is_none());
+            }
+
+            let block_layout = Layout::from_size_align(32, 8).unwrap();
+            let block = heap_a.allocate(block_layout).unwrap();
+            for i in 0..32 {
+                *block.add(i) = i as u8;
+            }
 
-            // And allocate the whole heap, just to make sure everything
-            // got cleaned up correctly.
-            let block_256_0 = heap
-                .allocate(Layout::from_size_align(256, 256).unwrap())
+            let moved = heap_a
+                .migrate_block(block, block_layout, &mut heap_b)
                 .unwrap();
-            assert_eq!(mem.offset(0), block_256_0);
+            assert_eq!(mem_b, moved);
+            for i in 0..32 {
+                assert_eq!(i as u8, *moved.add(i));
+            }
+            #[cfg(feature = "remap-table")]
+            {
+                assert_eq!(Some(moved), heap_a.remapped(block));
+                assert_eq!(None, heap_a.remapped(mem_a.offset(128)));
+            }
+
+            // The source block was freed, so heap A can hand out all of its
+            // memory again.
+            let whole = Layout::from_size_align(heap_size, heap_size).unwrap();
+            assert_eq!(Ok(mem_a), heap_a.allocate(whole));
+            heap_a.deallocate(mem_a, whole);
+
+            // A failed migration leaves both heaps alone.
+            let big = heap_a.allocate(whole).unwrap();
+            assert_eq!(
+                Err(AllocationError::HeapExhausted),
+                heap_a.migrate_block(big, whole, &mut heap_b)
+            );
+            heap_a.deallocate(big, whole);
+
+            // Migrating a reused address updates its entry rather than adding a
+            // stale twin.
+            #[cfg(feature = "remap-table")]
+            {
+                let mut last = ptr::null_mut();
+                for _ in 0..3 {
+                    let block = heap_a.allocate(block_layout).unwrap();
+                    last = heap_a
+                        .migrate_block(block, block_layout, &mut heap_b)
+                        .unwrap();
+                }
+                assert_eq!(Some(last), heap_a.remapped(mem_a));
+
+                let table = heap_a
+                    .set_remap_table(std::boxed::Box::leak(std::boxed::Box::new([])))
+                    .unwrap();
+                assert_eq!(2, table.len());
+            }
+
+            drop((heap_a, heap_b));
+            std::alloc::dealloc(mem_a, layout);
+            std::alloc::dealloc(mem_b, layout);
+        }
+    }
+
+    #[test]
+    fn test_split_stays_in_bounds() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let end = mem.add(heap_size);
+
+            // Split every block the heap could hold, at every order, down
+            // to every smaller order.  The chunks all land in the heap.
+            for order in 0..9 {
+                let size = 16 << order;
+                for block in (0..heap_size).step_by(size).map(|offset| mem.add(offset)) {
+                    for order_needed in 0..=order {
+                        let mut free_lists = [ptr::null_mut(); 9];
+                        split_free_block(
+                            &mut free_lists,
+                            mem,
+                            heap_size,
+                            4,
+                            block,
+                            order,
+                            order_needed,
+                        );
+                        let mut count = 0;
+                        for i in 0..9 {
+                            free_list_for_each(&free_lists, i, |chunk| {
+                                assert!(chunk > block && chunk < block.add(size));
+                                assert!(chunk.add(16 << i) <= end);
+                                count += 1;
+                            });
+                        }
+                        assert_eq!(order - order_needed, count);
+                    }
+                }
+            }
 
             std::alloc::dealloc(mem, layout);
         }
     }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "outside the heap"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_split_out_of_bounds_panics() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+
+            // The upper half of the heap, taken for the whole of it.
+            let mut free_lists = [ptr::null_mut(); 5];
+            split_free_block(&mut free_lists, mem, heap_size, 4, mem.add(128), 4, 0);
+        }
+    }
 }
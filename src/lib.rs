@@ -7,8 +7,143 @@
 //!
 //! Note that the [Heap] API is still somewhat unstable.
 #![no_std]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+// Tests drop heaps explicitly before making others over the same memory,
+// which `heap-registry` needs, but without it `Heap` has nothing to drop.
+#![cfg_attr(test, allow(clippy::drop_non_drop))]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(target_has_atomic = "8")]
+pub use boxed::*;
+pub use brand::*;
+pub use builder::*;
+#[cfg(target_has_atomic = "8")]
+pub use cache::*;
+#[cfg(target_has_atomic = "ptr")]
+pub use concurrent::*;
+#[cfg(feature = "debug-info")]
+pub use debug_info::*;
+#[cfg(feature = "dma")]
+pub use dma::*;
+pub use dump::*;
+pub use error::*;
+#[cfg(feature = "std")]
+pub use export::*;
+#[cfg(target_has_atomic = "8")]
+pub use fence::*;
+#[cfg(feature = "x86_64")]
+pub use frame::*;
 pub use heap::*;
+#[cfg(target_has_atomic = "8")]
+pub use locked::*;
+pub use pages::*;
+#[cfg(feature = "demand-paging")]
+pub use paging::PageFaultHandler;
+#[cfg(all(feature = "std", target_has_atomic = "8"))]
+pub use panic_stats::install_panic_stats_hook;
+#[cfg(feature = "profiling")]
+pub use profile::*;
+#[cfg(target_has_atomic = "8")]
+pub use rc::*;
+#[cfg(feature = "sbrk")]
+pub use sbrk::*;
+#[cfg(target_has_atomic = "ptr")]
+pub use sharded::*;
+pub use stats::*;
+#[cfg(feature = "debug-track")]
+pub use track::AllocationInfo;
+
+/// Panic with the given message, which may have format arguments.  With
+/// the `tiny` feature enabled the message is compiled out, so no strings
+/// or formatting code end up in the final binary.
+macro_rules! heap_panic {
+    ($msg:literal) => {{
+        #[cfg(not(feature = "tiny"))]
+        panic!($msg);
+        #[cfg(feature = "tiny")]
+        $crate::tiny_panic();
+    }};
+    ($fmt:literal, $($arg:expr),+ $(,)?) => {{
+        #[cfg(not(feature = "tiny"))]
+        panic!($fmt, $($arg),+);
+        #[cfg(feature = "tiny")]
+        {
+            let _ = ($(&$arg),+);
+            $crate::tiny_panic();
+        }
+    }};
+}
+
+/// The one and only panic site used by the `tiny` profile.
+#[cfg(feature = "tiny")]
+#[cold]
+#[inline(never)]
+fn tiny_panic() -> ! {
+    panic!()
+}
 
+#[cfg(feature = "small-batch")]
+mod batch;
+mod bist;
+mod bitmap;
+#[cfg(target_has_atomic = "8")]
+mod boxed;
+mod brand;
+mod builder;
+#[cfg(target_has_atomic = "8")]
+mod cache;
+mod color;
+#[cfg(all(feature = "llalloc-compat", target_has_atomic = "8"))]
+mod compat;
+#[cfg(target_has_atomic = "ptr")]
+mod concurrent;
+#[cfg(feature = "debug-info")]
+mod debug_info;
+mod deferred;
+#[cfg(feature = "dma")]
+mod dma;
+mod dump;
+#[cfg(feature = "emergency")]
+mod emergency;
+mod error;
+#[cfg(feature = "std")]
+mod export;
+#[cfg(target_has_atomic = "8")]
+mod fence;
+#[cfg(feature = "x86_64")]
+mod frame;
+#[cfg(feature = "freertos")]
+pub mod freertos;
+mod guard;
 mod heap;
+#[cfg(target_has_atomic = "8")]
+mod locked;
 mod math;
+#[cfg(all(feature = "heap-registry", debug_assertions))]
+mod overlap;
+mod pages;
+mod paging;
+mod panic_stats;
+#[cfg(feature = "profiling")]
+mod profile;
+#[cfg(target_has_atomic = "8")]
+mod rc;
+#[cfg(all(feature = "registry", target_has_atomic = "ptr"))]
+pub mod registry;
+#[cfg(feature = "sbrk")]
+mod sbrk;
+#[cfg(feature = "serde")]
+mod serde_array;
+#[cfg(target_has_atomic = "ptr")]
+mod sharded;
+mod stats;
+#[cfg(feature = "debug-track")]
+mod track;
+#[cfg(feature = "viz")]
+mod viz;
+#[cfg(feature = "low-watermark")]
+mod watermark;
+#[cfg(feature = "wear-leveling")]
+mod wear;
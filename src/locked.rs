@@ -0,0 +1,593 @@
+//! A heap behind a spin lock, so it can be shared between threads and
+//! used as Rust's global allocator.
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+#[cfg(target_has_atomic = "ptr")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::Heap;
+
+/// A [Heap] protected by a simple spin lock.  This implements
+/// [GlobalAlloc], so it can be installed with `#[global_allocator]`.
+///
+/// With the `allocator-api` feature (nightly only) it also implements
+/// `Allocator`, and so does `&LockedHeap<N>`, which lets any number of
+/// collections allocate from the same heap at once:
+///
+/// ```ignore
+/// let heap: LockedHeap<10> = LockedHeap::new(heap);
+/// let mut a = Vec::new_in(&heap);
+/// let mut b = Vec::new_in(&heap);
+/// ```
+///
+/// ```no_run
+/// # use buddyalloc::{Heap, LockedHeap};
+/// const HEAP_MEM: usize  = 0xFFF0_0000;
+/// const HEAP_SIZE: usize = 0x0008_0000;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: LockedHeap<16> =
+///     LockedHeap::new(unsafe { Heap::new_unchecked(HEAP_MEM as *mut u8, HEAP_SIZE) });
+/// ```
+#[derive(Debug)]
+pub struct LockedHeap<const N: usize = 16> {
+    locked: AtomicBool,
+    heap: UnsafeCell<Heap<N>>,
+    #[cfg(target_has_atomic = "ptr")]
+    stats: AtomicStats,
+}
+
+/// A snapshot of a [LockedHeap]'s usage taken without its lock, from
+/// [LockedHeap::stats_relaxed].  The counts are as in [crate::HeapStats].
+#[cfg(target_has_atomic = "ptr")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RelaxedStats {
+    /// See [Heap::used_bytes].
+    pub used_bytes: usize,
+    /// See [Heap::free_bytes].
+    pub free_bytes: usize,
+    /// The most [Heap::used_bytes] has been when the lock was released.
+    pub peak_used_bytes: usize,
+    /// The number of successful allocations.  This and the next two are
+    /// only counted with the `stats` feature, and are zero otherwise.
+    pub allocations: usize,
+    /// The number of deallocations.
+    pub deallocations: usize,
+    /// The number of allocations which failed.
+    pub failures: usize,
+}
+
+/// The counters behind [RelaxedStats], copied from the heap each time the
+/// lock is released.
+#[cfg(target_has_atomic = "ptr")]
+#[derive(Debug)]
+struct AtomicStats {
+    used_bytes: AtomicUsize,
+    free_bytes: AtomicUsize,
+    peak_used_bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl AtomicStats {
+    const fn new<const N: usize>(heap: &Heap<N>) -> Self {
+        Self {
+            used_bytes: AtomicUsize::new(heap.used_bytes()),
+            free_bytes: AtomicUsize::new(heap.free_bytes()),
+            peak_used_bytes: AtomicUsize::new(heap.used_bytes()),
+            allocations: AtomicUsize::new(heap.allocations),
+            deallocations: AtomicUsize::new(heap.deallocations),
+            failures: AtomicUsize::new(heap.failures),
+        }
+    }
+
+    /// Copy the heap's counters.  Only the lock holder calls this, so
+    /// plain stores are enough, even for the peak.
+    fn publish<const N: usize>(&self, heap: &Heap<N>) {
+        let used = heap.used_bytes();
+        self.used_bytes.store(used, Ordering::Relaxed);
+        self.free_bytes.store(heap.free_bytes(), Ordering::Relaxed);
+        if used > self.peak_used_bytes.load(Ordering::Relaxed) {
+            self.peak_used_bytes.store(used, Ordering::Relaxed);
+        }
+        self.allocations.store(heap.allocations, Ordering::Relaxed);
+        self.deallocations
+            .store(heap.deallocations, Ordering::Relaxed);
+        self.failures.store(heap.failures, Ordering::Relaxed);
+    }
+}
+
+// SAFETY: All access to the heap goes through the lock.
+unsafe impl<const N: usize> Sync for LockedHeap<N> {}
+
+impl<const N: usize> LockedHeap<N> {
+    /// Wrap `heap` in a lock.
+    pub const fn new(heap: Heap<N>) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(target_has_atomic = "ptr")]
+            stats: AtomicStats::new(&heap),
+            heap: UnsafeCell::new(heap),
+        }
+    }
+
+    /// Read the heap's usage without taking the lock, so that a monitoring
+    /// thread never holds up allocation.  The counters are updated each
+    /// time the lock is released.
+    ///
+    /// Each field is read separately with relaxed ordering, so they may
+    /// come from different moments: `used_bytes` and `free_bytes` need not
+    /// add up to the heap's size, and the counts may be one operation
+    /// apart.  That's fine for telemetry, but take the lock and use
+    /// [Heap::stats] for anything that needs a consistent view.  Memory
+    /// allocated and freed again while the lock was held doesn't show up
+    /// in `peak_used_bytes`.
+    #[cfg(target_has_atomic = "ptr")]
+    pub fn stats_relaxed(&self) -> RelaxedStats {
+        let stats = &self.stats;
+        RelaxedStats {
+            used_bytes: stats.used_bytes.load(Ordering::Relaxed),
+            free_bytes: stats.free_bytes.load(Ordering::Relaxed),
+            peak_used_bytes: stats.peak_used_bytes.load(Ordering::Relaxed),
+            allocations: stats.allocations.load(Ordering::Relaxed),
+            deallocations: stats.deallocations.load(Ordering::Relaxed),
+            failures: stats.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Lock the heap, spinning until it's available.
+    pub fn lock(&self) -> LockedHeapGuard<'_, N> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+
+        LockedHeapGuard { lock: self }
+    }
+
+    /// Lock the heap if it's free right now, without spinning.  This is
+    /// for code which mustn't wait, such as a panic handler which might be
+    /// running on the thread that holds the lock.
+    pub fn try_lock(&self) -> Option<LockedHeapGuard<'_, N>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| LockedHeapGuard { lock: self })
+    }
+
+    /// Consume the lock, returning the heap inside it.
+    pub fn into_inner(self) -> Heap<N> {
+        self.heap.into_inner()
+    }
+}
+
+/// Exclusive access to the heap inside a [LockedHeap].  The lock is released
+/// when this is dropped.
+#[derive(Debug)]
+pub struct LockedHeapGuard<'a, const N: usize> {
+    lock: &'a LockedHeap<N>,
+}
+
+impl<const N: usize> Deref for LockedHeapGuard<'_, N> {
+    type Target = Heap<N>;
+
+    fn deref(&self) -> &Heap<N> {
+        // SAFETY: We hold the lock.
+        unsafe { &*self.lock.heap.get() }
+    }
+}
+
+impl<const N: usize> DerefMut for LockedHeapGuard<'_, N> {
+    fn deref_mut(&mut self) -> &mut Heap<N> {
+        // SAFETY: We hold the lock.
+        unsafe { &mut *self.lock.heap.get() }
+    }
+}
+
+impl<const N: usize> Drop for LockedHeapGuard<'_, N> {
+    fn drop(&mut self) {
+        #[cfg(target_has_atomic = "ptr")]
+        self.lock.stats.publish(self);
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for LockedHeap<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.lock()
+            .allocate_zeroed(layout)
+            .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        self.lock()
+            .reallocate(ptr, layout, new_layout)
+            .unwrap_or(ptr::null_mut())
+    }
+}
+
+// `&LockedHeap<N>` picks up `Allocator` from the blanket impl for `&A`.
+#[cfg(feature = "allocator-api")]
+unsafe impl<const N: usize> core::alloc::Allocator for LockedHeap<N> {
+    fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let block = self
+            .lock()
+            .allocate(layout)
+            .map_err(|_| core::alloc::AllocError)?;
+
+        // SAFETY: The heap never hands out a null block.
+        Ok(unsafe {
+            ptr::NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(block, layout.size()))
+        })
+    }
+
+    fn allocate_zeroed(
+        &self,
+        layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let block = self
+            .lock()
+            .allocate_zeroed(layout)
+            .map_err(|_| core::alloc::AllocError)?;
+
+        // SAFETY: The heap never hands out a null block.
+        Ok(unsafe {
+            ptr::NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(block, layout.size()))
+        })
+    }
+
+    unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        self.lock().deallocate(ptr.as_ptr(), layout)
+    }
+
+    // The default `grow` and `shrink` always allocate, copy and free.
+    // `Heap::reallocate` keeps the block where it is whenever the buddy
+    // system allows it.
+
+    unsafe fn grow(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let block = self.grow(ptr, old_layout, new_layout)?;
+
+        // Only the bytes past the old size are new.
+        let base = block.as_ptr() as *mut u8;
+        base.add(old_layout.size())
+            .write_bytes(0, new_layout.size() - old_layout.size());
+        Ok(block)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<const N: usize> LockedHeap<N> {
+    /// `Allocator::grow` and `Allocator::shrink`, which only differ in
+    /// what the caller promises about the sizes.
+    unsafe fn resize(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let block = self
+            .lock()
+            .reallocate(ptr.as_ptr(), old_layout, new_layout)
+            .map_err(|_| core::alloc::AllocError)?;
+
+        // SAFETY: The heap never hands out a null block.
+        Ok(ptr::NonNull::new_unchecked(ptr::slice_from_raw_parts_mut(
+            block,
+            new_layout.size(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_global_alloc() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: Arc<LockedHeap<8>> = Arc::new(LockedHeap::new(
+                Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap(),
+            ));
+
+            // Hammer the heap from a few threads at once.  If the lock didn't
+            // work, the free lists would be corrupted and blocks would be
+            // handed out twice.
+            let threads: Vec<_> = (0..4)
+                .map(|i| {
+                    let heap = heap.clone();
+                    std::thread::spawn(move || {
+                        let block = Layout::from_size_align(16, 16).unwrap();
+                        for _ in 0..1000 {
+                            let p = heap.alloc(block);
+                            assert!(!p.is_null());
+                            p.write_bytes(i, 16);
+                            assert!(std::slice::from_raw_parts(p, 16).iter().all(|&b| b == i));
+                            heap.dealloc(p, block);
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+
+            let whole = Layout::from_size_align(heap_size, heap_size).unwrap();
+            assert_eq!(mem, heap.alloc(whole));
+            assert!(heap.alloc(whole).is_null());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_stats_relaxed() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: LockedHeap<8> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+            let block = Layout::from_size_align(64, 16).unwrap();
+            // Without the `stats` feature the counts stay at zero.
+            let counted = cfg!(feature = "stats") as usize;
+
+            // The counters move as soon as the lock is released, and can be
+            // read while it's held.
+            let a = heap.alloc(block);
+            let b = heap.alloc(block);
+            assert!(heap
+                .alloc(Layout::from_size_align(8192, 16).unwrap())
+                .is_null());
+            heap.dealloc(a, block);
+            let guard = heap.lock();
+            let stats = heap.stats_relaxed();
+            assert_eq!(
+                RelaxedStats {
+                    used_bytes: 64,
+                    free_bytes: heap_size - 64,
+                    peak_used_bytes: 128,
+                    allocations: 2 * counted,
+                    deallocations: counted,
+                    failures: counted,
+                },
+                stats
+            );
+            drop(guard);
+            heap.dealloc(b, block);
+
+            // A monitor sampling throughout a busy run sees the counts only
+            // go up, and the peak never goes past the heap.
+            let done = AtomicBool::new(false);
+            std::thread::scope(|s| {
+                let workers: Vec<_> = (0..3)
+                    .map(|_| {
+                        s.spawn(|| {
+                            for _ in 0..1000 {
+                                let p = heap.alloc(block);
+                                heap.dealloc(p, block);
+                            }
+                        })
+                    })
+                    .collect();
+                s.spawn(|| {
+                    let mut last = heap.stats_relaxed();
+                    while !done.load(Ordering::Relaxed) {
+                        let now = heap.stats_relaxed();
+                        assert!(now.allocations >= last.allocations);
+                        assert!(now.deallocations >= last.deallocations);
+                        assert!(now.peak_used_bytes >= last.peak_used_bytes);
+                        assert!(now.peak_used_bytes <= heap_size);
+                        last = now;
+                    }
+                });
+                for worker in workers {
+                    worker.join().unwrap();
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+
+            // Once things are quiet, the snapshot matches the heap's own.
+            let stats = heap.stats_relaxed();
+            let exact = heap.lock().stats();
+            assert_eq!(
+                (exact.used_bytes, exact.free_bytes, exact.allocations),
+                (stats.used_bytes, stats.free_bytes, stats.allocations)
+            );
+            assert_eq!(
+                (3002 * counted, 3002 * counted),
+                (stats.allocations, stats.deallocations)
+            );
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn test_shared_allocator() {
+        unsafe {
+            let heap_size = 16384;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: LockedHeap<10> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            // Two collections sharing one heap, growing (and so reallocating)
+            // in lockstep.
+            {
+                let mut a = Vec::new_in(&heap);
+                let mut b = Vec::new_in(&heap);
+                for i in 0..200u32 {
+                    a.push(i);
+                    b.push(!i);
+                    if i % 50 == 49 {
+                        a.shrink_to_fit();
+                    }
+                }
+                assert!(a.iter().copied().eq(0..200));
+                assert!(b.iter().copied().eq((0..200).map(|i: u32| !i)));
+                assert!(heap.lock().accounting_check());
+                heap.lock().assert_alignment_invariants();
+            }
+            assert_eq!(0, heap.lock().used_bytes());
+
+            // The same, with each collection owned by a different thread.
+            std::thread::scope(|s| {
+                for t in 0..4u32 {
+                    let heap = &heap;
+                    s.spawn(move || {
+                        for _ in 0..100 {
+                            let mut v = Vec::new_in(heap);
+                            v.extend((0..64).map(|i| i * t));
+                            assert!(v.iter().copied().eq((0..64).map(|i| i * t)));
+                        }
+                    });
+                }
+            });
+            assert!(heap.lock().accounting_check());
+            heap.lock().assert_alignment_invariants();
+            assert_eq!(0, heap.lock().used_bytes());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn test_allocator_resize() {
+        use core::alloc::Allocator;
+
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            mem.write_bytes(0xee, heap_size);
+            let heap: LockedHeap<5> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+            let at = |size, align| Layout::from_size_align(size, align).unwrap();
+
+            // Growing with the buddies above free stays put, and zeroes just
+            // the bytes between the old and new sizes.
+            let block = heap.lock().allocate_at(mem, at(16, 16)).unwrap();
+            block.write_bytes(0xa5, 16);
+            let grown = heap
+                .grow_zeroed(NonNull::new(block).unwrap(), at(16, 16), at(40, 16))
+                .unwrap();
+            assert_eq!(block, grown.as_ptr() as *mut u8);
+            assert_eq!(40, grown.len());
+            let bytes = std::slice::from_raw_parts(block, 64);
+            assert!(bytes[..16].iter().all(|&b| b == 0xa5));
+            assert!(bytes[16..40].iter().all(|&b| b == 0));
+            assert!(bytes[40..].iter().all(|&b| b == 0xee));
+            assert_eq!(64, heap.lock().used_bytes());
+
+            // Shrinking stays put and gives the rest back.
+            let shrunk = heap
+                .shrink(NonNull::new(block).unwrap(), at(40, 16), at(8, 8))
+                .unwrap();
+            assert_eq!(block, shrunk.as_ptr() as *mut u8);
+            assert_eq!(8, shrunk.len());
+            assert_eq!(16, heap.lock().used_bytes());
+
+            // With a buddy in use, growing has to move the block.
+            let blocker = heap.lock().allocate_at(mem.add(16), at(16, 16)).unwrap();
+            let moved = heap
+                .grow(NonNull::new(block).unwrap(), at(8, 8), at(64, 8))
+                .unwrap();
+            let moved = moved.as_ptr() as *mut u8;
+            assert_ne!(block, moved);
+            assert!(std::slice::from_raw_parts(moved, 8)
+                .iter()
+                .all(|&b| b == 0xa5));
+            assert!(heap.lock().is_block_free(0, block));
+            assert_eq!(80, heap.lock().used_bytes());
+
+            // A failed grow leaves the block where it was.
+            assert!(heap
+                .grow(NonNull::new(moved).unwrap(), at(64, 8), at(256, 8))
+                .is_err());
+            assert_eq!(80, heap.lock().used_bytes());
+
+            heap.deallocate(NonNull::new(moved).unwrap(), at(64, 8));
+            heap.deallocate(NonNull::new(blocker).unwrap(), at(16, 16));
+            assert_eq!(0, heap.lock().used_bytes());
+
+            // A vector doubling its capacity in an empty heap grows in place
+            // all the way up to the whole heap, and shrinks in place too.
+            {
+                let mut v: Vec<u32, _> = Vec::with_capacity_in(4, &heap);
+                let start = v.as_ptr();
+                v.extend(0..64);
+                assert_eq!(start, v.as_ptr());
+                assert_eq!(256, heap.lock().used_bytes());
+                assert!(v.iter().copied().eq(0..64));
+
+                v.truncate(10);
+                v.shrink_to_fit();
+                assert_eq!(start, v.as_ptr());
+                assert_eq!(64, heap.lock().used_bytes());
+                assert!(v.iter().copied().eq(0..10));
+            }
+            assert_eq!(0, heap.lock().used_bytes());
+            assert!(heap.lock().accounting_check());
+            heap.lock().assert_alignment_invariants();
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
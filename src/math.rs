@@ -1,21 +1,78 @@
-/// Calculate the base-2 logarithm of this value.
-///
-/// This will normally round down, except for the case of `0.log2()`,
-/// which will return 0.
-///
-/// Based on the obvious code at
-/// http://graphics.stanford.edu/~seander/bithacks.html#IntegerLogObvious
-pub const fn log2(n: usize) -> u8 {
-    let mut temp = n;
-    let mut result = 0;
-    temp >>= 1;
-    while temp != 0 {
-        result += 1;
-        temp >>= 1;
-    }
-    result
+use core::cmp::max;
+
+use crate::AllocationSizeError;
+
+/// Define the size arithmetic for the integer type `$t`.  The allocator
+/// only ever uses the `usize` versions, but generating them from one
+/// definition lets the tests run the very same code at 16 bits, where
+/// overflow is much easier to hit.
+macro_rules! size_math {
+    ($t:ty, $log2:ident, $allocation_size:ident) => {
+        /// Calculate the base-2 logarithm of this value.
+        ///
+        /// This will normally round down, except for the case of `0.log2()`,
+        /// which will return 0.
+        ///
+        /// Based on the obvious code at
+        /// http://graphics.stanford.edu/~seander/bithacks.html#IntegerLogObvious
+        pub const fn $log2(n: $t) -> u8 {
+            let mut temp = n;
+            let mut result = 0;
+            temp >>= 1;
+            while temp != 0 {
+                result += 1;
+                temp >>= 1;
+            }
+            result
+        }
+
+        /// See `Heap::allocation_size`.  `max_align` is the heap's base
+        /// alignment, the most we can align a block to.
+        pub fn $allocation_size(
+            min_block_size: $t,
+            heap_size: $t,
+            max_align: $t,
+            mut size: $t,
+            align: $t,
+        ) -> Result<$t, AllocationSizeError> {
+            // Sorry, we don't support weird alignments.
+            if !align.is_power_of_two() {
+                return Err(AllocationSizeError::BadAlignment);
+            }
+
+            // We can't align any more precisely than our heap base alignment
+            // without getting much too clever, so don't bother.
+            if align > max_align {
+                return Err(AllocationSizeError::BadAlignment);
+            }
+
+            // We're automatically aligned to `size` because of how our heap is
+            // sub-divided, but if we need a larger alignment, we can only do
+            // it be allocating more memory.
+            if align > size {
+                size = align;
+            }
+
+            // We can't allocate a block bigger than our heap.  Since the heap
+            // size is a power of two, checking this before rounding up also
+            // means the rounding can't overflow.
+            if size > heap_size {
+                return Err(AllocationSizeError::TooLarge);
+            }
+
+            // We can't allocate blocks smaller than `min_block_size`.
+            size = max(size, min_block_size);
+
+            // Round up to the next power of two.
+            Ok(size.next_power_of_two())
+        }
+    };
 }
 
+size_math!(usize, log2, allocation_size);
+#[cfg(test)]
+size_math!(u16, log2_u16, allocation_size_u16);
+
 #[test]
 fn test_log2() {
     assert_eq!(0, log2(0));
@@ -24,3 +81,25 @@ fn test_log2() {
     assert_eq!(5, log2(32));
     assert_eq!(10, log2(1024));
 }
+
+#[test]
+fn test_16_bit_math() {
+    assert_eq!(15, log2_u16(u16::MAX));
+    assert_eq!(15, log2_u16(0x8000));
+
+    // The biggest heap a 16-bit target can have, with a 16 byte minimum.
+    let size = |size, align| allocation_size_u16(16, 0x8000, 64, size, align);
+    assert_eq!(Ok(16), size(0, 1));
+    assert_eq!(Ok(0x4000), size(0x3fff, 2));
+    assert_eq!(Ok(0x8000), size(0x4001, 1));
+    assert_eq!(Ok(0x8000), size(0x8000, 64));
+    assert_eq!(Ok(64), size(1, 64));
+
+    // None of these fit, and rounding them up would overflow.
+    assert_eq!(Err(AllocationSizeError::TooLarge), size(0x8001, 1));
+    assert_eq!(Err(AllocationSizeError::TooLarge), size(u16::MAX, 1));
+
+    assert_eq!(Err(AllocationSizeError::BadAlignment), size(16, 128));
+    assert_eq!(Err(AllocationSizeError::BadAlignment), size(16, 0x8000));
+    assert_eq!(Err(AllocationSizeError::BadAlignment), size(16, 3));
+}
@@ -0,0 +1,204 @@
+//! A record of the memory under every live heap, to catch two heaps being
+//! created over the same memory while developing, rather than debugging
+//! the corruption that follows.
+//!
+//! The record is shared by the whole process, since heaps can be made on
+//! one thread and dropped on another.  A heap's memory has to outlive it,
+//! so free the memory only after dropping the heap: otherwise a heap made
+//! over that memory elsewhere trips over one that's as good as gone.
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::vec::Vec;
+
+/// The address ranges of the heaps registered by
+/// [Heap::new](crate::Heap::new) and not yet dropped.
+static LIVE: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+/// The live ranges.  Nothing panics while holding the lock, but a poisoned
+/// one is still usable anyway.
+fn live_ranges() -> MutexGuard<'static, Vec<(usize, usize)>> {
+    LIVE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Record the heap over `start..end`, panicking if it overlaps one already
+/// recorded.
+pub(crate) fn register(start: usize, end: usize) {
+    let overlap = {
+        let mut live = live_ranges();
+        let overlap = live
+            .iter()
+            .copied()
+            .find(|&(other_start, other_end)| other_start < end && start < other_end);
+        if overlap.is_none() {
+            live.push((start, end));
+        }
+        overlap
+    };
+
+    if let Some((other_start, other_end)) = overlap {
+        heap_panic!(
+            "Heap at {:#x}..{:#x} overlaps a live heap at {:#x}..{:#x}",
+            start,
+            end,
+            other_start,
+            other_end
+        );
+    }
+}
+
+/// Forget the heap recorded over `start..end`.
+pub(crate) fn unregister(start: usize, end: usize) {
+    let mut live = live_ranges();
+    if let Some(i) = live.iter().position(|&range| range == (start, end)) {
+        live.swap_remove(i);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::Heap;
+    use core::ptr::NonNull;
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "overlaps a live heap"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_overlapping_heaps_panic() {
+        unsafe {
+            let heap_size = 8192;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let at = |offset| NonNull::new(mem.add(offset)).unwrap();
+
+            // Heaps side by side are fine, and memory can be reused once
+            // its heap is gone.
+            let low: Heap<9> = Heap::new(at(0), 4096).unwrap();
+            let high: Heap<9> = Heap::new(at(4096), 4096).unwrap();
+            drop(high);
+            let high: Heap<9> = Heap::new(at(4096), 4096).unwrap();
+
+            // The memory is leaked, since the panic skips freeing it.
+            let _whole: Heap<10> = Heap::new(at(0), heap_size).unwrap();
+            drop((low, high));
+        }
+    }
+
+    /// The live ranges inside `mem..mem + size`, lowest first.  Other
+    /// tests' heaps are live too, but never over this test's memory.
+    fn live(mem: *mut u8, size: usize) -> Vec<(usize, usize)> {
+        let (low, high) = (mem as usize, mem as usize + size);
+        let mut ranges: Vec<_> = live_ranges()
+            .iter()
+            .copied()
+            .filter(|&(start, end)| low <= start && end <= high)
+            .collect();
+        ranges.sort_unstable();
+        ranges
+    }
+
+    #[test]
+    fn test_move_heap_moves_registration() {
+        unsafe {
+            let heap_size = 8192;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let at = |offset| NonNull::new(mem.add(offset)).unwrap();
+            let (low, high) = (mem as usize, mem as usize + 4096);
+
+            let mut heap: Heap<9> = Heap::new(at(0), 4096).unwrap();
+            assert_eq!(std::vec![(low, high)], live(mem, heap_size));
+            heap.move_heap(at(4096), 4096).unwrap();
+            assert_eq!(std::vec![(high, high + 4096)], live(mem, heap_size));
+
+            // The old memory is free for another heap.
+            let other: Heap<9> = Heap::new(at(0), 4096).unwrap();
+            drop((heap, other));
+            assert!(live(mem, heap_size).is_empty());
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_migrate_moves_registration() {
+        unsafe {
+            let heap_size = 8192;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let at = |offset| NonNull::new(mem.add(offset)).unwrap();
+            let (low, high) = (mem as usize, mem as usize + 4096);
+
+            let mut heap: Heap<9> = Heap::new(at(0), 4096).unwrap();
+            let small = core::alloc::Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(small).unwrap();
+            heap.migrate(at(4096), |src, dst, len| core::ptr::copy(src, dst, len))
+                .unwrap();
+            assert_eq!(std::vec![(high, high + 4096)], live(mem, heap_size));
+
+            // The old memory is free for another heap.
+            let other: Heap<9> = Heap::new(at(0), 4096).unwrap();
+            assert_eq!(
+                std::vec![(low, high), (high, high + 4096)],
+                live(mem, heap_size)
+            );
+            heap.deallocate(block.add(4096), small);
+            drop((heap, other));
+            assert!(live(mem, heap_size).is_empty());
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_shrink_heap_to_fit_releases_tail() {
+        unsafe {
+            let heap_size = 8192;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let at = |offset| NonNull::new(mem.add(offset)).unwrap();
+            let (low, high) = (mem as usize, mem as usize + 4096);
+
+            let mut heap: Heap<10> = Heap::new(at(0), heap_size).unwrap();
+            heap.shrink_heap_to_fit(4096).unwrap();
+            assert_eq!(std::vec![(low, high)], live(mem, heap_size));
+
+            // The trimmed half can be a heap of its own.
+            let tail: Heap<9> = Heap::new(at(4096), 4096).unwrap();
+            assert_eq!(
+                std::vec![(low, high), (high, high + 4096)],
+                live(mem, heap_size)
+            );
+            drop((heap, tail));
+            assert!(live(mem, heap_size).is_empty());
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_drop_on_another_thread() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+
+            // A heap moved to another thread and dropped there is gone from
+            // this one's point of view too.
+            let heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            std::thread::spawn(move || drop(heap)).join().unwrap();
+            assert!(live(mem, heap_size).is_empty());
+            let heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // And one made on another thread is seen here.
+            let base = mem as usize;
+            let other = std::thread::spawn(move || {
+                let result = std::panic::catch_unwind(|| {
+                    let _: Heap<9> =
+                        Heap::new(NonNull::new(base as *mut u8).unwrap(), heap_size).unwrap();
+                });
+                result.is_err()
+            });
+            assert!(other.join().unwrap());
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! A page allocator interface over a heap, for kernel code which only
+//! ever wants whole pages, such as page tables and DMA buffers.
+use core::alloc::Layout;
+
+use crate::paging::PAGE_SIZE;
+use crate::Heap;
+
+/// The address of a page, as a plain integer.  The heap hands out pages
+/// at their addresses in its own address space, so on a kernel with
+/// physical memory mapped at some offset this has to be subtracted to get
+/// the real physical address.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct PhysAddr(pub usize);
+
+/// Something which hands out memory one 4 KiB page at a time.
+pub trait PageAllocator {
+    /// Allocate a page, aligned to its size, or return `None` if there
+    /// isn't one free.
+    fn alloc_page(&mut self) -> Option<PhysAddr>;
+
+    /// Give back a page.
+    ///
+    /// # Safety
+    /// `addr` must have come from [PageAllocator::alloc_page] on this
+    /// allocator, and not have been freed since.
+    unsafe fn free_page(&mut self, addr: PhysAddr);
+}
+
+/// The [PageAllocator] returned by [Heap::make_page_allocator].
+struct HeapPages<'a, const N: usize> {
+    heap: &'a mut Heap<N>,
+    /// The layout of one page, or `None` if the heap can't hold one.
+    page: Option<Layout>,
+}
+
+impl<const N: usize> Heap<N> {
+    /// Borrow the heap as a [PageAllocator], which allocates blocks of the
+    /// order that holds exactly one page, `log2(4096 / min_block_size)`.
+    /// The order is worked out once, here.  If the heap's minimum block is
+    /// bigger than a page, each page takes a whole minimum block, and if
+    /// the heap is smaller than a page, no pages can be allocated at all.
+    ///
+    /// Pages come from the same free lists as everything else, and count
+    /// in the heap's statistics like any other allocation.
+    pub fn make_page_allocator(&mut self) -> impl PageAllocator + '_ {
+        let page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE)
+            .ok()
+            .filter(|&page| self.order_and_waste(page).is_ok());
+        HeapPages { heap: self, page }
+    }
+}
+
+impl<const N: usize> PageAllocator for HeapPages<'_, N> {
+    fn alloc_page(&mut self) -> Option<PhysAddr> {
+        let block = self.heap.allocate(self.page?).ok()?;
+        Some(PhysAddr(block as usize))
+    }
+
+    unsafe fn free_page(&mut self, addr: PhysAddr) {
+        match self.page {
+            Some(page) => self.heap.deallocate(addr.0 as *mut u8, page),
+            None => heap_panic!("Tried to free a page the heap couldn't have allocated"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_page_allocator() {
+        unsafe {
+            let heap_size = 64 * 1024;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<13> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Sixteen pages fit, each page-aligned and distinct, and then
+            // the heap is full.
+            let mut pages = heap.make_page_allocator();
+            let mut got: Vec<PhysAddr> = (0..16).map(|_| pages.alloc_page().unwrap()).collect();
+            assert_eq!(None, pages.alloc_page());
+            for addr in &got {
+                assert_eq!(0, addr.0 % PAGE_SIZE);
+                assert!((mem as usize..mem as usize + heap_size).contains(&addr.0));
+            }
+            got.sort();
+            got.dedup();
+            assert_eq!(16, got.len());
+
+            for addr in got {
+                pages.free_page(addr);
+            }
+            drop(pages);
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(12, mem));
+            drop(heap);
+
+            // A heap smaller than a page has none to give.
+            let mut small: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), 256).unwrap();
+            assert_eq!(None, small.make_page_allocator().alloc_page());
+
+            drop(small);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
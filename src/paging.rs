@@ -0,0 +1,137 @@
+//! Support for heaps whose memory is only backed on demand, such as guest
+//! RAM under a hypervisor that maps pages lazily.
+#[cfg(feature = "demand-paging")]
+use core::mem::transmute;
+#[cfg(feature = "demand-paging")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "demand-paging")]
+use crate::Heap;
+
+/// The granularity pages are backed in.
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+/// Backs the pages of a demand-paged heap as they're first touched, from a
+/// page fault handler.
+///
+/// A fault can hit while the heap is in the middle of writing a free block
+/// header, with a `&mut Heap` live further up the stack (and, behind a
+/// [crate::LockedHeap], its lock held), so the handler mustn't touch the
+/// heap itself.  Instead this keeps its own copy of the heap's range and
+/// page allocator in atomics, taken by [PageFaultHandler::attach], and
+/// usually lives in a `static` where the fault handler can reach it:
+///
+/// ```ignore
+/// static PAGER: PageFaultHandler = PageFaultHandler::new();
+///
+/// PAGER.attach(&heap, map_guest_page);
+/// // ... and in the page fault exception handler:
+/// if PAGER.handle(faulting_address) { return; /* retry the access */ }
+/// ```
+///
+/// Creating a heap doesn't touch its memory, so the whole heap can start
+/// out unbacked.
+#[cfg(feature = "demand-paging")]
+#[derive(Debug)]
+pub struct PageFaultHandler {
+    base: AtomicUsize,
+    size: AtomicUsize,
+    /// The page allocator, as a `usize`, or 0 if there isn't one.
+    page_allocator: AtomicUsize,
+}
+
+#[cfg(feature = "demand-paging")]
+impl PageFaultHandler {
+    /// A handler which isn't attached to a heap yet, and so handles
+    /// nothing.
+    pub const fn new() -> Self {
+        Self {
+            base: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            page_allocator: AtomicUsize::new(0),
+        }
+    }
+
+    /// Handle faults in `heap`'s memory with `page_allocator`, which is
+    /// called with the address of the 4 KiB page that faulted, and should
+    /// map memory there, returning whether it succeeded.
+    ///
+    /// This copies the heap's range, so attach it again after moving or
+    /// growing the heap.  It shouldn't race with [PageFaultHandler::handle]
+    /// for another heap.
+    pub fn attach<const N: usize>(&self, heap: &Heap<N>, page_allocator: fn(usize) -> bool) {
+        self.page_allocator.store(0, Ordering::Relaxed);
+        self.base.store(heap.heap_base as usize, Ordering::Relaxed);
+        self.size.store(heap.heap_size, Ordering::Relaxed);
+        self.page_allocator
+            .store(page_allocator as usize, Ordering::Release);
+    }
+
+    /// Handle a page fault at `faulting_addr`.  If the address is inside
+    /// the attached heap, this backs its page with the page allocator and
+    /// returns whether that succeeded, after which the faulting access can
+    /// be retried.  Returns false for addresses outside the heap, or if no
+    /// heap is attached, so the fault can be handled elsewhere.
+    pub fn handle(&self, faulting_addr: usize) -> bool {
+        let page_allocator = self.page_allocator.load(Ordering::Acquire);
+        if page_allocator == 0 {
+            return false;
+        }
+        let base = self.base.load(Ordering::Relaxed);
+        match faulting_addr.checked_sub(base) {
+            Some(offset) if offset < self.size.load(Ordering::Relaxed) => {}
+            _ => return false,
+        }
+
+        // SAFETY: `attach` stored it from a `fn(usize) -> bool`.
+        let page_allocator: fn(usize) -> bool = unsafe { transmute(page_allocator) };
+        page_allocator(faulting_addr & !(PAGE_SIZE - 1))
+    }
+}
+
+#[cfg(feature = "demand-paging")]
+impl Default for PageFaultHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "demand-paging"))]
+mod test {
+    use super::*;
+
+    /// The last page our mock page allocator was asked to back.
+    static LAST_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+    fn mock_page_allocator(page: usize) -> bool {
+        LAST_PAGE.store(page, Ordering::Relaxed);
+        page != 0x1_3000
+    }
+
+    #[test]
+    fn test_page_fault_handler() {
+        let heap: Heap<16> = unsafe { Heap::new_unchecked(0x1_0000 as *mut u8, 0x8000) };
+        let pager = PageFaultHandler::new();
+
+        // Nothing to call yet.
+        assert!(!pager.handle(0x1_0000));
+
+        pager.attach(&heap, mock_page_allocator);
+        assert!(pager.handle(0x1_0000));
+        assert_eq!(0x1_0000, LAST_PAGE.load(Ordering::Relaxed));
+        assert!(pager.handle(0x1_2abc));
+        assert_eq!(0x1_2000, LAST_PAGE.load(Ordering::Relaxed));
+        assert!(pager.handle(0x1_7fff));
+        assert_eq!(0x1_7000, LAST_PAGE.load(Ordering::Relaxed));
+
+        // The page allocator can fail.
+        assert!(!pager.handle(0x1_3008));
+
+        // Faults outside the heap are left alone.
+        LAST_PAGE.store(0, Ordering::Relaxed);
+        assert!(!pager.handle(0xffff));
+        assert!(!pager.handle(0x1_8000));
+        assert!(!pager.handle(0));
+        assert_eq!(0, LAST_PAGE.load(Ordering::Relaxed));
+    }
+}
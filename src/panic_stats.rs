@@ -0,0 +1,299 @@
+//! A plain-text report of a heap's state for crash logs, which can be
+//! written from a panic handler: nothing is allocated, and a heap whose
+//! lock is held is never waited for.
+#[cfg(feature = "debug-info")]
+use core::convert::TryFrom;
+use core::fmt;
+
+#[cfg(feature = "debug-info")]
+use crate::AllocationError;
+use crate::Heap;
+#[cfg(target_has_atomic = "8")]
+use crate::LockedHeap;
+
+impl<const N: usize> Heap<N> {
+    /// Write a few lines about the heap to `w`: its usage, the running
+    /// totals, the number of free blocks of each order, and with the
+    /// `debug-info` feature, the last allocation failure.  For example:
+    ///
+    /// ```text
+    /// heap: 4096 bytes at 0x7f3a1c000000, 16 byte blocks
+    /// used 64 bytes, free 4032 bytes, reserved 0 bytes
+    /// allocations 1, deallocations 0, failures 1
+    /// free blocks by order: 0:0 1:0 2:1 3:1 4:1 5:1 6:1 7:1 8:0
+    /// last failure: 8192 bytes aligned to 8, allocation larger than the heap
+    /// ```
+    ///
+    /// Nothing is allocated, so this can be called from a panic handler
+    /// with a `w` which writes to a UART or a fixed buffer.  Each free
+    /// list is walked no further than the number of blocks of its order
+    /// the heap could hold, so a corrupt list can't hang it.
+    pub fn write_stats_report<W: fmt::Write>(&self, mut w: W) -> fmt::Result {
+        writeln!(
+            w,
+            "heap: {} bytes at {:p}, {} byte blocks",
+            self.heap_size, self.heap_base, self.min_block_size
+        )?;
+        writeln!(
+            w,
+            "used {} bytes, free {} bytes, reserved {} bytes",
+            self.used_bytes(),
+            self.free_bytes(),
+            self.reserved_bytes()
+        )?;
+        writeln!(
+            w,
+            "allocations {}, deallocations {}, failures {}",
+            self.allocations, self.deallocations, self.failures
+        )?;
+
+        w.write_str("free blocks by order:")?;
+        for order in 0..N {
+            write!(w, " {}:{}", order, self.bounded_free_list_len(order))?;
+        }
+        w.write_str("\n")?;
+
+        #[cfg(feature = "debug-info")]
+        {
+            let failure = self.debug_info.last_failure;
+            match AllocationError::try_from(failure.reason) {
+                Ok(error) => writeln!(
+                    w,
+                    "last failure: {} bytes aligned to {}, {}",
+                    failure.size, failure.align, error
+                )?,
+                Err(_) => w.write_str("last failure: none\n")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The length of the free list for `order`, counting no further than
+    /// the number of blocks of that order which fit in the heap.
+    fn bounded_free_list_len(&self, order: usize) -> usize {
+        let mut block = self.free_lists[order];
+        let mut len = 0;
+        while !block.is_null() && len < self.heap_size / self.order_size(order) {
+            len += 1;
+            // As in `free_list_pop`, the whole-heap block's `next` pointer
+            // may never have been written.
+            if order == N - 1 {
+                break;
+            }
+            // SAFETY: Blocks on the free lists are ours to read.
+            block = unsafe { (*block).next };
+        }
+        len
+    }
+}
+
+#[cfg(target_has_atomic = "8")]
+impl<const N: usize> LockedHeap<N> {
+    /// Write [Heap::write_stats_report] for the heap to `w`, if it isn't
+    /// locked.  If it is, say the panic came from inside the allocator,
+    /// then waiting for it would never end, so this writes what
+    /// [LockedHeap::stats_relaxed] has instead, without the free block
+    /// counts or the last failure.
+    pub fn write_stats_report<W: fmt::Write>(&self, mut w: W) -> fmt::Result {
+        if let Some(heap) = self.try_lock() {
+            return heap.write_stats_report(w);
+        }
+
+        w.write_str("heap locked, showing its counters as of the last unlock\n")?;
+        #[cfg(target_has_atomic = "ptr")]
+        {
+            let stats = self.stats_relaxed();
+            writeln!(
+                w,
+                "used {} bytes, free {} bytes, peak {} bytes",
+                stats.used_bytes, stats.free_bytes, stats.peak_used_bytes
+            )?;
+            writeln!(
+                w,
+                "allocations {}, deallocations {}, failures {}",
+                stats.allocations, stats.deallocations, stats.failures
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How much of the report [install_panic_stats_hook] writes.
+#[cfg(all(feature = "std", target_has_atomic = "8"))]
+const REPORT_SIZE: usize = 2048;
+
+/// Add a panic hook which writes [LockedHeap::write_stats_report] for
+/// `heap` to stderr, after whatever the hook installed before it (by
+/// default, the panic message) has run.
+///
+/// The report is formatted into a buffer on the stack and written out in
+/// one go, so the hook doesn't allocate, and works even when the panic
+/// was an allocation failure.  Anything past the first 2 KiB is cut off.
+#[cfg(all(feature = "std", target_has_atomic = "8"))]
+pub fn install_panic_stats_hook<const N: usize>(heap: &'static LockedHeap<N>) {
+    install_panic_stats_hook_with(heap, |report| {
+        use std::io::Write;
+        let _ = std::io::stderr().write_all(report);
+    })
+}
+
+/// [install_panic_stats_hook], with `sink` writing out the report, so
+/// tests can capture it.
+#[cfg(all(feature = "std", target_has_atomic = "8"))]
+fn install_panic_stats_hook_with<const N: usize>(heap: &'static LockedHeap<N>, sink: fn(&[u8])) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(std::boxed::Box::new(move |info| {
+        previous(info);
+
+        let mut report = ReportBuffer {
+            buf: [0; REPORT_SIZE],
+            len: 0,
+        };
+        // An error only means the report was cut off.
+        let _ = heap.write_stats_report(&mut report);
+        sink(&report.buf[..report.len]);
+    }));
+}
+
+/// A fixed buffer to format a report into, which fails once it's full.
+#[cfg(all(feature = "std", target_has_atomic = "8"))]
+struct ReportBuffer {
+    buf: [u8; REPORT_SIZE],
+    len: usize,
+}
+
+#[cfg(all(feature = "std", target_has_atomic = "8"))]
+impl fmt::Write for ReportBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buf.len() - self.len;
+        let n = s.len().min(room);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        if n < s.len() {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::string::String;
+
+    #[test]
+    fn test_write_stats_report() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            let block = heap
+                .allocate(Layout::from_size_align(64, 8).unwrap())
+                .unwrap();
+            let _ = heap.allocate(Layout::from_size_align(8192, 8).unwrap());
+
+            let mut report = String::new();
+            heap.write_stats_report(&mut report).unwrap();
+            let mut lines = report.lines();
+            assert_eq!(
+                std::format!("heap: 4096 bytes at {:p}, 16 byte blocks", mem),
+                lines.next().unwrap()
+            );
+            assert_eq!(
+                "used 64 bytes, free 4032 bytes, reserved 0 bytes",
+                lines.next().unwrap()
+            );
+            #[cfg(feature = "stats")]
+            assert_eq!(
+                "allocations 1, deallocations 0, failures 1",
+                lines.next().unwrap()
+            );
+            #[cfg(not(feature = "stats"))]
+            assert_eq!(
+                "allocations 0, deallocations 0, failures 0",
+                lines.next().unwrap()
+            );
+            assert_eq!(
+                "free blocks by order: 0:0 1:0 2:1 3:1 4:1 5:1 6:1 7:1 8:0",
+                lines.next().unwrap()
+            );
+            #[cfg(feature = "debug-info")]
+            assert_eq!(
+                "last failure: 8192 bytes aligned to 8, allocation larger than the heap",
+                lines.next().unwrap()
+            );
+            assert_eq!(None, lines.next());
+
+            heap.deallocate(block, Layout::from_size_align(64, 8).unwrap());
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[cfg(all(feature = "std", feature = "stats", target_has_atomic = "8"))]
+    #[test]
+    fn test_panic_stats_hook() {
+        use std::sync::Mutex;
+
+        // The hook sees every panic in the process, so only keep reports
+        // from this test's threads.
+        static CAPTURED: Mutex<String> = Mutex::new(String::new());
+        fn capture(report: &[u8]) {
+            if std::thread::current().name() == Some("panic-stats") {
+                let report = std::str::from_utf8(report).unwrap();
+                CAPTURED.lock().unwrap().push_str(report);
+            }
+        }
+        fn panic_in_thread(f: impl FnOnce() + Send + 'static) -> String {
+            let thread = std::thread::Builder::new().name("panic-stats".into());
+            assert!(thread.spawn(f).unwrap().join().is_err());
+            core::mem::take(&mut *CAPTURED.lock().unwrap())
+        }
+
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let heap: &'static LockedHeap<9> =
+                std::boxed::Box::leak(std::boxed::Box::new(LockedHeap::new(heap)));
+            install_panic_stats_hook_with(heap, capture);
+
+            let small = Layout::from_size_align(64, 8).unwrap();
+            let report = panic_in_thread(move || {
+                heap.lock().allocate(small).unwrap();
+                panic!("out of buffers");
+            });
+            let mut expected = String::new();
+            heap.lock().write_stats_report(&mut expected).unwrap();
+            assert_eq!(expected, report);
+            assert!(report.contains("\nused 64 bytes, free 4032 bytes, reserved 0 bytes\n"));
+
+            // A panic with the heap locked gets the relaxed counters.
+            let report = panic_in_thread(move || {
+                let _heap = heap.lock();
+                panic!("inside the allocator");
+            });
+            assert_eq!(
+                "heap locked, showing its counters as of the last unlock\n\
+                 used 64 bytes, free 4032 bytes, peak 64 bytes\n\
+                 allocations 1, deallocations 0, failures 0\n",
+                report
+            );
+            let _ = std::panic::take_hook();
+
+            // A full buffer cuts the report off rather than failing.
+            let mut short = ReportBuffer {
+                buf: [0; REPORT_SIZE],
+                len: REPORT_SIZE - 20,
+            };
+            assert!(heap.write_stats_report(&mut short).is_err());
+            assert_eq!(&expected.as_bytes()[..20], &short.buf[REPORT_SIZE - 20..]);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! Per-callsite allocation profiling, written out in the JSON format used
+//! by [DHAT](https://valgrind.org/docs/manual/dh-manual.html), so the
+//! result can be loaded straight into the DHAT viewer
+//! (`dh_view.html`).
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::panic::Location;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::string::{String, ToString};
+use std::sync::Mutex;
+use std::vec::Vec;
+
+/// The most stack frames we'll record for a single allocation.
+const MAX_FRAMES: usize = 32;
+
+/// One frame of the call stack an allocation was made from.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Frame {
+    /// An instruction pointer, from a captured backtrace.
+    Ip(usize),
+    /// A source location, from `#[track_caller]`.
+    Caller(&'static Location<'static>),
+}
+
+/// Statistics for a single allocation site.  The field names follow the
+/// DHAT JSON format.
+#[derive(Debug, Default)]
+struct Site {
+    frames: Vec<Frame>,
+    total_bytes: u64,
+    total_blocks: u64,
+    total_lifetime: u64,
+    curr_bytes: u64,
+    curr_blocks: u64,
+    max_bytes: u64,
+    max_blocks: u64,
+    gmax_bytes: u64,
+    gmax_blocks: u64,
+}
+
+/// A live, sampled allocation.
+#[derive(Debug)]
+struct Live {
+    site: usize,
+    size: u64,
+    born: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    sites: Vec<Site>,
+    site_index: HashMap<Vec<Frame>, usize>,
+    live: HashMap<usize, Live>,
+    curr_bytes: u64,
+    gmax_bytes: u64,
+    t_gmax: u64,
+}
+
+/// Records allocation statistics per call site.  See [Profiled] for how to
+/// use it.
+#[derive(Debug)]
+pub struct Profiler {
+    /// Record one out of every `sample_every` allocations.
+    sample_every: usize,
+
+    /// The number of allocations seen so far, which is also our clock.
+    now: AtomicU64,
+
+    /// The number of sampled allocations that are still live, so that
+    /// deallocations can skip the lock when there's nothing to find.
+    live: AtomicUsize,
+
+    state: Mutex<Option<State>>,
+}
+
+impl Profiler {
+    /// Create a profiler which records one out of every `sample_every`
+    /// allocations.  Zero is treated as one, recording everything.
+    pub const fn new(sample_every: usize) -> Self {
+        Self {
+            sample_every,
+            now: AtomicU64::new(0),
+            live: AtomicUsize::new(0),
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Advance the clock, returning the new time if this allocation should
+    /// be sampled.
+    fn tick(&self) -> Option<u64> {
+        let now = self.now.fetch_add(1, Ordering::Relaxed);
+        if now.is_multiple_of(self.sample_every.max(1) as u64) {
+            Some(now + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Record an allocation of `size` bytes at `ptr`, attributed to the
+    /// caller's source location.  This is useful for allocations made
+    /// directly from a [Heap](crate::Heap) rather than through a
+    /// [Profiled] allocator.
+    #[track_caller]
+    pub fn record_alloc(&self, ptr: *mut u8, size: usize) {
+        let caller = Location::caller();
+        recording(|| {
+            if let Some(now) = self.tick() {
+                self.insert(&[Frame::Caller(caller)], ptr, size, now);
+            }
+        });
+    }
+
+    /// Record an allocation, attributed to the current call stack.
+    fn record_alloc_backtrace(&self, ptr: *mut u8, size: usize) {
+        recording(|| self.insert_backtrace(ptr, size));
+    }
+
+    fn insert_backtrace(&self, ptr: *mut u8, size: usize) {
+        let now = match self.tick() {
+            Some(now) => now,
+            None => return,
+        };
+
+        let mut frames = [Frame::Ip(0); MAX_FRAMES];
+        let mut len = 0;
+        unsafe {
+            backtrace::trace_unsynchronized(|frame| {
+                frames[len] = Frame::Ip(frame.ip() as usize);
+                len += 1;
+                len < MAX_FRAMES
+            });
+        }
+
+        self.insert(&frames[..len], ptr, size, now);
+    }
+
+    fn insert(&self, frames: &[Frame], ptr: *mut u8, size: usize, now: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = state.get_or_insert_with(State::default);
+
+        let site = match state.site_index.get(frames) {
+            Some(&site) => site,
+            None => {
+                let site = state.sites.len();
+                state.sites.push(Site {
+                    frames: frames.to_vec(),
+                    ..Site::default()
+                });
+                state.site_index.insert(frames.to_vec(), site);
+                site
+            }
+        };
+
+        let size = size as u64;
+        let s = &mut state.sites[site];
+        s.total_bytes += size;
+        s.total_blocks += 1;
+        s.curr_bytes += size;
+        s.curr_blocks += 1;
+        if s.curr_bytes > s.max_bytes {
+            s.max_bytes = s.curr_bytes;
+            s.max_blocks = s.curr_blocks;
+        }
+
+        state.live.insert(
+            ptr as usize,
+            Live {
+                site,
+                size,
+                born: now,
+            },
+        );
+        self.live.fetch_add(1, Ordering::Relaxed);
+
+        // If this is a new global peak, remember what every site looked
+        // like at this moment.
+        state.curr_bytes += size;
+        if state.curr_bytes > state.gmax_bytes {
+            state.gmax_bytes = state.curr_bytes;
+            state.t_gmax = now;
+            for s in state.sites.iter_mut() {
+                s.gmax_bytes = s.curr_bytes;
+                s.gmax_blocks = s.curr_blocks;
+            }
+        }
+    }
+
+    /// Record that the block at `ptr` was freed.  Blocks that weren't
+    /// sampled are ignored.
+    pub fn record_dealloc(&self, ptr: *mut u8) {
+        recording(|| self.remove(ptr));
+    }
+
+    fn remove(&self, ptr: *mut u8) {
+        if self.live.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let now = self.now.load(Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+
+        if let Some(live) = state.live.remove(&(ptr as usize)) {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+
+            let s = &mut state.sites[live.site];
+            s.curr_bytes -= live.size;
+            s.curr_blocks -= 1;
+            s.total_lifetime += now - live.born;
+            state.curr_bytes -= live.size;
+        }
+    }
+
+    /// Write the profile to a file at `path` in DHAT's JSON format.
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_json(&mut w)?;
+        w.flush()
+    }
+
+    /// Write the profile to `w` in DHAT's JSON format.
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        // Nothing we allocate while writing the profile should show up in
+        // it.  In particular, a profiled global allocator would otherwise
+        // deadlock trying to record allocations made with the lock held.
+        let _recording = Recording::enter();
+
+        let (sites, t_gmax, te) = {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let te = self.now.load(Ordering::Relaxed);
+            match state.as_ref() {
+                Some(state) => {
+                    let sites: Vec<_> = state
+                        .sites
+                        .iter()
+                        .enumerate()
+                        .map(|(i, s)| {
+                            // Blocks still live at the end count as living
+                            // until the end.
+                            let live_lifetime: u64 = state
+                                .live
+                                .values()
+                                .filter(|l| l.site == i)
+                                .map(|l| te - l.born)
+                                .sum();
+                            (
+                                s.frames.clone(),
+                                [
+                                    s.total_bytes,
+                                    s.total_blocks,
+                                    s.total_lifetime + live_lifetime,
+                                    s.max_bytes,
+                                    s.max_blocks,
+                                    s.gmax_bytes,
+                                    s.gmax_blocks,
+                                    s.curr_bytes,
+                                    s.curr_blocks,
+                                ],
+                            )
+                        })
+                        .collect();
+                    (sites, state.t_gmax, te)
+                }
+                None => (Vec::new(), 0, te),
+            }
+        };
+
+        let mut ftbl: Vec<String> = Vec::new();
+        let mut ftbl_index: HashMap<String, usize> = HashMap::new();
+        let mut frame_index = |name: String| -> usize {
+            if let Some(&i) = ftbl_index.get(&name) {
+                return i;
+            }
+            ftbl.push(name.clone());
+            ftbl_index.insert(name, ftbl.len() - 1);
+            ftbl.len() - 1
+        };
+        frame_index(String::from("[root]"));
+
+        let pps: Vec<_> = sites
+            .into_iter()
+            .map(|(frames, stats)| {
+                let fs: Vec<usize> = frames
+                    .iter()
+                    .flat_map(frame_names)
+                    .map(&mut frame_index)
+                    .collect();
+                (stats, fs)
+            })
+            .collect();
+
+        let cmd: Vec<String> = std::env::args().collect();
+
+        writeln!(w, "{{")?;
+        writeln!(w, "\"dhatFileVersion\": 2,")?;
+        writeln!(w, "\"mode\": \"rust-heap\",")?;
+        writeln!(w, "\"verb\": \"Allocated\",")?;
+        writeln!(w, "\"bklt\": true,")?;
+        writeln!(w, "\"bkacc\": false,")?;
+        writeln!(w, "\"tu\": \"allocs\",")?;
+        writeln!(w, "\"Mtu\": \"allocs\",")?;
+        writeln!(w, "\"cmd\": {},", json_string(&cmd.join(" ")))?;
+        writeln!(w, "\"pid\": {},", std::process::id())?;
+        writeln!(w, "\"tg\": {},", t_gmax)?;
+        writeln!(w, "\"te\": {},", te)?;
+        writeln!(w, "\"pps\": [")?;
+        for (i, (s, fs)) in pps.iter().enumerate() {
+            let fs: Vec<String> = fs.iter().map(|f| f.to_string()).collect();
+            writeln!(
+                w,
+                "{{\"tb\":{},\"tbk\":{},\"tl\":{},\"mb\":{},\"mbk\":{},\"gb\":{},\"gbk\":{},\"eb\":{},\"ebk\":{},\"fs\":[{}]}}{}",
+                s[0],
+                s[1],
+                s[2],
+                s[3],
+                s[4],
+                s[5],
+                s[6],
+                s[7],
+                s[8],
+                fs.join(","),
+                if i + 1 < pps.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "],")?;
+        writeln!(w, "\"ftbl\": [")?;
+        for (i, f) in ftbl.iter().enumerate() {
+            let sep = if i + 1 < ftbl.len() { "," } else { "" };
+            writeln!(w, "{}{}", json_string(f), sep)?;
+        }
+        writeln!(w, "]")?;
+        writeln!(w, "}}")
+    }
+}
+
+/// Describe a frame the way DHAT expects.  A single instruction pointer
+/// can expand into several frames if functions were inlined into it.
+fn frame_names(frame: &Frame) -> Vec<String> {
+    match *frame {
+        Frame::Caller(loc) => std::vec![std::format!(
+            "{}:{}:{}",
+            loc.file(),
+            loc.line(),
+            loc.column()
+        )],
+        Frame::Ip(ip) => {
+            let mut names = Vec::new();
+            backtrace::resolve(ip as *mut c_void, |sym| {
+                let name = sym
+                    .name()
+                    .map(|n| std::format!("{:#}", n))
+                    .unwrap_or_else(|| String::from("???"));
+                let file = sym
+                    .filename()
+                    .map(|f| f.display().to_string())
+                    .unwrap_or_else(|| String::from("???"));
+                names.push(std::format!(
+                    "{:#x}: {} ({}:{}:{})",
+                    ip,
+                    name,
+                    file,
+                    sym.lineno().unwrap_or(0),
+                    sym.colno().unwrap_or(0)
+                ));
+            });
+            if names.is_empty() {
+                names.push(std::format!("{:#x}: ???", ip));
+            }
+            names
+        }
+    }
+}
+
+/// Quote and escape `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&std::format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+std::thread_local! {
+    /// Set while we're recording an allocation, so that the allocations
+    /// the profiler makes for itself aren't recorded (and don't recurse).
+    static RECORDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks this thread as recording until dropped.
+struct Recording {
+    /// Whether we were already recording when this was created.
+    nested: bool,
+}
+
+impl Recording {
+    fn enter() -> Self {
+        // If the thread-local is gone (the thread is exiting), act as if
+        // we're already recording so nothing gets recorded.
+        let nested = RECORDING.try_with(|r| r.replace(true)).unwrap_or(true);
+        Self { nested }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if !self.nested {
+            let _ = RECORDING.try_with(|r| r.set(false));
+        }
+    }
+}
+
+/// Run `f` unless we're already recording on this thread.
+fn recording(f: impl FnOnce()) {
+    let guard = Recording::enter();
+    if !guard.nested {
+        f();
+    }
+}
+
+/// An allocator wrapper which records every allocation (or only every Nth
+/// one, to bound the overhead) in a [Profiler], attributed to the call stack
+/// it was made from.  Call [Profiler::dump] to write out the results:
+///
+/// ```no_run
+/// # use buddyalloc::{Heap, LockedHeap, Profiled};
+/// const HEAP_MEM: usize  = 0xFFF0_0000;
+/// const HEAP_SIZE: usize = 0x0008_0000;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Profiled<LockedHeap<16>> = Profiled::new(
+///     LockedHeap::new(unsafe { Heap::new_unchecked(HEAP_MEM as *mut u8, HEAP_SIZE) }),
+///     1,
+/// );
+///
+/// fn main() {
+///     // ... do some work ...
+///     ALLOCATOR.profiler().dump("dhat-heap.json").unwrap();
+/// }
+/// ```
+///
+/// Time is measured in allocations rather than instructions or seconds: a
+/// block's lifetime is the number of allocations made while it was live.
+/// When sampling, the statistics only cover the sampled allocations.
+#[derive(Debug)]
+pub struct Profiled<A> {
+    inner: A,
+    profiler: Profiler,
+}
+
+impl<A> Profiled<A> {
+    /// Wrap `inner`, sampling one out of every `sample_every` allocations.
+    pub const fn new(inner: A, sample_every: usize) -> Self {
+        Self {
+            inner,
+            profiler: Profiler::new(sample_every),
+        }
+    }
+
+    /// The profiler holding the recorded statistics.
+    pub fn profiler(&self) -> &Profiler {
+        &self.profiler
+    }
+
+    /// The wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Profiled<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            self.profiler.record_alloc_backtrace(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.profiler.record_dealloc(ptr);
+        self.inner.dealloc(ptr, layout)
+    }
+}
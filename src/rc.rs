@@ -0,0 +1,235 @@
+//! An `Rc`-like shared pointer into a [LockedHeap], for sharing immutable
+//! data without a global allocator.
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::fmt;
+use core::mem;
+use core::ops::Deref;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+
+use crate::{AllocationError, LockedHeap};
+
+/// What a [HeapRc] points to: the reference count, followed by the value.
+/// `repr(C)` keeps the count first, with the value after it padded out to
+/// its own alignment, and the block as a whole aligned for both.
+#[repr(C)]
+struct RcBlock<T> {
+    count: Cell<usize>,
+    value: T,
+}
+
+/// A reference-counted `T` stored in a [LockedHeap].  Like `Rc`, cloning it
+/// only bumps the count, and the value is dropped and its memory freed when
+/// the last clone is dropped.  The count lives in the same block as the
+/// value, so each `HeapRc` costs one allocation.
+///
+/// The count isn't atomic, so a `HeapRc` can't be sent to another thread.
+/// There are no weak references.
+pub struct HeapRc<'a, T, const N: usize> {
+    ptr: NonNull<RcBlock<T>>,
+    heap: &'a LockedHeap<N>,
+}
+
+impl<'a, T, const N: usize> HeapRc<'a, T, N> {
+    /// Move `value` into a new block allocated from `heap`, with a count of
+    /// one.
+    pub fn new(heap: &'a LockedHeap<N>, value: T) -> Result<Self, AllocationError> {
+        let block = heap.lock().allocate(Layout::new::<RcBlock<T>>())? as *mut RcBlock<T>;
+
+        // SAFETY: The heap never hands out a null block, and it's big
+        // enough and aligned for an `RcBlock<T>`.
+        unsafe {
+            block.write(RcBlock {
+                count: Cell::new(1),
+                value,
+            });
+            Ok(HeapRc {
+                ptr: NonNull::new_unchecked(block),
+                heap,
+            })
+        }
+    }
+
+    /// The number of `HeapRc`s pointing at this value.
+    pub fn strong_count(this: &Self) -> usize {
+        this.block().count.get()
+    }
+
+    /// Whether two `HeapRc`s point at the same value.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    /// A mutable reference to the value, if this is the only `HeapRc`
+    /// pointing at it.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if Self::strong_count(this) == 1 {
+            // SAFETY: Nobody else can see the value.
+            Some(unsafe { &mut this.ptr.as_mut().value })
+        } else {
+            None
+        }
+    }
+
+    /// Move the value out and free its memory, if this is the only
+    /// `HeapRc` pointing at it.  Otherwise `this` is handed back.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if Self::strong_count(&this) != 1 {
+            return Err(this);
+        }
+
+        // SAFETY: We own the only reference, and forget `this` so it isn't
+        // dropped twice.
+        unsafe {
+            let value = addr_of!((*this.ptr.as_ptr()).value).read();
+            this.free();
+            mem::forget(this);
+            Ok(value)
+        }
+    }
+
+    fn block(&self) -> &RcBlock<T> {
+        // SAFETY: The block stays alive as long as any `HeapRc` does.
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Return the block to the heap.
+    ///
+    /// # Safety
+    /// This must be the last reference, and the value must already have
+    /// been dropped or moved out.
+    unsafe fn free(&self) {
+        self.heap
+            .lock()
+            .deallocate(self.ptr.as_ptr() as *mut u8, Layout::new::<RcBlock<T>>());
+    }
+}
+
+impl<T, const N: usize> Clone for HeapRc<'_, T, N> {
+    fn clone(&self) -> Self {
+        let count = &self.block().count;
+        match count.get().checked_add(1) {
+            Some(n) => count.set(n),
+            None => heap_panic!("HeapRc count overflowed"),
+        }
+        HeapRc {
+            ptr: self.ptr,
+            heap: self.heap,
+        }
+    }
+}
+
+impl<T, const N: usize> Deref for HeapRc<'_, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.block().value
+    }
+}
+
+impl<T, const N: usize> Drop for HeapRc<'_, T, N> {
+    fn drop(&mut self) {
+        let count = &self.block().count;
+        count.set(count.get() - 1);
+        if count.get() != 0 {
+            return;
+        }
+
+        // The value is dropped before the heap is locked, so its `Drop` can
+        // use the same heap.
+        // SAFETY: This was the last reference, so nothing uses the value
+        // or the block again.
+        unsafe {
+            addr_of_mut!((*self.ptr.as_ptr()).value).drop_in_place();
+            self.free();
+        }
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for HeapRc<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::{AllocationSizeError, Heap};
+
+    /// Needs a 64-byte block of its own, and 64-byte alignment.
+    #[repr(align(64))]
+    #[derive(Debug, PartialEq)]
+    struct Aligned(u8);
+
+    /// Allocates from the heap it's in when dropped.
+    struct AllocOnDrop<'a>(&'a LockedHeap<5>, &'a Cell<bool>);
+
+    impl Drop for AllocOnDrop<'_> {
+        fn drop(&mut self) {
+            let inner = HeapRc::new(self.0, 5u32).unwrap();
+            self.1.set(*inner == 5);
+        }
+    }
+
+    #[test]
+    fn test_heap_rc() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: LockedHeap<5> =
+                LockedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            {
+                let mut a = HeapRc::new(&heap, 7u64).unwrap();
+                *HeapRc::get_mut(&mut a).unwrap() += 1;
+                let b = a.clone();
+                assert_eq!(8, *b);
+                assert_eq!(2, HeapRc::strong_count(&a));
+                assert!(HeapRc::ptr_eq(&a, &b));
+                assert_eq!(None, HeapRc::get_mut(&mut a));
+
+                // Sharing it doesn't allocate again.
+                assert_eq!(16, heap.lock().used_bytes());
+                let b = HeapRc::try_unwrap(b).unwrap_err();
+                drop(a);
+                assert_eq!(16, heap.lock().used_bytes());
+                assert_eq!(Some(8), HeapRc::try_unwrap(b).ok());
+                assert_eq!(0, heap.lock().used_bytes());
+
+                // The count goes in front, so the value is pushed out to
+                // its alignment and the whole thing takes two 64-byte
+                // blocks' worth.
+                let c = HeapRc::new(&heap, Aligned(3)).unwrap();
+                assert_eq!(0, &*c as *const Aligned as usize % 64);
+                assert_eq!(mem.add(64), &*c as *const Aligned as *mut u8);
+                assert_eq!(128, heap.lock().used_bytes());
+                let d = c.clone();
+                drop(c);
+                assert_eq!(Aligned(3), *d);
+                drop(d);
+                assert_eq!(0, heap.lock().used_bytes());
+
+                // The value is dropped without the heap locked.
+                let ran = Cell::new(false);
+                let e = HeapRc::new(&heap, AllocOnDrop(&heap, &ran)).unwrap();
+                drop(e);
+                assert!(ran.get());
+
+                // With its count, this no longer fits in the heap.
+                assert_eq!(
+                    Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                    HeapRc::new(&heap, [0u8; 256]).map(|_| ())
+                );
+            }
+            assert_eq!(0, heap.lock().used_bytes());
+            assert!(heap.lock().accounting_check());
+            heap.lock().assert_alignment_invariants();
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! A table of named heaps, so that a debug console or the like can list
+//! every heap in the system and its usage without each subsystem wiring
+//! its heap up to it.
+//!
+//! The table has room for [CAPACITY] heaps, in a static, and never
+//! allocates.  A heap is listed from [register] until the [Registration]
+//! it returns is dropped:
+//!
+//! ```
+//! # use buddyalloc::{Heap, LockedHeap};
+//! # use core::ptr::NonNull;
+//! # #[repr(align(4096))]
+//! # struct Memory([u8; 4096]);
+//! # let memory = Box::leak(Box::new(Memory([0; 4096])));
+//! # let heap: Heap<6> = unsafe { Heap::new(NonNull::from(&mut memory.0).cast(), 4096) }.unwrap();
+//! let dma_heap: &'static LockedHeap<6> = Box::leak(Box::new(LockedHeap::new(heap)));
+//! let registration = buddyalloc::registry::register("dma", dma_heap).unwrap();
+//! for entry in buddyalloc::registry::iter() {
+//!     println!("{}: {} bytes used", entry.name, entry.stats.used_bytes);
+//! }
+//! # drop(registration);
+//! ```
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{LockedHeap, RelaxedStats};
+
+/// The most heaps the table can hold at once.
+pub const CAPACITY: usize = 8;
+
+/// A heap in the table.
+#[derive(Clone, Copy)]
+struct Slot {
+    name: &'static str,
+    heap: *const (),
+    /// Reads the stats of `heap`, a `LockedHeap` of the right `N`.
+    stats: unsafe fn(*const ()) -> RelaxedStats,
+}
+
+struct Table {
+    lock: AtomicBool,
+    slots: UnsafeCell<[Option<Slot>; CAPACITY]>,
+}
+
+// SAFETY: The slots are only touched under the lock, and the heaps in them
+// are `Sync`.
+unsafe impl Sync for Table {}
+
+static TABLE: Table = Table {
+    lock: AtomicBool::new(false),
+    slots: UnsafeCell::new([None; CAPACITY]),
+};
+
+impl Table {
+    /// Run `f` on the slots, holding the lock.
+    fn with_slots<R>(&self, f: impl FnOnce(&mut [Option<Slot>; CAPACITY]) -> R) -> R {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.lock.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+
+        // SAFETY: We hold the lock.
+        let result = f(unsafe { &mut *self.slots.get() });
+        self.lock.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// A heap as [iter] lists it.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// The name it was registered with.
+    pub name: &'static str,
+    /// The address of the [LockedHeap].  With the `debug-info` feature, a
+    /// debugger can find its [crate::HeapDebugInfo] near here by the magic
+    /// number.
+    pub heap: *const (),
+    /// Its usage, read without taking its lock.
+    pub stats: RelaxedStats,
+}
+
+/// Keeps a heap in the table until it's dropped.  To list a heap for good,
+/// [core::mem::forget] this.
+#[must_use = "the heap is removed from the registry when this is dropped"]
+#[derive(Debug)]
+pub struct Registration {
+    slot: usize,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        TABLE.with_slots(|slots| slots[self.slot] = None);
+    }
+}
+
+/// [register] found the table full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegistryFull;
+
+impl fmt::Display for RegistryFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("heap registry full")
+    }
+}
+
+impl core::error::Error for RegistryFull {}
+
+/// Add `heap` to the table as `name`, failing if it already holds
+/// [CAPACITY] heaps.  The heap has to be `'static`, since a [Registration]
+/// can be forgotten.  Registering a heap twice lists it twice.
+pub fn register<const N: usize>(
+    name: &'static str,
+    heap: &'static LockedHeap<N>,
+) -> Result<Registration, RegistryFull> {
+    unsafe fn stats<const N: usize>(heap: *const ()) -> RelaxedStats {
+        (*(heap as *const LockedHeap<N>)).stats_relaxed()
+    }
+
+    let entry = Slot {
+        name,
+        heap: heap as *const LockedHeap<N> as *const (),
+        stats: stats::<N>,
+    };
+    TABLE.with_slots(|slots| {
+        let slot = slots.iter().position(Option::is_none).ok_or(RegistryFull)?;
+        slots[slot] = Some(entry);
+        Ok(Registration { slot })
+    })
+}
+
+/// Every registered heap, in no particular order.  The table is only
+/// locked for a moment at each step, so heaps can come and go while this
+/// runs; each one still listed when the iterator reaches its slot shows
+/// up exactly once.
+pub fn iter() -> Iter {
+    Iter { next: 0 }
+}
+
+/// The iterator returned by [iter].
+#[derive(Debug)]
+pub struct Iter {
+    next: usize,
+}
+
+impl Iterator for Iter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let (slot, found) = TABLE.with_slots(|slots| {
+            slots[self.next.min(CAPACITY)..]
+                .iter()
+                .enumerate()
+                .find_map(|(i, slot)| slot.map(|slot| (self.next + i, slot)))
+        })?;
+        self.next = slot + 1;
+
+        Some(Entry {
+            name: found.name,
+            heap: found.heap,
+            // SAFETY: The heap is `'static`, and `stats` is for its `N`.
+            stats: unsafe { (found.stats)(found.heap) },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::Heap;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    // The table is shared by the whole process, so this is the only test
+    // that uses it.
+    #[test]
+    fn test_registry() {
+        unsafe {
+            // The heaps are leaked, as registered heaps have to be.
+            let mem =
+                std::alloc::alloc(std::alloc::Layout::from_size_align(16 * 4096, 4096).unwrap());
+            let heap = |i: usize| -> &'static LockedHeap<5> {
+                let base = NonNull::new(mem.add(i * 4096)).unwrap();
+                std::boxed::Box::leak(std::boxed::Box::new(LockedHeap::new(
+                    Heap::new(base, 256).unwrap(),
+                )))
+            };
+            let names = |entries: &mut dyn Iterator<Item = Entry>| {
+                entries.map(|entry| entry.name).collect::<Vec<_>>()
+            };
+
+            let general = heap(0);
+            let dma = heap(1);
+            let core0 = heap(2);
+            let general_reg = register("general", general).unwrap();
+            let dma_reg = register("dma", dma).unwrap();
+            let core0_reg = register("core0", core0).unwrap();
+            assert_eq!(["general", "dma", "core0"], names(&mut iter())[..]);
+
+            // The stats are live.
+            let block = dma
+                .lock()
+                .allocate(Layout::from_size_align(64, 8).unwrap())
+                .unwrap();
+            let entry = iter().find(|entry| entry.name == "dma").unwrap();
+            assert_eq!(dma as *const LockedHeap<5> as *const (), entry.heap);
+            assert_eq!(64, entry.stats.used_bytes);
+            #[cfg(feature = "stats")]
+            assert_eq!(1, entry.stats.allocations);
+            dma.lock()
+                .deallocate(block, Layout::from_size_align(64, 8).unwrap());
+
+            // Dropping a registration takes the heap out, and frees its slot
+            // for the next one.
+            drop(dma_reg);
+            assert_eq!(["general", "core0"], names(&mut iter())[..]);
+            let core1_reg = register("core1", heap(3)).unwrap();
+            assert_eq!(["general", "core1", "core0"], names(&mut iter())[..]);
+
+            // A heap leaving mid-iteration is skipped if the iterator hasn't
+            // reached it yet.
+            let mut entries = iter();
+            assert_eq!("general", entries.next().unwrap().name);
+            drop(core0_reg);
+            assert_eq!(["core1"], names(&mut entries)[..]);
+
+            // Fill the table up.
+            let mut more: Vec<_> = (0..CAPACITY - 2)
+                .map(|i| register("more", heap(4 + i)).unwrap())
+                .collect();
+            assert_eq!(CAPACITY, iter().count());
+            assert_eq!(RegistryFull, register("full", heap(15)).unwrap_err());
+            more.pop();
+            assert!(register("room", heap(14)).is_ok());
+
+            drop((general_reg, core1_reg, more));
+            assert_eq!(0, iter().count());
+        }
+    }
+}
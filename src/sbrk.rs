@@ -0,0 +1,144 @@
+//! An `sbrk`-style interface, for porting C allocators which grow their
+//! arena by moving a program break.
+use core::alloc::Layout;
+
+use crate::{AllocationError, Heap};
+
+/// Why [Heap::sbrk_compatibility_layer] couldn't move the break.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SbrkError {
+    /// The memory past the break is in use or beyond the end of the heap.
+    /// This is what [AllocationError::HeapExhausted] becomes.
+    InsufficientMemory,
+    /// The break would have moved below the start of the heap.
+    BelowBase,
+}
+
+impl From<AllocationError> for SbrkError {
+    fn from(_: AllocationError) -> SbrkError {
+        SbrkError::InsufficientMemory
+    }
+}
+
+impl<const N: usize> Heap<N> {
+    /// Move the heap's "program break" by `increment` bytes, as `sbrk`
+    /// does, and return where it was before.  The break starts at the base
+    /// of the heap, so the memory from there up to it is one contiguous
+    /// arena, which a C allocator built on `sbrk` can carve up as it likes.
+    ///
+    /// The arena is backed by minimum-sized blocks allocated at the break,
+    /// so growing it fails with [SbrkError::InsufficientMemory] if any of
+    /// that memory has been allocated some other way.  Shrinking it frees
+    /// the blocks it no longer reaches.  On failure the break doesn't move.
+    pub fn sbrk_compatibility_layer(&mut self, increment: isize) -> Result<*mut u8, SbrkError> {
+        let old = self.brk;
+        let new = if increment < 0 {
+            old.checked_sub(increment.unsigned_abs())
+                .ok_or(SbrkError::BelowBase)?
+        } else {
+            match old.checked_add(increment as usize) {
+                Some(new) if new <= self.heap_size => new,
+                _ => return Err(SbrkError::InsufficientMemory),
+            }
+        };
+
+        // The blocks covering the arena end at the break, rounded up.
+        let (old_end, new_end) = (self.round_up(old), self.round_up(new));
+        let block = self.min_block_layout();
+        let mut end = old_end;
+        while end < new_end {
+            match self.allocate_at_offset(end, block) {
+                Ok(_) => end += self.min_block_size,
+                Err(e) => {
+                    self.release_arena(old_end, end);
+                    return Err(e.into());
+                }
+            }
+        }
+        self.release_arena(new_end, old_end);
+
+        self.brk = new;
+        Ok(self.heap_base.wrapping_add(old))
+    }
+
+    /// Round `offset` up to a whole number of minimum-sized blocks.
+    fn round_up(&self, offset: usize) -> usize {
+        (offset + self.min_block_size - 1) & !(self.min_block_size - 1)
+    }
+
+    /// The layout of one minimum-sized block.
+    fn min_block_layout(&self) -> Layout {
+        // SAFETY: The block size is a nonzero power of two, and an
+        // alignment of 1 is always valid.
+        unsafe { Layout::from_size_align_unchecked(self.min_block_size, 1) }
+    }
+
+    /// Free the arena's blocks between offsets `start` and `end`.
+    fn release_arena(&mut self, start: usize, end: usize) {
+        let block = self.min_block_layout();
+        for offset in (start..end).step_by(self.min_block_size) {
+            // SAFETY: The arena allocated every block below its end.
+            unsafe { self.deallocate(self.heap_base.wrapping_add(offset), block) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+
+    #[test]
+    fn test_sbrk() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // Each call returns the old break, and the arena behind it is
+            // backed by whole 16-byte blocks.
+            assert_eq!(Ok(mem), heap.sbrk_compatibility_layer(0));
+            assert_eq!(Ok(mem), heap.sbrk_compatibility_layer(10));
+            assert_eq!(16, heap.used_bytes());
+            assert_eq!(Ok(mem.add(10)), heap.sbrk_compatibility_layer(40));
+            assert_eq!(64, heap.used_bytes());
+            assert_eq!(Ok(mem.add(50)), heap.sbrk_compatibility_layer(-20));
+            assert_eq!(32, heap.used_bytes());
+            assert_eq!(Ok(mem.add(30)), heap.sbrk_compatibility_layer(0));
+
+            // Other allocations don't come from the arena, and one in the
+            // way stops it growing, leaving the break where it was.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let other = heap.allocate_at(mem.add(64), small).unwrap();
+            assert_eq!(
+                Err(SbrkError::InsufficientMemory),
+                heap.sbrk_compatibility_layer(64)
+            );
+            assert_eq!(48, heap.used_bytes());
+            assert_eq!(Ok(mem.add(30)), heap.sbrk_compatibility_layer(34));
+            heap.deallocate(other, small);
+
+            // The break can't leave the heap at either end.
+            assert_eq!(
+                Err(SbrkError::InsufficientMemory),
+                heap.sbrk_compatibility_layer(193)
+            );
+            assert_eq!(
+                Err(SbrkError::BelowBase),
+                heap.sbrk_compatibility_layer(-65)
+            );
+            assert_eq!(Ok(mem.add(64)), heap.sbrk_compatibility_layer(192));
+            assert_eq!(256, heap.used_bytes());
+
+            // Giving it all back leaves the heap as it started.
+            assert_eq!(Ok(mem.add(256)), heap.sbrk_compatibility_layer(-256));
+            assert_eq!(0, heap.used_bytes());
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
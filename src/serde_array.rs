@@ -0,0 +1,179 @@
+//! `serde` support for the `[T; N]` arrays in the statistics types.  serde's
+//! own implementations only go up to 32 elements, and not for a const
+//! generic `N`, so the fields use these with `#[serde(with)]`.  An array is
+//! written as a tuple of `N` elements, which is a plain array in JSON and
+//! has no length prefix in postcard.
+use core::fmt;
+use core::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+
+pub(crate) fn serialize<S, T, const N: usize>(
+    array: &[T; N],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    let mut tuple = serializer.serialize_tuple(N)?;
+    for element in array {
+        tuple.serialize_element(element)?;
+    }
+    tuple.end()
+}
+
+pub(crate) fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Copy + Default,
+{
+    deserializer.deserialize_tuple(N, ArrayVisitor(PhantomData))
+}
+
+/// Reads exactly `N` elements.
+struct ArrayVisitor<T, const N: usize>(PhantomData<T>);
+
+impl<'de, T, const N: usize> Visitor<'de> for ArrayVisitor<T, N>
+where
+    T: Deserialize<'de> + Copy + Default,
+{
+    type Value = [T; N];
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an array of length {}", N)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[T; N], A::Error> {
+        let mut array = [T::default(); N];
+        for (i, element) in array.iter_mut().enumerate() {
+            *element = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+        }
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use crate::{HeapStats, HeapStatsDelta};
+    use std::vec;
+
+    fn stats() -> HeapStats<3> {
+        HeapStats {
+            used_bytes: 16,
+            free_bytes: 240,
+            reserved_bytes: 0,
+            allocations: 2,
+            deallocations: 1,
+            failures: 0,
+            free_blocks: [1, 1, 300],
+        }
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let json = serde_json::to_string(&stats()).unwrap();
+        assert_eq!(
+            "{\"used_bytes\":16,\"free_bytes\":240,\"reserved_bytes\":0,\
+             \"allocations\":2,\"deallocations\":1,\"failures\":0,\
+             \"free_blocks\":[1,1,300]}",
+            json
+        );
+        assert_eq!(stats(), serde_json::from_str(&json).unwrap());
+
+        // The array has to be exactly `N` long.
+        for free_blocks in ["[1,1]", "[1,1,300,4]"] {
+            let json = json.replace("[1,1,300]", free_blocks);
+            assert!(serde_json::from_str::<HeapStats<3>>(&json).is_err());
+        }
+
+        let delta = stats().diff(&HeapStats {
+            used_bytes: 32,
+            free_blocks: [0, 1, 300],
+            ..stats()
+        });
+        let json = serde_json::to_string(&delta).unwrap();
+        assert_eq!(
+            "{\"used_bytes\":-16,\"free_bytes\":0,\"allocations\":0,\
+             \"deallocations\":0,\"failures\":0,\"free_blocks\":[1,0,0]}",
+            json
+        );
+        assert_eq!(
+            delta,
+            serde_json::from_str::<HeapStatsDelta<3>>(&json).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stats_postcard() {
+        // Varints, and no length prefix for the array.
+        let bytes = postcard::to_allocvec(&stats()).unwrap();
+        assert_eq!(vec![16, 0xf0, 0x01, 0, 2, 1, 0, 1, 1, 0xac, 0x02], bytes);
+        assert_eq!(stats(), postcard::from_bytes(&bytes).unwrap());
+        assert!(postcard::from_bytes::<HeapStats<3>>(&bytes[..bytes.len() - 2]).is_err());
+
+        // Signed fields are zigzag-encoded.
+        let delta = HeapStats {
+            failures: 1,
+            ..stats()
+        }
+        .diff(&stats());
+        let bytes = postcard::to_allocvec(&delta).unwrap();
+        assert_eq!(vec![0, 0, 0, 0, 2, 0, 0, 0], bytes);
+        assert_eq!(delta, postcard::from_bytes(&bytes).unwrap());
+        let back = HeapStats::<3>::diff(
+            &stats(),
+            &HeapStats {
+                used_bytes: 17,
+                ..stats()
+            },
+        );
+        assert_eq!(
+            vec![1, 0, 0, 0, 0, 0, 0, 0],
+            postcard::to_allocvec(&back).unwrap()
+        );
+    }
+
+    #[cfg(feature = "debug-info")]
+    #[test]
+    fn test_failure_info() {
+        let failure = crate::FailureInfo {
+            size: 64,
+            align: 8,
+            reason: 1,
+        };
+        let json = serde_json::to_string(&failure).unwrap();
+        assert_eq!("{\"size\":64,\"align\":8,\"reason\":1}", json);
+        assert_eq!(failure, serde_json::from_str(&json).unwrap());
+        let bytes = postcard::to_allocvec(&failure).unwrap();
+        assert_eq!(vec![64, 8, 1], bytes);
+        assert_eq!(failure, postcard::from_bytes(&bytes).unwrap());
+    }
+
+    #[cfg(target_has_atomic = "ptr")]
+    #[test]
+    fn test_relaxed_stats() {
+        let stats = crate::RelaxedStats {
+            used_bytes: 1,
+            free_bytes: 2,
+            peak_used_bytes: 3,
+            allocations: 4,
+            deallocations: 5,
+            failures: 6,
+        };
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(
+            "{\"used_bytes\":1,\"free_bytes\":2,\"peak_used_bytes\":3,\
+             \"allocations\":4,\"deallocations\":5,\"failures\":6}",
+            json
+        );
+        assert_eq!(stats, serde_json::from_str(&json).unwrap());
+        let bytes = postcard::to_allocvec(&stats).unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], bytes);
+        assert_eq!(stats, postcard::from_bytes(&bytes).unwrap());
+    }
+}
@@ -0,0 +1,439 @@
+//! A heap with a lock for each free list rather than one for the whole
+//! heap, so that threads allocating different sizes don't queue up behind
+//! each other.
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::cmp::min;
+use core::hint::spin_loop;
+use core::ptr::{self, addr_of_mut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::heap::{buddy, list_insert, list_pop, list_remove, FreeBlock, MIN_HEAP_ALIGN};
+use crate::math::{allocation_size, log2};
+use crate::{AllocationError, AllocationSizeError, Heap};
+
+/// A [Heap] shared between threads with one spin lock per order, instead
+/// of the single lock of a [crate::LockedHeap].  Allocations of different
+/// orders only meet on the shared counters, and when a block has to be
+/// split or merged across orders.  This implements [GlobalAlloc].
+///
+/// Only the buddy algorithm itself is shared this way.  The heap's hooks
+/// and side tables (a reclaim function, an emergency reserve, the
+/// allocation limit, debug tracking, an occupancy map and so on) aren't
+/// consulted, so don't set them up on a heap wrapped in one of these.
+///
+/// # Locking rules
+///
+/// - A thread holds at most one of the locks at any time, so there's no
+///   order to take them in and nothing can deadlock.
+/// - Allocating searches upwards from the order it needs, holding each
+///   list's lock only for as long as it takes to try to pop a block.  A
+///   larger block it pops is its own until it's pushed the upper halves
+///   back onto their lists, one order at a time.
+/// - Freeing looks for the block's buddy and, if it isn't free, pushes the
+///   block, all under one hold of that order's lock.  If two buddies are
+///   freed at once, whichever thread gets the lock second finds the other,
+///   so a merge is never missed.  The merged block is that thread's own
+///   until it's pushed further up.
+/// - A block moving between lists is on none of them, so an allocation
+///   racing a split or merge could find every list empty.  Each split or
+///   merge is counted as in flight from taking its first block off a list,
+///   under that list's lock, until it's pushed the last one.  Allocating
+///   searches again while any are in flight, or if any block was pushed
+///   while it searched, so it only fails if the memory was really in use.
+//
+// Measured with `benches/sharded.rs` on a single-core x86_64 VM, with a
+// 1 MiB `Heap<16>` and each thread allocating and freeing bursts of 32
+// blocks of its own size: one thread took 610 us a run against 171 us for
+// a `LockedHeap`, and four threads 3.3 ms against 1.28 ms.  With one core
+// no two threads ever hold locks at once, so there's no contention for the
+// extra locks to avoid, only their cost: a lock and a few atomic updates
+// for each order a split or merge crosses.  Any win needs threads running
+// on several cores at once, which hasn't been measured yet.
+#[derive(Debug)]
+pub struct ShardedHeap<const N: usize = 16> {
+    heap: UnsafeCell<Heap<N>>,
+    locks: [AtomicBool; N],
+    /// Bit `order` is set while the list for `order` isn't empty, so that
+    /// allocating can skip empty lists without locking them.
+    nonempty: AtomicUsize,
+    /// Counts the operations which pushed blocks onto any free list.
+    pushes: AtomicUsize,
+    /// The number of splits and merges with a block off every list.
+    moving: AtomicUsize,
+    used_bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+// SAFETY: Each free list is only touched under its own lock, and the rest
+// of the heap is only read.
+unsafe impl<const N: usize> Sync for ShardedHeap<N> {}
+
+impl<const N: usize> ShardedHeap<N> {
+    /// Share `heap` between threads, with a lock per order.
+    pub const fn new(heap: Heap<N>) -> Self {
+        Self {
+            locks: [const { AtomicBool::new(false) }; N],
+            nonempty: AtomicUsize::new(nonempty(&heap.free_lists)),
+            pushes: AtomicUsize::new(0),
+            moving: AtomicUsize::new(0),
+            used_bytes: AtomicUsize::new(heap.used_bytes),
+            allocations: AtomicUsize::new(heap.allocations),
+            deallocations: AtomicUsize::new(heap.deallocations),
+            failures: AtomicUsize::new(heap.failures),
+            heap: UnsafeCell::new(heap),
+        }
+    }
+
+    /// Consume the lock, returning the heap inside it with its counters
+    /// brought up to date.  Since blocks were handed out without the
+    /// heap's own bookkeeping, it no longer assumes any of its memory is
+    /// still zero.
+    pub fn into_inner(self) -> Heap<N> {
+        let mut heap = self.heap.into_inner();
+        heap.used_bytes = self.used_bytes.into_inner();
+        heap.allocations = self.allocations.into_inner();
+        heap.deallocations = self.deallocations.into_inner();
+        heap.failures = self.failures.into_inner();
+        heap.zero_from = heap.heap_size;
+
+        #[cfg(feature = "debug-info")]
+        heap.refresh_free_counts();
+
+        heap
+    }
+
+    /// The number of bytes in allocated blocks.
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Allocate a block for `layout`, as [Heap::allocate] does.
+    pub fn allocate(&self, layout: Layout) -> Result<*mut u8, AllocationError> {
+        let order_needed = match self.allocation_order(layout) {
+            Ok(order) => order,
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return Err(AllocationError::InvalidSize(e));
+            }
+        };
+
+        loop {
+            let pushes = self.pushes.load(Ordering::Acquire);
+            let mut candidates = self.nonempty.load(Ordering::Acquire) >> order_needed;
+            while candidates != 0 {
+                let order = order_needed + candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
+                let block = self.with_list(order, |head| {
+                    let block = list_pop(head, order == N - 1);
+                    if block.is_some() && order > order_needed {
+                        self.moving.fetch_add(1, Ordering::Relaxed);
+                    }
+                    block
+                });
+                let block = match block {
+                    Some(block) => block,
+                    None => continue,
+                };
+
+                // Give back the upper halves we don't need.
+                if order > order_needed {
+                    for split in (order_needed..order).rev() {
+                        // SAFETY: The block we popped covers all of them.
+                        let upper = unsafe { block.add(self.order_size(split)) };
+                        self.with_list(split, |head| unsafe { list_insert(head, upper) });
+                    }
+                    self.pushes.fetch_add(1, Ordering::Release);
+                    self.moving.fetch_sub(1, Ordering::Release);
+                }
+
+                self.used_bytes
+                    .fetch_add(self.order_size(order_needed), Ordering::Relaxed);
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                return Ok(block);
+            }
+
+            // A move which finished since the search began pushed its
+            // blocks first, so checking in this order can't miss it.
+            if self.moving.load(Ordering::Acquire) != 0 {
+                spin_loop();
+            } else if self.pushes.load(Ordering::Acquire) == pushes {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return Err(AllocationError::HeapExhausted);
+            }
+        }
+    }
+
+    /// Free a block from [ShardedHeap::allocate], merging it with its
+    /// buddies as [Heap::deallocate] does.
+    ///
+    /// # Safety
+    /// As for [Heap::deallocate].
+    pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let initial_order = match self.allocation_order(layout) {
+            Ok(order) => order,
+            Err(_) => heap_panic!("Tried to dispose of invalid block"),
+        };
+        self.used_bytes
+            .fetch_sub(self.order_size(initial_order), Ordering::Relaxed);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+
+        // SAFETY: These fields never change while the heap is shared.
+        let (base, size, log2) = unsafe {
+            let heap = self.heap.get();
+            (
+                (*heap).heap_base,
+                (*heap).heap_size,
+                (*heap).min_block_size_log2,
+            )
+        };
+
+        let mut block = ptr;
+        let mut merging = false;
+        for order in initial_order..N {
+            let buddy = buddy(base, size, log2, order, block);
+            let merged = self.with_list(order, |head| match buddy {
+                Some(buddy) if list_remove(head, buddy) => {
+                    if !merging {
+                        self.moving.fetch_add(1, Ordering::Relaxed);
+                    }
+                    true
+                }
+                _ => {
+                    // SAFETY: The block is ours, and free.
+                    unsafe { list_insert(head, block) };
+                    false
+                }
+            });
+            match buddy {
+                Some(buddy) if merged => {
+                    block = min(block, buddy);
+                    merging = true;
+                }
+                _ => {
+                    self.pushes.fetch_add(1, Ordering::Release);
+                    if merging {
+                        self.moving.fetch_sub(1, Ordering::Release);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The order of the block [ShardedHeap::allocate] needs for `layout`.
+    fn allocation_order(&self, layout: Layout) -> Result<usize, AllocationSizeError> {
+        // SAFETY: These fields never change while the heap is shared.
+        let (min_block_size, heap_size, log2_min) = unsafe {
+            let heap = self.heap.get();
+            (
+                (*heap).min_block_size,
+                (*heap).heap_size,
+                (*heap).min_block_size_log2,
+            )
+        };
+        allocation_size(
+            min_block_size,
+            heap_size,
+            MIN_HEAP_ALIGN,
+            layout.size(),
+            layout.align(),
+        )
+        .map(|s| (log2(s) - log2_min) as usize)
+    }
+
+    /// The size of the blocks of `order`.
+    fn order_size(&self, order: usize) -> usize {
+        // SAFETY: The minimum block size never changes while the heap is
+        // shared.
+        1 << (unsafe { (*self.heap.get()).min_block_size_log2 } as usize + order)
+    }
+
+    /// Run `f` on the head of the free list for `order`, holding that
+    /// list's lock.
+    fn with_list<R>(&self, order: usize, f: impl FnOnce(&mut *mut FreeBlock) -> R) -> R {
+        let lock = &self.locks[order];
+        while lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while lock.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+
+        // SAFETY: We hold the lock for this list, and reach its head
+        // without making a reference to the rest of the heap.
+        let head = unsafe {
+            let lists = addr_of_mut!((*self.heap.get()).free_lists) as *mut *mut FreeBlock;
+            &mut *lists.add(order)
+        };
+        let was_empty = head.is_null();
+        let result = f(head);
+        match (was_empty, head.is_null()) {
+            (true, false) => self.nonempty.fetch_or(1 << order, Ordering::Release),
+            (false, true) => self.nonempty.fetch_and(!(1 << order), Ordering::Release),
+            _ => 0,
+        };
+
+        lock.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// The bits for `ShardedHeap::nonempty`.
+const fn nonempty(free_lists: &[*mut FreeBlock]) -> usize {
+    let mut bits = 0;
+    let mut order = 0;
+    while order < free_lists.len() {
+        if !free_lists[order].is_null() {
+            bits |= 1 << order;
+        }
+        order += 1;
+    }
+    bits
+}
+
+unsafe impl<const N: usize> GlobalAlloc for ShardedHeap<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_sharded_heap() {
+        unsafe {
+            let heap_size = 1 << 16;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: ShardedHeap<12> =
+                ShardedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            // Each thread mostly sticks to its own size class, but now and
+            // then takes a big block, so splits and merges race with
+            // everything else.  Blocks are stamped, so any block handed out
+            // twice would show.
+            std::thread::scope(|s| {
+                for t in 0..8u8 {
+                    let heap = &heap;
+                    s.spawn(move || {
+                        let mut live = Vec::new();
+                        for i in 0..4000usize {
+                            let size = if i % 97 == 0 { 4096 } else { 16 << (t % 4) };
+                            let block = Layout::from_size_align(size, 1).unwrap();
+                            if live.len() < 16 && i % 5 != 4 {
+                                if let Ok(p) = heap.allocate(block) {
+                                    p.write_bytes(t, size);
+                                    live.push((p, block));
+                                }
+                            } else if let Some((p, block)) = live.pop() {
+                                let bytes = std::slice::from_raw_parts(p, block.size());
+                                assert!(bytes.iter().all(|&b| b == t));
+                                heap.deallocate(p, block);
+                            }
+                        }
+                        for (p, block) in live {
+                            heap.deallocate(p, block);
+                        }
+                    });
+                }
+            });
+
+            // Everything merged back together.
+            assert_eq!(0, heap.used_bytes());
+            let heap = heap.into_inner();
+            assert!(heap.accounting_check());
+            heap.assert_alignment_invariants();
+            assert!(heap.is_block_free(11, mem));
+            assert_eq!(heap.stats().allocations, heap.stats().deallocations);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_sharded_no_false_exhaustion() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: ShardedHeap<5> =
+                ShardedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            // There's always room for every thread's block, but each free
+            // merges all the way up and each allocation splits all the way
+            // down, so the lists are empty while any of them is moving.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            std::thread::scope(|s| {
+                for t in 0..4u8 {
+                    let heap = &heap;
+                    s.spawn(move || {
+                        for _ in 0..200000 {
+                            let p = heap.allocate(small).unwrap();
+                            p.write_bytes(t, 16);
+                            heap.deallocate(p, small);
+                        }
+                    });
+                }
+            });
+
+            let heap = heap.into_inner();
+            assert_eq!(0, heap.stats().failures);
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_sharded_exhaustion() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let heap: ShardedHeap<5> =
+                ShardedHeap::new(Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap());
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: Vec<_> = (0..16).map(|_| heap.allocate(small).unwrap()).collect();
+            assert_eq!(Err(AllocationError::HeapExhausted), heap.allocate(small));
+            assert_eq!(
+                Err(AllocationError::InvalidSize(AllocationSizeError::TooLarge)),
+                heap.allocate(Layout::from_size_align(512, 1).unwrap())
+            );
+            for block in blocks {
+                heap.deallocate(block, small);
+            }
+
+            let heap = heap.into_inner();
+            let stats = heap.stats();
+            assert_eq!(
+                (16, 16, 2),
+                (stats.allocations, stats.deallocations, stats.failures)
+            );
+            assert!(heap.is_block_free(4, mem));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Snapshots of a heap's usage, and the differences between them.
+use core::fmt;
+
+use crate::heap::free_list_len;
+use crate::Heap;
+
+/// A snapshot of a heap's usage, from [Heap::stats].
+///
+/// `allocations`, `deallocations` and `failures` are running totals which
+/// wrap around on overflow.  Compare snapshots with [HeapStats::diff]
+/// rather than looking at the totals directly, and the difference stays
+/// right across a wrap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeapStats<const N: usize> {
+    /// See [Heap::used_bytes].
+    pub used_bytes: usize,
+    /// See [Heap::free_bytes].
+    pub free_bytes: usize,
+    /// See [Heap::reserved_bytes].  Never changes, so it isn't in
+    /// [HeapStatsDelta].
+    pub reserved_bytes: usize,
+    /// The number of successful allocations.  This and the next two are
+    /// only counted with the `stats` feature, and are zero otherwise.
+    pub allocations: usize,
+    /// The number of deallocations.
+    pub deallocations: usize,
+    /// The number of allocations which failed.
+    pub failures: usize,
+    /// The number of free blocks of each order.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_array"))]
+    pub free_blocks: [usize; N],
+}
+
+/// The change in each field of [HeapStats] between two snapshots, from
+/// [HeapStats::diff].  Its `Display` implementation prints one line per
+/// field that changed, and nothing at all if none did.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeapStatsDelta<const N: usize> {
+    pub used_bytes: isize,
+    pub free_bytes: isize,
+    pub allocations: isize,
+    pub deallocations: isize,
+    pub failures: isize,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_array"))]
+    pub free_blocks: [isize; N],
+}
+
+impl<const N: usize> Heap<N> {
+    /// Take a snapshot of the heap's usage.  This walks every free list.
+    pub fn stats(&self) -> HeapStats<N> {
+        let mut free_blocks = [0; N];
+        for (order, count) in free_blocks.iter_mut().enumerate() {
+            *count = free_list_len(&self.free_lists, order);
+        }
+
+        HeapStats {
+            used_bytes: self.used_bytes(),
+            free_bytes: self.free_bytes(),
+            reserved_bytes: self.reserved_bytes(),
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            failures: self.failures,
+            free_blocks,
+        }
+    }
+
+    /// Run `f` on the heap, and return its result along with how it changed
+    /// the heap's usage.  After a balanced workload, the delta should be
+    /// zero apart from the allocation and deallocation counts:
+    ///
+    /// ```ignore
+    /// let ((), delta) = heap.measure(|heap| workload(heap));
+    /// assert_eq!(delta.allocations, delta.deallocations);
+    /// assert_eq!(0, delta.used_bytes);
+    /// ```
+    pub fn measure<R>(&mut self, f: impl FnOnce(&mut Heap<N>) -> R) -> (R, HeapStatsDelta<N>) {
+        let before = self.stats();
+        let result = f(self);
+        (result, self.stats().diff(&before))
+    }
+}
+
+impl<const N: usize> HeapStats<N> {
+    /// The change from `earlier` to `self`.
+    pub fn diff(&self, earlier: &HeapStats<N>) -> HeapStatsDelta<N> {
+        // Wrapping subtraction gives the right answer even if a running
+        // total wrapped around in between, as long as it changed by less
+        // than `isize::MAX`.
+        let delta = |now: usize, then: usize| now.wrapping_sub(then) as isize;
+
+        let mut free_blocks = [0; N];
+        for (order, d) in free_blocks.iter_mut().enumerate() {
+            *d = delta(self.free_blocks[order], earlier.free_blocks[order]);
+        }
+
+        HeapStatsDelta {
+            used_bytes: delta(self.used_bytes, earlier.used_bytes),
+            free_bytes: delta(self.free_bytes, earlier.free_bytes),
+            allocations: delta(self.allocations, earlier.allocations),
+            deallocations: delta(self.deallocations, earlier.deallocations),
+            failures: delta(self.failures, earlier.failures),
+            free_blocks,
+        }
+    }
+}
+
+impl<const N: usize> HeapStatsDelta<N> {
+    /// Returns true if nothing changed at all.
+    pub fn is_zero(&self) -> bool {
+        *self
+            == Self {
+                used_bytes: 0,
+                free_bytes: 0,
+                allocations: 0,
+                deallocations: 0,
+                failures: 0,
+                free_blocks: [0; N],
+            }
+    }
+}
+
+impl<const N: usize> fmt::Display for HeapStatsDelta<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("used_bytes", self.used_bytes),
+            ("free_bytes", self.free_bytes),
+            ("allocations", self.allocations),
+            ("deallocations", self.deallocations),
+            ("failures", self.failures),
+        ];
+        for (name, delta) in fields {
+            if delta != 0 {
+                writeln!(f, "{}: {:+}", name, delta)?;
+            }
+        }
+        for (order, &delta) in self.free_blocks.iter().enumerate() {
+            if delta != 0 {
+                writeln!(f, "free_blocks[{}]: {:+}", order, delta)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::string::ToString;
+
+    #[test]
+    fn test_measure() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let small = Layout::from_size_align(16, 16).unwrap();
+
+            // A balanced workload.
+            let ((), delta) = heap.measure(|heap| {
+                let a = heap.allocate(small).unwrap();
+                let b = heap.allocate(small).unwrap();
+                assert!(heap
+                    .allocate(Layout::from_size_align(512, 1).unwrap())
+                    .is_err());
+                heap.deallocate(a, small);
+                heap.deallocate(b, small);
+            });
+            assert_eq!(0, delta.used_bytes);
+            assert_eq!([0; 5], delta.free_blocks);
+            assert_eq!(
+                (2, 2, 1),
+                (delta.allocations, delta.deallocations, delta.failures)
+            );
+            assert_eq!(
+                "allocations: +2\ndeallocations: +2\nfailures: +1\n",
+                delta.to_string()
+            );
+
+            // A leak.
+            let (leaked, delta) = heap.measure(|heap| heap.allocate(small).unwrap());
+            assert_eq!(
+                "used_bytes: +16\nfree_bytes: -16\nallocations: +1\n\
+                 free_blocks[0]: +1\nfree_blocks[1]: +1\nfree_blocks[2]: +1\n\
+                 free_blocks[3]: +1\nfree_blocks[4]: -1\n",
+                delta.to_string()
+            );
+
+            let ((), delta) = heap.measure(|_| ());
+            assert!(delta.is_zero());
+            assert_eq!("", delta.to_string());
+
+            heap.deallocate(leaked, small);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_counter_wrap() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            heap.allocations = usize::MAX - 1;
+            heap.failures = usize::MAX;
+            let before = heap.stats();
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let blocks: std::vec::Vec<_> = (0..3).map(|_| heap.allocate(small).unwrap()).collect();
+            assert!(heap
+                .allocate(Layout::from_size_align(512, 1).unwrap())
+                .is_err());
+
+            // The totals wrapped around, but the deltas are still right.
+            let after = heap.stats();
+            assert_eq!(1, after.allocations);
+            assert_eq!(0, after.failures);
+            let delta = after.diff(&before);
+            assert_eq!(3, delta.allocations);
+            assert_eq!(1, delta.failures);
+
+            // And diffing the other way round gives the negation.
+            assert_eq!(-3, before.diff(&after).allocations);
+
+            for p in blocks {
+                heap.deallocate(p, small);
+            }
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
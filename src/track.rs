@@ -0,0 +1,497 @@
+//! Tracking of the order of every live allocation, to catch misuse of
+//! `deallocate` before it corrupts the heap.
+use crate::{Heap, HeapError};
+
+/// A table of allocation times, and the clock they were read from.
+pub(crate) type AgeTable = (&'static mut [u64], fn() -> u64);
+
+/// A table of allocation IDs, and the ID the next allocation will get.
+pub(crate) type IdTable = (&'static mut [u64], u64);
+
+/// A live allocation, as reported by [Heap::oldest_allocations].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AllocationInfo {
+    /// The start of the block.
+    pub ptr: *mut u8,
+    /// The size of the block, which may be larger than what was asked for.
+    pub size: usize,
+    /// How many clock ticks ago the block was allocated.
+    pub age: u64,
+}
+
+impl AllocationInfo {
+    /// A placeholder, for initializing buffers.
+    pub const EMPTY: Self = Self {
+        ptr: core::ptr::null_mut(),
+        size: 0,
+        age: 0,
+    };
+}
+
+/// The value in the order table of a slot which doesn't start a live
+/// allocation.  Live allocations store their order plus one.
+const NOT_ALLOCATED: u8 = 0;
+
+impl<const N: usize> Heap<N> {
+    /// The number of entries an order table for this heap needs: one per
+    /// minimum-sized block.
+    pub const fn order_table_len(&self) -> usize {
+        self.heap_size >> self.min_block_size_log2
+    }
+
+    /// Register a table in which to record the order of every live
+    /// allocation, keyed by its offset in the heap.  `deallocate` then
+    /// checks that it's given a live block and a layout of the same order
+    /// it was allocated with, and panics if not, naming the live block if
+    /// the pointer is inside a bigger one.
+    ///
+    /// The table needs [Heap::order_table_len] entries, and must be
+    /// registered before anything is allocated, so that every live block is
+    /// known.
+    pub fn set_order_table(&mut self, table: &'static mut [u8]) -> Result<(), HeapError> {
+        if table.len() < self.order_table_len() {
+            return Err(HeapError::MetadataTooSmall);
+        }
+
+        // The heap is only unused if the whole thing is one free block,
+        // which a small batch may still be split into.
+        #[cfg(feature = "small-batch")]
+        self.flush_small_batch();
+        if self.free_lists[N - 1].is_null() {
+            return Err(HeapError::HeapInUse);
+        }
+
+        table.fill(NOT_ALLOCATED);
+        self.order_table = Some(table);
+        Ok(())
+    }
+
+    /// Register a table in which to record when each live allocation was
+    /// made, according to `now`, which can be any monotonic counter (a tick
+    /// count, say).  [Heap::oldest_allocations] can then report the oldest
+    /// live allocations, which is where slow leaks tend to hide.
+    ///
+    /// Like the order table, which it relies on to know which blocks are
+    /// live, the table needs [Heap::order_table_len] entries and must be
+    /// registered before anything is allocated.
+    pub fn set_age_table(
+        &mut self,
+        table: &'static mut [u64],
+        now: fn() -> u64,
+    ) -> Result<(), HeapError> {
+        if table.len() < self.order_table_len() {
+            return Err(HeapError::MetadataTooSmall);
+        }
+        #[cfg(feature = "small-batch")]
+        self.flush_small_batch();
+        if self.free_lists[N - 1].is_null() {
+            return Err(HeapError::HeapInUse);
+        }
+
+        table.fill(0);
+        self.age_table = Some((table, now));
+        Ok(())
+    }
+
+    /// Register a table in which to record an ID for each live allocation.
+    /// IDs count up from 1 in the order allocations are made, so a leak
+    /// found by [Heap::live_allocations] at the end of a test can be
+    /// traced back to the allocation that made it.
+    ///
+    /// Like the age table, the table needs [Heap::order_table_len] entries
+    /// and must be registered before anything is allocated, and IDs are
+    /// only reported if an order table is registered too.
+    pub fn set_id_table(&mut self, table: &'static mut [u64]) -> Result<(), HeapError> {
+        if table.len() < self.order_table_len() {
+            return Err(HeapError::MetadataTooSmall);
+        }
+        #[cfg(feature = "small-batch")]
+        self.flush_small_batch();
+        if self.free_lists[N - 1].is_null() {
+            return Err(HeapError::HeapInUse);
+        }
+
+        table.fill(0);
+        self.id_table = Some((table, 1));
+        Ok(())
+    }
+
+    /// Every live allocation, in address order, as `(id, ptr, order)`.
+    /// The ID is 0 if there's no ID table registered (see
+    /// [Heap::set_id_table]).  This needs an order table to know which
+    /// blocks are live, and yields nothing without one.
+    pub fn live_allocations(&self) -> impl Iterator<Item = (u64, *mut u8, usize)> + '_ {
+        let slots = match self.order_table {
+            Some(_) => self.order_table_len(),
+            None => 0,
+        };
+        (0..slots).filter_map(move |slot| {
+            let ptr = self
+                .heap_base
+                .wrapping_add(slot << self.min_block_size_log2);
+            let order = self.tracked_order(ptr)?;
+            let id = self.id_table.as_ref().map_or(0, |(ids, _)| ids[slot]);
+            Some((id, ptr, order))
+        })
+    }
+
+    /// Fill `out` with the oldest live allocations, oldest first, and
+    /// return how many there were (at most `out.len()`).  Allocations made
+    /// at the same time are ordered by address.
+    ///
+    /// This needs both an order table and an age table registered, and
+    /// returns 0 otherwise.  It scans the whole order table, and keeps only
+    /// the `out.len()` oldest allocations seen so far, so it needs no memory
+    /// beyond `out`.
+    pub fn oldest_allocations(&self, out: &mut [AllocationInfo]) -> usize {
+        let (ages, now) = match (&self.order_table, &self.age_table) {
+            (Some(_), Some((ages, now))) => (ages, now()),
+            _ => return 0,
+        };
+
+        let mut found = 0;
+        for slot in 0..self.order_table_len() {
+            let ptr = self
+                .heap_base
+                .wrapping_add(slot << self.min_block_size_log2);
+            let order = match self.tracked_order(ptr) {
+                Some(order) => order,
+                None => continue,
+            };
+            let info = AllocationInfo {
+                ptr,
+                size: self.order_size(order),
+                age: now.wrapping_sub(ages[slot]),
+            };
+
+            // Insert `info` into the sorted prefix of `out`, dropping the
+            // youngest entry if it's full.
+            let mut i = found;
+            if found < out.len() {
+                found += 1;
+            } else if i == 0 || out[i - 1].age >= info.age {
+                continue;
+            } else {
+                i -= 1;
+            }
+            while i > 0 && out[i - 1].age < info.age {
+                out[i] = out[i - 1];
+                i -= 1;
+            }
+            out[i] = info;
+        }
+        found
+    }
+
+    /// The order `ptr` was allocated with, if it's a live allocation and an
+    /// order table is registered.
+    pub fn tracked_order(&self, ptr: *const u8) -> Option<usize> {
+        let table = self.order_table.as_deref()?;
+        match *table.get(self.block_index(ptr)?)? {
+            NOT_ALLOCATED => None,
+            entry => Some(entry as usize - 1),
+        }
+    }
+
+    /// Record that `block` was allocated with order `order`.
+    pub(crate) fn track_allocate(&mut self, block: *mut u8, order: usize) {
+        if let Some(slot) = self.block_index(block) {
+            if let Some(table) = self.order_table.as_deref_mut() {
+                table[slot] = order as u8 + 1;
+            }
+            if let Some((ages, now)) = &mut self.age_table {
+                ages[slot] = now();
+            }
+            if let Some((ids, next)) = &mut self.id_table {
+                ids[slot] = *next;
+                *next += 1;
+            }
+        }
+    }
+
+    /// Check that `ptr` is a live allocation of order `order`, and record
+    /// that it's being freed.
+    pub(crate) fn track_deallocate(&mut self, ptr: *mut u8, order: usize) {
+        let slot = self.block_index(ptr);
+        let table = match self.order_table.as_deref_mut() {
+            Some(table) => table,
+            None => return,
+        };
+
+        let slot = match slot {
+            Some(slot) => slot,
+            None => heap_panic!("Tried to deallocate a pointer outside of the heap"),
+        };
+
+        if table[slot] == NOT_ALLOCATED {
+            // Freeing a piece of a bigger block would put it on a free list
+            // while the rest is still in use, so say which block it was.
+            if let Some((start, live_order)) = enclosing_allocation(table, slot, N) {
+                heap_panic!(
+                    "Tried to deallocate part of a larger live allocation: order {} \
+                     at offset {:#x}, freed with a layout of order {}",
+                    live_order,
+                    start << self.min_block_size_log2,
+                    order
+                );
+            }
+            heap_panic!("Tried to deallocate a block which isn't allocated");
+        }
+        let live_order = table[slot] as usize - 1;
+        if live_order != order {
+            heap_panic!(
+                "Tried to deallocate a block with a layout of a different order: \
+                 allocated with order {}, freed with order {}",
+                live_order,
+                order
+            );
+        }
+
+        table[slot] = NOT_ALLOCATED;
+        if let Some((ids, _)) = &mut self.id_table {
+            ids[slot] = 0;
+        }
+    }
+}
+
+/// The first slot and order of a live allocation in `table`, of one of
+/// the first `orders` orders, which covers the free `slot`, if there is one.
+fn enclosing_allocation(table: &[u8], slot: usize, orders: usize) -> Option<(usize, usize)> {
+    (1..orders).find_map(|order| {
+        let start = slot & !((1 << order) - 1);
+        match table.get(start) {
+            Some(&entry) if entry as usize == order + 1 => Some((start, order)),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr;
+    use core::ptr::NonNull;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Run `f` with a fresh 256-byte heap with an order table, and its base.
+    fn with_tracked_heap(f: impl FnOnce(&mut Heap<5>, *mut u8)) {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            let table = Box::leak(vec![0; heap.order_table_len()].into_boxed_slice());
+            heap.set_order_table(table).unwrap();
+
+            f(&mut heap, mem);
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+
+    #[test]
+    fn test_set_order_table() {
+        with_tracked_heap(|heap, _| {
+            assert_eq!(16, heap.order_table_len());
+            assert_eq!(
+                Err(HeapError::MetadataTooSmall),
+                heap.set_order_table(Box::leak(vec![0; 15].into_boxed_slice()))
+            );
+
+            let layout = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(layout).unwrap();
+            assert_eq!(
+                Err(HeapError::HeapInUse),
+                heap.set_order_table(Box::leak(vec![0; 16].into_boxed_slice()))
+            );
+            unsafe { heap.deallocate(block, layout) };
+        });
+    }
+
+    #[test]
+    fn test_order_equivalent_layouts() {
+        with_tracked_heap(|heap, mem| unsafe {
+            // Allocated as 24 bytes aligned to 8, freed as 17 bytes aligned
+            // to 1: both are order 1 (32 bytes), so that's fine.
+            let block = heap
+                .allocate(Layout::from_size_align(24, 8).unwrap())
+                .unwrap();
+            assert_eq!(Some(1), heap.tracked_order(block));
+            heap.deallocate(block, Layout::from_size_align(17, 1).unwrap());
+            assert_eq!(None, heap.tracked_order(block));
+
+            // Alignment can be what decides the order, too.
+            let block = heap
+                .allocate(Layout::from_size_align(8, 64).unwrap())
+                .unwrap();
+            assert_eq!(Some(2), heap.tracked_order(block));
+            heap.deallocate(block, Layout::from_size_align(64, 1).unwrap());
+
+            let block = heap.allocate_exact(Layout::from_size_align(256, 1).unwrap());
+            assert_eq!(Ok(mem), block);
+            assert_eq!(Some(4), heap.tracked_order(mem));
+            heap.deallocate(mem, Layout::from_size_align(129, 2).unwrap());
+
+            // Everything was freed correctly.
+            assert!(heap.is_block_free(4, mem));
+        });
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "different order"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_different_order_panics() {
+        with_tracked_heap(|heap, _| unsafe {
+            let block = heap
+                .allocate(Layout::from_size_align(24, 8).unwrap())
+                .unwrap();
+            heap.deallocate(block, Layout::from_size_align(8, 8).unwrap());
+        });
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(feature = "tiny"),
+        should_panic(expected = "part of a larger live allocation: order 2 at offset 0x40")
+    )]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_free_sub_block_panics() {
+        with_tracked_heap(|heap, mem| unsafe {
+            heap.allocate(Layout::from_size_align(16, 16).unwrap())
+                .unwrap();
+            let block = heap
+                .allocate(Layout::from_size_align(64, 16).unwrap())
+                .unwrap();
+            assert_eq!(mem.add(64), block);
+
+            // The second 16 bytes of the 64-byte block.
+            heap.deallocate(block.add(16), Layout::from_size_align(16, 16).unwrap());
+        });
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tiny"), should_panic(expected = "isn't allocated"))]
+    #[cfg_attr(feature = "tiny", should_panic)]
+    fn test_double_free_panics() {
+        with_tracked_heap(|heap, _| unsafe {
+            let layout = Layout::from_size_align(16, 16).unwrap();
+            let block = heap.allocate(layout).unwrap();
+            heap.deallocate(block, layout);
+            heap.deallocate(block, layout);
+        });
+    }
+
+    /// A clock for the age table tests, advanced by hand.
+    static NOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    fn now() -> u64 {
+        NOW.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn set_now(t: u64) {
+        NOW.store(t, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_oldest_allocations() {
+        with_tracked_heap(|heap, mem| unsafe {
+            assert_eq!(0, heap.oldest_allocations(&mut [AllocationInfo::EMPTY; 4]));
+            heap.set_age_table(Box::leak(vec![0; 16].into_boxed_slice()), now)
+                .unwrap();
+
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks = [ptr::null_mut(); 5];
+            for (t, block) in blocks.iter_mut().enumerate() {
+                set_now(100 + t as u64 * 10);
+                *block = heap.allocate(small).unwrap();
+            }
+            set_now(200);
+
+            // Only the three oldest fit.
+            let mut out = [AllocationInfo::EMPTY; 3];
+            assert_eq!(3, heap.oldest_allocations(&mut out));
+            let ages: Vec<_> = out.iter().map(|info| (info.ptr, info.age)).collect();
+            assert_eq!(
+                vec![(blocks[0], 100), (blocks[1], 90), (blocks[2], 80)],
+                ages
+            );
+            assert_eq!(16, out[0].size);
+
+            // Freeing and reallocating a block makes it young again.
+            heap.deallocate(blocks[0], small);
+            blocks[0] = heap.allocate(small).unwrap();
+            assert_eq!(mem, blocks[0]);
+            assert_eq!(3, heap.oldest_allocations(&mut out));
+            assert_eq!(
+                vec![(blocks[1], 90), (blocks[2], 80), (blocks[3], 70)],
+                out.iter()
+                    .map(|info| (info.ptr, info.age))
+                    .collect::<Vec<_>>()
+            );
+
+            // A bigger buffer gets everything, youngest last.
+            let mut out = [AllocationInfo::EMPTY; 8];
+            assert_eq!(5, heap.oldest_allocations(&mut out));
+            assert_eq!((blocks[0], 0), (out[4].ptr, out[4].age));
+
+            for block in blocks {
+                heap.deallocate(block, small);
+            }
+            assert_eq!(0, heap.oldest_allocations(&mut out));
+        });
+    }
+
+    #[test]
+    fn test_live_allocations() {
+        with_tracked_heap(|heap, mem| unsafe {
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let big = Layout::from_size_align(64, 64).unwrap();
+
+            // Without an ID table, allocations are reported with ID 0.
+            let a = heap.allocate(small).unwrap();
+            assert_eq!(
+                vec![(0, mem, 0)],
+                heap.live_allocations().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                Err(HeapError::HeapInUse),
+                heap.set_id_table(Box::leak(vec![0; 16].into_boxed_slice()))
+            );
+            heap.deallocate(a, small);
+
+            assert_eq!(
+                Err(HeapError::MetadataTooSmall),
+                heap.set_id_table(Box::leak(vec![0; 15].into_boxed_slice()))
+            );
+            heap.set_id_table(Box::leak(vec![0; 16].into_boxed_slice()))
+                .unwrap();
+
+            let a = heap.allocate(small).unwrap();
+            let b = heap.allocate(big).unwrap();
+            let c = heap.allocate(small).unwrap();
+            assert_eq!(
+                vec![(1, a, 0), (3, c, 0), (2, b, 2)],
+                heap.live_allocations().collect::<Vec<_>>()
+            );
+
+            // Freed allocations drop out, and new ones get fresh IDs.
+            heap.deallocate(a, small);
+            let d = heap.allocate(small).unwrap();
+            assert_eq!(a, d);
+            assert_eq!(
+                vec![(4, d, 0), (3, c, 0), (2, b, 2)],
+                heap.live_allocations().collect::<Vec<_>>()
+            );
+
+            heap.deallocate(b, big);
+            heap.deallocate(c, small);
+            heap.deallocate(d, small);
+            assert_eq!(0, heap.live_allocations().count());
+        });
+    }
+}
@@ -0,0 +1,169 @@
+//! A Graphviz view of the buddy tree which needs neither `std` nor an
+//! allocator, so it can be printed from the target itself.
+use core::fmt::{self, Write};
+
+use crate::heap::{free_list_contains, free_list_for_each};
+use crate::Heap;
+
+impl<const N: usize> Heap<N> {
+    /// Write the buddy tree to `w` as a Graphviz `digraph`, rooted at the
+    /// whole heap.  Each node is a block, labelled with its offset and
+    /// size, and colored by its state: free (green), split into two smaller
+    /// blocks (white), or allocated (red).  A split block has an edge to
+    /// each of its halves, which are buddies.
+    ///
+    /// This is the same graph as `export_dot` draws with the `std`
+    /// feature, but it's worked out straight from the free lists as it's
+    /// written, so it takes time quadratic in the number of free blocks.
+    /// As there, without an order table (see the `debug-track` feature) a
+    /// completely allocated subtree is drawn as a single node.
+    pub fn export_xdot_graph(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(w, "digraph buddyalloc {{")?;
+        writeln!(w, "  node [shape=box, style=filled];")?;
+        self.xdot_node(w, 0, N - 1)?;
+        writeln!(w, "}}")
+    }
+
+    /// Write the node for the block at `offset` of order `order`, and its
+    /// children.
+    fn xdot_node(&self, w: &mut impl Write, offset: usize, order: usize) -> fmt::Result {
+        let size = self.order_size(order);
+        let block = self.heap_base.wrapping_add(offset);
+
+        #[cfg(feature = "debug-track")]
+        let (tracked, allocated) = (
+            self.order_table.is_some(),
+            self.tracked_order(block) == Some(order),
+        );
+        #[cfg(not(feature = "debug-track"))]
+        let (tracked, allocated) = (false, false);
+
+        let (name, color) = if free_list_contains(&self.free_lists, order, block) {
+            ("free", "palegreen")
+        } else if order == 0 || allocated {
+            ("allocated", "salmon")
+        } else if tracked || self.any_free_in(offset, order) {
+            ("split", "white")
+        } else {
+            ("allocated", "salmon")
+        };
+        writeln!(
+            w,
+            "  b{}_{} [label=\"{:#x}\\n{} bytes\\n{}\", fillcolor={}];",
+            order, offset, offset, size, name, color
+        )?;
+
+        if name == "split" {
+            let half = size / 2;
+            for child in [offset, offset + half] {
+                writeln!(w, "  b{}_{} -> b{}_{};", order, offset, order - 1, child)?;
+                self.xdot_node(w, child, order - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any smaller free block lies inside the block at `offset` of
+    /// order `order`.
+    fn any_free_in(&self, offset: usize, order: usize) -> bool {
+        let range = offset..offset + self.order_size(order);
+        let mut found = false;
+        for smaller in 0..order {
+            free_list_for_each(&self.free_lists, smaller, |block| {
+                found |= range.contains(&(block as usize - self.heap_base as usize));
+            });
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::string::String;
+
+    /// Check the parts of DOT syntax the graph uses: one `digraph` block,
+    /// and a statement ending in `;` on every line in between, each either
+    /// a node with attributes or an edge between two nodes already seen.
+    fn assert_valid_dot(dot: &str) {
+        let lines: std::vec::Vec<&str> = dot.lines().collect();
+        assert_eq!(Some(&"digraph buddyalloc {"), lines.first());
+        assert_eq!(Some(&"}"), lines.last());
+
+        let mut nodes = std::vec::Vec::new();
+        for line in &lines[1..lines.len() - 1] {
+            let statement = line.strip_prefix("  ").unwrap().strip_suffix(';').unwrap();
+            if let Some((from, to)) = statement.split_once(" -> ") {
+                assert!(nodes.contains(&from), "{}", line);
+                assert!(!to.contains(' '), "{}", line);
+            } else {
+                let (name, attributes) = statement.split_once(" [").unwrap();
+                assert!(attributes.ends_with(']'), "{}", line);
+                assert_eq!(0, attributes.matches('"').count() % 2, "{}", line);
+                nodes.push(name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_xdot_graph() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+
+            // A fresh heap is a single free node.
+            let mut dot = String::new();
+            heap.export_xdot_graph(&mut dot).unwrap();
+            assert_valid_dot(&dot);
+            assert_eq!(
+                "digraph buddyalloc {\n\
+                 \x20 node [shape=box, style=filled];\n\
+                 \x20 b4_0 [label=\"0x0\\n256 bytes\\nfree\", fillcolor=palegreen];\n\
+                 }\n",
+                dot
+            );
+
+            // After an allocation of 64 bytes, the left quarter is allocated,
+            // and the tree is split down to it.
+            let block_64 = Layout::from_size_align(64, 64).unwrap();
+            let block = heap.allocate(block_64).unwrap();
+            let mut dot = String::new();
+            heap.export_xdot_graph(&mut dot).unwrap();
+            assert_valid_dot(&dot);
+            for line in [
+                "  b4_0 [label=\"0x0\\n256 bytes\\nsplit\", fillcolor=white];",
+                "  b4_0 -> b3_0;",
+                "  b4_0 -> b3_128;",
+                "  b3_128 [label=\"0x80\\n128 bytes\\nfree\", fillcolor=palegreen];",
+                "  b3_0 -> b2_64;",
+                "  b2_0 [label=\"0x0\\n64 bytes\\nallocated\", fillcolor=salmon];",
+                "  b2_64 [label=\"0x40\\n64 bytes\\nfree\", fillcolor=palegreen];",
+            ] {
+                assert!(dot.lines().any(|l| l == line), "{} not in\n{}", line, dot);
+            }
+            assert_eq!(5, dot.matches(" [label=").count());
+
+            // A full heap is one allocated node.
+            let rest = heap
+                .allocate(Layout::from_size_align(128, 128).unwrap())
+                .unwrap();
+            let other = heap.allocate(block_64).unwrap();
+            let mut dot = String::new();
+            heap.export_xdot_graph(&mut dot).unwrap();
+            assert_valid_dot(&dot);
+            assert_eq!(1, dot.matches(" [label=").count());
+            assert!(dot.contains("b4_0 [label=\"0x0\\n256 bytes\\nallocated\""));
+
+            heap.deallocate(other, block_64);
+            heap.deallocate(rest, Layout::from_size_align(128, 128).unwrap());
+            heap.deallocate(block, block_64);
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
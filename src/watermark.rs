@@ -0,0 +1,167 @@
+//! Warnings that the heap is running low on memory, raised before any
+//! allocation actually fails.
+use core::cmp::max;
+
+use crate::Heap;
+
+/// The thresholds set with [Heap::set_low_watermark] and
+/// [Heap::set_watermark_recovery], and which side of them the heap is on.
+#[derive(Debug, Default)]
+pub(crate) struct Watermark {
+    low: usize,
+    recover: usize,
+    below: bool,
+    callback: Option<fn(bool)>,
+}
+
+impl<const N: usize> Heap<N> {
+    /// Flag the heap as low on memory once fewer than `free_bytes` bytes
+    /// are free, so that callers can start shedding load before
+    /// allocations fail.  It stays low until at least the recovery
+    /// threshold ([Heap::set_watermark_recovery], which defaults to this)
+    /// is free again.  A watermark of 0 is never crossed.
+    ///
+    /// This only looks at [Heap::free_bytes], so it's O(1), but it doesn't
+    /// account for fragmentation.  A heap which is already below the
+    /// watermark reports crossing it after its next allocation or
+    /// deallocation.
+    pub fn set_low_watermark(&mut self, free_bytes: usize) {
+        let watermark = self.watermark.get_or_insert_with(Default::default);
+        watermark.low = free_bytes;
+        watermark.below = false;
+    }
+
+    /// How many bytes have to be free before a heap below its low
+    /// watermark counts as recovered.  Setting this above the watermark
+    /// stops operations around the boundary from flipping the heap in and
+    /// out of the low state; anything below it is treated as equal to it.
+    pub fn set_watermark_recovery(&mut self, free_bytes: usize) {
+        self.watermark.get_or_insert_with(Default::default).recover = free_bytes;
+    }
+
+    /// Register a function to call when the heap crosses its low
+    /// watermark, with true when it drops below, and false when it
+    /// recovers.
+    ///
+    /// It runs inside the [Heap::allocate] or [Heap::deallocate] call that
+    /// crossed the threshold, so it mustn't allocate or free memory: with
+    /// a [crate::LockedHeap] as the global allocator, that would deadlock.
+    /// Setting a flag for a task to act on later is about all it should do.
+    pub fn set_watermark_callback(&mut self, callback: fn(bool)) {
+        self.watermark.get_or_insert_with(Default::default).callback = Some(callback);
+    }
+
+    /// Returns true if the heap has dropped below its low watermark and
+    /// hasn't recovered yet.
+    pub fn below_watermark(&self) -> bool {
+        self.watermark.as_ref().is_some_and(|w| w.below)
+    }
+
+    /// See whether the last operation moved the heap across a threshold.
+    pub(crate) fn check_watermark(&mut self) {
+        let free = self.free_bytes();
+        if let Some(watermark) = &mut self.watermark {
+            let below = if watermark.below {
+                free < max(watermark.low, watermark.recover)
+            } else {
+                free < watermark.low
+            };
+
+            if below != watermark.below {
+                watermark.below = below;
+                if let Some(callback) = watermark.callback {
+                    callback(below);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    static RECOVERED: AtomicUsize = AtomicUsize::new(0);
+
+    fn notify(below: bool) {
+        if below {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            RECOVERED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_low_watermark() {
+        unsafe {
+            let heap_size = 256;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            heap.set_low_watermark(96);
+            heap.set_watermark_recovery(160);
+            heap.set_watermark_callback(notify);
+            let counts = || {
+                (
+                    DROPPED.load(Ordering::Relaxed),
+                    RECOVERED.load(Ordering::Relaxed),
+                )
+            };
+
+            // Drop below 96 free bytes, then wobble around the watermark.
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let mut blocks: std::vec::Vec<_> =
+                (0..11).map(|_| heap.allocate(small).unwrap()).collect();
+            assert!(heap.below_watermark());
+            assert_eq!((1, 0), counts());
+            for _ in 0..5 {
+                heap.deallocate(blocks.pop().unwrap(), small);
+                heap.deallocate(blocks.pop().unwrap(), small);
+                blocks.push(heap.allocate(small).unwrap());
+                blocks.push(heap.allocate(small).unwrap());
+            }
+            assert_eq!((1, 0), counts());
+
+            // Climbing back over the watermark isn't enough to recover.
+            for _ in 0..3 {
+                heap.deallocate(blocks.pop().unwrap(), small);
+            }
+            assert_eq!(128, heap.free_bytes());
+            assert!(heap.below_watermark());
+
+            // Getting past the recovery threshold is, and then falling
+            // back just under it doesn't count as dropping again.
+            for _ in 0..4 {
+                heap.deallocate(blocks.pop().unwrap(), small);
+            }
+            assert!(!heap.below_watermark());
+            assert_eq!((1, 1), counts());
+            for _ in 0..3 {
+                blocks.push(heap.allocate(small).unwrap());
+                heap.deallocate(blocks.pop().unwrap(), small);
+            }
+            blocks.push(heap.allocate(small).unwrap());
+            assert_eq!((1, 1), counts());
+
+            // A second trip down and back is reported once each way.
+            let big = heap
+                .allocate(Layout::from_size_align(128, 1).unwrap())
+                .unwrap();
+            assert_eq!((2, 1), counts());
+            heap.deallocate(big, Layout::from_size_align(128, 1).unwrap());
+            assert_eq!((2, 2), counts());
+
+            for block in blocks {
+                heap.deallocate(block, small);
+            }
+            assert_eq!((2, 2), counts());
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
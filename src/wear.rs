@@ -0,0 +1,226 @@
+//! Wear leveling, for heaps in memory with limited write endurance, such
+//! as FRAM or flash-backed RAM.  Normally the same low addresses are handed
+//! out over and over; this spreads allocations across the whole heap.
+use crate::heap::{free_list_for_each, free_list_insert, free_list_pop, free_list_remove};
+use crate::{Heap, HeapError};
+
+/// The wear leveling state of a heap.
+#[derive(Debug)]
+pub(crate) struct WearLeveling<const N: usize> {
+    /// Whether allocations rotate through the heap.
+    enabled: bool,
+    /// For each order, the offset just past the last block of that order
+    /// handed out while rotating, which is where the next search starts.
+    cursors: [usize; N],
+    /// How many times each minimum-sized block has been allocated.
+    counts: Option<&'static mut [u32]>,
+}
+
+impl<const N: usize> WearLeveling<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            enabled: false,
+            cursors: [0; N],
+            counts: None,
+        }
+    }
+}
+
+impl<const N: usize> Heap<N> {
+    /// Turn allocation rotation on or off.  While it's on, [Heap::allocate]
+    /// keeps a cursor for each order, and hands out the free block at or
+    /// after it (wrapping around at the end of the heap) rather than the
+    /// one at the head of the free list.  A larger block is split towards
+    /// the cursor, so consecutive allocations of the same order sweep
+    /// through the heap, splitting a different large block each time round.
+    ///
+    /// Only the choice between free blocks changes, so the buddy
+    /// invariants hold either way, but finding the block takes time linear
+    /// in the number of free blocks, and the heap may fragment more.
+    pub fn set_wear_leveling(&mut self, enabled: bool) {
+        self.wear.enabled = enabled;
+    }
+
+    /// Register a table in which to count how many times each minimum-sized
+    /// block is allocated, to check how evenly a workload wears the heap.
+    /// Entry `i` counts the block at `i * min_block_size`; an allocation of
+    /// a larger block counts once for every minimum-sized block in it.
+    /// The counts saturate rather than wrap.
+    ///
+    /// The table needs one entry per minimum-sized block (see
+    /// [Heap::order_table_len]), and is zeroed when it's registered.
+    /// Returns the table registered before, if any.
+    pub fn set_wear_counters(
+        &mut self,
+        table: &'static mut [u32],
+    ) -> Result<Option<&'static mut [u32]>, HeapError> {
+        if table.len() < self.heap_size >> self.min_block_size_log2 {
+            return Err(HeapError::MetadataTooSmall);
+        }
+
+        table.fill(0);
+        Ok(self.wear.counts.replace(table))
+    }
+
+    /// The table registered with [Heap::set_wear_counters], if any.
+    pub fn wear_counters(&self) -> Option<&[u32]> {
+        self.wear.counts.as_deref()
+    }
+
+    /// Returns true if [Heap::allocate] should use
+    /// [Heap::allocate_rotating].
+    pub(crate) fn wear_leveling(&self) -> bool {
+        self.wear.enabled
+    }
+
+    /// Count an allocation of the block of order `order` at `block`.
+    pub(crate) fn count_wear(&mut self, block: *mut u8, order: usize) {
+        if let Some(counts) = self.wear.counts.as_deref_mut() {
+            let first = (block as usize - self.heap_base as usize) >> self.min_block_size_log2;
+            for count in counts.iter_mut().skip(first).take(1 << order) {
+                *count = count.saturating_add(1);
+            }
+        }
+    }
+
+    /// Take a block of order `order_needed` from the free lists, starting
+    /// from that order's cursor, as described in [Heap::set_wear_leveling].
+    /// Returns `None` if the heap is exhausted.
+    pub(crate) fn allocate_rotating(&mut self, order_needed: usize) -> Option<*mut u8> {
+        // Take the free block which would put the allocation nearest after
+        // the cursor, of any order, and only wrap around if there isn't one.
+        let mut cursor = self.wear.cursors[order_needed];
+        let find = |cursor| {
+            (order_needed..N)
+                .filter_map(|order| {
+                    self.next_free_from(order, cursor)
+                        .map(|(lands, block)| (lands, block, order))
+                })
+                .min_by_key(|&(lands, _, _)| lands)
+                .map(|(_, block, order)| (block, order))
+        };
+        let (mut block, mut order) = match find(cursor) {
+            Some(found) => found,
+            None => {
+                cursor = 0;
+                find(cursor)?
+            }
+        };
+
+        // SAFETY: The block was just found on the free list for `order`.
+        unsafe {
+            if order == N - 1 {
+                free_list_pop(&mut self.free_lists, order);
+            } else {
+                free_list_remove(&mut self.free_lists, order, block);
+            }
+
+            // Split it down, keeping whichever half the cursor is in, or
+            // the lower half if it's in neither.
+            while order > order_needed {
+                order -= 1;
+                let upper = block.add(self.order_size(order));
+                if cursor >= upper as usize - self.heap_base as usize {
+                    free_list_insert(&mut self.free_lists, order, block);
+                    block = upper;
+                } else {
+                    free_list_insert(&mut self.free_lists, order, upper);
+                }
+            }
+        }
+
+        let end = block as usize - self.heap_base as usize + self.order_size(order_needed);
+        self.wear.cursors[order_needed] = end % self.heap_size;
+        Some(block)
+    }
+
+    /// The lowest free block of order `order` which ends after `cursor`,
+    /// along with the offset an allocation from it would land at: the
+    /// cursor if the block contains it, and the block's start if not.
+    fn next_free_from(&self, order: usize, cursor: usize) -> Option<(usize, *mut u8)> {
+        let size = self.order_size(order);
+        let mut next: Option<(usize, *mut u8)> = None;
+        free_list_for_each(&self.free_lists, order, |block| {
+            let offset = block as usize - self.heap_base as usize;
+            if offset + size > cursor && next.is_none_or(|(_, best)| block < best) {
+                next = Some((offset.max(cursor), block));
+            }
+        });
+        next
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    fn leak(len: usize) -> &'static mut [u32] {
+        std::boxed::Box::leak(std::vec![0; len].into_boxed_slice())
+    }
+
+    /// Allocate and free `rounds` times, keeping a few blocks live, and
+    /// return the per-block allocation counts.
+    fn churn(heap: &mut Heap<9>, rounds: usize) -> Vec<u32> {
+        let small = Layout::from_size_align(16, 16).unwrap();
+        let mut live = Vec::new();
+        for i in 0..rounds {
+            live.push(heap.allocate(small).unwrap());
+            if live.len() > 4 {
+                unsafe { heap.deallocate(live.remove(i % 4), small) };
+            }
+            heap.assert_alignment_invariants();
+        }
+        for block in live {
+            unsafe { heap.deallocate(block, small) };
+        }
+        assert_eq!(0, heap.used_bytes());
+        assert!(heap.accounting_check());
+        heap.wear_counters().unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_wear_leveling() {
+        unsafe {
+            let heap_size = 4096;
+            let layout = std::alloc::Layout::from_size_align(heap_size, 4096).unwrap();
+            let mem = std::alloc::alloc(layout);
+            let mut heap: Heap<9> = Heap::new(NonNull::new(mem).unwrap(), heap_size).unwrap();
+            assert_eq!(
+                Err(HeapError::MetadataTooSmall),
+                heap.set_wear_counters(leak(255)).map(|_| ())
+            );
+
+            // Normally a handful of low blocks take all of the wear.
+            heap.set_wear_counters(leak(256)).unwrap();
+            let counts = churn(&mut heap, 2560);
+            assert_eq!(2560, counts.iter().sum::<u32>());
+            assert!(counts.iter().filter(|&&c| c > 0).count() <= 8);
+
+            // Rotating spreads it evenly over every block.
+            assert!(heap.set_wear_counters(leak(256)).unwrap().is_some());
+            heap.set_wear_leveling(true);
+            let counts = churn(&mut heap, 2560);
+            assert_eq!(2560, counts.iter().sum::<u32>());
+            let (min, max) = (counts.iter().min().unwrap(), counts.iter().max().unwrap());
+            assert!(*min >= 8 && *max <= 12, "{} to {}: {:?}", min, max, counts);
+
+            // Larger blocks rotate too, and count for every block in them.
+            let big = Layout::from_size_align(256, 16).unwrap();
+            let first = heap.allocate(big).unwrap();
+            let second = heap.allocate(big).unwrap();
+            assert_eq!(first.add(256), second);
+            heap.deallocate(first, big);
+            heap.deallocate(second, big);
+            let counts = heap.wear_counters().unwrap();
+            let offset = (first as usize - mem as usize) / 16;
+            assert!(counts[offset..offset + 32].iter().all(|&c| c >= 9));
+
+            drop(heap);
+            std::alloc::dealloc(mem, layout);
+        }
+    }
+}
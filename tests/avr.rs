@@ -0,0 +1,44 @@
+//! Build test for 16-bit targets.
+//!
+//! Builds the library for an ATmega328P (`avr-none`), where `usize` is 16
+//! bits wide, to make sure nothing assumes a wider one.  The arithmetic
+//! itself is tested at 16 bits by the unit tests in `src/math.rs`.
+//!
+//! AVR is a tier 3 target, so this needs `core` built from source, and is
+//! skipped (with a note on stderr) without it: `rustup component add
+//! rust-src`.
+use std::path::Path;
+use std::process::Command;
+
+const TARGET: &str = "avr-none";
+const CPU: &str = "atmega328p";
+
+fn rust_src_installed() -> bool {
+    let output = match Command::new("rustc").args(["--print", "sysroot"]).output() {
+        Ok(output) => output,
+        Err(_) => return false,
+    };
+    let sysroot = String::from_utf8_lossy(&output.stdout);
+    Path::new(sysroot.trim())
+        .join("lib/rustlib/src/rust/library/core")
+        .exists()
+}
+
+#[test]
+fn builds_for_avr() {
+    if !rust_src_installed() {
+        eprintln!("skipping: rust-src isn't installed");
+        return;
+    }
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(env!("CARGO"))
+        .current_dir(dir)
+        .args(["build", "--lib", "--release", "-Z", "build-std=core"])
+        .args(["--target", TARGET, "--target-dir"])
+        .arg(dir.join("target").join("avr"))
+        .env("RUSTFLAGS", format!("-C target-cpu={}", CPU))
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "failed to build for {}", TARGET);
+}
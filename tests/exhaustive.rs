@@ -0,0 +1,201 @@
+//! Exhaustive tests for a tiny heap.  Every sequence of up to `DEPTH`
+//! operations, each either an allocation of some order or the
+//! deallocation of some live block, is run against the heap and a simple
+//! reference model of which minimum-sized blocks are in use, and the two
+//! are checked against each other after every step.
+//!
+//! The search deepens one operation at a time, so a failure is reported
+//! with the shortest sequence that causes it.  Raise `DEPTH` (or
+//! `HEAP_SIZE` along with `ORDERS`) locally for a longer soak.
+use buddyalloc::Heap;
+use std::alloc::Layout;
+use std::ptr::{self, NonNull};
+
+const HEAP_SIZE: usize = 128;
+const ORDERS: usize = 4;
+const MIN_BLOCK_SIZE: usize = HEAP_SIZE >> (ORDERS - 1);
+const DEPTH: usize = 8;
+
+/// The number of minimum-sized blocks in the heap.
+const SLOTS: usize = HEAP_SIZE / MIN_BLOCK_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Allocate(usize),
+    Deallocate(usize),
+}
+
+fn layout(order: usize) -> Layout {
+    Layout::from_size_align(MIN_BLOCK_SIZE << order, 1).unwrap()
+}
+
+/// The reference model: which minimum-sized blocks are in use.
+#[derive(Clone, Copy)]
+struct Model {
+    used: [bool; SLOTS],
+}
+
+impl Model {
+    fn slots(order: usize, slot: usize) -> std::ops::Range<usize> {
+        slot..slot + (1 << order)
+    }
+
+    fn is_free(&self, order: usize, slot: usize) -> bool {
+        Self::slots(order, slot).all(|s| !self.used[s])
+    }
+
+    /// A buddy allocator which merges every pair of free buddies can
+    /// satisfy an allocation exactly when some aligned run of that size is
+    /// free.
+    fn can_allocate(&self, order: usize) -> bool {
+        (0..SLOTS)
+            .step_by(1 << order)
+            .any(|slot| self.is_free(order, slot))
+    }
+
+    fn set(&mut self, order: usize, slot: usize, used: bool) {
+        for s in Self::slots(order, slot) {
+            self.used[s] = used;
+        }
+    }
+}
+
+struct Search {
+    mem: *mut u8,
+    heap: Heap<ORDERS>,
+    model: Model,
+    live: Vec<(*mut u8, usize)>,
+    path: Vec<Op>,
+    sequences: usize,
+}
+
+impl Search {
+    fn slot(&self, block: *mut u8) -> usize {
+        (block as usize - self.mem as usize) / MIN_BLOCK_SIZE
+    }
+
+    fn apply(&mut self, op: Op) {
+        match op {
+            Op::Allocate(order) => {
+                let expected = self.model.can_allocate(order);
+                match self.heap.allocate(layout(order)) {
+                    Ok(block) => {
+                        assert!(expected, "{:?}: allocation should have failed", self.path);
+                        let slot = self.slot(block);
+                        assert_eq!(0, slot % (1 << order), "{:?}: misaligned block", self.path);
+                        assert!(
+                            slot + (1 << order) <= SLOTS && self.model.is_free(order, slot),
+                            "{:?}: block {} overlaps a live allocation",
+                            self.path,
+                            slot
+                        );
+                        self.model.set(order, slot, true);
+                        self.live.push((block, order));
+                    }
+                    Err(e) => assert!(!expected, "{:?}: {:?}", self.path, e),
+                }
+            }
+            Op::Deallocate(index) => {
+                let (block, order) = self.live.remove(index);
+                unsafe { self.heap.deallocate(block, layout(order)) };
+                let slot = self.slot(block);
+                self.model.set(order, slot, false);
+            }
+        }
+    }
+
+    fn check_invariants(&self) {
+        let used: usize = self
+            .live
+            .iter()
+            .map(|&(_, order)| MIN_BLOCK_SIZE << order)
+            .sum();
+        assert_eq!(used, self.heap.used_bytes(), "{:?}", self.path);
+        assert!(self.heap.accounting_check(), "{:?}", self.path);
+        self.heap.assert_alignment_invariants();
+
+        // Every free block is free in the model, and no two free buddies
+        // were left unmerged.
+        for order in 0..ORDERS {
+            for slot in (0..SLOTS).step_by(1 << order) {
+                let block = self.mem.wrapping_add(slot * MIN_BLOCK_SIZE);
+                if !self.heap.is_block_free(order, block) {
+                    continue;
+                }
+                assert!(self.model.is_free(order, slot), "{:?}", self.path);
+
+                let buddy = self
+                    .mem
+                    .wrapping_add((slot ^ (1 << order)) * MIN_BLOCK_SIZE);
+                assert!(
+                    order == ORDERS - 1 || !self.heap.is_block_free(order, buddy),
+                    "{:?}: free buddies at order {} weren't merged",
+                    self.path,
+                    order
+                );
+            }
+        }
+    }
+
+    /// Try every sequence of `remaining` more operations from here.
+    fn explore(&mut self, remaining: usize) {
+        if remaining == 0 {
+            self.sequences += 1;
+            return;
+        }
+
+        let ops = (0..ORDERS)
+            .map(Op::Allocate)
+            .chain((0..self.live.len()).map(Op::Deallocate))
+            .collect::<Vec<_>>();
+        for op in ops {
+            // SAFETY: The heap has no side tables, so it's plain data
+            // describing `mem`; saving both and putting them back restores
+            // it exactly.
+            let saved_heap = unsafe { ptr::read(&self.heap) };
+            let saved_mem: [u8; HEAP_SIZE] = unsafe { ptr::read(self.mem as *const _) };
+            let (saved_model, saved_live) = (self.model, self.live.clone());
+
+            self.path.push(op);
+            self.apply(op);
+            self.check_invariants();
+            self.explore(remaining - 1);
+            self.path.pop();
+
+            unsafe {
+                ptr::write(&mut self.heap, saved_heap);
+                ptr::write(self.mem as *mut [u8; HEAP_SIZE], saved_mem);
+            }
+            self.model = saved_model;
+            self.live = saved_live;
+        }
+    }
+}
+
+#[test]
+fn exhaustive_small_heap() {
+    let mem_layout = Layout::from_size_align(HEAP_SIZE, 4096).unwrap();
+    let mem = unsafe { std::alloc::alloc_zeroed(mem_layout) };
+    let heap = unsafe { Heap::new(NonNull::new(mem).unwrap(), HEAP_SIZE).unwrap() };
+    let mut search = Search {
+        mem,
+        heap,
+        model: Model {
+            used: [false; SLOTS],
+        },
+        live: Vec::new(),
+        path: Vec::new(),
+        sequences: 0,
+    };
+
+    for depth in 1..=DEPTH {
+        search.sequences = 0;
+        search.explore(depth);
+        assert!(search.sequences > 0);
+    }
+
+    // Everything was undone, so the heap is back to one free block.
+    assert!(search.heap.is_block_free(ORDERS - 1, mem));
+    drop(search);
+    unsafe { std::alloc::dealloc(mem, mem_layout) };
+}
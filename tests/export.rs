@@ -0,0 +1,113 @@
+//! Tests for the heap state exporters.  A small scripted heap's snapshots
+//! are compared against `tests/golden/heap.json` and `tests/golden/heap.dot`;
+//! run with `BLESS=1` to regenerate them after an intentional change.
+#![cfg(feature = "std")]
+
+use buddyalloc::Heap;
+use std::alloc::Layout;
+use std::path::Path;
+use std::ptr::NonNull;
+
+const HEAP_SIZE: usize = 256;
+
+/// Run `f` with a small heap in a known state: a 16-byte block, a 64-byte
+/// block and a 32-byte block allocated, in that order, and the second
+/// 16-byte block freed again.
+fn with_scripted_heap(track: bool, f: impl FnOnce(&mut Heap<5>)) {
+    let layout = Layout::from_size_align(HEAP_SIZE, 4096).unwrap();
+    unsafe {
+        let mem = std::alloc::alloc(layout);
+        let mut heap: Heap<5> = Heap::new(NonNull::new(mem).unwrap(), HEAP_SIZE).unwrap();
+        if track {
+            #[cfg(feature = "debug-track")]
+            heap.set_order_table(Box::leak(
+                vec![0; heap.order_table_len()].into_boxed_slice(),
+            ))
+            .unwrap();
+        }
+
+        let small = Layout::from_size_align(16, 16).unwrap();
+        heap.allocate(small).unwrap();
+        let freed = heap.allocate(small).unwrap();
+        heap.allocate(Layout::from_size_align(64, 8).unwrap())
+            .unwrap();
+        heap.allocate(Layout::from_size_align(32, 32).unwrap())
+            .unwrap();
+        heap.deallocate(freed, small);
+
+        f(&mut heap);
+        drop(heap);
+        std::alloc::dealloc(mem, layout);
+    }
+}
+
+/// Check `actual` against the golden file `name`, replacing the heap's
+/// address, which changes from run to run.
+fn check_golden(name: &str, actual: &[u8]) {
+    let actual: String = String::from_utf8(actual.to_vec())
+        .unwrap()
+        .lines()
+        .map(|line| {
+            if line.starts_with("\"heap_base\":") {
+                "\"heap_base\": \"<base>\",\n".to_string()
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect();
+
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("BLESS").is_some() {
+        std::fs::write(&golden, &actual).unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(golden).unwrap(), actual);
+}
+
+#[test]
+fn golden_json() {
+    with_scripted_heap(false, |heap| {
+        let mut out = Vec::new();
+        heap.export_json(&mut out).unwrap();
+        check_golden("heap.json", &out);
+    });
+}
+
+#[test]
+fn golden_dot() {
+    with_scripted_heap(false, |heap| {
+        let mut out = Vec::new();
+        heap.export_dot(&mut out).unwrap();
+        check_golden("heap.dot", &out);
+    });
+}
+
+#[cfg(feature = "debug-track")]
+#[test]
+fn tracked_allocations() {
+    with_scripted_heap(true, |heap| {
+        let mut json = Vec::new();
+        heap.export_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.ends_with(
+            "\"allocations\": [\n\
+             {\"offset\":0,\"order\":0},\n\
+             {\"offset\":32,\"order\":1},\n\
+             {\"offset\":64,\"order\":2}\n\
+             ]\n}\n"
+        ));
+
+        // Once the freed 16-byte block is reused, the first 64 bytes are
+        // completely allocated.  Only the order table can tell that they're
+        // three allocations rather than one, so they're still drawn split.
+        let small = Layout::from_size_align(16, 16).unwrap();
+        let p = heap.allocate(small).unwrap();
+        let mut dot = Vec::new();
+        heap.export_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("b2_0 [label=\"0x0\\n64 bytes\\nsplit\", fillcolor=white];"));
+        assert!(dot.contains("b0_16 [label=\"0x10\\n16 bytes\\nallocated\", fillcolor=salmon];"));
+        unsafe { heap.deallocate(p, small) };
+    });
+}
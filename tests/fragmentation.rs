@@ -0,0 +1,289 @@
+//! Fragmentation scenarios.  Each one replays a seeded, reproducible
+//! allocation pattern that's known to be hard on allocators against a fixed
+//! size heap, and then measures how fragmented the heap was left.  The
+//! results are compared against `tests/golden/fragmentation.txt`, so that a
+//! change to the allocation policy shows up as a change in these numbers;
+//! run with `BLESS=1` to record new ones after an intentional change.
+//!
+//! Sorted free lists hand out blocks in a different order, so they have
+//! their own golden file.
+#![cfg(feature = "std")]
+
+use buddyalloc::Heap;
+use std::alloc::Layout;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::mem::ManuallyDrop;
+use std::path::Path;
+use std::ptr::NonNull;
+
+const HEAP_SIZE: usize = 1 << 20;
+const ORDERS: usize = 16;
+const MIN_BLOCK_SIZE: usize = HEAP_SIZE >> (ORDERS - 1);
+
+/// How far a measured fragmentation ratio may drift from the recorded one.
+const RATIO_TOLERANCE: f64 = 0.02;
+
+/// How far a measured failure count may drift from the recorded one, as a
+/// fraction of the recorded count.
+const FAILURE_TOLERANCE: f64 = 0.05;
+
+/// A cheap deterministic pseudo-random number generator (xorshift64).
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `lo..hi`.
+    fn range(&mut self, lo: usize, hi: usize) -> usize {
+        lo + (self.next() % (hi - lo) as u64) as usize
+    }
+}
+
+/// A heap under test, the allocations still live in it, and how many
+/// failed.
+struct Scenario {
+    mem: *mut u8,
+    /// Dropped by hand, since it has to go before its memory does.
+    heap: ManuallyDrop<Heap<ORDERS>>,
+    live: Vec<(*mut u8, Layout)>,
+    failures: usize,
+}
+
+impl Scenario {
+    fn new() -> Self {
+        unsafe {
+            let mem = std::alloc::alloc(Self::layout());
+            let heap = Heap::new(NonNull::new(mem).unwrap(), HEAP_SIZE).unwrap();
+            Scenario {
+                mem,
+                heap: ManuallyDrop::new(heap),
+                live: Vec::new(),
+                failures: 0,
+            }
+        }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(HEAP_SIZE, 4096).unwrap()
+    }
+
+    /// Allocate `size` bytes, returning the index of the new allocation in
+    /// `live`, or `None` if it failed.
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = match self.heap.allocate(layout) {
+            Ok(ptr) => ptr,
+            Err(_) => {
+                self.failures += 1;
+                return None;
+            }
+        };
+        self.live.push((ptr, layout));
+        Some(self.live.len() - 1)
+    }
+
+    /// Free the live allocation at `index`.
+    fn free(&mut self, index: usize) {
+        let (ptr, layout) = self.live.remove(index);
+        unsafe { self.heap.deallocate(ptr, layout) };
+    }
+
+    /// The size of the largest block that can still be allocated.
+    fn largest_block(&self) -> usize {
+        self.heap
+            .available_orders()
+            .iter()
+            .rposition(|&available| available)
+            .map_or(0, |order| MIN_BLOCK_SIZE << order)
+    }
+
+    fn metrics(&self) -> Metrics {
+        let largest_block = self.largest_block();
+        let free_bytes = self.heap.free_bytes();
+        Metrics {
+            largest_block,
+            failures: self.failures,
+            fragmentation: if free_bytes == 0 {
+                0.0
+            } else {
+                1.0 - largest_block as f64 / free_bytes as f64
+            },
+        }
+    }
+}
+
+impl Drop for Scenario {
+    fn drop(&mut self) {
+        while !self.live.is_empty() {
+            self.free(self.live.len() - 1);
+        }
+        assert_eq!(0, self.heap.used_bytes());
+        unsafe {
+            ManuallyDrop::drop(&mut self.heap);
+            std::alloc::dealloc(self.mem, Self::layout());
+        }
+    }
+}
+
+/// What's measured at the end of a scenario.
+#[derive(Debug)]
+struct Metrics {
+    /// The largest block which could still be allocated.
+    largest_block: usize,
+    /// How many allocations failed along the way.
+    failures: usize,
+    /// External fragmentation: the fraction of free memory which isn't part
+    /// of the largest free block.
+    fragmentation: f64,
+}
+
+/// Interleave long-lived allocations with bursts of short-lived ones, so
+/// the survivors end up scattered across the heap.
+fn alternating_lifetimes() -> Metrics {
+    let mut s = Scenario::new();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..2000 {
+        s.allocate(rng.range(16, 512));
+        let burst: Vec<_> = (0..8)
+            .filter_map(|_| s.allocate(rng.range(16, 2048)))
+            .collect();
+        for index in burst.into_iter().rev() {
+            s.free(index);
+        }
+    }
+    s.metrics()
+}
+
+/// Ramp allocation sizes up and back down, freeing every other block of
+/// each ramp, so the holes left behind never fit the next ramp's peak.
+fn sawtooth_sizes() -> Metrics {
+    let mut s = Scenario::new();
+    let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+    for _ in 0..40 {
+        let first = s.live.len();
+        let peak = rng.range(8, 14);
+        for shift in (4..peak).chain((4..peak).rev()) {
+            s.allocate((1 << shift) + rng.range(0, 1 << shift));
+        }
+        for index in (first..s.live.len()).rev().step_by(2) {
+            s.free(index);
+        }
+    }
+    s.metrics()
+}
+
+/// Fill the heap to 90%, then keep replacing random allocations with new
+/// ones of random sizes.
+fn fill_then_churn() -> Metrics {
+    let mut s = Scenario::new();
+    let mut rng = Rng(0x6a09_e667_f3bc_c908);
+    while s.heap.used_bytes() < HEAP_SIZE / 10 * 9 {
+        if s.allocate(rng.range(16, 4096)).is_none() {
+            break;
+        }
+    }
+    for _ in 0..20_000 {
+        let victim = rng.range(0, s.live.len());
+        s.free(victim);
+        s.allocate(rng.range(16, 4096));
+    }
+    s.metrics()
+}
+
+/// A queue of messages freed in the order they were allocated, whose sizes
+/// slowly grow, so each new message tends not to fit where an old one was.
+fn producer_consumer_drift() -> Metrics {
+    let mut s = Scenario::new();
+    let mut rng = Rng(0xbb67_ae85_84ca_a73b);
+    let mut queue = VecDeque::new();
+    for step in 0..20_000 {
+        let mean = 64 + step / 8;
+        if let Some(index) = s.allocate(rng.range(mean / 2, mean * 3 / 2)) {
+            queue.push_back(s.live[index].0);
+        }
+        while queue.len() > 200 {
+            let oldest = queue.pop_front().unwrap();
+            let index = s.live.iter().position(|&(ptr, _)| ptr == oldest).unwrap();
+            s.free(index);
+        }
+    }
+    s.metrics()
+}
+
+type Run = fn() -> Metrics;
+
+const SCENARIOS: &[(&str, Run)] = &[
+    ("alternating_lifetimes", alternating_lifetimes),
+    ("sawtooth_sizes", sawtooth_sizes),
+    ("fill_then_churn", fill_then_churn),
+    ("producer_consumer_drift", producer_consumer_drift),
+];
+
+/// Parse one line of the golden file.
+fn parse(line: &str) -> (&str, Metrics) {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let value = |key: &str| {
+        fields
+            .iter()
+            .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+            .unwrap_or_else(|| panic!("no {} in {:?}", key, line))
+    };
+    let metrics = Metrics {
+        largest_block: value("largest_block").parse().unwrap(),
+        failures: value("failures").parse().unwrap(),
+        fragmentation: value("fragmentation").parse().unwrap(),
+    };
+    (fields[0], metrics)
+}
+
+#[test]
+fn fragmentation_scenarios() {
+    let mut actual = String::new();
+    for (name, scenario) in SCENARIOS {
+        let m = scenario();
+        writeln!(
+            actual,
+            "{} largest_block={} failures={} fragmentation={:.4}",
+            name, m.largest_block, m.failures, m.fragmentation
+        )
+        .unwrap();
+    }
+
+    let golden = if cfg!(feature = "sorted-free-lists") {
+        "tests/golden/fragmentation-sorted.txt"
+    } else {
+        "tests/golden/fragmentation.txt"
+    };
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join(golden);
+    if std::env::var_os("BLESS").is_some() {
+        std::fs::write(&golden, &actual).unwrap();
+    }
+
+    let expected = std::fs::read_to_string(golden).unwrap();
+    assert_eq!(
+        expected.lines().count(),
+        actual.lines().count(),
+        "scenarios changed; rerun with BLESS=1"
+    );
+    for (expected, actual) in expected.lines().zip(actual.lines()) {
+        let (name, want) = parse(expected);
+        let (got_name, got) = parse(actual);
+        assert_eq!(name, got_name, "scenarios changed; rerun with BLESS=1");
+
+        let failure_slack = (want.failures as f64 * FAILURE_TOLERANCE).ceil() as usize;
+        let ok = want.largest_block == got.largest_block
+            && want.failures.abs_diff(got.failures) <= failure_slack
+            && (want.fragmentation - got.fragmentation).abs() <= RATIO_TOLERANCE;
+        assert!(
+            ok,
+            "{}: expected {:?}, got {:?}; rerun with BLESS=1 if this is intended",
+            name, want, got
+        );
+    }
+}
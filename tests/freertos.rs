@@ -0,0 +1,19 @@
+//! Smoke test for the FreeRTOS port layer.  `tests/freertos/smoke.c` calls
+//! the heap functions as FreeRTOS would, through C declarations of them, so
+//! this checks the symbols' names and signatures as well as the plumbing.
+//! `build.rs` compiles it when the `freertos` feature is on.
+#![cfg(all(feature = "freertos", freertos_smoke))]
+
+// Pulls in the symbols the C code calls.
+use buddyalloc as _;
+
+#[link(name = "freertos_smoke", kind = "static")]
+extern "C" {
+    fn freertos_smoke() -> i32;
+}
+
+#[test]
+fn test_freertos_smoke() {
+    let line = unsafe { freertos_smoke() };
+    assert_eq!(0, line, "check at tests/freertos/smoke.c:{} failed", line);
+}
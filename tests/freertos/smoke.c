@@ -0,0 +1,50 @@
+/* The C side of the FreeRTOS smoke test: calls the heap functions through
+ * the declarations FreeRTOS's portable.h gives them, to check that the
+ * symbols and their types match.  Returns 0 on success, or the line of the
+ * first check that failed. */
+#include <stddef.h>
+#include <stdint.h>
+#include <string.h>
+
+void *pvPortMalloc(size_t xWantedSize);
+void vPortFree(void *pv);
+size_t xPortGetFreeHeapSize(void);
+size_t xPortGetMinimumEverFreeHeapSize(void);
+
+#define CHECK(condition) \
+    do { \
+        if (!(condition)) \
+            return __LINE__; \
+    } while (0)
+
+int freertos_smoke(void)
+{
+    size_t total = xPortGetFreeHeapSize();
+    CHECK(total > 0);
+    CHECK(xPortGetMinimumEverFreeHeapSize() == total);
+    CHECK(pvPortMalloc(0) == NULL);
+    CHECK(pvPortMalloc(total) == NULL);
+
+    /* A task's stack and TCB, say. */
+    uint8_t *stack = pvPortMalloc(1000);
+    void *tcb = pvPortMalloc(100);
+    CHECK(stack != NULL && tcb != NULL);
+    CHECK((uintptr_t)stack % 8 == 0 && (uintptr_t)tcb % 8 == 0);
+    memset(stack, 0xa5, 1000);
+    memset(tcb, 0x5a, 100);
+    size_t used = total - xPortGetFreeHeapSize();
+    CHECK(used >= 1100);
+    CHECK(xPortGetMinimumEverFreeHeapSize() == total - used);
+
+    vPortFree(tcb);
+    vPortFree(NULL);
+    vPortFree(stack);
+    CHECK(xPortGetFreeHeapSize() == total);
+    CHECK(xPortGetMinimumEverFreeHeapSize() == total - used);
+
+    /* Everything freed merges back, so the whole heap is available. */
+    void *all = pvPortMalloc(total / 2);
+    CHECK(all != NULL);
+    vPortFree(all);
+    return 0;
+}
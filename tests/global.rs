@@ -0,0 +1,139 @@
+//! Runs real standard library workloads with a [LockedHeap] installed as the
+//! global allocator, to catch interaction bugs the unit tests can't: every
+//! allocation in this binary, including the test harness's own, goes
+//! through the buddy heap.
+#![cfg(feature = "std")]
+
+use buddyalloc::{Heap, LockedHeap};
+use std::collections::{BTreeMap, HashMap};
+use std::ptr::addr_of_mut;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const HEAP_SIZE: usize = 64 << 20;
+
+/// The heap's memory.  It lives in `.bss`, so the OS maps it in lazily as
+/// anonymous pages, just like an `mmap`'d region.
+#[repr(align(4096))]
+struct Memory([u8; HEAP_SIZE]);
+
+static mut MEMORY: Memory = Memory([0; HEAP_SIZE]);
+
+// 22 orders gives 32-byte minimum blocks.
+#[global_allocator]
+static ALLOCATOR: LockedHeap<22> =
+    LockedHeap::new(unsafe { Heap::new_unchecked(addr_of_mut!(MEMORY.0) as *mut u8, HEAP_SIZE) });
+
+/// A cheap deterministic pseudo-random sequence (xorshift64).
+fn shuffled(count: usize) -> impl Iterator<Item = u64> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..count).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    })
+}
+
+// Freeing a block searches its buddy's free list, which is linear in the
+// number of free blocks of that order, so the maps are kept fairly small.
+fn maps() {
+    let btree: BTreeMap<u64, String> = shuffled(20_000).map(|k| (k, k.to_string())).collect();
+    assert_eq!(20_000, btree.len());
+    assert!(btree.iter().all(|(k, v)| v.parse::<u64>().unwrap() == *k));
+
+    let mut hash: HashMap<String, Vec<u64>> = HashMap::new();
+    for k in shuffled(20_000) {
+        hash.entry(format!("{:x}", k % 1024)).or_default().push(k);
+    }
+    assert_eq!(1024, hash.len());
+    assert_eq!(20_000, hash.values().map(Vec::len).sum::<usize>());
+}
+
+fn formatting() {
+    let mut text = String::new();
+    for (i, k) in shuffled(10_000).enumerate() {
+        text += &format!("{:>8} {:#018x} {:?}\n", i, k, (k as f64).sqrt());
+    }
+    assert_eq!(10_000, text.lines().count());
+}
+
+fn sorting() {
+    let mut values: Vec<u64> = shuffled(1_000_000).collect();
+    values.sort();
+    assert!(values.windows(2).all(|w| w[0] <= w[1]));
+}
+
+fn shared_graphs() {
+    struct Node {
+        children: Vec<Rc<Node>>,
+    }
+
+    // A chain of nodes, each holding four references to the one below.
+    let mut level = vec![Rc::new(Node { children: vec![] })];
+    for _ in 0..16 {
+        let node = Rc::new(Node {
+            children: level.iter().chain(level.iter()).cloned().collect(),
+        });
+        level = vec![node.clone(), node];
+    }
+    assert_eq!(4, level[0].children.len());
+    drop(level);
+
+    let shared: Vec<Arc<Vec<u64>>> = (0..1000).map(|i| Arc::new(vec![i; 100])).collect();
+    let copies: Vec<_> = shared.iter().cycle().take(10_000).cloned().collect();
+    assert_eq!(11, Arc::strong_count(&shared[0]));
+    drop(copies);
+    assert!(shared.iter().all(|a| Arc::strong_count(a) == 1));
+}
+
+fn threads() {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let results = results.clone();
+            thread::spawn(move || {
+                let mut local = Vec::new();
+                for k in shuffled(20_000) {
+                    local.push(Box::new(k ^ t));
+                    if k % 3 == 0 {
+                        local.pop();
+                    }
+                }
+                let sum = local.iter().map(|b| **b).fold(0u64, u64::wrapping_add);
+                results.lock().unwrap().push(sum);
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(8, results.lock().unwrap().len());
+}
+
+#[test]
+fn test_std_workloads() {
+    // Let std set up whatever it keeps for the life of the process (thread
+    // bookkeeping and so on) before taking the snapshot.
+    thread::spawn(|| {}).join().unwrap();
+
+    let before = ALLOCATOR.lock().stats();
+
+    maps();
+    formatting();
+    sorting();
+    shared_graphs();
+    threads();
+
+    // Don't hold the lock while asserting: a failure message allocates.
+    let (delta, consistent) = {
+        let heap = ALLOCATOR.lock();
+        (heap.stats().diff(&before), heap.accounting_check())
+    };
+    assert_eq!(0, delta.used_bytes, "leaked: {:?}", delta);
+    assert_eq!(delta.allocations, delta.deallocations);
+    assert_eq!(0, delta.failures);
+    assert!(consistent);
+}
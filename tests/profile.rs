@@ -0,0 +1,113 @@
+//! Tests for the DHAT profiler.  The scripted workload's output is compared
+//! against `tests/golden/profile.json`; run with `BLESS=1` to regenerate it
+//! after an intentional change.
+#![cfg(feature = "profiling")]
+
+use buddyalloc::{Heap, LockedHeap, Profiled, Profiler};
+use std::alloc::{GlobalAlloc, Layout};
+use std::path::Path;
+use std::ptr::NonNull;
+
+const HEAP_SIZE: usize = 4096;
+
+/// Run `f` with a heap backed by memory from the system allocator.
+fn with_heap(f: impl FnOnce(LockedHeap<8>)) {
+    let layout = Layout::from_size_align(HEAP_SIZE, 4096).unwrap();
+    unsafe {
+        let mem = std::alloc::alloc(layout);
+        f(LockedHeap::new(
+            Heap::new(NonNull::new(mem).unwrap(), HEAP_SIZE).unwrap(),
+        ));
+        std::alloc::dealloc(mem, layout);
+    }
+}
+
+/// Replace the parts of the profile that change from run to run.
+fn normalize(json: &str) -> String {
+    json.lines()
+        .map(|line| {
+            if line.starts_with("\"cmd\":") {
+                "\"cmd\": \"<cmd>\","
+            } else if line.starts_with("\"pid\":") {
+                "\"pid\": 0,"
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+#[test]
+fn golden_profile() {
+    let profiler = Profiler::new(1);
+
+    with_heap(|heap| {
+        let small = Layout::from_size_align(16, 16).unwrap();
+        let large = Layout::from_size_align(256, 16).unwrap();
+
+        let mut smalls = Vec::new();
+        for _ in 0..4 {
+            let p = heap.lock().allocate(small).unwrap();
+            profiler.record_alloc(p, small.size());
+            smalls.push(p);
+        }
+
+        let big = heap.lock().allocate(large).unwrap();
+        profiler.record_alloc(big, large.size());
+
+        // Free half the small blocks, and the large one.
+        for p in smalls.drain(..2) {
+            profiler.record_dealloc(p);
+            unsafe { heap.lock().deallocate(p, small) };
+        }
+        profiler.record_dealloc(big);
+        unsafe { heap.lock().deallocate(big, large) };
+
+        let again = heap.lock().allocate(small).unwrap();
+        profiler.record_alloc(again, small.size());
+    });
+
+    let mut out = Vec::new();
+    profiler.write_json(&mut out).unwrap();
+    let actual = normalize(&String::from_utf8(out).unwrap());
+
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/profile.json");
+    if std::env::var_os("BLESS").is_some() {
+        std::fs::write(&golden, &actual).unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(golden).unwrap(), actual);
+}
+
+#[test]
+fn sampled_global_alloc() {
+    with_heap(|heap| {
+        let profiled = Profiled::new(heap, 2);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let blocks: Vec<_> = (0..6).map(|_| profiled.alloc(layout)).collect();
+            for &p in &blocks[..3] {
+                profiled.dealloc(p, layout);
+            }
+
+            let mut out = Vec::new();
+            profiled.profiler().write_json(&mut out).unwrap();
+            let json = String::from_utf8(out).unwrap();
+
+            // Every allocation came from the same call stack, and only every
+            // other one was sampled.
+            let pps: Vec<_> = json.lines().filter(|l| l.starts_with("{\"tb\"")).collect();
+            assert_eq!(1, pps.len());
+            assert!(pps[0].starts_with("{\"tb\":96,\"tbk\":3,"), "{}", pps[0]);
+            assert!(pps[0].contains("\"eb\":32,\"ebk\":1,"), "{}", pps[0]);
+            assert!(json.contains("\"te\": 6,"));
+            assert!(json.contains("\"[root]\""));
+
+            for &p in &blocks[3..] {
+                profiled.dealloc(p, layout);
+            }
+        }
+    });
+}
@@ -0,0 +1,114 @@
+//! Code size regression test.
+//!
+//! Builds the bare-metal program in `size-check/` for `thumbv6m-none-eabi`
+//! in both the default and `tiny` configurations, and checks how much flash
+//! each one uses against a fixed budget.  If you make the allocator
+//! legitimately bigger, bump the budget here and the table in the README.
+//!
+//! The test is skipped (with a note on stderr) if the target isn't
+//! installed: `rustup target add thumbv6m-none-eabi`.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const TARGET: &str = "thumbv6m-none-eabi";
+
+/// Flash budget, in bytes, for the default configuration.
+const DEFAULT_FLASH_BUDGET: usize = 1600;
+
+/// Flash budget, in bytes, for the `tiny` configuration.
+const TINY_FLASH_BUDGET: usize = 1600;
+
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u32 = 0x1;
+const SHF_ALLOC: u32 = 0x2;
+
+fn target_installed() -> bool {
+    let output = match Command::new("rustc").args(["--print", "sysroot"]).output() {
+        Ok(output) => output,
+        Err(_) => return false,
+    };
+    let sysroot = String::from_utf8_lossy(&output.stdout);
+    Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(TARGET)
+        .exists()
+}
+
+/// Build the size-check program and return the path to the binary.
+fn build(tiny: bool) -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("size-check");
+    let target_dir = dir
+        .join("target")
+        .join(if tiny { "tiny" } else { "default" });
+
+    // N.B: This has to run from inside `size-check` so that its
+    // `.cargo/config.toml` gets picked up.
+    let mut cmd = Command::new(env!("CARGO"));
+    cmd.current_dir(&dir)
+        .args(["build", "--release", "--target", TARGET, "--target-dir"])
+        .arg(&target_dir);
+    if tiny {
+        cmd.args(["--features", "tiny"]);
+    }
+
+    let status = cmd.status().expect("failed to run cargo");
+    assert!(status.success(), "failed to build size-check");
+
+    target_dir
+        .join(TARGET)
+        .join("release")
+        .join("buddyalloc-size-check")
+}
+
+/// Sum the sizes of all the sections of a 32-bit little-endian ELF file
+/// that end up in flash (allocated, read-only, and with file contents).
+fn flash_size(elf: &[u8]) -> usize {
+    let u16_at = |off: usize| u16::from_le_bytes([elf[off], elf[off + 1]]) as usize;
+    let u32_at =
+        |off: usize| u32::from_le_bytes([elf[off], elf[off + 1], elf[off + 2], elf[off + 3]]);
+
+    assert_eq!(&elf[..5], b"\x7fELF\x01", "not a 32-bit ELF file");
+
+    let shoff = u32_at(0x20) as usize;
+    let shentsize = u16_at(0x2e);
+    let shnum = u16_at(0x30);
+
+    (0..shnum)
+        .map(|i| shoff + i * shentsize)
+        .filter(|&sh| {
+            let ty = u32_at(sh + 4);
+            let flags = u32_at(sh + 8);
+            ty != SHT_NOBITS && flags & SHF_ALLOC != 0 && flags & SHF_WRITE == 0
+        })
+        .map(|sh| u32_at(sh + 20) as usize)
+        .sum()
+}
+
+fn check(tiny: bool, budget: usize) {
+    let elf = std::fs::read(build(tiny)).unwrap();
+    let size = flash_size(&elf);
+    let name = if tiny { "tiny" } else { "default" };
+
+    eprintln!(
+        "{} profile: {} bytes of flash (budget {})",
+        name, size, budget
+    );
+    assert!(
+        size <= budget,
+        "{} profile uses {} bytes of flash, over its budget of {}",
+        name,
+        size,
+        budget
+    );
+}
+
+#[test]
+fn flash_budget() {
+    if !target_installed() {
+        eprintln!("skipping: {} is not installed", TARGET);
+        return;
+    }
+
+    check(false, DEFAULT_FLASH_BUDGET);
+    check(true, TINY_FLASH_BUDGET);
+}